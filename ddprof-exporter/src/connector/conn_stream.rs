@@ -2,12 +2,14 @@
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
 
 use std::{
+    net::SocketAddr,
     pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
     task::{Context, Poll},
 };
 
 use futures::{future, Future, FutureExt, TryFutureExt};
-use hyper_rustls::HttpsConnector;
 use pin_project::pin_project;
 
 #[pin_project(project=ConnStreamProj)]
@@ -26,16 +28,117 @@ pub enum ConnStream {
         #[pin]
         transport: tokio::net::UnixStream,
     },
+    /// Any of the others, with the bytes written paced by `bucket`.
+    Throttled {
+        #[pin]
+        transport: Box<ConnStream>,
+        bucket: TokenBucket,
+    },
+    /// Any of the others, reporting whether anything was written to it.
+    Tracked {
+        #[pin]
+        transport: Box<ConnStream>,
+        tracker: Arc<WriteTracker>,
+    },
 }
 
 pub type ConnStreamError = Box<dyn std::error::Error + Send + Sync>;
 
-use hyper::{client::HttpConnector, service::Service};
+/// The addresses of the connection a response came on, found in the
+/// extensions of the responses of the exporter, for logging. Both are None
+/// for unix sockets. Through a proxy, the peer is the proxy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    pub local_addr: Option<SocketAddr>,
+    pub peer_addr: Option<SocketAddr>,
+}
+
+tokio::task_local! {
+    static WRITE_TRACKER: Arc<WriteTracker>;
+}
+
+/// Whether a connection was made for a request, and whether any of the
+/// request was written to it. When a connection was made and nothing was
+/// written, the request never reached the peer.
+#[derive(Debug, Default)]
+pub(crate) struct WriteTracker {
+    connected: AtomicBool,
+    written: AtomicBool,
+}
+
+impl WriteTracker {
+    /// Runs `future`, the connections the connector makes for it reporting
+    /// to this tracker. Connections made outside of the task of `future`
+    /// aren't tracked.
+    pub(crate) async fn scope<F: Future>(self: Arc<Self>, future: F) -> F::Output {
+        WRITE_TRACKER.scope(self, future).await
+    }
+
+    /// The tracker of the current task, if any, see `scope`.
+    pub(crate) fn current() -> Option<Arc<Self>> {
+        WRITE_TRACKER.try_with(Arc::clone).ok()
+    }
+
+    /// Whether any of the request was written to a connection.
+    pub(crate) fn written(&self) -> bool {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Whether a connection was made, and nothing was written to it.
+    pub(crate) fn nothing_written(&self) -> bool {
+        self.connected.load(Ordering::Relaxed) && !self.written.load(Ordering::Relaxed)
+    }
+}
+
+use super::throttle::{BandwidthLimit, TokenBucket};
+use super::{TcpConnector, TlsConnector};
+use hyper::service::Service;
 impl ConnStream {
+    /// The local address of the connection, None for unix sockets.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp { transport } => transport.local_addr().ok(),
+            Self::Tls { transport } => transport.get_ref().0.local_addr().ok(),
+            #[cfg(unix)]
+            Self::Udp { .. } => None,
+            Self::Throttled { transport, .. } | Self::Tracked { transport, .. } => {
+                transport.local_addr()
+            }
+        }
+    }
+
+    /// The remote address of the connection, None for unix sockets.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp { transport } => transport.peer_addr().ok(),
+            Self::Tls { transport } => transport.get_ref().0.peer_addr().ok(),
+            #[cfg(unix)]
+            Self::Udp { .. } => None,
+            Self::Throttled { transport, .. } | Self::Tracked { transport, .. } => {
+                transport.peer_addr()
+            }
+        }
+    }
+
+    pub fn throttled(self, limit: BandwidthLimit) -> ConnStream {
+        ConnStream::Throttled {
+            transport: Box::new(self),
+            bucket: TokenBucket::new(limit),
+        }
+    }
+
+    pub(crate) fn tracked(self, tracker: Arc<WriteTracker>) -> ConnStream {
+        tracker.connected.store(true, Ordering::Relaxed);
+        ConnStream::Tracked {
+            transport: Box::new(self),
+            tracker,
+        }
+    }
+
     pub async fn from_uds_uri(uri: hyper::Uri) -> Result<ConnStream, ConnStreamError> {
         #[cfg(unix)]
         {
-            let path = super::uds::socket_path_from_uri(&uri)?;
+            let path = super::uds::connect_path(super::uds::socket_path_from_uri(&uri)?)?;
             Ok(ConnStream::Udp {
                 transport: tokio::net::UnixStream::connect(path).await?,
             })
@@ -47,35 +150,34 @@ impl ConnStream {
     }
 
     pub fn from_http_connector_with_uri(
-        c: &mut HttpConnector,
+        c: &mut TcpConnector,
         uri: hyper::Uri,
     ) -> impl Future<Output = Result<ConnStream, ConnStreamError>> {
         c.call(uri).map(|r| match r {
             Ok(t) => Ok(ConnStream::Tcp { transport: t }),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         })
     }
 
-    pub fn from_https_connector_with_uri(
-        c: &mut HttpsConnector<HttpConnector>,
+    pub fn from_tls_connector_with_uri(
+        c: &mut TlsConnector,
         uri: hyper::Uri,
         require_tls: bool,
-    ) -> impl Future<Output = Result<ConnStream, ConnStreamError>> {
-        c.call(uri).and_then(move |stream| match stream {
-            // move only require_tls
-            hyper_rustls::MaybeHttpsStream::Http(t) => {
-                if require_tls {
-                    future::ready(Err(
-                        crate::errors::Error::CannotEstablishTlsConnection.into()
-                    ))
-                } else {
-                    future::ready(Ok(ConnStream::Tcp { transport: t }))
-                }
-            }
-            hyper_rustls::MaybeHttpsStream::Https(t) => future::ready(Ok(ConnStream::Tls {
-                transport: Box::from(t),
-            })),
-        })
+    ) -> future::Either<
+        impl Future<Output = Result<ConnStream, ConnStreamError>>,
+        impl Future<Output = Result<ConnStream, ConnStreamError>>,
+    > {
+        if require_tls {
+            c.connect_tls(uri)
+                .map_ok(|t| ConnStream::Tls {
+                    transport: Box::from(t),
+                })
+                .left_future()
+        } else {
+            c.connect_tcp(uri)
+                .map_ok(|t| ConnStream::Tcp { transport: t })
+                .right_future()
+        }
     }
 }
 
@@ -90,13 +192,19 @@ impl tokio::io::AsyncRead for ConnStream {
             ConnStreamProj::Tls { transport } => transport.poll_read(cx, buf),
             #[cfg(unix)]
             ConnStreamProj::Udp { transport } => transport.poll_read(cx, buf),
+            ConnStreamProj::Throttled { transport, .. } => transport.poll_read(cx, buf),
+            ConnStreamProj::Tracked { transport, .. } => transport.poll_read(cx, buf),
         }
     }
 }
 
 impl hyper::client::connect::Connection for ConnStream {
     fn connected(&self) -> hyper::client::connect::Connected {
-        match self {
+        let info = ConnectionInfo {
+            local_addr: self.local_addr(),
+            peer_addr: self.peer_addr(),
+        };
+        let connected = match self {
             Self::Tcp { transport } => transport.connected(),
             Self::Tls { transport } => {
                 let (tcp, _) = transport.get_ref();
@@ -104,7 +212,11 @@ impl hyper::client::connect::Connection for ConnStream {
             }
             #[cfg(unix)]
             Self::Udp { transport: _ } => hyper::client::connect::Connected::new(),
-        }
+            Self::Throttled { transport, .. } | Self::Tracked { transport, .. } => {
+                transport.connected()
+            }
+        };
+        connected.extra(info)
     }
 }
 
@@ -119,6 +231,21 @@ impl tokio::io::AsyncWrite for ConnStream {
             ConnStreamProj::Tls { transport } => transport.poll_write(cx, buf),
             #[cfg(unix)]
             ConnStreamProj::Udp { transport } => transport.poll_write(cx, buf),
+            ConnStreamProj::Throttled { transport, bucket } => {
+                let allowed = futures::ready!(bucket.poll_ready(cx, buf.len()));
+                let result = futures::ready!(transport.poll_write(cx, &buf[..allowed]));
+                if let Ok(written) = result {
+                    bucket.consume(written);
+                }
+                Poll::Ready(result)
+            }
+            ConnStreamProj::Tracked { transport, tracker } => {
+                let result = futures::ready!(transport.poll_write(cx, buf));
+                if matches!(result, Ok(written) if written > 0) {
+                    tracker.written.store(true, Ordering::Relaxed);
+                }
+                Poll::Ready(result)
+            }
         }
     }
 
@@ -131,6 +258,8 @@ impl tokio::io::AsyncWrite for ConnStream {
             ConnStreamProj::Tls { transport } => transport.poll_shutdown(cx),
             #[cfg(unix)]
             ConnStreamProj::Udp { transport } => transport.poll_shutdown(cx),
+            ConnStreamProj::Throttled { transport, .. } => transport.poll_shutdown(cx),
+            ConnStreamProj::Tracked { transport, .. } => transport.poll_shutdown(cx),
         }
     }
 
@@ -140,6 +269,8 @@ impl tokio::io::AsyncWrite for ConnStream {
             ConnStreamProj::Tls { transport } => transport.poll_flush(cx),
             #[cfg(unix)]
             ConnStreamProj::Udp { transport } => transport.poll_flush(cx),
+            ConnStreamProj::Throttled { transport, .. } => transport.poll_flush(cx),
+            ConnStreamProj::Tracked { transport, .. } => transport.poll_flush(cx),
         }
     }
 }
@@ -0,0 +1,335 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Tunnelling tcp connections through an HTTP proxy with the CONNECT method,
+//! see RFC 9110 section 9.3.6. Connections to http targets are tunnelled
+//! too, rather than sending the requests to the proxy in absolute form, so
+//! the proxy sees the same thing for both schemes.
+
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+use percent_encoding::percent_decode_str;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::conn_stream::ConnStreamError;
+
+/// Proxies reply with a few short headers, this is plenty.
+const MAX_REPLY_LEN: usize = 8 * 1024;
+
+/// An HTTP proxy, parsed from a `http://[user:password@]host[:port]` uri.
+#[derive(Clone, Eq, PartialEq)]
+pub struct HttpProxy {
+    /// The address of the proxy, as an http uri for the tcp connector.
+    pub(crate) uri: hyper::Uri,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for HttpProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpProxy")
+            .field("uri", &self.uri)
+            .field(
+                "credentials",
+                &self
+                    .credentials
+                    .as_ref()
+                    .map(|(user, _)| (user, "<redacted>")),
+            )
+            .finish()
+    }
+}
+
+impl FromStr for HttpProxy {
+    type Err = anyhow::Error;
+
+    /// Proxy variables commonly leave the scheme out, so `host:port` is
+    /// accepted too.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let uri: hyper::Uri = if s.contains("://") {
+            s.parse()?
+        } else {
+            format!("http://{}", s).parse()?
+        };
+        if uri.scheme_str() != Some("http") {
+            return Err(crate::errors::Error::InvalidUrl.into());
+        }
+        let authority = uri.authority().ok_or(crate::errors::Error::InvalidUrl)?;
+        let port = authority.port_u16().unwrap_or(80);
+
+        let credentials = match authority.as_str().rsplit_once('@') {
+            Some((userinfo, _)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                Some((
+                    percent_decode_str(user).decode_utf8()?.into_owned(),
+                    percent_decode_str(password).decode_utf8()?.into_owned(),
+                ))
+            }
+            None => None,
+        };
+        if matches!(&credentials, Some((user, _)) if user.is_empty()) {
+            return Err(crate::errors::Error::InvalidUrl.into());
+        }
+
+        Ok(Self {
+            uri: format!("http://{}:{}", authority.host(), port).parse()?,
+            credentials,
+        })
+    }
+}
+
+/// A failure reported by the proxy, or a reply which doesn't make sense.
+#[derive(Debug)]
+struct HttpProxyError(String);
+
+impl fmt::Display for HttpProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http proxy: {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpProxyError {}
+
+impl HttpProxy {
+    /// Asks the proxy, over `stream`, to connect to `host` and `port`. Once
+    /// this succeeds, the stream is a tunnel to the target.
+    pub(crate) async fn connect<S>(
+        &self,
+        stream: &mut S,
+        host: &str,
+        port: u16,
+    ) -> Result<(), ConnStreamError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = format!(
+            "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+            host = host,
+            port = port
+        );
+        if let Some((user, password)) = &self.credentials {
+            let token = base64::encode(format!("{}:{}", user, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read the reply a byte at a time, as anything after it belongs to
+        // the tunnel.
+        let mut reply = Vec::new();
+        while !reply.ends_with(b"\r\n\r\n") {
+            if reply.len() == MAX_REPLY_LEN {
+                return Err(HttpProxyError("reply too long".to_owned()).into());
+            }
+            reply.push(stream.read_u8().await?);
+        }
+
+        let reply = String::from_utf8_lossy(&reply);
+        let status_line = reply.lines().next().unwrap_or_default();
+        // The status, followed by the reason phrase
+        let status = match status_line.split_once(' ') {
+            Some((version, status)) if version.starts_with("HTTP/1.") => status,
+            _ => return Err(HttpProxyError("invalid reply".to_owned()).into()),
+        };
+        if !status.starts_with('2') {
+            return Err(HttpProxyError(format!(
+                "CONNECT to {}:{} refused with {}",
+                host, port, status
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Which HTTP proxies tcp connections go through, depending on the scheme
+/// of the target. Build it explicitly, or with `from_env` to follow the
+/// `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` variables.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProxyConfig {
+    /// The proxy for http targets.
+    pub http: Option<HttpProxy>,
+    /// The proxy for https targets, which are still encrypted end to end.
+    pub https: Option<HttpProxy>,
+    /// Hosts connected to directly: an entry matches the host itself and
+    /// its subdomains, and `*` matches every host. Ports are ignored.
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    /// The configuration of the usual proxy variables, lower case ones
+    /// taking precedence. Unset or invalid proxies are left out.
+    pub fn from_env() -> Self {
+        Self::default().with_env_fallback()
+    }
+
+    /// Fills in whatever this configuration leaves unset from the proxy
+    /// variables, see `from_env`.
+    pub fn with_env_fallback(self) -> Self {
+        self.with_fallback(|name| env::var(name).ok())
+    }
+
+    fn with_fallback<F: Fn(&str) -> Option<String>>(mut self, var: F) -> Self {
+        let lookup = |name: &str| {
+            var(&name.to_ascii_lowercase())
+                .or_else(|| var(name))
+                .filter(|value| !value.is_empty())
+        };
+        if self.http.is_none() {
+            self.http = lookup("HTTP_PROXY").and_then(|proxy| proxy.parse().ok());
+        }
+        if self.https.is_none() {
+            self.https = lookup("HTTPS_PROXY").and_then(|proxy| proxy.parse().ok());
+        }
+        if self.no_proxy.is_empty() {
+            self.no_proxy = lookup("NO_PROXY")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        self
+    }
+
+    /// The proxy to connect to `uri` through, if any.
+    pub(crate) fn proxy_for(&self, uri: &hyper::Uri) -> Option<&HttpProxy> {
+        let proxy = match uri.scheme_str() {
+            Some("https") => self.https.as_ref(),
+            _ => self.http.as_ref(),
+        }?;
+        let host = uri
+            .host()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        let bypassed = self.no_proxy.iter().any(|entry| {
+            let entry = entry.trim_start_matches('.');
+            // Drop the port of `host:port` and `[address]:port` entries
+            let entry = match entry.rsplit_once(':') {
+                Some((name, port))
+                    if (!name.contains(':') || name.ends_with(']'))
+                        && port.bytes().all(|b| b.is_ascii_digit()) =>
+                {
+                    name
+                }
+                _ => entry,
+            };
+            let entry = entry
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_ascii_lowercase();
+            entry == "*"
+                || host == entry
+                || (host.ends_with(&entry) && host[..host.len() - entry.len()].ends_with('.'))
+        });
+        if bypassed {
+            None
+        } else {
+            Some(proxy)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse() {
+        let proxy: HttpProxy = "http://proxy.local".parse().unwrap();
+        assert_eq!(proxy.uri, "http://proxy.local:80");
+        assert_eq!(proxy.credentials, None);
+
+        let proxy: HttpProxy = "user:p%40ss@10.0.0.1:3128".parse().unwrap();
+        assert_eq!(proxy.uri, "http://10.0.0.1:3128");
+        assert_eq!(
+            proxy.credentials,
+            Some(("user".to_owned(), "p@ss".to_owned()))
+        );
+        assert!(!format!("{:?}", proxy).contains("p@ss"));
+
+        assert!("socks5://proxy.local:1080".parse::<HttpProxy>().is_err());
+        assert!("http://:password@proxy.local".parse::<HttpProxy>().is_err());
+    }
+
+    #[test]
+    fn test_env_fallback() {
+        let vars: HashMap<&str, &str> = [
+            ("HTTP_PROXY", "http://upper.local:3128"),
+            ("https_proxy", "lower.local:3128"),
+            ("HTTPS_PROXY", "http://upper.local:3128"),
+            ("no_proxy", "localhost, .internal.example,10.0.0.1:8126"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let var = |name: &str| vars.get(name).map(|value| value.to_string());
+
+        let config = ProxyConfig::default().with_fallback(var);
+        assert_eq!(config.http, Some("upper.local:3128".parse().unwrap()));
+        assert_eq!(config.https, Some("lower.local:3128".parse().unwrap()));
+        assert_eq!(
+            config.no_proxy,
+            vec!["localhost", ".internal.example", "10.0.0.1:8126"]
+        );
+
+        // Explicit settings win
+        let explicit = ProxyConfig {
+            https: Some("explicit.local:8080".parse().unwrap()),
+            ..Default::default()
+        };
+        let config = explicit.with_fallback(var);
+        assert_eq!(config.https, Some("explicit.local:8080".parse().unwrap()));
+        assert_eq!(config.http, Some("upper.local:3128".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_proxy_for() {
+        let config = ProxyConfig {
+            http: Some("http-proxy.local:3128".parse().unwrap()),
+            https: Some("https-proxy.local:3128".parse().unwrap()),
+            no_proxy: vec![
+                "localhost".to_owned(),
+                ".internal.example".to_owned(),
+                "10.0.0.1:8126".to_owned(),
+                "[::1]:8126".to_owned(),
+            ],
+        };
+        let proxy_for = |uri: &'static str| {
+            config
+                .proxy_for(&hyper::Uri::from_static(uri))
+                .map(|proxy| proxy.uri.host().unwrap().to_owned())
+        };
+
+        assert_eq!(
+            proxy_for("http://intake.example").as_deref(),
+            Some("http-proxy.local")
+        );
+        assert_eq!(
+            proxy_for("https://intake.example").as_deref(),
+            Some("https-proxy.local")
+        );
+        assert_eq!(proxy_for("http://localhost:8126"), None);
+        assert_eq!(proxy_for("https://agent.internal.example"), None);
+        assert_eq!(proxy_for("https://internal.example"), None);
+        assert_eq!(proxy_for("http://10.0.0.1"), None);
+        assert_eq!(proxy_for("http://[::1]:8126"), None);
+        assert_eq!(
+            proxy_for("https://notinternal.example").as_deref(),
+            Some("https-proxy.local")
+        );
+
+        let everything = ProxyConfig {
+            no_proxy: vec!["*".to_owned()],
+            ..config.clone()
+        };
+        assert!(everything
+            .proxy_for(&hyper::Uri::from_static("https://intake.example"))
+            .is_none());
+    }
+}
@@ -2,30 +2,244 @@
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
 
 use futures::future::BoxFuture;
-use futures::{future, FutureExt};
+use futures::{future, FutureExt, TryFutureExt};
 use hyper::client::HttpConnector;
-use rustls::ClientConfig;
+use hyper::service::Service;
 use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 
 #[cfg(unix)]
 pub mod uds;
 
 mod conn_stream;
+mod http_proxy;
+pub mod proxy_protocol;
+mod resolver;
+mod socks5;
+mod throttle;
+mod tls;
+use crate::errors::ConnectError;
+pub use conn_stream::ConnectionInfo;
+pub(crate) use conn_stream::WriteTracker;
 use conn_stream::{ConnStream, ConnStreamError};
+pub use http_proxy::{HttpProxy, ProxyConfig};
+use proxy_protocol::ProxyHeader;
+use resolver::RecordingResolver;
+pub use resolver::SharedResolver;
+pub use socks5::Socks5Proxy;
+pub use throttle::BandwidthLimit;
+pub(crate) use tls::SharedTlsConfig;
+pub(crate) use tls::TlsMetrics;
+pub use tls::{IpTlsOptions, TlsConnector, TlsHandshakeStats};
 
 #[derive(Clone)]
 pub enum Connector {
-    Http(hyper::client::HttpConnector),
-    Https(hyper_rustls::HttpsConnector<hyper::client::HttpConnector>),
+    Http(TcpConnector),
+    Https(TlsConnector),
 }
 
-impl Connector {
+/// Settings of the connections made by a `Connector`. The tcp settings apply
+/// to tcp connections, with or without tls; they have no effect on unix
+/// sockets.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectorConfig {
+    pub tcp_keepalive: Option<Duration>,
+    pub tcp_nodelay: bool,
+    /// Not sent on connections through `socks5_proxy` or `http_proxy`, where
+    /// it would only name the proxy as the destination.
+    pub proxy_header: Option<ProxyHeader>,
+    pub socks5_proxy: Option<Socks5Proxy>,
+    /// Ignored if `socks5_proxy` is set.
+    pub http_proxy: Option<ProxyConfig>,
+    pub ip_tls: Option<IpTlsOptions>,
+    /// Connects to https uris on loopback addresses in plain text, for
+    /// agents on the same host, where tls only adds overhead. Each such
+    /// connection is counted in `TlsHandshakeStats::plaintext_downgrades`.
+    pub plaintext_loopback: bool,
+    /// Paces the bytes written to every connection, unix sockets included.
+    pub bandwidth_limit: Option<BandwidthLimit>,
+    /// Resolves the host names of tcp connections, and of SOCKS5 proxies.
+    pub resolver: SharedResolver,
+    /// Shared by the connectors built from clones of this config, so the
+    /// stats survive rebuilding the client.
+    pub tls_metrics: Arc<TlsMetrics>,
+    /// Not set if no root certificates could be loaded, in which case only
+    /// plain http connections are made.
+    pub tls: Option<SharedTlsConfig>,
+}
+
+impl ConnectorConfig {
+    /// The default settings, with tls verifying certificates against the
+    /// system root certificates, if any can be loaded.
     pub(crate) fn new() -> Self {
-        match build_https_connector() {
-            Ok(connector) => Connector::Https(connector),
-            Err(_) => Connector::Http(HttpConnector::new()),
+        Self {
+            tls: load_root_certs().ok().map(SharedTlsConfig::new),
+            ..Default::default()
+        }
+    }
+}
+
+/// Makes tcp connections, either directly, sending a PROXY protocol header
+/// first if one is configured, or through a SOCKS5 or HTTP proxy.
+#[derive(Clone)]
+pub struct TcpConnector {
+    resolver: SharedResolver,
+    tcp_keepalive: Option<Duration>,
+    tcp_nodelay: bool,
+    proxy_header: Option<ProxyHeader>,
+    socks5_proxy: Option<Socks5Proxy>,
+    http_proxy: Option<ProxyConfig>,
+    /// Set if https uris on loopback addresses are connected to in plain
+    /// text, to record the downgrades.
+    plaintext_loopback: Option<Arc<TlsMetrics>>,
+    /// Applies to all the connections of the `Connector`, not just tcp ones.
+    bandwidth_limit: Option<BandwidthLimit>,
+}
+
+impl hyper::service::Service<hyper::Uri> for TcpConnector {
+    type Response = tokio::net::TcpStream;
+    type Error = ConnStreamError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Each connection gets its own http connector, which is always ready
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        let host = uri.host().unwrap_or_default().to_owned();
+        let port = port_of(&uri);
+        let target = format!("{}:{}", host, port);
+        if let Some(proxy) = self.socks5_proxy.clone() {
+            let connecting = self.connect(proxy.uri.clone(), target, true);
+            return async move {
+                let mut stream = connecting.await?;
+                proxy.connect(&mut stream, &host, port).await?;
+                Ok(stream)
+            }
+            .boxed();
+        }
+        let http_proxy = self
+            .http_proxy
+            .as_ref()
+            .and_then(|config| config.proxy_for(&uri))
+            .cloned();
+        if let Some(proxy) = http_proxy {
+            let connecting = self.connect(proxy.uri.clone(), target, true);
+            return async move {
+                let mut stream = connecting.await?;
+                proxy.connect(&mut stream, &host, port).await?;
+                Ok(stream)
+            }
+            .boxed();
+        }
+
+        let connecting = self.connect(uri, target, false);
+        let proxy_header = self.proxy_header;
+        async move {
+            let mut stream = connecting.await?;
+            if let Some(proxy_header) = proxy_header {
+                let header = proxy_header.encode(stream.peer_addr()?);
+                stream.write_all(&header).await?;
+            }
+            Ok(stream)
+        }
+        .boxed()
+    }
+}
+
+impl TcpConnector {
+    /// Makes a tcp connection to `uri`, which is `target` or the proxy to it.
+    /// Failures are reported as a `ConnectError` with the addresses `uri`
+    /// resolved to, which were all attempted.
+    fn connect(
+        &self,
+        uri: hyper::Uri,
+        target: String,
+        proxied: bool,
+    ) -> BoxFuture<'static, Result<tokio::net::TcpStream, ConnStreamError>> {
+        let resolver = RecordingResolver::new(self.resolver.clone());
+        let mut http = HttpConnector::new_with_resolver(resolver.clone());
+        http.set_keepalive(self.tcp_keepalive);
+        http.set_nodelay(self.tcp_nodelay);
+        // The tls connector hands https uris to the tcp connector
+        http.enforce_http(false);
+
+        let port = port_of(&uri);
+        let literal = ip_literal(&uri).map(|ip| SocketAddr::new(ip, port));
+        let proxy = match proxied {
+            true => uri.authority().map(ToString::to_string),
+            false => None,
+        };
+        let connecting = http.call(uri);
+        async move {
+            connecting.await.map_err(|err| {
+                let addresses = match literal {
+                    // Not resolved, so not recorded either
+                    Some(address) => vec![address],
+                    None => resolver.resolved(port),
+                };
+                ConnectError {
+                    target,
+                    proxy,
+                    addresses,
+                    source: err.into(),
+                }
+                .into()
+            })
+        }
+        .boxed()
+    }
+
+    /// The `host:port` of the proxy connections to `uri` go through, if any.
+    fn proxy_for(&self, uri: &hyper::Uri) -> Option<String> {
+        let proxy_uri = match &self.socks5_proxy {
+            Some(proxy) => &proxy.uri,
+            None => &self.http_proxy.as_ref()?.proxy_for(uri)?.uri,
+        };
+        proxy_uri.authority().map(ToString::to_string)
+    }
+}
+
+impl Connector {
+    /// Creates a connector, falling back to plain http if the config has no
+    /// root certificates.
+    pub(crate) fn new(config: &ConnectorConfig) -> Self {
+        let tcp = TcpConnector {
+            resolver: config.resolver.clone(),
+            tcp_keepalive: config.tcp_keepalive,
+            tcp_nodelay: config.tcp_nodelay,
+            proxy_header: config.proxy_header,
+            socks5_proxy: config.socks5_proxy.clone(),
+            http_proxy: config.http_proxy.clone(),
+            plaintext_loopback: if config.plaintext_loopback {
+                Some(config.tls_metrics.clone())
+            } else {
+                None
+            },
+            bandwidth_limit: config.bandwidth_limit,
+        };
+
+        match &config.tls {
+            Some(tls) => Connector::Https(TlsConnector::new(
+                tcp,
+                tls,
+                config.ip_tls.as_ref(),
+                config.tls_metrics.clone(),
+            )),
+            None => Connector::Http(tcp),
+        }
+    }
+
+    fn tcp(&self) -> &TcpConnector {
+        match self {
+            Self::Http(c) => c,
+            Self::Https(c) => c.tcp(),
         }
     }
 
@@ -45,25 +259,89 @@ impl Connector {
                     ConnStream::from_http_connector_with_uri(c, uri).boxed()
                 }
             }
-            Self::Https(c) => {
-                ConnStream::from_https_connector_with_uri(c, uri, require_tls).boxed()
-            }
+            Self::Https(c) => ConnStream::from_tls_connector_with_uri(c, uri, require_tls).boxed(),
+        }
+    }
+}
+
+fn is_loopback(uri: &hyper::Uri) -> bool {
+    let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+    if host == "localhost" || host.ends_with(".localhost") {
+        return true;
+    }
+    let address = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>();
+    matches!(address, Ok(address) if address.is_loopback())
+}
+
+/// Turns an https uri into an http one for the same port.
+fn downgrade_to_http(uri: hyper::Uri) -> Result<hyper::Uri, ConnStreamError> {
+    let mut parts = uri.into_parts();
+    parts.scheme = Some(http::uri::Scheme::HTTP);
+    if let Some(authority) = &parts.authority {
+        if authority.port().is_none() {
+            parts.authority = Some(format!("{}:443", authority).parse()?);
         }
     }
+    Ok(hyper::Uri::from_parts(parts)?)
+}
+
+fn ip_literal(uri: &hyper::Uri) -> Option<IpAddr> {
+    let host = uri.host().unwrap_or_default();
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .ok()
+}
+
+fn is_ip_literal(uri: &hyper::Uri) -> bool {
+    ip_literal(uri).is_some()
+}
+
+fn port_of(uri: &hyper::Uri) -> u16 {
+    uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    })
 }
 
-fn build_https_connector(
-) -> anyhow::Result<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>> {
-    let certs = load_root_certs()?;
-    let client_config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(certs)
-        .with_no_client_auth();
-    Ok(hyper_rustls::HttpsConnectorBuilder::new()
-        .with_tls_config(client_config)
-        .https_or_http()
-        .enable_http1()
-        .build())
+/// Wraps a connection failure into a `ConnectError` naming the target and the
+/// proxy to it, if the tcp connector didn't already, e.g. for tls handshake
+/// failures. Our own errors describe configuration problems rather than
+/// connection failures, so they are passed through untouched.
+fn annotate_connect_error(
+    uri: hyper::Uri,
+    proxy: Option<String>,
+    err: ConnStreamError,
+) -> ConnStreamError {
+    if err.is::<crate::errors::Error>() || err.is::<ConnectError>() {
+        return err;
+    }
+
+    #[cfg(unix)]
+    if uri.scheme_str() == Some("unix") {
+        let target = match uds::socket_path_from_uri(&uri) {
+            Ok(path) => format!("unix://{}", path.display()),
+            Err(_) => uri.to_string(),
+        };
+        return ConnectError {
+            target,
+            proxy: None,
+            addresses: vec![],
+            source: err,
+        }
+        .into();
+    }
+
+    ConnectError {
+        target: format!("{}:{}", uri.host().unwrap_or_default(), port_of(&uri)),
+        proxy,
+        addresses: vec![],
+        source: err,
+    }
+    .into()
 }
 
 fn load_root_certs() -> anyhow::Result<rustls::RootCertStore> {
@@ -91,16 +369,43 @@ impl hyper::service::Service<hyper::Uri> for Connector {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&mut self, uri: hyper::Uri) -> Self::Future {
-        match uri.scheme_str() {
+        let target = uri.clone();
+        let proxy = self.tcp().proxy_for(&uri);
+        let plaintext_loopback = self.tcp().plaintext_loopback.clone();
+        let bandwidth_limit = self.tcp().bandwidth_limit;
+        let tracker = WriteTracker::current();
+        let stream = match uri.scheme_str() {
             Some("unix") => conn_stream::ConnStream::from_uds_uri(uri).boxed(),
+            Some("https") if plaintext_loopback.is_some() && is_loopback(&uri) => {
+                match downgrade_to_http(uri) {
+                    Ok(uri) => {
+                        if let Some(metrics) = plaintext_loopback {
+                            metrics.record_downgrade();
+                        }
+                        self.build_conn_stream(uri, false)
+                    }
+                    Err(err) => future::err(err).boxed(),
+                }
+            }
             Some("https") => self.build_conn_stream(uri, true),
             _ => self.build_conn_stream(uri, false),
-        }
+        };
+        stream
+            .map_err(|err| annotate_connect_error(target, proxy, err))
+            .map_ok(move |stream| match bandwidth_limit {
+                Some(limit) => stream.throttled(limit),
+                None => stream,
+            })
+            .map_ok(move |stream| match tracker {
+                Some(tracker) => stream.tracked(tracker),
+                None => stream,
+            })
+            .boxed()
     }
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         match self {
-            Connector::Http(c) => c.poll_ready(cx).map_err(|e| e.into()),
+            Connector::Http(c) => c.poll_ready(cx),
             Connector::Https(c) => c.poll_ready(cx),
         }
     }
@@ -110,6 +415,7 @@ impl hyper::service::Service<hyper::Uri> for Connector {
 mod tests {
     use hyper::service::Service;
     use std::env;
+    use tokio::io::AsyncReadExt;
 
     use super::*;
 
@@ -117,7 +423,8 @@ mod tests {
     /// Verify that the Connector type implements the correct bound Connect + Clone
     /// to be able to use the hyper::Client
     fn test_hyper_client_from_connector() {
-        let _: hyper::Client<Connector> = hyper::Client::builder().build(Connector::new());
+        let _: hyper::Client<Connector> =
+            hyper::Client::builder().build(Connector::new(&ConnectorConfig::new()));
     }
 
     #[tokio::test]
@@ -128,7 +435,7 @@ mod tests {
         let old_value = env::var(ENV_SSL_CERT_FILE).unwrap_or_default();
 
         env::set_var(ENV_SSL_CERT_FILE, "this/folder/does/not/exist");
-        let mut connector = Connector::new();
+        let mut connector = Connector::new(&ConnectorConfig::new());
         assert!(matches!(connector, Connector::Http(_)));
 
         let stream = connector
@@ -143,4 +450,662 @@ mod tests {
 
         env::set_var(ENV_SSL_CERT_FILE, old_value);
     }
+
+    #[tokio::test]
+    /// Verify that connection errors name the address which was attempted
+    async fn test_connection_error_names_address() {
+        // Bind and immediately drop a listener to find a port nothing listens on
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut connector = Connector::new(&ConnectorConfig::default());
+        let uri: hyper::Uri = format!("http://{}", address).parse().unwrap();
+        let err = connector.call(uri).await.unwrap_err();
+
+        let message = err.to_string();
+        assert!(
+            message.contains(&format!("attempted {}", address)),
+            "unexpected error message: {}",
+            message
+        );
+        let err = err.downcast::<ConnectError>().unwrap();
+        assert_eq!(err.target, address.to_string());
+        assert_eq!(err.proxy, None);
+        assert_eq!(err.addresses, vec![address]);
+
+        // Host names report the addresses resolved for the connection,
+        // without looking them up again
+        let resolver = SharedResolver::new(Duration::from_secs(60));
+        let mut connector = Connector::new(&ConnectorConfig {
+            resolver: resolver.clone(),
+            ..Default::default()
+        });
+        let uri: hyper::Uri = format!("http://localhost:{}", address.port())
+            .parse()
+            .unwrap();
+        let err = connector.call(uri).await.unwrap_err();
+        let err = err.downcast::<ConnectError>().unwrap();
+        assert_eq!(err.target, format!("localhost:{}", address.port()));
+        assert!(err.addresses.contains(&address), "{:?}", err.addresses);
+        assert_eq!(resolver.lookups(), 1);
+    }
+
+    #[tokio::test]
+    /// Verify that connection errors name the proxy, and the addresses of
+    /// the proxy which were attempted
+    async fn test_connection_error_names_proxy() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut connector = Connector::new(&ConnectorConfig {
+            socks5_proxy: Some(format!("socks5://{}", address).parse().unwrap()),
+            ..Default::default()
+        });
+        let uri = hyper::Uri::from_static("http://intake.example:8126");
+        let err = connector.call(uri).await.unwrap_err();
+
+        let message = err.to_string();
+        assert!(
+            message.starts_with(&format!(
+                "cannot connect to intake.example:8126 through proxy {} (attempted {})",
+                address, address
+            )),
+            "unexpected error message: {}",
+            message
+        );
+        let err = err.downcast::<ConnectError>().unwrap();
+        assert_eq!(err.proxy, Some(address.to_string()));
+        assert_eq!(err.addresses, vec![address]);
+    }
+
+    #[tokio::test]
+    /// Verify that connections report the addresses they are between
+    async fn test_local_and_peer_addr() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut connector = Connector::new(&ConnectorConfig {
+            bandwidth_limit: Some(BandwidthLimit {
+                bytes_per_second: 1_000_000,
+                burst: 1_000,
+            }),
+            ..Default::default()
+        });
+        let uri: hyper::Uri = format!("http://{}", address).parse().unwrap();
+        let (accepted, stream) = tokio::join!(listener.accept(), connector.call(uri));
+        let (_, client_address) = accepted.unwrap();
+        let stream = stream.unwrap();
+
+        assert!(matches!(stream, ConnStream::Throttled { .. }));
+        assert_eq!(stream.peer_addr(), Some(address));
+        assert_eq!(stream.local_addr(), Some(client_address));
+    }
+
+    #[tokio::test]
+    /// Verify that tcp keepalive and nodelay are set on the connections made
+    async fn test_tcp_keepalive_and_nodelay() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        for (keepalive, nodelay) in [(Some(Duration::from_secs(30)), true), (None, false)] {
+            let mut connector = Connector::new(&ConnectorConfig {
+                tcp_keepalive: keepalive,
+                tcp_nodelay: nodelay,
+                ..Default::default()
+            });
+            let uri: hyper::Uri = format!("http://{}", address).parse().unwrap();
+            let stream = connector.call(uri).await.unwrap();
+
+            let transport = match &stream {
+                ConnStream::Tcp { transport } => transport,
+                _ => panic!("expected a tcp connection, got {:?}", stream),
+            };
+            assert_eq!(transport.nodelay().unwrap(), nodelay);
+            assert_eq!(
+                socket2::SockRef::from(transport).keepalive().unwrap(),
+                keepalive.is_some()
+            );
+        }
+    }
+
+    /// Builds an acceptor with the test certificate for agent.local, and a
+    /// connector which trusts it.
+    fn test_tls(
+        ip_tls: Option<&IpTlsOptions>,
+        metrics: &Arc<TlsMetrics>,
+    ) -> (tokio_rustls::TlsAcceptor, Connector) {
+        test_tls_via(ip_tls, metrics, None)
+    }
+
+    /// Same as `test_tls`, with the connector going through `http_proxy`.
+    fn test_tls_via(
+        ip_tls: Option<&IpTlsOptions>,
+        metrics: &Arc<TlsMetrics>,
+        http_proxy: Option<ProxyConfig>,
+    ) -> (tokio_rustls::TlsAcceptor, Connector) {
+        let (acceptor, config) = test_tls_config(ip_tls, metrics, http_proxy);
+        (acceptor, Connector::new(&config))
+    }
+
+    /// Same as `test_tls_via`, returning the config to build connectors with.
+    fn test_tls_config(
+        ip_tls: Option<&IpTlsOptions>,
+        metrics: &Arc<TlsMetrics>,
+        http_proxy: Option<ProxyConfig>,
+    ) -> (tokio_rustls::TlsAcceptor, ConnectorConfig) {
+        const CA: &[u8] = include_bytes!("../../tests/tls/ca.der");
+        const CERT: &[u8] = include_bytes!("../../tests/tls/agent.local.der");
+        const KEY: &[u8] = include_bytes!("../../tests/tls/agent.local.key.der");
+
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(CERT.to_vec())],
+                rustls::PrivateKey(KEY.to_vec()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(CA.to_vec())).unwrap();
+        let config = ConnectorConfig {
+            http_proxy,
+            ip_tls: ip_tls.cloned(),
+            tls_metrics: metrics.clone(),
+            tls: Some(SharedTlsConfig::new(roots)),
+            ..Default::default()
+        };
+        (acceptor, config)
+    }
+
+    #[tokio::test]
+    /// Verify that tls connections to IP addresses use the configured server
+    /// name, and only send it as SNI if enabled
+    async fn test_ip_tls_server_name() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        for disable_sni in [true, false] {
+            let options = IpTlsOptions {
+                server_name: "agent.local".to_owned(),
+                disable_sni,
+            };
+            let (acceptor, mut connector) = test_tls(Some(&options), &Arc::default());
+
+            let server = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = acceptor.accept(stream).await.unwrap();
+                stream.get_ref().1.sni_hostname().map(str::to_owned)
+            };
+            let uri: hyper::Uri = format!("https://{}", address).parse().unwrap();
+            let (sni, stream) = tokio::join!(server, connector.call(uri));
+
+            assert!(matches!(stream.unwrap(), ConnStream::Tls { .. }));
+            let expected_sni = if disable_sni {
+                None
+            } else {
+                Some("agent.local".to_owned())
+            };
+            assert_eq!(sni, expected_sni);
+        }
+    }
+
+    #[tokio::test]
+    /// Verify that tls handshakes are counted, and that the second one
+    /// resumes the session of the first
+    async fn test_tls_handshake_stats() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = IpTlsOptions {
+            server_name: "agent.local".to_owned(),
+            disable_sni: false,
+        };
+        let metrics = Arc::default();
+        let (acceptor, mut connector) = test_tls(Some(&options), &metrics);
+
+        for _ in 0..2 {
+            let server = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = acceptor.accept(stream).await.unwrap();
+                // TLS 1.3 session tickets are sent after the handshake, so
+                // wait for the client to read them before hanging up.
+                let mut byte = [0];
+                stream.read_exact(&mut byte).await.unwrap();
+            };
+            let uri: hyper::Uri = format!("https://{}", address).parse().unwrap();
+            let client = async {
+                let mut stream = connector.call(uri).await.unwrap();
+                stream.write_all(b"x").await.unwrap();
+                stream.flush().await.unwrap();
+                // Reading also processes the tickets, the server then hangs up.
+                let mut buffer = Vec::new();
+                stream.read_to_end(&mut buffer).await.ok();
+            };
+            tokio::join!(server, client);
+        }
+
+        let stats = metrics.stats();
+        assert_eq!(stats.handshakes, 2);
+        assert_eq!(stats.resumed, 1);
+        assert_eq!(stats.resume_rate(), 0.5);
+        assert!(stats.total_duration > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    /// Verify that connectors built from the same config, as when the client
+    /// is rebuilt, resume the sessions of each other
+    async fn test_tls_sessions_survive_rebuild() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = IpTlsOptions {
+            server_name: "agent.local".to_owned(),
+            disable_sni: false,
+        };
+        let metrics = Arc::default();
+        let (acceptor, config) = test_tls_config(Some(&options), &metrics, None);
+
+        for _ in 0..2 {
+            let mut connector = Connector::new(&config.clone());
+            let server = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = acceptor.accept(stream).await.unwrap();
+                stream.read_exact(&mut [0]).await.unwrap();
+            };
+            let uri: hyper::Uri = format!("https://{}", address).parse().unwrap();
+            let client = async {
+                let mut stream = connector.call(uri).await.unwrap();
+                stream.write_all(b"x").await.unwrap();
+                stream.flush().await.unwrap();
+                stream.read_to_end(&mut Vec::new()).await.ok();
+            };
+            tokio::join!(server, client);
+        }
+
+        let stats = metrics.stats();
+        assert_eq!(stats.handshakes, 2);
+        assert_eq!(stats.resumed, 1);
+    }
+
+    #[tokio::test]
+    /// Verify that a resumed handshake is counted as such while a full one
+    /// completes during it
+    async fn test_tls_handshake_stats_concurrent() {
+        let listeners = [
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let uris: Vec<hyper::Uri> = listeners
+            .iter()
+            .map(|listener| {
+                let address = listener.local_addr().unwrap();
+                format!("https://{}", address).parse().unwrap()
+            })
+            .collect();
+        let options = IpTlsOptions {
+            server_name: "agent.local".to_owned(),
+            disable_sni: false,
+        };
+        let metrics = Arc::default();
+        let (acceptor, mut resuming) = test_tls(Some(&options), &metrics);
+        // Another session cache, so its handshake is a full one
+        let (_, mut full) = test_tls(Some(&options), &metrics);
+
+        // A first connection, for a session to resume
+        let server = async {
+            let (stream, _) = listeners[0].accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            stream.read_exact(&mut [0]).await.unwrap();
+        };
+        let client = async {
+            let mut stream = resuming.call(uris[0].clone()).await.unwrap();
+            stream.write_all(b"x").await.unwrap();
+            stream.flush().await.unwrap();
+            stream.read_to_end(&mut Vec::new()).await.ok();
+        };
+        tokio::join!(server, client);
+
+        // The resumed handshake waits for the server until the full one is
+        // done.
+        let (full_done, resume) = tokio::sync::oneshot::channel();
+        let resuming_server = async {
+            let (stream, _) = listeners[0].accept().await.unwrap();
+            resume.await.unwrap();
+            acceptor.accept(stream).await.unwrap()
+        };
+        let full_handshake = async {
+            let server = async {
+                let (stream, _) = listeners[1].accept().await.unwrap();
+                acceptor.accept(stream).await.unwrap()
+            };
+            let (_, stream) = tokio::join!(server, full.call(uris[1].clone()));
+            stream.unwrap();
+            full_done.send(()).unwrap();
+        };
+        let (_, stream, _) = tokio::join!(
+            resuming_server,
+            resuming.call(uris[0].clone()),
+            full_handshake
+        );
+        stream.unwrap();
+
+        let stats = metrics.stats();
+        assert_eq!(stats.handshakes, 3);
+        assert_eq!(stats.resumed, 1);
+    }
+
+    #[tokio::test]
+    /// Verify that https connections to loopback addresses are only made in
+    /// plain text when enabled
+    async fn test_plaintext_loopback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let uri: hyper::Uri = format!("https://{}", address).parse().unwrap();
+
+        let config = ConnectorConfig {
+            plaintext_loopback: true,
+            ..Default::default()
+        };
+        let mut connector = Connector::new(&config);
+        let (accepted, stream) = tokio::join!(listener.accept(), connector.call(uri.clone()));
+        accepted.unwrap();
+        assert!(matches!(stream.unwrap(), ConnStream::Tcp { .. }));
+        assert_eq!(config.tls_metrics.stats().plaintext_downgrades, 1);
+        assert_eq!(config.tls_metrics.stats().handshakes, 0);
+
+        let options = IpTlsOptions {
+            server_name: "agent.local".to_owned(),
+            disable_sni: false,
+        };
+        let metrics = Arc::default();
+        let (acceptor, mut connector) = test_tls(Some(&options), &metrics);
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await.unwrap()
+        };
+        let (_, stream) = tokio::join!(server, connector.call(uri));
+        assert!(matches!(stream.unwrap(), ConnStream::Tls { .. }));
+        assert_eq!(metrics.stats().plaintext_downgrades, 0);
+        assert_eq!(metrics.stats().handshakes, 1);
+
+        assert!(is_loopback(&hyper::Uri::from_static("https://localhost")));
+        assert!(is_loopback(&hyper::Uri::from_static("https://[::1]:8126")));
+        assert!(!is_loopback(&hyper::Uri::from_static("https://10.0.0.1")));
+        assert!(!is_loopback(&hyper::Uri::from_static(
+            "https://agent.local"
+        )));
+        assert_eq!(
+            downgrade_to_http(hyper::Uri::from_static("https://localhost/v1")).unwrap(),
+            "http://localhost:443/v1"
+        );
+    }
+
+    #[tokio::test]
+    /// Verify that connections go through the SOCKS5 proxy, which is asked
+    /// for the target by name after authenticating
+    async fn test_socks5_tunnel() {
+        use tokio::net::{TcpListener, TcpStream};
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_address = proxy.local_addr().unwrap();
+
+        // Just enough of a SOCKS5 server for one connection
+        let server = async {
+            let (mut client, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            client.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0; 2 + 4 + 1 + 6];
+            client.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x06secret");
+            client.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 5];
+            client.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..4], [5, 1, 0, 3]);
+            let mut host = vec![0; request[4] as usize];
+            client.read_exact(&mut host).await.unwrap();
+            let port = client.read_u16().await.unwrap();
+            let mut upstream = TcpStream::connect((String::from_utf8(host.clone()).unwrap(), port))
+                .await
+                .unwrap();
+            client
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+                .await
+                .unwrap();
+
+            tokio::io::copy_bidirectional(&mut client, &mut upstream)
+                .await
+                .ok();
+            (String::from_utf8(host).unwrap(), port)
+        };
+
+        let target_server = async {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut received = [0; 5];
+            stream.read_exact(&mut received).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            received
+        };
+
+        let client = async {
+            let mut connector = Connector::new(&ConnectorConfig {
+                socks5_proxy: Some(
+                    format!("socks5://user:secret@{}", proxy_address)
+                        .parse()
+                        .unwrap(),
+                ),
+                ..Default::default()
+            });
+            let uri: hyper::Uri = format!("http://localhost:{}", target_port).parse().unwrap();
+            let mut stream = connector.call(uri).await.unwrap();
+            stream.write_all(b"ping!").await.unwrap();
+            let mut reply = [0; 4];
+            stream.read_exact(&mut reply).await.unwrap();
+            drop(stream);
+            reply
+        };
+
+        let ((host, port), received, reply) = tokio::join!(server, target_server, client);
+        assert_eq!(host, "localhost");
+        assert_eq!(port, target_port);
+        assert_eq!(&received, b"ping!");
+        assert_eq!(&reply, b"pong");
+    }
+
+    #[tokio::test]
+    /// Verify that https connections are tunnelled through the HTTP proxy
+    /// with CONNECT, and that the tls handshake then happens with the target
+    async fn test_http_proxy_tunnel() {
+        use tokio::net::TcpListener;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_address = target.local_addr().unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_address = proxy.local_addr().unwrap();
+
+        let options = IpTlsOptions {
+            server_name: "agent.local".to_owned(),
+            disable_sni: false,
+        };
+        let http_proxy = ProxyConfig {
+            https: Some(
+                format!("http://user:secret@{}", proxy_address)
+                    .parse()
+                    .unwrap(),
+            ),
+            ..Default::default()
+        };
+        let (acceptor, mut connector) =
+            test_tls_via(Some(&options), &Arc::default(), Some(http_proxy));
+
+        // Just enough of an HTTP proxy for one connection
+        let server = async {
+            let (mut client, _) = proxy.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(client.read_u8().await.unwrap());
+            }
+            let mut upstream = tokio::net::TcpStream::connect(target_address)
+                .await
+                .unwrap();
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            tokio::io::copy_bidirectional(&mut client, &mut upstream)
+                .await
+                .ok();
+            String::from_utf8(request).unwrap()
+        };
+
+        let target_server = async {
+            let (stream, _) = target.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut received = [0; 5];
+            stream.read_exact(&mut received).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            stream.shutdown().await.unwrap();
+            received
+        };
+
+        let client = async {
+            let uri: hyper::Uri = format!("https://{}", target_address).parse().unwrap();
+            let mut stream = connector.call(uri).await.unwrap();
+            assert!(matches!(stream, ConnStream::Tls { .. }));
+            stream.write_all(b"ping!").await.unwrap();
+            let mut reply = [0; 4];
+            stream.read_exact(&mut reply).await.unwrap();
+            drop(stream);
+            reply
+        };
+
+        let (request, received, reply) = tokio::join!(server, target_server, client);
+        let mut lines = request.lines();
+        assert_eq!(
+            lines.next(),
+            Some(format!("CONNECT {} HTTP/1.1", target_address).as_str())
+        );
+        // base64 of "user:secret"
+        assert!(lines.any(|line| line == "Proxy-Authorization: Basic dXNlcjpzZWNyZXQ="));
+        assert_eq!(&received, b"ping!");
+        assert_eq!(&reply, b"pong");
+    }
+
+    #[tokio::test]
+    /// Verify that a refused CONNECT fails the connection
+    async fn test_http_proxy_refused() {
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_address = proxy.local_addr().unwrap();
+
+        let server = async {
+            let (mut client, _) = proxy.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(client.read_u8().await.unwrap());
+            }
+            client
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        };
+        let mut connector = Connector::new(&ConnectorConfig {
+            http_proxy: Some(ProxyConfig {
+                http: Some(proxy_address.to_string().parse().unwrap()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let uri = hyper::Uri::from_static("http://intake.example:8126");
+        let (_, result) = tokio::join!(server, connector.call(uri));
+
+        let err = result.unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("CONNECT to intake.example:8126 refused with 407"),
+            "unexpected error message: {}",
+            message
+        );
+        let err = err.downcast::<ConnectError>().unwrap();
+        assert_eq!(err.target, "intake.example:8126");
+        assert_eq!(err.proxy, Some(proxy_address.to_string()));
+    }
+
+    #[tokio::test]
+    /// Verify that writes are paced to the bandwidth limit once the burst is
+    /// spent
+    async fn test_bandwidth_limit() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let limit = BandwidthLimit {
+            bytes_per_second: 20_000,
+            burst: 1_000,
+        };
+        let mut connector = Connector::new(&ConnectorConfig {
+            bandwidth_limit: Some(limit),
+            ..Default::default()
+        });
+
+        let payload = vec![b'x'; 10_000];
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received.len()
+        };
+        let client = async {
+            let uri: hyper::Uri = format!("http://{}", address).parse().unwrap();
+            let mut stream = connector.call(uri).await.unwrap();
+            assert!(matches!(stream, ConnStream::Throttled { .. }));
+            let start = std::time::Instant::now();
+            stream.write_all(&payload).await.unwrap();
+            stream.shutdown().await.unwrap();
+            start.elapsed()
+        };
+        let (received, elapsed) = tokio::join!(server, client);
+        assert_eq!(received, payload.len());
+
+        // The burst goes out at once, the other 9000 bytes take 450ms
+        assert!(elapsed >= Duration::from_millis(430), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(900), "took {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    /// Verify that the PROXY protocol header is the first thing written
+    async fn test_proxy_header_written_first() {
+        use proxy_protocol::ProxyProtocol;
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            let proxy_header = ProxyHeader {
+                version,
+                source: "192.0.2.10:51000".parse().unwrap(),
+            };
+            let mut connector = Connector::new(&ConnectorConfig {
+                proxy_header: Some(proxy_header),
+                ..Default::default()
+            });
+            let uri: hyper::Uri = format!("http://{}", address).parse().unwrap();
+            let mut stream = connector.call(uri).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+            let expected = proxy_header.encode(address);
+            let (mut accepted, _) = listener.accept().unwrap();
+            let mut received = vec![0; expected.len() + 3];
+            accepted.read_exact(&mut received).unwrap();
+            assert_eq!(&received[..expected.len()], expected.as_slice());
+            assert_eq!(&received[expected.len()..], b"GET");
+        }
+    }
 }
@@ -0,0 +1,121 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! PROXY protocol headers, which tell a load balancer in front of the agent
+//! the address of the client it is relaying for. See
+//! https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProxyProtocol {
+    /// The human readable header.
+    V1,
+    /// The binary header.
+    V2,
+}
+
+/// A PROXY protocol header to send at the start of each tcp connection,
+/// before anything else (including a tls handshake).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ProxyHeader {
+    pub version: ProxyProtocol,
+    /// The address of the client the connection is made on behalf of.
+    pub source: SocketAddr,
+}
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+impl ProxyHeader {
+    /// Encodes the header for a connection to `destination`. When only one
+    /// of the addresses is IPv6, the other one is sent as an IPv4-mapped
+    /// IPv6 address, as the protocol needs both to be of the same family.
+    pub(crate) fn encode(&self, destination: SocketAddr) -> Vec<u8> {
+        let source_port = self.source.port();
+        let destination_port = destination.port();
+        let (source, destination) = match (self.source.ip(), destination.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+            (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+        };
+
+        match self.version {
+            ProxyProtocol::V1 => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    family, source, destination, source_port, destination_port
+                )
+                .into_bytes()
+            }
+            ProxyProtocol::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                // Version 2, PROXY command
+                header.push(0x21);
+                let mut addresses = octets(source);
+                addresses.extend(octets(destination));
+                // TCP over IPv4 or IPv6
+                header.push(if source.is_ipv4() { 0x11 } else { 0x21 });
+                header.extend_from_slice(&(addresses.len() as u16 + 4).to_be_bytes());
+                header.extend_from_slice(&addresses);
+                header.extend_from_slice(&source_port.to_be_bytes());
+                header.extend_from_slice(&destination_port.to_be_bytes());
+                header
+            }
+        }
+    }
+}
+
+fn octets(address: IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(address) => address.octets().to_vec(),
+        IpAddr::V6(address) => address.octets().to_vec(),
+    }
+}
+
+fn to_ipv6(address: IpAddr) -> std::net::Ipv6Addr {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped(),
+        IpAddr::V6(address) => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_v1() {
+        let header = ProxyHeader {
+            version: ProxyProtocol::V1,
+            source: "192.0.2.10:51000".parse().unwrap(),
+        };
+        assert_eq!(
+            header.encode("10.0.0.1:8126".parse().unwrap()),
+            b"PROXY TCP4 192.0.2.10 10.0.0.1 51000 8126\r\n"
+        );
+        assert_eq!(
+            header.encode("[2001:db8::1]:8126".parse().unwrap()),
+            b"PROXY TCP6 ::ffff:192.0.2.10 2001:db8::1 51000 8126\r\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_v2() {
+        let header = ProxyHeader {
+            version: ProxyProtocol::V2,
+            source: "192.0.2.10:51000".parse().unwrap(),
+        };
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        expected.extend_from_slice(&[192, 0, 2, 10, 10, 0, 0, 1]);
+        expected.extend_from_slice(&[0xc7, 0x38, 0x1f, 0xbe]);
+        assert_eq!(header.encode("10.0.0.1:8126".parse().unwrap()), expected);
+
+        let encoded = header.encode("[2001:db8::1]:8126".parse().unwrap());
+        assert_eq!(&encoded[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(encoded.len(), 16 + 36);
+    }
+}
@@ -0,0 +1,139 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Host name resolution for the tcp connector, which can be shared by
+//! several exporters so they don't each resolve the same agent.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::client::connect::dns::Name;
+
+#[derive(Debug, Default)]
+struct Cache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>,
+    lookups: AtomicU64,
+}
+
+/// Resolves host names with the system resolver, keeping the addresses
+/// found for `ttl`. Clones share the same cache, so that exporters given
+/// clones of a resolver look each host up once. The resolver of an exporter
+/// which wasn't given one doesn't cache anything.
+#[derive(Clone, Debug, Default)]
+pub struct SharedResolver {
+    cache: Arc<Cache>,
+}
+
+impl SharedResolver {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            cache: Arc::new(Cache {
+                ttl,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// The number of lookups made so far, not counting the ones answered
+    /// from the cache.
+    pub fn lookups(&self) -> u64 {
+        self.cache.lookups.load(Ordering::Relaxed)
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<SocketAddr>> {
+        let entries = self.cache.entries.lock().unwrap();
+        match entries.get(host) {
+            Some((resolved_at, addresses)) if resolved_at.elapsed() < self.cache.ttl => {
+                Some(addresses.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Resolves with a `SharedResolver`, keeping the addresses found so that a
+/// connection which fails can tell which addresses it attempted. Each
+/// connection has its own.
+#[derive(Clone)]
+pub(crate) struct RecordingResolver {
+    resolver: SharedResolver,
+    resolved: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl RecordingResolver {
+    pub(crate) fn new(resolver: SharedResolver) -> Self {
+        Self {
+            resolver,
+            resolved: Arc::default(),
+        }
+    }
+
+    /// The addresses resolved so far, with the port the connector replaces
+    /// set to `port`.
+    pub(crate) fn resolved(&self, port: u16) -> Vec<SocketAddr> {
+        let mut addresses = self.resolved.lock().unwrap().clone();
+        for address in addresses.iter_mut() {
+            address.set_port(port);
+        }
+        addresses
+    }
+}
+
+impl hyper::service::Service<Name> for RecordingResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = self.resolver.call(name);
+        let resolved = self.resolved.clone();
+        async move {
+            let addresses: Vec<SocketAddr> = resolving.await?.collect();
+            resolved.lock().unwrap().extend(addresses.iter().cloned());
+            Ok(addresses.into_iter())
+        }
+        .boxed()
+    }
+}
+
+impl hyper::service::Service<Name> for SharedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.as_str().to_owned();
+        if let Some(addresses) = self.cached(&host) {
+            return futures::future::ok(addresses.into_iter()).boxed();
+        }
+
+        let cache = self.cache.clone();
+        async move {
+            cache.lookups.fetch_add(1, Ordering::Relaxed);
+            // The port is replaced by the connector
+            let addresses: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if cache.ttl > Duration::from_secs(0) {
+                let mut entries = cache.entries.lock().unwrap();
+                entries.insert(host, (Instant::now(), addresses.clone()));
+            }
+            Ok(addresses.into_iter())
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,77 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Pacing of the bytes written to connections, so that uploads don't starve
+//! the traffic of the application on hosts with little bandwidth.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::{Instant, Sleep};
+
+/// A cap on the bytes written to each connection: `bytes_per_second` on
+/// average, in bursts of up to `burst` bytes. Both are taken to be at least 1.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BandwidthLimit {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+}
+
+/// A token bucket holding the bytes which can be written right away. It
+/// starts full, and refills at the limit's rate up to the burst.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: BandwidthLimit) -> Self {
+        let burst = limit.burst.max(1) as f64;
+        Self {
+            rate: limit.bytes_per_second.max(1) as f64,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = now;
+    }
+
+    /// Waits until `wanted` bytes, capped to the burst, can be written, and
+    /// returns how many. They are only taken from the bucket by `consume`.
+    pub(crate) fn poll_ready(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        if wanted == 0 {
+            return Poll::Ready(0);
+        }
+        let wanted = (wanted as f64).min(self.burst).floor();
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                futures::ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            self.refill();
+            if self.tokens >= wanted {
+                return Poll::Ready(wanted as usize);
+            }
+            let wait = Duration::from_secs_f64((wanted - self.tokens) / self.rate);
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    /// Takes `written` bytes from the bucket.
+    pub(crate) fn consume(&mut self, written: usize) {
+        self.tokens -= written as f64;
+    }
+}
@@ -0,0 +1,231 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Tls connections made on top of `TcpConnector`, and statistics about their
+//! handshakes.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use hyper::service::Service;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+use super::conn_stream::ConnStreamError;
+use super::TcpConnector;
+
+/// How to make tls connections to endpoints addressed by IP. rustls only
+/// verifies certificates for dns names, so the certificate is verified
+/// against `server_name` instead, which is also sent as SNI unless
+/// `disable_sni` is set. Without these options, tls connections to IP
+/// addresses fail.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IpTlsOptions {
+    pub server_name: String,
+    pub disable_sni: bool,
+}
+
+/// Aggregate statistics of the tls handshakes made by a connector.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TlsHandshakeStats {
+    /// Number of completed handshakes.
+    pub handshakes: u64,
+    /// Number of those handshakes which resumed a previous session.
+    pub resumed: u64,
+    /// Time spent in completed handshakes, excluding the tcp connection.
+    pub total_duration: Duration,
+    /// Number of https connections to loopback addresses made in plain text
+    /// instead, see `ProfileExporterV3::set_plaintext_loopback`.
+    pub plaintext_downgrades: u64,
+}
+
+impl TlsHandshakeStats {
+    /// The fraction of handshakes which resumed a previous session.
+    pub fn resume_rate(&self) -> f64 {
+        if self.handshakes == 0 {
+            0.0
+        } else {
+            self.resumed as f64 / self.handshakes as f64
+        }
+    }
+}
+
+/// Collects `TlsHandshakeStats`, shared by all the connectors built from the
+/// same `ConnectorConfig`.
+#[derive(Debug, Default)]
+pub(crate) struct TlsMetrics {
+    stats: Mutex<TlsHandshakeStats>,
+}
+
+impl TlsMetrics {
+    pub(crate) fn stats(&self) -> TlsHandshakeStats {
+        *self.stats.lock().unwrap()
+    }
+
+    fn record(&self, duration: Duration, resumed: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.handshakes += 1;
+        stats.resumed += u64::from(resumed);
+        stats.total_duration += duration;
+    }
+
+    pub(crate) fn record_downgrade(&self) {
+        self.stats.lock().unwrap().plaintext_downgrades += 1;
+    }
+}
+
+/// Verifies the certificate of a single connection with webpki, recording
+/// that it did. rustls doesn't say whether a handshake resumed a session,
+/// but it only verifies the certificate of full handshakes. This is the only
+/// reason for the `dangerous_configuration` feature of rustls: verification
+/// itself is left to webpki, as it would be without it.
+struct RecordingVerifier {
+    inner: Arc<WebPkiVerifier>,
+    verified: Arc<AtomicBool>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verified.store(true, Ordering::Relaxed);
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+/// The tls client config built from the root certificates, which are only
+/// read from the system trust store once. It is shared by the connectors
+/// built from clones of the same `ConnectorConfig`, and so is its session
+/// cache: rebuilding the client doesn't lose the sessions to resume.
+#[derive(Clone)]
+pub(crate) struct SharedTlsConfig {
+    config: Arc<ClientConfig>,
+    verifier: Arc<WebPkiVerifier>,
+}
+
+impl SharedTlsConfig {
+    pub(crate) fn new(roots: RootCertStore) -> Self {
+        let verifier = Arc::new(WebPkiVerifier::new(roots.clone(), None));
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Self {
+            config: Arc::new(config),
+            verifier,
+        }
+    }
+}
+
+impl fmt::Debug for SharedTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedTlsConfig").finish_non_exhaustive()
+    }
+}
+
+/// Makes tls connections, using the host of the uri as server name, or the
+/// one from `IpTlsOptions` for IP addresses.
+#[derive(Clone)]
+pub struct TlsConnector {
+    tcp: TcpConnector,
+    tls: Arc<ClientConfig>,
+    ip_tls: Option<(Arc<ClientConfig>, String)>,
+    verifier: Arc<WebPkiVerifier>,
+    metrics: Arc<TlsMetrics>,
+}
+
+impl TlsConnector {
+    /// The config for IP addresses is a copy of the shared one, which shares
+    /// its session cache.
+    pub(crate) fn new(
+        tcp: TcpConnector,
+        shared: &SharedTlsConfig,
+        ip_tls: Option<&IpTlsOptions>,
+        metrics: Arc<TlsMetrics>,
+    ) -> Self {
+        let ip_tls = ip_tls.map(|options| {
+            let mut client_config = ClientConfig::clone(&shared.config);
+            client_config.enable_sni = !options.disable_sni;
+            (Arc::new(client_config), options.server_name.clone())
+        });
+        Self {
+            tcp,
+            tls: shared.config.clone(),
+            ip_tls,
+            verifier: shared.verifier.clone(),
+            metrics,
+        }
+    }
+
+    pub(crate) fn tcp(&self) -> &TcpConnector {
+        &self.tcp
+    }
+
+    pub(crate) fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ConnStreamError>> {
+        self.tcp.poll_ready(cx)
+    }
+
+    /// Makes a plain tcp connection, for http uris.
+    pub(crate) fn connect_tcp(
+        &mut self,
+        uri: hyper::Uri,
+    ) -> impl Future<Output = Result<TcpStream, ConnStreamError>> {
+        self.tcp.call(uri)
+    }
+
+    /// Makes a tls connection, recording its handshake in the metrics. A
+    /// handshake counts as resumed if the certificate of its connection
+    /// wasn't verified. The connections share the session cache of their
+    /// config, each only gets its own verifier.
+    pub(crate) fn connect_tls(
+        &mut self,
+        uri: hyper::Uri,
+    ) -> impl Future<Output = Result<TlsStream<TcpStream>, ConnStreamError>> {
+        let (tls, server_name) = match &self.ip_tls {
+            Some((tls, server_name)) if super::is_ip_literal(&uri) => {
+                (tls.clone(), server_name.clone())
+            }
+            _ => (self.tls.clone(), uri.host().unwrap_or_default().to_owned()),
+        };
+        let verified = Arc::new(AtomicBool::new(false));
+        let mut client_config = ClientConfig::clone(&tls);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(RecordingVerifier {
+                inner: self.verifier.clone(),
+                verified: verified.clone(),
+            }));
+        let tls = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let metrics = self.metrics.clone();
+        let connecting = self.tcp.call(uri);
+        async move {
+            let server_name = ServerName::try_from(server_name.as_str())?;
+            let tcp = connecting.await?;
+
+            let started_at = Instant::now();
+            let stream = tls.connect(server_name, tcp).await?;
+            metrics.record(started_at.elapsed(), !verified.load(Ordering::Relaxed));
+            Ok(stream)
+        }
+    }
+}
@@ -7,7 +7,9 @@ use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 
 /// Creates a new Uri, with the `unix` scheme, and the path to the socket
-/// encoded as a hex string, to prevent special characters in the url authority
+/// encoded as a hex string, to prevent special characters in the url authority.
+/// On Linux, a path of the form `@name` designates `name` in the abstract
+/// socket namespace rather than a file.
 pub fn socket_path_to_uri(path: &Path) -> Result<hyper::Uri, Box<dyn Error>> {
     let path = hex::encode(path.as_os_str().as_bytes());
     Ok(hyper::Uri::builder()
@@ -30,6 +32,23 @@ pub fn socket_path_from_uri(uri: &hyper::Uri) -> anyhow::Result<PathBuf> {
     Ok(PathBuf::from(OsString::from_vec(path)))
 }
 
+/// Returns the path to connect to for a socket path from
+/// `socket_path_from_uri`. Abstract sockets are connected to with their name
+/// prefixed by a null byte, instead of the `@` of the `@name` convention.
+pub(crate) fn connect_path(path: PathBuf) -> anyhow::Result<PathBuf> {
+    match path.as_os_str().as_bytes().strip_prefix(b"@") {
+        None => Ok(path),
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            let mut bytes = vec![0];
+            bytes.extend_from_slice(name);
+            Ok(PathBuf::from(OsString::from_vec(bytes)))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(crate::errors::Error::AbstractSocketUnsupported.into()),
+    }
+}
+
 #[test]
 fn test_encode_unix_socket_path_absolute() {
     let expected_path = "/path/to/a/socket.sock".as_ref();
@@ -40,6 +59,48 @@ fn test_encode_unix_socket_path_absolute() {
     assert_eq!(actual_path.as_path(), Path::new(expected_path))
 }
 
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_abstract_socket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let name = format!("@ddprof-test-{}", std::process::id());
+    let uri = socket_path_to_uri(name.as_ref()).unwrap();
+    let path = socket_path_from_uri(&uri).unwrap();
+    assert_eq!(path.as_path(), Path::new(&name));
+
+    let connect_path = connect_path(path).unwrap();
+    assert_eq!(connect_path.as_os_str().as_bytes()[0], 0);
+    assert_eq!(
+        &connect_path.as_os_str().as_bytes()[1..],
+        &name.as_bytes()[1..]
+    );
+
+    let listener = tokio::net::UnixListener::bind(&connect_path).unwrap();
+    let server = async {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"agent").await.unwrap();
+    };
+    let client = async {
+        let mut stream = super::ConnStream::from_uds_uri(uri).await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        received
+    };
+    let (_, received) = tokio::join!(server, client);
+    assert_eq!(received, b"agent");
+}
+
+#[cfg(not(target_os = "linux"))]
+#[test]
+fn test_abstract_socket_unsupported() {
+    let err = connect_path(PathBuf::from("@datadog-agent")).unwrap_err();
+    assert_eq!(
+        err.downcast::<crate::errors::Error>().unwrap(),
+        crate::errors::Error::AbstractSocketUnsupported
+    );
+}
+
 #[test]
 fn test_encode_unix_socket_relative_path() {
     let expected_path = "relative/path/to/a/socket.sock".as_ref();
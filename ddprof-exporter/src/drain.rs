@@ -0,0 +1,86 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Tracking of the requests in flight, so an exporter can bound how many are
+//! sent at once, and let them finish when shutting down while refusing new
+//! ones.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct State {
+    in_flight: usize,
+    /// At least 1 when set.
+    max_in_flight: Option<usize>,
+    shut_down: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Drain {
+    state: Mutex<State>,
+    /// Notified when a request completes, or on shutdown.
+    changed: Condvar,
+}
+
+/// A request in flight, until dropped.
+pub(crate) struct InFlight<'a>(&'a Drain);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.in_flight -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Drain {
+    /// Registers a request about to be sent, unless shutting down. If the
+    /// maximum number of requests are in flight, waits for one to complete.
+    pub(crate) fn start(&self) -> Result<InFlight<'_>, crate::errors::Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shut_down {
+                return Err(crate::errors::Error::ShutDown);
+            }
+            match state.max_in_flight {
+                Some(max) if state.in_flight >= max => {
+                    state = self.changed.wait(state).unwrap();
+                }
+                _ => break,
+            }
+        }
+        state.in_flight += 1;
+        Ok(InFlight(self))
+    }
+
+    /// Bounds the number of requests in flight, or not if None. Requests
+    /// already in flight are left alone.
+    pub(crate) fn set_max_in_flight(&self, max: Option<usize>) {
+        let mut state = self.state.lock().unwrap();
+        state.max_in_flight = max.map(|max| max.max(1));
+        self.changed.notify_all();
+    }
+
+    pub(crate) fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    /// Refuses new requests from now on, and waits up to `grace` for those
+    /// in flight to finish. Returns whether they all did.
+    pub(crate) fn shutdown(&self, grace: Duration) -> bool {
+        let deadline = Instant::now() + grace;
+        let mut state = self.state.lock().unwrap();
+        state.shut_down = true;
+        // Requests waiting for others to complete give up
+        self.changed.notify_all();
+        while state.in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
@@ -3,6 +3,7 @@
 
 use std::error;
 use std::fmt;
+use std::net::SocketAddr;
 
 #[derive(Clone, Debug, PartialEq)]
 #[allow(dead_code)]
@@ -10,9 +11,11 @@ pub(crate) enum Error {
     InvalidUrl,
     OperationTimedOut,
     UnixSocketUnsupported,
+    AbstractSocketUnsupported,
     CannotEstablishTlsConnection,
     NoValidCertifacteRootsFound,
     UserRequestedCancellation,
+    ShutDown,
 }
 
 impl fmt::Display for Error {
@@ -21,6 +24,7 @@ impl fmt::Display for Error {
             Self::InvalidUrl => "invalid url",
             Self::OperationTimedOut => "operation timed out",
             Self::UnixSocketUnsupported => "unix sockets unsupported on windows",
+            Self::AbstractSocketUnsupported => "abstract unix sockets are only supported on linux",
             Self::CannotEstablishTlsConnection => {
                 "cannot establish requested secure TLS connection"
             }
@@ -28,8 +32,45 @@ impl fmt::Display for Error {
                 "native tls couldn't find any valid certifacte roots"
             }
             Self::UserRequestedCancellation => "operation cancelled by user",
+            Self::ShutDown => "exporter is shut down",
         })
     }
 }
 
 impl error::Error for Error {}
+
+/// A failure to establish a connection, annotated with the target which was
+/// being connected to, and the proxy it was connected through if any.
+#[derive(Debug)]
+pub struct ConnectError {
+    /// The `host:port` of a tcp target, or `unix://<path>` for a unix socket.
+    pub target: String,
+    /// The `host:port` of the SOCKS5 or HTTP proxy, which is what
+    /// `addresses` are the addresses of when set.
+    pub proxy: Option<String>,
+    /// The addresses which were attempted, as resolved for the connection.
+    /// Empty when the failure came after connecting, e.g. during the tls
+    /// handshake, or for unix sockets.
+    pub addresses: Vec<SocketAddr>,
+    pub source: Box<dyn error::Error + Send + Sync>,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot connect to {}", self.target)?;
+        if let Some(proxy) = &self.proxy {
+            write!(f, " through proxy {}", proxy)?;
+        }
+        if !self.addresses.is_empty() {
+            let addresses: Vec<String> = self.addresses.iter().map(|a| a.to_string()).collect();
+            write!(f, " (attempted {})", addresses.join(", "))?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
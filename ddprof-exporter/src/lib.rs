@@ -15,23 +15,45 @@ use hyper_multipart_rfc7578::client::multipart;
 use tokio::runtime::Runtime;
 use tokio_util::sync::CancellationToken;
 
+mod agent_url;
 mod connector;
+mod drain;
 mod errors;
+mod metrics;
+mod schedule;
 pub mod tag;
 
+pub use agent_url::{validate_agent_url, AgentUrlError};
+pub use errors::ConnectError;
+pub use metrics::ExporterMetrics;
+pub use schedule::JitteredInterval;
 pub use tag::*;
 
+pub use connector::proxy_protocol::{ProxyHeader, ProxyProtocol};
 #[cfg(unix)]
 pub use connector::uds::socket_path_to_uri;
+pub use connector::{
+    BandwidthLimit, ConnectionInfo, HttpProxy, IpTlsOptions, ProxyConfig, SharedResolver,
+    Socks5Proxy, TlsHandshakeStats,
+};
 
 const DURATION_ZERO: std::time::Duration = std::time::Duration::from_millis(0);
 const DATADOG_CONTAINER_ID_HEADER: &str = "Datadog-Container-ID";
+const DATADOG_EVP_SUBDOMAIN_HEADER: &str = "X-Datadog-EVP-Subdomain";
+
+/// The intake the agent's EVP proxy forwards profiles to, as the subdomain
+/// of the agent's site, and the path of the agentless endpoint on it.
+const PROFILE_INTAKE_SUBDOMAIN: &str = "intake.profile";
+const EVP_PROXY_PATH: &str = "/evp_proxy/v4/v1/input";
 
 type HttpClient = hyper::Client<connector::Connector, hyper::Body>;
 
 pub struct Exporter {
     client: HttpClient,
     runtime: Runtime,
+    connector_config: connector::ConnectorConfig,
+    counters: metrics::RequestCounters,
+    drain: drain::Drain,
 }
 
 pub struct FieldsV3 {
@@ -42,6 +64,27 @@ pub struct FieldsV3 {
 pub struct Endpoint {
     url: Uri,
     api_key: Option<Cow<'static, str>>,
+    /// The intake the agent forwards to, in `AgentMode::AgentProxy`.
+    evp_subdomain: Option<&'static str>,
+}
+
+/// How profiles are sent through the agent.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AgentMode {
+    /// To the agent's profiling endpoint. This is the default, and what all
+    /// agent versions support.
+    Direct,
+    /// Through the EVP proxy of newer agents, which forwards them to intake
+    /// as is and adds its API key, so the profiler doesn't need one.
+    AgentProxy,
+}
+
+// #[default] on enum variants is newer than our minimum supported Rust version.
+#[allow(clippy::derivable_impls)]
+impl Default for AgentMode {
+    fn default() -> Self {
+        AgentMode::Direct
+    }
 }
 
 pub struct ProfileExporterV3 {
@@ -49,6 +92,29 @@ pub struct ProfileExporterV3 {
     endpoint: Endpoint,
     family: Cow<'static, str>,
     tags: Option<Vec<Tag>>,
+    tag_placement: TagPlacement,
+    host_header: Option<HeaderValue>,
+}
+
+/// Where the profile's tags are put in the request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TagPlacement {
+    /// Each tag is sent as its own `tags[]` form field. This is the default,
+    /// and what the v3 intake and current agents expect.
+    FormField,
+    /// The tags are sent comma separated as `tags_profiler` in an
+    /// `event.json` form file, which newer intakes expect. The rest of the
+    /// upload is described there too, see `event_json`, instead of in form
+    /// fields.
+    EventJson,
+}
+
+// #[default] on enum variants is newer than our minimum supported Rust version.
+#[allow(clippy::derivable_impls)]
+impl Default for TagPlacement {
+    fn default() -> Self {
+        TagPlacement::FormField
+    }
 }
 
 pub struct Request {
@@ -87,6 +153,22 @@ impl Request {
     async fn send(
         self,
         client: &HttpClient,
+        counters: &metrics::RequestCounters,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<hyper::Response<hyper::Body>, Box<dyn std::error::Error>> {
+        let result = self.send_cancellable(client, counters, cancel).await;
+        match &result {
+            Ok(response) => counters.record_response(response.status()),
+            Err(err) if is_cancellation(err.as_ref()) => {}
+            Err(_) => counters.record_error(),
+        }
+        result
+    }
+
+    async fn send_cancellable(
+        self,
+        client: &HttpClient,
+        counters: &metrics::RequestCounters,
         cancel: Option<&CancellationToken>,
     ) -> Result<hyper::Response<hyper::Body>, Box<dyn std::error::Error>> {
         tokio::select! {
@@ -98,49 +180,205 @@ impl Request {
             => Err(crate::errors::Error::UserRequestedCancellation.into()),
             result = async {
                 Ok(match self.timeout {
-                    Some(t) => tokio::time::timeout(t, client.request(self.req))
+                    Some(t) => tokio::time::timeout(t, request_with_reconnect(client, counters, self.req))
                         .await
                         .map_err(|_| crate::errors::Error::OperationTimedOut)?,
-                    None => client.request(self.req).await,
+                    None => request_with_reconnect(client, counters, self.req).await,
                 }?)}
             => result,
         }
     }
 }
 
+/// Sends the request, and if its connection was closed before any of it was
+/// written (e.g. the agent restarted or drained its connections), so that it
+/// provably never reached the agent, reconnects and sends it once more.
+/// Requests which were written even in part aren't sent again, as the agent
+/// may have read them whole. Connecting again goes through the socket path
+/// anew, so when the agent recreated its unix socket the retry reaches the
+/// new one. This is separate from any retries at the application level.
+///
+/// The client only speaks HTTP/1.1, where a closed connection is all there
+/// is to go by, there are no HTTP/2 GOAWAY frames telling which requests
+/// were left unprocessed.
+///
+/// The body is buffered so it can be sent twice. Bodies made from bytes,
+/// as those of `Exporter::send`, are only reference counted, not copied.
+async fn request_with_reconnect(
+    client: &HttpClient,
+    counters: &metrics::RequestCounters,
+    req: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+
+    let rebuild = |body: Bytes| {
+        let mut req = hyper::Request::new(hyper::Body::from(body));
+        *req.method_mut() = parts.method.clone();
+        *req.uri_mut() = parts.uri.clone();
+        *req.version_mut() = parts.version;
+        *req.headers_mut() = parts.headers.clone();
+        req
+    };
+
+    let rebuild = &rebuild;
+    let attempt = |body: Bytes| async move {
+        let len = body.len();
+        let tracker = std::sync::Arc::new(connector::WriteTracker::default());
+        let result = tracker.clone().scope(client.request(rebuild(body))).await;
+        // Only bodies of requests which reached a connection count as sent
+        if result.is_ok() || tracker.written() {
+            counters.record_body(len);
+        }
+        (result, tracker)
+    };
+
+    let (first, tracker) = attempt(body.clone()).await;
+    match first {
+        Err(err) if never_reached_peer(&err, &tracker) => attempt(body).await.0,
+        result => result,
+    }
+}
+
+/// Whether the request failed before any of it was written. hyper cancels
+/// the requests it hasn't started writing when their connection closes, and
+/// otherwise the tracker of the connection tells.
+fn never_reached_peer(err: &hyper::Error, tracker: &connector::WriteTracker) -> bool {
+    err.is_canceled() || tracker.nothing_written()
+}
+
+fn is_cancellation(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<crate::errors::Error>()
+        == Some(&crate::errors::Error::UserRequestedCancellation)
+}
+
+fn format_timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.9fZ").to_string()
+}
+
+/// Renders the event document which describes a profile upload to the
+/// intake: its start and end as RFC 3339 timestamps, the profiler family,
+/// and the tags, comma separated. The start and end are typically those of
+/// an encoded profile. This is the `event.json` part of the requests built
+/// with `TagPlacement::EventJson`.
+pub fn event_json(
+    start: std::time::SystemTime,
+    end: std::time::SystemTime,
+    tags: &[Tag],
+    family: &str,
+) -> String {
+    let tags: Vec<String> = tags.iter().map(ToString::to_string).collect();
+    serde_json::json!({
+        "version": "4",
+        "family": family,
+        "start": format_timestamp(start.into()),
+        "end": format_timestamp(end.into()),
+        "tags_profiler": tags.join(","),
+    })
+    .to_string()
+}
+
+/// A file to upload, in the part named `data[name]`. The part's filename
+/// is derived from `name` and whether `bytes` are gzipped, see
+/// `part_filename`.
 pub struct File<'a> {
     pub name: &'a str,
     pub bytes: &'a [u8],
 }
 
+/// The filename of the part of `file`. Some intake versions reject parts
+/// without a filename, and key on its extension, so pprof files, named
+/// `.pprof` or `.pb`, are sent as `.pb`, or `.pb.gz` when gzipped. Other
+/// files keep their name, with `.gz` appended when gzipped.
+fn part_filename(file: &File) -> String {
+    let gzipped = file.bytes.starts_with(&[0x1f, 0x8b]);
+    let name = match file.name {
+        "" => "profile.pb",
+        name => name,
+    };
+    let stem = name.strip_suffix(".gz").unwrap_or(name);
+    let pprof = [".pprof", ".pb"]
+        .iter()
+        .find_map(|extension| stem.strip_suffix(extension));
+    match (pprof, gzipped) {
+        (Some(stem), true) => format!("{}.pb.gz", stem),
+        (Some(stem), false) => format!("{}.pb", stem),
+        (None, true) => format!("{}.gz", stem),
+        (None, false) => stem.to_owned(),
+    }
+}
+
 impl Endpoint {
+    /// Describes where requests are sent, in metrics.
+    fn target(&self) -> String {
+        #[cfg(unix)]
+        if let Ok(path) = connector::uds::socket_path_from_uri(&self.url) {
+            return format!("unix://{}", path.display());
+        }
+        let port = self.url.port_u16().unwrap_or(match self.url.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+        format!("{}:{}", self.url.host().unwrap_or_default(), port)
+    }
+
     /// Creates an Endpoint for talking to the Datadog agent.
     ///
     /// # Arguments
     /// * `base_url` - has protocol, host, and port e.g. http://localhost:8126/
     pub fn agent(base_url: Uri) -> Result<Endpoint, Box<dyn Error>> {
+        Self::agent_with_mode(base_url, AgentMode::Direct)
+    }
+
+    /// Creates an Endpoint for talking to the Datadog agent, in the given
+    /// mode.
+    ///
+    /// # Arguments
+    /// * `base_url` - has protocol, host, and port e.g. http://localhost:8126/
+    /// * `mode` - whether to use the agent's profiling endpoint or its EVP
+    ///   proxy, see `AgentMode`.
+    pub fn agent_with_mode(base_url: Uri, mode: AgentMode) -> Result<Endpoint, Box<dyn Error>> {
+        let (endpoint_path, evp_subdomain) = match mode {
+            AgentMode::Direct => ("/profiling/v1/input", None),
+            AgentMode::AgentProxy => (EVP_PROXY_PATH, Some(PROFILE_INTAKE_SUBDOMAIN)),
+        };
         let mut parts = base_url.into_parts();
         let p_q = match parts.path_and_query {
             None => None,
             Some(pq) => {
                 let path = pq.path();
                 let path = path.strip_suffix('/').unwrap_or(path);
-                Some(format!("{}/profiling/v1/input", path).parse()?)
+                Some(format!("{}{}", path, endpoint_path).parse()?)
             }
         };
         parts.path_and_query = p_q;
         let url = Uri::from_parts(parts)?;
-        Ok(Endpoint { url, api_key: None })
+        Ok(Endpoint {
+            url,
+            api_key: None,
+            evp_subdomain,
+        })
     }
 
     /// Creates an Endpoint for talking to the Datadog agent though a unix socket.
     ///
     /// # Arguments
-    /// * `socket_path` - file system path to the socket
+    /// * `socket_path` - file system path to the socket, or `@name` for an
+    ///   abstract socket on Linux
     #[cfg(unix)]
     pub fn agent_uds(path: &std::path::Path) -> Result<Endpoint, Box<dyn Error>> {
+        Self::agent_uds_with_mode(path, AgentMode::Direct)
+    }
+
+    /// Creates an Endpoint for talking to the Datadog agent though a unix
+    /// socket, in the given mode, see `agent_with_mode`.
+    #[cfg(unix)]
+    pub fn agent_uds_with_mode(
+        path: &std::path::Path,
+        mode: AgentMode,
+    ) -> Result<Endpoint, Box<dyn Error>> {
         let base_url = socket_path_to_uri(path)?;
-        Self::agent(base_url)
+        Self::agent_with_mode(base_url, mode)
     }
 
     /// Creates an Endpoint for talking to Datadog intake without using the agent.
@@ -158,6 +396,7 @@ impl Endpoint {
         Ok(Endpoint {
             url: Uri::from_str(intake_url.as_str())?,
             api_key: Some(api_key.into()),
+            evp_subdomain: None,
         })
     }
 }
@@ -173,9 +412,118 @@ impl ProfileExporterV3 {
             endpoint,
             family: family.into(),
             tags,
+            tag_placement: TagPlacement::default(),
+            host_header: None,
         })
     }
 
+    pub fn set_tag_placement(&mut self, tag_placement: TagPlacement) {
+        self.tag_placement = tag_placement;
+    }
+
+    /// Sends `host` as the Host header instead of the host of the endpoint,
+    /// for intakes behind a virtual host while connecting by IP, or through
+    /// a unix socket where there is no host at all.
+    pub fn set_host_header(&mut self, host: Option<HeaderValue>) {
+        self.host_header = host;
+    }
+
+    /// Sends `proxy_header` at the start of each tcp connection, for agents
+    /// behind a load balancer which expects the PROXY protocol.
+    pub fn set_proxy_header(&mut self, proxy_header: Option<ProxyHeader>) {
+        self.exporter.connector_config.proxy_header = proxy_header;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// Sets how tls connections are made to endpoints addressed by IP, see
+    /// `IpTlsOptions`.
+    pub fn set_ip_tls_options(&mut self, ip_tls: Option<IpTlsOptions>) {
+        self.exporter.connector_config.ip_tls = ip_tls;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// Connects to https endpoints on loopback addresses, such as an agent on
+    /// the same host, in plain text. Endpoints on other hosts still use tls.
+    /// This is off by default; the downgrades are counted in
+    /// `tls_handshake_stats`.
+    pub fn set_plaintext_loopback(&mut self, enabled: bool) {
+        self.exporter.connector_config.plaintext_loopback = enabled;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// Makes all tcp connections through a SOCKS5 proxy, for networks which
+    /// only allow egress through one. Tls connections are tunnelled too.
+    pub fn set_socks5_proxy(&mut self, socks5_proxy: Option<Socks5Proxy>) {
+        self.exporter.connector_config.socks5_proxy = socks5_proxy;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// Makes tcp connections through the HTTP proxies of `http_proxy`, see
+    /// `ProxyConfig::from_env` to follow the usual proxy variables. Tls
+    /// connections are tunnelled with CONNECT. Has no effect while a SOCKS5
+    /// proxy is set.
+    pub fn set_http_proxy(&mut self, http_proxy: Option<ProxyConfig>) {
+        self.exporter.connector_config.http_proxy = http_proxy;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// Resolves host names with `resolver`, which can be a clone of the
+    /// resolver of another exporter so they share its cache, see `resolver`.
+    pub fn set_resolver(&mut self, resolver: SharedResolver) {
+        self.exporter.connector_config.resolver = resolver;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// The resolver of the exporter, to share with other exporters.
+    pub fn resolver(&self) -> SharedResolver {
+        self.exporter.connector_config.resolver.clone()
+    }
+
+    /// Enables tcp keepalive on the connections, probing idle ones every
+    /// `interval`, for networks which drop quiet connections. None, the
+    /// default, disables it.
+    pub fn set_tcp_keepalive(&mut self, interval: Option<std::time::Duration>) {
+        self.exporter.connector_config.tcp_keepalive = interval;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// Sets TCP_NODELAY on the connections, so small writes aren't delayed
+    /// by Nagle's algorithm. Off by default.
+    pub fn set_tcp_nodelay(&mut self, enabled: bool) {
+        self.exporter.connector_config.tcp_nodelay = enabled;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// Caps the bandwidth used by uploads, for hosts where they would
+    /// otherwise starve the traffic of the application.
+    pub fn set_bandwidth_limit(&mut self, limit: Option<BandwidthLimit>) {
+        self.exporter.connector_config.bandwidth_limit = limit;
+        self.exporter.client = Exporter::build_client(&self.exporter.connector_config);
+    }
+
+    /// Statistics of the tls handshakes made so far. Connections aren't kept
+    /// alive between requests, so each https request makes a handshake,
+    /// which is cheaper when it resumes a previous session.
+    pub fn tls_handshake_stats(&self) -> TlsHandshakeStats {
+        self.exporter.connector_config.tls_metrics.stats()
+    }
+
+    /// The counters of the requests sent so far, for monitoring the exporter
+    /// itself, e.g. with `ExporterMetrics::render_prometheus`.
+    pub fn metrics(&self) -> ExporterMetrics {
+        ExporterMetrics::new(
+            self.endpoint.target(),
+            &self.exporter.counters,
+            self.exporter.in_flight(),
+            self.tls_handshake_stats(),
+        )
+    }
+
+    /// See `Exporter::set_max_in_flight`.
+    pub fn set_max_in_flight(&mut self, max: Option<usize>) {
+        self.exporter.set_max_in_flight(max);
+    }
+
     /// Build a Request object representing the profile information provided.
     pub fn build(
         &self,
@@ -187,14 +535,30 @@ impl ProfileExporterV3 {
     ) -> Result<Request, Box<dyn Error>> {
         let mut form = multipart::Form::default();
 
-        form.add_text("version", "3");
-        form.add_text("start", start.format("%Y-%m-%dT%H:%M:%S%.9fZ").to_string());
-        form.add_text("end", end.format("%Y-%m-%dT%H:%M:%S%.9fZ").to_string());
-        form.add_text("family", self.family.to_owned());
-
-        for tags in self.tags.as_ref().iter().chain(additional_tags.iter()) {
-            for tag in tags.iter() {
-                form.add_text("tags[]", tag.to_string());
+        let tags = self
+            .tags
+            .iter()
+            .chain(additional_tags)
+            .flat_map(|tags| tags.iter());
+        match self.tag_placement {
+            TagPlacement::FormField => {
+                form.add_text("version", "3");
+                form.add_text("start", format_timestamp(start));
+                form.add_text("end", format_timestamp(end));
+                form.add_text("family", self.family.to_string());
+                for tag in tags {
+                    form.add_text("tags[]", tag.to_string());
+                }
+            }
+            TagPlacement::EventJson => {
+                let tags: Vec<Tag> = tags.cloned().collect();
+                let event = event_json(start.into(), end.into(), &tags, &self.family);
+                form.add_reader_file_with_mime(
+                    "event",
+                    Cursor::new(event.into_bytes()),
+                    "event.json",
+                    mime_guess::mime::APPLICATION_JSON,
+                );
             }
         }
 
@@ -202,7 +566,7 @@ impl ProfileExporterV3 {
             form.add_reader_file(
                 format!("data[{}]", file.name),
                 Cursor::new(file.bytes.to_owned()),
-                file.name,
+                part_filename(file),
             )
         }
 
@@ -212,6 +576,11 @@ impl ProfileExporterV3 {
             .header("User-Agent", concat!("DDProf/", env!("CARGO_PKG_VERSION")))
             .header("Connection", "close");
 
+        // hyper only derives the Host header from the uri when it isn't set
+        if let Some(host) = &self.host_header {
+            builder = builder.header(hyper::header::HOST, host.clone());
+        }
+
         if let Some(api_key) = &self.endpoint.api_key {
             builder = builder.header(
                 "DD-API-KEY",
@@ -219,6 +588,10 @@ impl ProfileExporterV3 {
             );
         }
 
+        if let Some(subdomain) = self.endpoint.evp_subdomain {
+            builder = builder.header(DATADOG_EVP_SUBDOMAIN_HEADER, subdomain);
+        }
+
         if let Some(container_id) = ddcommon::container_id::get_container_id() {
             builder = builder.header(DATADOG_CONTAINER_ID_HEADER, container_id);
         }
@@ -229,28 +602,44 @@ impl ProfileExporterV3 {
         )
     }
 
+    /// Sends the request. The extensions of the response hold the
+    /// `ConnectionInfo` of the connection it came on.
     pub fn send(
         &self,
         request: Request,
         cancel: Option<&CancellationToken>,
     ) -> Result<hyper::Response<hyper::Body>, Box<dyn Error>> {
-        self.exporter
-            .runtime
-            .block_on(request.send(&self.exporter.client, cancel))
+        self.exporter.execute(request, cancel)
+    }
+
+    /// See `Exporter::shutdown`.
+    pub fn shutdown(&self, grace: std::time::Duration) -> bool {
+        self.exporter.shutdown(grace)
     }
 }
 
 impl Exporter {
     /// Creates a new Exporter, initializing the TLS stack.
     pub fn new() -> Result<Self, Box<dyn Error>> {
-        // Set idle to 0, which prevents the pipe being broken every 2nd request
-        let client = hyper::Client::builder()
-            .pool_max_idle_per_host(0)
-            .build(connector::Connector::new());
+        let connector_config = connector::ConnectorConfig::new();
+        let client = Self::build_client(&connector_config);
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()?;
-        Ok(Self { client, runtime })
+        Ok(Self {
+            client,
+            runtime,
+            connector_config,
+            counters: Default::default(),
+            drain: Default::default(),
+        })
+    }
+
+    fn build_client(connector_config: &connector::ConnectorConfig) -> HttpClient {
+        // Set idle to 0, which prevents the pipe being broken every 2nd request
+        hyper::Client::builder()
+            .pool_max_idle_per_host(0)
+            .build(connector::Connector::new(connector_config))
     }
 
     pub fn send(
@@ -261,15 +650,720 @@ impl Exporter {
         body: &[u8],
         timeout: std::time::Duration,
     ) -> Result<hyper::Response<hyper::Body>, Box<dyn std::error::Error>> {
-        self.runtime.block_on(async {
-            let mut request = hyper::Request::builder()
-                .method(http_method)
-                .uri(url)
-                .body(hyper::Body::from(Bytes::copy_from_slice(body)))?;
-            std::mem::swap(request.headers_mut(), &mut headers);
-
-            let request: Request = request.into();
-            request.with_timeout(timeout).send(&self.client, None).await
-        })
+        let mut request = hyper::Request::builder()
+            .method(http_method)
+            .uri(url)
+            .body(hyper::Body::from(Bytes::copy_from_slice(body)))?;
+        std::mem::swap(request.headers_mut(), &mut headers);
+
+        let request: Request = request.into();
+        self.execute(request.with_timeout(timeout), None)
+    }
+
+    fn execute(
+        &self,
+        request: Request,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<hyper::Response<hyper::Body>, Box<dyn std::error::Error>> {
+        let _in_flight = self.drain.start()?;
+        self.runtime
+            .block_on(request.send(&self.client, &self.counters, cancel))
+    }
+
+    /// Bounds the number of requests sent at once, for when several profiles
+    /// are ready together, so they don't all connect to the agent at once.
+    /// Sends beyond the limit wait for earlier ones to complete. A limit of
+    /// 0 is taken as 1, and None, the default, removes the limit.
+    pub fn set_max_in_flight(&mut self, max: Option<usize>) {
+        self.drain.set_max_in_flight(max);
+    }
+
+    /// The number of requests being sent, not counting those waiting for
+    /// others to complete, see `set_max_in_flight`.
+    pub fn in_flight(&self) -> usize {
+        self.drain.in_flight()
+    }
+
+    /// Stops the exporter, for when the process exits: sends made from now
+    /// on fail, and those in flight are given up to `grace` to complete, so
+    /// the last profile isn't lost. Returns whether they all completed.
+    /// Connections aren't kept alive between requests, so none are left
+    /// open once the requests in flight are done.
+    pub fn shutdown(&self, grace: std::time::Duration) -> bool {
+        self.drain.shutdown(grace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn read_request_head<S: Read>(stream: &mut S) -> String {
+        let mut head = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8_lossy(&head).into_owned()
+    }
+
+    /// Reads a request with a content-length, leaving none of it unread.
+    fn read_request<S: Read>(stream: &mut S) {
+        let mut head = Vec::new();
+        let mut byte = [0u8];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head).to_lowercase();
+        let length = head
+            .split("\r\n")
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).unwrap();
+    }
+
+    fn profile_interval() -> (std::time::SystemTime, std::time::SystemTime) {
+        let start = std::time::UNIX_EPOCH + std::time::Duration::new(1_650_000_000, 5);
+        (start, start + std::time::Duration::from_secs(60))
+    }
+
+    fn build_with_tag_placement(tag_placement: TagPlacement) -> String {
+        let endpoint = Endpoint::agent("http://localhost:8126".parse().unwrap()).unwrap();
+        let tags = vec![Tag::new("service", "php").unwrap()];
+        let mut exporter = ProfileExporterV3::new("php", Some(tags), endpoint).unwrap();
+        exporter.set_tag_placement(tag_placement);
+
+        let additional_tags = vec![Tag::new("host", "bits").unwrap()];
+        let (start, end) = profile_interval();
+        let request = exporter
+            .build(
+                start.into(),
+                end.into(),
+                &[],
+                Some(&additional_tags),
+                DURATION_ZERO,
+            )
+            .unwrap();
+
+        let body = exporter
+            .exporter
+            .runtime
+            .block_on(hyper::body::to_bytes(request.req.into_body()))
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn part_filenames() {
+        let endpoint = Endpoint::agent("http://localhost:8126".parse().unwrap()).unwrap();
+        let mut exporter = ProfileExporterV3::new("php", None, endpoint).unwrap();
+        exporter.set_tag_placement(TagPlacement::EventJson);
+
+        let gzipped = [0x1f, 0x8b, 0x08, 0x00];
+        let files = [
+            File {
+                name: "auto.pprof",
+                bytes: b"profile",
+            },
+            File {
+                name: "heap.pb.gz",
+                bytes: &gzipped,
+            },
+            File {
+                name: "code-provenance.json",
+                bytes: &gzipped,
+            },
+        ];
+        let now = chrono::Utc::now();
+        let request = exporter
+            .build(now, now, &files, None, DURATION_ZERO)
+            .unwrap();
+        let body = exporter
+            .exporter
+            .runtime
+            .block_on(hyper::body::to_bytes(request.req.into_body()))
+            .unwrap();
+        let body = String::from_utf8_lossy(&body);
+
+        for (name, filename) in [
+            ("event", "event.json"),
+            ("data[auto.pprof]", "auto.pb"),
+            ("data[heap.pb.gz]", "heap.pb.gz"),
+            ("data[code-provenance.json]", "code-provenance.json.gz"),
+        ] {
+            let disposition = format!(
+                "content-disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+                name, filename
+            );
+            assert!(
+                body.to_lowercase().contains(&disposition.to_lowercase()),
+                "{}",
+                disposition
+            );
+        }
+        assert_eq!(
+            part_filename(&File {
+                name: "",
+                bytes: b"profile"
+            }),
+            "profile.pb"
+        );
+    }
+
+    #[test]
+    fn tags_as_form_fields() {
+        let body = build_with_tag_placement(TagPlacement::FormField);
+
+        assert_eq!(body.matches("name=\"tags[]\"").count(), 2);
+        assert!(body.contains("\r\n\r\nservice:php\r\n"));
+        assert!(body.contains("\r\n\r\nhost:bits\r\n"));
+        assert!(body.contains("name=\"version\"\r\n\r\n3\r\n"));
+        assert!(body.contains("name=\"start\"\r\n\r\n2022-04-15T05:20:00.000000005Z\r\n"));
+        assert!(!body.contains("event.json"));
+    }
+
+    #[test]
+    fn tags_in_event_json() {
+        let body = build_with_tag_placement(TagPlacement::EventJson);
+
+        assert!(!body.contains("tags[]"));
+        assert!(body.contains("name=\"event\"; filename=\"event.json\""));
+        let (start, end) = profile_interval();
+        let tags = [
+            Tag::new("service", "php").unwrap(),
+            Tag::new("host", "bits").unwrap(),
+        ];
+        assert!(body.contains(&event_json(start, end, &tags, "php")));
+        // Only the event document describes the upload.
+        for field in ["version", "start", "end", "family"] {
+            assert!(!body.contains(&format!("name=\"{}\"", field)), "{}", field);
+        }
+    }
+
+    #[test]
+    fn event_json_document() {
+        let (start, end) = profile_interval();
+        let tags = vec![
+            Tag::new("service", "php").unwrap(),
+            Tag::new("host", "bits").unwrap(),
+        ];
+
+        let event: serde_json::Value =
+            serde_json::from_str(&event_json(start, end, &tags, "php")).unwrap();
+        let mut keys: Vec<_> = event.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["end", "family", "start", "tags_profiler", "version"]);
+        assert_eq!(event["start"], "2022-04-15T05:20:00.000000005Z");
+        assert_eq!(event["end"], "2022-04-15T05:21:00.000000005Z");
+        assert_eq!(event["family"], "php");
+        assert_eq!(event["version"], "4");
+        assert_eq!(event["tags_profiler"], "service:php,host:bits");
+
+        let parsed = chrono::DateTime::parse_from_rfc3339(event["start"].as_str().unwrap());
+        assert_eq!(std::time::SystemTime::from(parsed.unwrap()), start);
+
+        let event: serde_json::Value =
+            serde_json::from_str(&event_json(start, end, &[], "php")).unwrap();
+        assert_eq!(event["tags_profiler"], "");
+    }
+
+    #[test]
+    fn reconnects_once_when_connection_closes_before_the_request() {
+        // An HTTP proxy which closes the first tunnel right after opening
+        // it, before the request is written, and then acts as the agent.
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = proxy.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = proxy.accept().unwrap();
+            read_request_head(&mut stream);
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+            drop(stream);
+
+            let (mut stream, _) = proxy.accept().unwrap();
+            read_request_head(&mut stream);
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .unwrap();
+            read_request(&mut stream);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let endpoint = Endpoint::agent("http://agent.example:8126".parse().unwrap()).unwrap();
+        let mut exporter = ProfileExporterV3::new("php", None, endpoint).unwrap();
+        exporter.set_http_proxy(Some(ProxyConfig {
+            http: Some(address.to_string().parse().unwrap()),
+            ..Default::default()
+        }));
+        let now = chrono::Utc::now();
+        let request = exporter
+            .build(now, now, &[], None, std::time::Duration::from_secs(10))
+            .unwrap();
+        let response = exporter.send(request, None).expect("the retry to succeed");
+        assert_eq!(response.status(), 200);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn does_not_resend_requests_the_agent_may_have_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            // The connection is dropped without a response once the request
+            // was read, as a server crashing while handling it would.
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request(&mut stream);
+            drop(stream);
+
+            listener.set_nonblocking(true).unwrap();
+            listener.accept().map(|_| ()).unwrap_err().kind()
+        });
+
+        let exporter = Exporter::new().unwrap();
+        exporter
+            .send(
+                http::Method::POST,
+                &format!("http://{}/", address),
+                hyper::HeaderMap::new(),
+                b"profile",
+                std::time::Duration::from_secs(10),
+            )
+            .expect_err("the request not to be sent again");
+
+        assert_eq!(server.join().unwrap(), std::io::ErrorKind::WouldBlock);
+    }
+
+    /// Checks that `text` is in the Prometheus text format, as far as we
+    /// produce it, returning the samples by name and labels.
+    fn parse_prometheus(text: &str) -> std::collections::HashMap<String, f64> {
+        let mut types = std::collections::HashMap::new();
+        let mut samples = std::collections::HashMap::new();
+        for line in text.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut words = comment.splitn(3, ' ');
+                let (keyword, name, rest) = (words.next(), words.next(), words.next());
+                assert!(matches!(rest, Some(rest) if !rest.is_empty()), "{}", line);
+                match keyword {
+                    Some("TYPE") => assert!(types.insert(name.unwrap(), rest).is_none()),
+                    Some("HELP") => {}
+                    _ => panic!("unexpected comment {}", line),
+                }
+                continue;
+            }
+            let (series, value) = line.rsplit_once(' ').expect("a value");
+            let value: f64 = value.parse().expect("a number");
+            let (name, labels) = series.split_once('{').expect("labels");
+            let kind = types.get(name).copied().flatten();
+            assert!(
+                matches!(kind, Some("counter") if name.ends_with("_total"))
+                    || kind == Some("gauge"),
+                "{}",
+                line
+            );
+            let labels = labels.strip_suffix('}').expect("labels to end");
+            for label in labels.split(',') {
+                let (key, value) = label.split_once('=').expect("a label");
+                assert!(key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+                assert!(value.starts_with('"') && value.ends_with('"'), "{}", line);
+            }
+            samples.insert(series.to_owned(), value);
+        }
+        samples
+    }
+
+    #[test]
+    fn metrics_in_prometheus_format() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut body_bytes = 0;
+            for status in ["200 OK", "500 Internal Server Error"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_lowercase();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length: usize = head
+                            .split("\r\ncontent-length: ")
+                            .nth(1)
+                            .and_then(|rest| rest.split("\r\n").next())
+                            .expect("a content length")
+                            .parse()
+                            .unwrap();
+                        if body.len() >= length {
+                            body_bytes += length;
+                            break;
+                        }
+                    }
+                }
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            body_bytes as u64
+        });
+
+        let endpoint = Endpoint::agent(format!("http://{}", address).parse().unwrap()).unwrap();
+        let exporter = ProfileExporterV3::new("php", None, endpoint).unwrap();
+        for expected in [200, 500] {
+            let file = File {
+                name: "auto.pprof",
+                bytes: b"profile",
+            };
+            let now = chrono::Utc::now();
+            let request = exporter
+                .build(now, now, &[file], None, std::time::Duration::from_secs(10))
+                .unwrap();
+            let response = exporter.send(request, None).unwrap();
+            assert_eq!(response.status(), expected);
+        }
+        let bytes_received = server.join().unwrap();
+
+        let metrics = exporter.metrics();
+        assert_eq!(metrics.target, address.to_string());
+        assert_eq!(metrics.requests_ok, 1);
+        assert_eq!(metrics.requests_failed, 1);
+        assert!(metrics.bytes_sent > 0);
+        assert_eq!(metrics.bytes_sent, bytes_received);
+
+        let samples = parse_prometheus(&metrics.render_prometheus());
+        let target = format!("target=\"{}\"", address);
+        let sample = |name: &str, labels: &str| samples[&format!("{}{{{}}}", name, labels)];
+        assert_eq!(
+            sample(
+                "ddprof_exporter_requests_total",
+                &format!("{},outcome=\"ok\"", target)
+            ),
+            1.0
+        );
+        assert_eq!(
+            sample(
+                "ddprof_exporter_requests_total",
+                &format!("{},outcome=\"failed\"", target)
+            ),
+            1.0
+        );
+        assert_eq!(
+            sample("ddprof_exporter_sent_bytes_total", &target),
+            metrics.bytes_sent as f64
+        );
+        assert_eq!(sample("ddprof_exporter_tls_handshakes_total", &target), 0.0);
+        assert_eq!(sample("ddprof_exporter_requests_in_flight", &target), 0.0);
+        assert_eq!(samples.len(), 8);
+    }
+
+    #[test]
+    fn refused_requests_send_no_bytes() {
+        // Bind and immediately drop a listener to find a port nothing listens on
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let endpoint = Endpoint::agent(format!("http://{}", address).parse().unwrap()).unwrap();
+        let exporter = ProfileExporterV3::new("php", None, endpoint).unwrap();
+        let now = chrono::Utc::now();
+        let request = exporter
+            .build(now, now, &[], None, std::time::Duration::from_secs(10))
+            .unwrap();
+        assert!(exporter.send(request, None).is_err());
+
+        let metrics = exporter.metrics();
+        assert_eq!(metrics.requests_failed, 1);
+        assert_eq!(metrics.bytes_sent, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn host_header_over_uds() {
+        let socket = std::env::temp_dir().join(format!("ddprof-host-{}.sock", std::process::id()));
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let head = read_request_head(&mut stream);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            head
+        });
+
+        let endpoint = Endpoint::agent_uds(&socket).unwrap();
+        let mut exporter = ProfileExporterV3::new("php", None, endpoint).unwrap();
+        exporter.set_host_header(Some(HeaderValue::from_static("intake.local")));
+        let now = chrono::Utc::now();
+        let request = exporter
+            .build(now, now, &[], None, std::time::Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(request.headers()[hyper::header::HOST], "intake.local");
+
+        let response = exporter.send(request, None).unwrap();
+        assert_eq!(response.status(), 200);
+
+        let head = server.join().unwrap().to_lowercase();
+        std::fs::remove_file(&socket).unwrap();
+        assert_eq!(head.matches("\r\nhost: ").count(), 1);
+        assert!(head.contains("\r\nhost: intake.local\r\n"));
+    }
+
+    #[test]
+    fn response_has_connection_info() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, client_address) = listener.accept().unwrap();
+            read_request(&mut stream);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            client_address
+        });
+
+        let url = format!("http://{}", address).parse().unwrap();
+        let exporter = ProfileExporterV3::new("php", None, Endpoint::agent(url).unwrap()).unwrap();
+        let now = chrono::Utc::now();
+        let request = exporter
+            .build(now, now, &[], None, std::time::Duration::from_secs(10))
+            .unwrap();
+        let response = exporter.send(request, None).unwrap();
+        assert_eq!(response.status(), 200);
+
+        let client_address = server.join().unwrap();
+        assert_eq!(
+            response.extensions().get::<ConnectionInfo>(),
+            Some(&ConnectionInfo {
+                local_addr: Some(client_address),
+                peer_addr: Some(address),
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn next_request_reaches_replaced_agent_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let socket =
+            std::env::temp_dir().join(format!("ddprof-replaced-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let server = {
+            let socket = socket.clone();
+            std::thread::spawn(move || {
+                // The agent restarts while the request is sent, recreating
+                // its socket, and the connection to the old one is reset
+                // with the request partly read. As the agent read some of
+                // it, the request isn't sent again, but the next one
+                // connects to the new socket.
+                let (mut stream, _) = listener.accept().unwrap();
+                stream.read_exact(&mut [0]).unwrap();
+                std::fs::remove_file(&socket).unwrap();
+                let listener = UnixListener::bind(&socket).unwrap();
+                drop(stream);
+
+                let (mut stream, _) = listener.accept().unwrap();
+                read_request(&mut stream);
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+            })
+        };
+
+        let endpoint = Endpoint::agent_uds(&socket).unwrap();
+        let exporter = ProfileExporterV3::new("php", None, endpoint).unwrap();
+        let now = chrono::Utc::now();
+        let build = || {
+            exporter
+                .build(now, now, &[], None, std::time::Duration::from_secs(10))
+                .unwrap()
+        };
+        exporter
+            .send(build(), None)
+            .expect_err("the partly read request not to be sent again");
+        let response = exporter.send(build(), None).unwrap();
+        assert_eq!(response.status(), 200);
+
+        server.join().unwrap();
+        std::fs::remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn shutdown_drains_requests_in_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (received, request_received) = std::sync::mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request_head(&mut stream);
+            received.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let exporter = std::sync::Arc::new(Exporter::new().unwrap());
+        let send = {
+            let exporter = exporter.clone();
+            let url = url.clone();
+            move || {
+                exporter
+                    .send(
+                        http::Method::POST,
+                        &url,
+                        Default::default(),
+                        b"profile",
+                        std::time::Duration::from_secs(10),
+                    )
+                    .map(|response| response.status())
+                    .map_err(|err| err.to_string())
+            }
+        };
+        let sender = std::thread::spawn(send.clone());
+
+        request_received.recv().unwrap();
+        assert!(exporter.shutdown(std::time::Duration::from_secs(5)));
+        assert_eq!(sender.join().unwrap(), Ok(hyper::StatusCode::OK));
+        server.join().unwrap();
+
+        assert_eq!(send(), Err("exporter is shut down".to_owned()));
+    }
+
+    #[test]
+    fn shared_resolver() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                read_request_head(&mut stream);
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .unwrap();
+            }
+        });
+
+        let endpoint =
+            || Endpoint::agent(format!("http://localhost:{}", port).parse().unwrap()).unwrap();
+        let mut first = ProfileExporterV3::new("php", None, endpoint()).unwrap();
+        first.set_resolver(SharedResolver::new(std::time::Duration::from_secs(60)));
+        let mut second = ProfileExporterV3::new("php", None, endpoint()).unwrap();
+        second.set_resolver(first.resolver());
+
+        for exporter in [&first, &second] {
+            let now = chrono::Utc::now();
+            let request = exporter
+                .build(now, now, &[], None, std::time::Duration::from_secs(10))
+                .unwrap();
+            let response = exporter.send(request, None).unwrap();
+            assert_eq!(response.status(), 200);
+        }
+        server.join().unwrap();
+
+        assert_eq!(first.resolver().lookups(), 1);
+        assert_eq!(second.resolver().lookups(), 1);
+    }
+
+    /// The options themselves are checked on the sockets of the connector,
+    /// see `connector::tests::test_tcp_keepalive_and_nodelay`.
+    #[test]
+    fn tcp_keepalive_and_nodelay() {
+        let endpoint = Endpoint::agent("http://localhost:8126".parse().unwrap()).unwrap();
+        let mut exporter = ProfileExporterV3::new("php", None, endpoint).unwrap();
+        let config = |exporter: &ProfileExporterV3| {
+            let config = &exporter.exporter.connector_config;
+            (config.tcp_keepalive, config.tcp_nodelay)
+        };
+        assert_eq!(config(&exporter), (None, false));
+
+        let interval = std::time::Duration::from_secs(30);
+        exporter.set_tcp_keepalive(Some(interval));
+        exporter.set_tcp_nodelay(true);
+        assert_eq!(config(&exporter), (Some(interval), true));
+        exporter.set_tcp_keepalive(None);
+        exporter.set_tcp_nodelay(false);
+        assert_eq!(config(&exporter), (None, false));
+    }
+
+    #[test]
+    fn max_in_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        const SENDS: usize = 6;
+        const MAX_IN_FLIGHT: usize = 2;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let (active, max_active) = (active.clone(), max_active.clone());
+            std::thread::spawn(move || {
+                let mut handlers = vec![];
+                for _ in 0..SENDS {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let (active, max_active) = (active.clone(), max_active.clone());
+                    handlers.push(std::thread::spawn(move || {
+                        read_request_head(&mut stream);
+                        let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_active.fetch_max(now_active, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(100));
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        stream
+                            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                            .unwrap();
+                    }));
+                }
+                for handler in handlers {
+                    handler.join().unwrap();
+                }
+            })
+        };
+
+        let mut exporter = Exporter::new().unwrap();
+        exporter.set_max_in_flight(Some(MAX_IN_FLIGHT));
+        let exporter = Arc::new(exporter);
+        let senders: Vec<_> = (0..SENDS)
+            .map(|_| {
+                let (exporter, url) = (exporter.clone(), url.clone());
+                std::thread::spawn(move || {
+                    let response = exporter
+                        .send(
+                            http::Method::POST,
+                            &url,
+                            Default::default(),
+                            b"profile",
+                            std::time::Duration::from_secs(10),
+                        )
+                        .unwrap();
+                    assert!(exporter.in_flight() <= MAX_IN_FLIGHT);
+                    response.status()
+                })
+            })
+            .collect();
+        for sender in senders {
+            assert_eq!(sender.join().unwrap(), hyper::StatusCode::OK);
+        }
+        server.join().unwrap();
+
+        assert_eq!(max_active.load(Ordering::SeqCst), MAX_IN_FLIGHT);
+        assert_eq!(exporter.in_flight(), 0);
     }
 }
@@ -0,0 +1,153 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Counters of the requests sent by an exporter, and their rendering in the
+//! Prometheus text exposition format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::TlsHandshakeStats;
+
+/// Counted by the exporter as it sends requests.
+#[derive(Debug, Default)]
+pub(crate) struct RequestCounters {
+    requests_ok: AtomicU64,
+    requests_failed: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+impl RequestCounters {
+    /// Counts a request which got a response, successful or not.
+    pub(crate) fn record_response(&self, status: hyper::StatusCode) {
+        if status.is_success() {
+            self.requests_ok.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.requests_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a request which failed without a response.
+    pub(crate) fn record_error(&self) {
+        self.requests_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a body written to a connection, at least in part, once for
+    /// every attempt.
+    pub(crate) fn record_body(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the counters of an exporter, see
+/// `ProfileExporterV3::metrics`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExporterMetrics {
+    /// The host and port requests are sent to, or the path of the unix socket.
+    pub target: String,
+    /// Number of requests which got a successful response.
+    pub requests_ok: u64,
+    /// Number of requests which failed, with or without a response.
+    /// Cancelled requests aren't counted at all.
+    pub requests_failed: u64,
+    /// Bytes of the bodies of requests which were written to a connection,
+    /// excluding headers. Requests which never got a connection, or whose
+    /// connection closed before they were written, aren't counted.
+    pub bytes_sent: u64,
+    /// Number of requests being sent at the time of the snapshot.
+    pub in_flight: u64,
+    pub tls: TlsHandshakeStats,
+}
+
+impl ExporterMetrics {
+    pub(crate) fn new(
+        target: String,
+        counters: &RequestCounters,
+        in_flight: usize,
+        tls: TlsHandshakeStats,
+    ) -> Self {
+        Self {
+            target,
+            requests_ok: counters.requests_ok.load(Ordering::Relaxed),
+            requests_failed: counters.requests_failed.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+            in_flight: in_flight as u64,
+            tls,
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format, each
+    /// labelled with the target. All are counters but `in_flight`.
+    pub fn render_prometheus(&self) -> String {
+        let target = escape_label_value(&self.target);
+        let mut out = String::new();
+        let mut metric =
+            |kind: &str, name: &str, help: &str, samples: &[(Option<&str>, String)]| {
+                writeln!(out, "# HELP ddprof_exporter_{} {}", name, help).unwrap();
+                writeln!(out, "# TYPE ddprof_exporter_{} {}", name, kind).unwrap();
+                for (outcome, value) in samples {
+                    let outcome = match outcome {
+                        Some(outcome) => format!(",outcome=\"{}\"", outcome),
+                        None => String::new(),
+                    };
+                    writeln!(
+                        out,
+                        "ddprof_exporter_{}{{target=\"{}\"{}}} {}",
+                        name, target, outcome, value
+                    )
+                    .unwrap();
+                }
+            };
+
+        let mut counter = |name: &str, help: &str, samples: &[(Option<&str>, String)]| {
+            metric("counter", name, help, samples)
+        };
+        counter(
+            "requests_total",
+            "Requests sent, by outcome.",
+            &[
+                (Some("ok"), self.requests_ok.to_string()),
+                (Some("failed"), self.requests_failed.to_string()),
+            ],
+        );
+        counter(
+            "sent_bytes_total",
+            "Bytes of request bodies sent.",
+            &[(None, self.bytes_sent.to_string())],
+        );
+        counter(
+            "tls_handshakes_total",
+            "Completed tls handshakes.",
+            &[(None, self.tls.handshakes.to_string())],
+        );
+        counter(
+            "tls_resumed_handshakes_total",
+            "Completed tls handshakes which resumed a previous session.",
+            &[(None, self.tls.resumed.to_string())],
+        );
+        counter(
+            "tls_handshake_seconds_total",
+            "Time spent in completed tls handshakes.",
+            &[(None, self.tls.total_duration.as_secs_f64().to_string())],
+        );
+        counter(
+            "plaintext_downgrades_total",
+            "Https connections to loopback addresses made in plain text.",
+            &[(None, self.tls.plaintext_downgrades.to_string())],
+        );
+        metric(
+            "gauge",
+            "requests_in_flight",
+            "Requests being sent.",
+            &[(None, self.in_flight.to_string())],
+        );
+        out
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,89 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Upload cadence. Profilers of a fleet which all upload on the same
+//! wall-clock boundaries send their profiles to the intake in spikes, so the
+//! time between uploads is randomized around the target interval.
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use rand::Rng;
+
+/// An upload interval with random jitter, see `next_sleep`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct JitteredInterval {
+    interval: Duration,
+    jitter: Duration,
+}
+
+impl JitteredInterval {
+    /// Uploads every `interval` on average, each time within `jitter` of it.
+    /// The jitter is capped to the interval, so sleeps are never negative.
+    pub fn new(interval: Duration, jitter: Duration) -> Self {
+        Self {
+            interval,
+            jitter: jitter.min(interval),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Returns how long to sleep until the next upload, uniformly distributed
+    /// between `interval - jitter` and `interval + jitter`.
+    pub fn next_sleep(&self) -> Duration {
+        let nanos = |duration: Duration| u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let low = nanos(self.interval - self.jitter);
+        let high = nanos(self.interval).saturating_add(nanos(self.jitter));
+        Duration::from_nanos(rand::thread_rng().gen_range(low..=high))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleeps_within_jitter_band() {
+        let interval = Duration::from_secs(60);
+        let jitter = Duration::from_secs(6);
+        let schedule = JitteredInterval::new(interval, jitter);
+
+        let count = 10_000;
+        let mut total = Duration::from_secs(0);
+        for _ in 0..count {
+            let sleep = schedule.next_sleep();
+            assert!(sleep >= interval - jitter, "{:?} is too short", sleep);
+            assert!(sleep <= interval + jitter, "{:?} is too long", sleep);
+            total += sleep;
+        }
+
+        // The standard deviation of the mean is jitter / sqrt(3 * count),
+        // about 35ms, so this fails by chance only once in over 10^20 runs.
+        let mean = total / count;
+        let tolerance = Duration::from_millis(350);
+        assert!(
+            mean > interval - tolerance && mean < interval + tolerance,
+            "mean is {:?}",
+            mean
+        );
+    }
+
+    #[test]
+    fn jitter_is_capped_to_interval() {
+        let schedule = JitteredInterval::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(schedule.jitter(), Duration::from_secs(1));
+        for _ in 0..100 {
+            assert!(schedule.next_sleep() <= Duration::from_secs(2));
+        }
+
+        let schedule = JitteredInterval::new(Duration::from_secs(1), Duration::from_secs(0));
+        assert_eq!(schedule.next_sleep(), Duration::from_secs(1));
+    }
+}
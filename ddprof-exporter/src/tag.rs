@@ -2,9 +2,11 @@
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
 
 use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::fmt::{Debug, Display, Formatter};
 
-#[derive(Clone, Eq, PartialEq)]
+/// Tags are ordered by key, then by value, for canonical sorted sets.
+#[derive(Clone, Eq, Hash, PartialEq)]
 pub struct Tag {
     value: Cow<'static, str>,
 }
@@ -15,6 +17,20 @@ impl Debug for Tag {
     }
 }
 
+impl Ord for Tag {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Tags can't end with a colon, so "key" and "key:" can't both exist
+        // and this agrees with Eq.
+        (self.key(), self.value()).cmp(&(other.key(), other.value()))
+    }
+}
+
+impl PartialOrd for Tag {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 // Any type which implements Display automatically has to_string.
 impl Display for Tag {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -63,8 +79,26 @@ impl Tag {
         Tag::from_value(format!("{}:{}", key, value))
     }
 
+    /// The part of the tag before the first colon, or the whole tag if it
+    /// has none.
+    pub fn key(&self) -> &str {
+        match self.value.split_once(':') {
+            Some((key, _)) => key,
+            None => &self.value,
+        }
+    }
+
+    /// The part of the tag after the first colon, which may have colons
+    /// itself, or "" if it has none.
+    pub fn value(&self) -> &str {
+        match self.value.split_once(':') {
+            Some((_, value)) => value,
+            None => "",
+        }
+    }
+
     pub fn into_owned(mut self) -> Self {
-        self.value = self.value.to_owned();
+        self.value = Cow::Owned(self.value.into_owned());
         self
     }
 }
@@ -107,9 +141,18 @@ pub fn parse_tags(str: &str) -> (Vec<Tag>, Option<String>) {
     (tags, error_message)
 }
 
+/// Parses tags like `parse_tags`, but all or nothing: if any of them is
+/// malformed, returns the message describing every malformed tag instead.
+pub fn parse_tags_strict(str: &str) -> Result<Vec<Tag>, String> {
+    match parse_tags(str) {
+        (tags, None) => Ok(tags),
+        (_, Some(error_message)) => Err(error_message),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{parse_tags, Tag};
+    use crate::{parse_tags, parse_tags_strict, Tag};
 
     #[test]
     fn test_empty_key() {
@@ -161,6 +204,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_canonical_order() {
+        let tag = |key, value| Tag::new(key, value).unwrap();
+        let mut tags = vec![
+            tag("service", "php"),
+            tag("env", "staging:east"),
+            tag("env-type", "canary"),
+            Tag::from_value("bare").unwrap(),
+            tag("env", "prod"),
+        ];
+        tags.sort();
+        let sorted: Vec<String> = tags.iter().map(Tag::to_string).collect();
+        // By key first, so "env:..." comes before "env-type:..." even though
+        // ':' sorts after '-'
+        assert_eq!(
+            sorted,
+            [
+                "bare",
+                "env:prod",
+                "env:staging:east",
+                "env-type:canary",
+                "service:php"
+            ]
+        );
+        assert_eq!(tags[2].key(), "env");
+        assert_eq!(tags[2].value(), "staging:east");
+        assert_eq!(tags[0].value(), "");
+
+        let set: std::collections::HashSet<Tag> =
+            tags.iter().cloned().chain(tags.clone()).collect();
+        assert_eq!(set.len(), tags.len());
+        assert!(set.contains(&tag("env", "prod")));
+        assert!(!set.contains(&tag("env", "dev")));
+    }
+
     #[test]
     fn test_missing_colon_parsing() {
         let tag = Tag::from_value("tag").unwrap();
@@ -177,6 +255,29 @@ mod tests {
         let _ = Tag::from_value("tag:").expect_err("Cannot end with a colon");
     }
 
+    #[test]
+    fn test_strict_parsing() {
+        let (tags, error_message) = parse_tags("ok:1,:bad");
+        assert_eq!(tags, vec![Tag::new("ok", "1").unwrap()]);
+        assert!(error_message.is_some());
+
+        assert_eq!(
+            parse_tags_strict("ok:1,:bad bad:"),
+            Err(
+                "Errors while parsing tags: tag ':bad' begins with a colon, \
+                 tag 'bad:' ends with a colon"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            parse_tags_strict("ok:1 env:staging"),
+            Ok(vec![
+                Tag::new("ok", "1").unwrap(),
+                Tag::new("env", "staging").unwrap()
+            ])
+        );
+    }
+
     #[test]
     fn test_tags_parsing() {
         let cases = [
@@ -68,6 +68,33 @@ mod tests {
 
         let actual_headers = request.headers();
         assert!(!actual_headers.contains_key("DD-API-KEY"));
+        assert!(!actual_headers.contains_key("X-Datadog-EVP-Subdomain"));
+    }
+
+    #[test]
+    fn multipart_agent_proxy() {
+        let base_url = "http://localhost:8126/".parse().expect("url to parse");
+        let endpoint = Endpoint::agent_with_mode(base_url, AgentMode::AgentProxy)
+            .expect("endpoint to construct");
+        let exporter = ProfileExporterV3::new("php", Some(default_tags()), endpoint)
+            .expect("exporter to construct");
+
+        let request = multipart(&exporter);
+
+        assert_eq!(
+            request.uri().to_string(),
+            "http://localhost:8126/evp_proxy/v4/v1/input"
+        );
+
+        let actual_headers = request.headers();
+        assert_eq!(
+            actual_headers
+                .get("X-Datadog-EVP-Subdomain")
+                .expect("subdomain header to exist"),
+            "intake.profile"
+        );
+        // The agent adds its own API key
+        assert!(!actual_headers.contains_key("DD-API-KEY"));
     }
 
     #[test]
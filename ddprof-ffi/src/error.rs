@@ -0,0 +1,88 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+thread_local! {
+    /// The message of the last panic caught on this thread, or of the last
+    /// error reported with `set_last_error`.
+    // const initializers of thread locals are newer than our minimum
+    // supported Rust version.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static LAST_ERROR: RefCell<Option<String>> = RefCell::new(None);
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Makes the next call to `catch_panic` on this thread panic, so tests
+    /// can check how each function reports it.
+    #[allow(clippy::missing_const_for_thread_local)]
+    pub(crate) static INJECT_PANIC: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Runs `f`, catching a panic rather than letting it unwind into C, which is
+/// undefined behavior. A caught panic is recorded as the last error of the
+/// thread, and returned as Err so the caller can turn it into its own error
+/// return. Objects `f` was modifying may be left half modified, but valid.
+pub(crate) fn catch_panic<R, F: FnOnce() -> R>(f: F) -> Result<R, String> {
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        #[cfg(test)]
+        if INJECT_PANIC.with(|inject| inject.replace(false)) {
+            panic!("injected panic");
+        }
+        f()
+    }));
+    result.map_err(|payload| {
+        let message = format!("panic: {}", panic_message(payload.as_ref()));
+        LAST_ERROR.with(|last| *last.borrow_mut() = Some(message.clone()));
+        message
+    })
+}
+
+/// Records `message` as the last error of the thread, for functions which
+/// can only return null on failure.
+pub(crate) fn set_last_error(message: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Takes the message of the last panic caught by a `ddprof_ffi_` function on
+/// the calling thread, or of the last error of a function documented to
+/// report one, leaving none behind. The result is empty if there was
+/// none, and must be freed with `ddprof_ffi_Vec_u8_drop`.
+#[must_use]
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_take_last_error() -> crate::Vec<u8> {
+    match LAST_ERROR.with(|last| last.borrow_mut().take()) {
+        Some(message) => message.into_bytes().into(),
+        None => crate::Vec::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caught_panics() {
+        assert_eq!(catch_panic(|| 1), Ok(1));
+        assert!(ddprof_ffi_take_last_error().is_empty());
+
+        let result = catch_panic(|| -> i32 { panic!("{} went wrong", "something") });
+        assert_eq!(result, Err("panic: something went wrong".to_owned()));
+        let message: Vec<u8> = ddprof_ffi_take_last_error().into();
+        assert_eq!(message, b"panic: something went wrong");
+        assert!(ddprof_ffi_take_last_error().is_empty());
+    }
+}
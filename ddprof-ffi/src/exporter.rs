@@ -4,16 +4,19 @@
 #![allow(renamed_and_removed_lints)]
 #![allow(clippy::box_vec)]
 
+use crate::error::catch_panic;
 use crate::{AsBytes, ByteSlice, CharSlice, Slice, Timespec};
 use ddprof_exporter as exporter;
 use ddprof_exporter::Tag;
-use exporter::ProfileExporterV3;
+use exporter::{AgentMode, ProfileExporterV3};
 use std::borrow::Cow;
 use std::error::Error;
 use std::ptr::NonNull;
 use std::str::FromStr;
 
+// The payloads are only read from C.
 #[repr(C)]
+#[allow(dead_code)]
 pub enum SendResult {
     HttpResponse(HttpStatus),
     Err(crate::Vec<u8>),
@@ -41,6 +44,7 @@ pub unsafe extern "C" fn new_profile_exporter_v3_result_drop(result: NewProfileE
 #[repr(C)]
 pub enum EndpointV3<'a> {
     Agent(CharSlice<'a>),
+    AgentProxy(CharSlice<'a>),
     Agentless(CharSlice<'a>, CharSlice<'a>),
 }
 
@@ -69,6 +73,15 @@ pub extern "C" fn endpoint_agent(base_url: CharSlice) -> EndpointV3 {
     EndpointV3::Agent(base_url)
 }
 
+/// Creates an endpoint that sends profiles through the EVP proxy of the
+/// agent, which adds its API key before forwarding them to the intake.
+/// # Arguments
+/// * `base_url` - Contains a URL with scheme, host, and port e.g. "https://agent:8126/".
+#[export_name = "ddprof_ffi_EndpointV3_agent_proxy"]
+pub extern "C" fn endpoint_agent_proxy(base_url: CharSlice) -> EndpointV3 {
+    EndpointV3::AgentProxy(base_url)
+}
+
 /// Creates an endpoint that uses the Datadog intake directly aka agentless.
 /// # Arguments
 /// * `site` - Contains a host and port e.g. "datadoghq.com".
@@ -103,6 +116,10 @@ unsafe fn try_to_endpoint(
             let base_url = try_to_url(url)?;
             ddprof_exporter::Endpoint::agent(base_url)
         }
+        EndpointV3::AgentProxy(url) => {
+            let base_url = try_to_url(url)?;
+            ddprof_exporter::Endpoint::agent_with_mode(base_url, AgentMode::AgentProxy)
+        }
         EndpointV3::Agentless(site, api_key) => {
             let site_str = site.try_to_utf8()?;
             let api_key_str = api_key.try_to_utf8()?;
@@ -121,14 +138,16 @@ pub extern "C" fn profile_exporter_new(
     tags: Option<&crate::Vec<Tag>>,
     endpoint: EndpointV3,
 ) -> NewProfileExporterV3Result {
-    match || -> Result<ProfileExporterV3, Box<dyn Error>> {
+    let result = catch_panic(|| -> Result<ProfileExporterV3, Box<dyn Error>> {
         let family = unsafe { family.to_utf8_lossy() }.into_owned();
         let converted_endpoint = unsafe { try_to_endpoint(endpoint)? };
         let tags = tags.map(|tags| tags.iter().map(|tag| tag.clone().into_owned()).collect());
         ProfileExporterV3::new(family, tags, converted_endpoint)
-    }() {
-        Ok(exporter) => NewProfileExporterV3Result::Ok(Box::into_raw(Box::new(exporter))),
-        Err(err) => NewProfileExporterV3Result::Err(err.into()),
+    });
+    match result {
+        Ok(Ok(exporter)) => NewProfileExporterV3Result::Ok(Box::into_raw(Box::new(exporter))),
+        Ok(Err(err)) => NewProfileExporterV3Result::Err(err.into()),
+        Err(message) => NewProfileExporterV3Result::Err(message.into_bytes().into()),
     }
 }
 
@@ -163,7 +182,7 @@ pub unsafe extern "C" fn profile_exporter_build(
     additional_tags: Option<&crate::Vec<Tag>>,
     timeout_ms: u64,
 ) -> Option<Box<Request>> {
-    match exporter {
+    let result = catch_panic(|| match exporter {
         None => None,
         Some(exporter) => {
             let timeout = std::time::Duration::from_millis(timeout_ms);
@@ -180,7 +199,8 @@ pub unsafe extern "C" fn profile_exporter_build(
                 Err(_) => None,
             }
         }
-    }
+    });
+    result.unwrap_or(None)
 }
 
 /// Sends the request, returning the HttpStatus.
@@ -217,13 +237,15 @@ pub unsafe extern "C" fn profile_exporter_send(
 
     let cancel_option = unwrap_cancellation_token(cancel);
 
-    match || -> Result<HttpStatus, Box<dyn std::error::Error>> {
+    let result = catch_panic(|| -> Result<HttpStatus, Box<dyn std::error::Error>> {
         let response = exp_ptr.as_ref().send((*request_ptr).0, cancel_option)?;
 
         Ok(HttpStatus(response.status().as_u16()))
-    }() {
-        Ok(code) => SendResult::HttpResponse(code),
-        Err(err) => SendResult::Err(err.into()),
+    });
+    match result {
+        Ok(Ok(code)) => SendResult::HttpResponse(code),
+        Ok(Err(err)) => SendResult::Err(err.into()),
+        Err(message) => SendResult::Err(message.into_bytes().into()),
     }
 }
 
@@ -232,7 +254,7 @@ fn unwrap_cancellation_token<'a>(
 ) -> Option<&'a tokio_util::sync::CancellationToken> {
     cancel.map(|c| {
         let wrapped_reference: &CancellationToken = unsafe { c.as_ref() };
-        let unwrapped_reference: &tokio_util::sync::CancellationToken = &(*wrapped_reference).0;
+        let unwrapped_reference: &tokio_util::sync::CancellationToken = &wrapped_reference.0;
 
         unwrapped_reference
     })
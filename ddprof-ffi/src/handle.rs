@@ -0,0 +1,289 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Profiles referred to by handle rather than by pointer.
+//!
+//! A `ddprof_ffi_ProfileHandle` is an index into a table of profiles owned
+//! by this library, plus the generation of the table slot at the time the
+//! profile was put in it. Freeing the profile empties the slot and bumps
+//! its generation, so a handle which is used after being freed, or freed
+//! twice, no longer matches its slot: the call is rejected with an error
+//! available from `ddprof_ffi_take_last_error`, instead of touching freed
+//! memory. Slots are reused, but a stale handle only matches again once the
+//! generation of its slot wraps around, after 2^32 - 1 frees.
+//!
+//! Each profile is behind its own lock, so handles may be used from several
+//! threads, and a profile being freed while another thread uses it is only
+//! dropped once that use completes. The all-zero handle is never valid, and
+//! can be used as a null handle.
+
+use crate::error::{catch_panic, set_last_error};
+use crate::profiles::{add_sample, EncodedProfile, Sample, SerializeResult, ValueType};
+use crate::Slice;
+use ddprof_profiles::Profile;
+use lazy_static::lazy_static;
+use std::convert::TryInto;
+use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProfileHandle {
+    pub index: u32,
+    /// Never zero for a handle returned by this library.
+    pub generation: u32,
+}
+
+struct Slot {
+    generation: u32,
+    profile: Option<Arc<Mutex<Profile>>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    slots: Vec<Slot>,
+    free_slots: Vec<u32>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+// Calls into the registry run under catch_panic, so the lock can't be
+// poisoned by them, but a profile lock can be if dropping a profile panics.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Registry {
+    fn insert(&mut self, profile: Profile) -> Option<ProfileHandle> {
+        let profile = Some(Arc::new(Mutex::new(profile)));
+        if let Some(index) = self.free_slots.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.profile = profile;
+            return Some(ProfileHandle {
+                index,
+                generation: slot.generation,
+            });
+        }
+        let index = self.slots.len().try_into().ok()?;
+        self.slots.push(Slot {
+            generation: 1,
+            profile,
+        });
+        Some(ProfileHandle {
+            index,
+            generation: 1,
+        })
+    }
+
+    fn slot(&mut self, handle: ProfileHandle) -> Result<&mut Slot, String> {
+        match self.slots.get_mut(handle.index as usize) {
+            Some(slot) if handle.generation != 0 && slot.generation == handle.generation => {
+                Ok(slot)
+            }
+            _ => Err(format!(
+                "invalid profile handle {}:{}: it is null, or the profile was freed",
+                handle.index, handle.generation
+            )),
+        }
+    }
+
+    fn get(&mut self, handle: ProfileHandle) -> Result<Arc<Mutex<Profile>>, String> {
+        let slot = self.slot(handle)?;
+        Ok(slot.profile.clone().expect("live slots hold a profile"))
+    }
+
+    fn remove(&mut self, handle: ProfileHandle) -> Result<Arc<Mutex<Profile>>, String> {
+        let slot = self.slot(handle)?;
+        let profile = slot.profile.take().expect("live slots hold a profile");
+        slot.generation = slot.generation.wrapping_add(1).max(1);
+        self.free_slots.push(handle.index);
+        Ok(profile)
+    }
+}
+
+/// Runs `f` on the profile of `handle`, or records why the handle is invalid
+/// as the last error and returns it. Panics are caught and recorded too.
+fn with_profile<R, F: FnOnce(&mut Profile) -> R>(handle: ProfileHandle, f: F) -> Result<R, String> {
+    let profile = match catch_panic(|| lock(&REGISTRY).get(handle))? {
+        Ok(profile) => profile,
+        Err(message) => {
+            set_last_error(message.clone());
+            return Err(message);
+        }
+    };
+    // The registry isn't locked anymore, so `f` may use other handles.
+    let mut profile = lock(&profile);
+    catch_panic(|| f(&mut profile))
+}
+
+/// Creates a new profile with the given sample types, and returns a handle
+/// to it. It must be freed with `ddprof_ffi_ProfileHandle_free`. Returns the
+/// null handle if creating the profile panicked, see
+/// `ddprof_ffi_take_last_error`.
+///
+/// # Safety
+/// All slices must be have pointers that are suitably aligned for their type
+/// and must have the correct number of elements for the slice.
+#[must_use]
+#[no_mangle]
+pub unsafe extern "C" fn ddprof_ffi_ProfileHandle_new(
+    sample_types: Slice<ValueType>,
+    period: Option<&crate::profiles::Period>,
+) -> ProfileHandle {
+    catch_panic(|| {
+        let types: Vec<ddprof_profiles::api::ValueType> =
+            sample_types.into_slice().iter().map(Into::into).collect();
+        let profile = Profile::builder()
+            .sample_types(types)
+            .period(period.map(Into::into))
+            .build();
+        lock(&REGISTRY).insert(profile)
+    })
+    .ok()
+    .flatten()
+    .unwrap_or_default()
+}
+
+/// Frees the profile of `handle`, which becomes invalid, as do all its
+/// copies. Returns false if the handle was already invalid, with the reason
+/// available from `ddprof_ffi_take_last_error`.
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_ProfileHandle_free(handle: ProfileHandle) -> bool {
+    match catch_panic(|| lock(&REGISTRY).remove(handle)) {
+        // Dropped outside the registry lock, once no other thread uses it.
+        Ok(Ok(profile)) => catch_panic(|| drop(profile)).is_ok(),
+        Ok(Err(message)) => {
+            set_last_error(message);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// Same as `ddprof_ffi_Profile_add`, for the profile of `handle`. Also
+/// returns 0 if the handle is invalid, with the reason available from
+/// `ddprof_ffi_take_last_error`.
+///
+/// # Safety
+/// All pointers inside the `sample` need to be valid for the duration of
+/// this call.
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_ProfileHandle_add(handle: ProfileHandle, sample: Sample) -> u64 {
+    with_profile(handle, |profile| add_sample(profile, sample)).unwrap_or(0)
+}
+
+/// Same as `ddprof_ffi_Profile_serialize`, for the profile of `handle`. The
+/// result holds an error if the handle is invalid. It must be freed with
+/// `ddprof_ffi_SerializeResult_drop`.
+#[must_use]
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_ProfileHandle_serialize(handle: ProfileHandle) -> SerializeResult {
+    let result = with_profile(
+        handle,
+        |profile| -> Result<EncodedProfile, Box<dyn Error>> { profile.serialize()?.try_into() },
+    );
+    match result {
+        Ok(Ok(ok)) => SerializeResult::Ok(ok),
+        Ok(Err(err)) => SerializeResult::Err(err.into()),
+        Err(message) => SerializeResult::Err(message.into_bytes().into()),
+    }
+}
+
+/// Same as `ddprof_ffi_Profile_reset`, for the profile of `handle`. Returns
+/// false if the handle is invalid, with the reason available from
+/// `ddprof_ffi_take_last_error`.
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_ProfileHandle_reset(handle: ProfileHandle) -> bool {
+    with_profile(handle, |profile| profile.reset().is_some()).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::ddprof_ffi_take_last_error;
+    use crate::profiles::{Function, Line, Location, Mapping};
+
+    fn new_handle() -> ProfileHandle {
+        let sample_type: *const ValueType = &ValueType::new("samples", "count");
+        let handle = unsafe { ddprof_ffi_ProfileHandle_new(Slice::new(sample_type, 1), None) };
+        assert_ne!(handle, ProfileHandle::default());
+        handle
+    }
+
+    fn add_main(handle: ProfileHandle) -> u64 {
+        let lines = vec![Line {
+            function: Function {
+                name: "{main}".into(),
+                ..Default::default()
+            },
+            line: 0,
+        }];
+        let locations = vec![Location {
+            mapping: Mapping {
+                filename: "php".into(),
+                ..Default::default()
+            },
+            lines: lines.as_slice().into(),
+            ..Default::default()
+        }];
+        let values: Vec<i64> = vec![1];
+        ddprof_ffi_ProfileHandle_add(
+            handle,
+            Sample {
+                locations: locations.as_slice().into(),
+                values: values.as_slice().into(),
+                labels: Slice::default(),
+            },
+        )
+    }
+
+    fn last_error() -> String {
+        String::from_utf8(ddprof_ffi_take_last_error().into()).unwrap()
+    }
+
+    #[test]
+    fn valid_handle() {
+        let handle = new_handle();
+        assert_eq!(add_main(handle), 1);
+        assert_eq!(add_main(handle), 1);
+        match ddprof_ffi_ProfileHandle_serialize(handle) {
+            SerializeResult::Ok(_) => {}
+            SerializeResult::Err(_) => panic!("serializing a valid handle failed"),
+        }
+        assert!(ddprof_ffi_ProfileHandle_reset(handle));
+        assert!(ddprof_ffi_ProfileHandle_free(handle));
+        assert!(ddprof_ffi_take_last_error().is_empty());
+    }
+
+    #[test]
+    fn freed_handle_is_rejected() {
+        let handle = new_handle();
+        assert!(ddprof_ffi_ProfileHandle_free(handle));
+
+        assert_eq!(add_main(handle), 0);
+        assert!(last_error().starts_with("invalid profile handle"));
+        assert!(!ddprof_ffi_ProfileHandle_reset(handle));
+        assert!(last_error().starts_with("invalid profile handle"));
+        match ddprof_ffi_ProfileHandle_serialize(handle) {
+            SerializeResult::Ok(_) => panic!("serializing a freed handle succeeded"),
+            SerializeResult::Err(message) => {
+                let message: Vec<u8> = message.into();
+                assert!(message.starts_with(b"invalid profile handle"));
+            }
+        }
+        assert!(last_error().starts_with("invalid profile handle"));
+
+        // Freeing twice is rejected too, and doesn't free the profile which
+        // reused the slot.
+        let other = new_handle();
+        assert!(!ddprof_ffi_ProfileHandle_free(handle));
+        assert!(last_error().starts_with("invalid profile handle"));
+        assert_eq!(add_main(other), 1);
+        assert!(ddprof_ffi_ProfileHandle_free(other));
+
+        assert_eq!(add_main(ProfileHandle::default()), 0);
+        assert!(last_error().starts_with("invalid profile handle"));
+    }
+}
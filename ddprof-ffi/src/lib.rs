@@ -8,7 +8,9 @@ use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use chrono::{DateTime, TimeZone, Utc};
 
+mod error;
 mod exporter;
+mod handle;
 mod profiles;
 mod slice;
 mod tags;
@@ -1,7 +1,9 @@
 // Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
 
+use crate::error::{catch_panic, set_last_error};
 use crate::{AsBytes, CharSlice, Slice, Timespec};
+use chrono::{DateTime, Utc};
 use ddprof_profiles as profiles;
 use std::convert::{TryFrom, TryInto};
 use std::error::Error;
@@ -126,6 +128,10 @@ pub struct Mapping<'a> {
     /// with high probability. E.g., for binaries generated by GNU tools,
     /// it could be the contents of the .note.gnu.build-id field.
     pub build_id: CharSlice<'a>,
+
+    /// Where to find the debug info of the binary, as a path or url. It is
+    /// serialized as a profile comment `debug_file:<mapping id>:<debug_file>`.
+    pub debug_file: CharSlice<'a>,
 }
 
 #[repr(C)]
@@ -153,12 +159,14 @@ impl<'a> TryFrom<&'a Mapping<'a>> for profiles::api::Mapping<'a> {
     fn try_from(mapping: &'a Mapping<'a>) -> Result<Self, Self::Error> {
         let filename = unsafe { mapping.filename.try_to_utf8() }?;
         let build_id = unsafe { mapping.build_id.try_to_utf8() }?;
+        let debug_file = unsafe { mapping.debug_file.try_to_utf8() }?;
         Ok(Self {
             memory_start: mapping.memory_start,
             memory_limit: mapping.memory_limit,
             file_offset: mapping.file_offset,
             filename,
             build_id,
+            debug_file,
         })
     }
 }
@@ -279,13 +287,15 @@ impl<'a> TryFrom<Sample<'a>> for profiles::api::Sample<'a> {
                 locations,
                 values,
                 labels,
+                ..Default::default()
             })
         }
     }
 }
 
 /// Create a new profile with the given sample types. Must call
-/// `ddprof_ffi_Profile_free` when you are done with the profile.
+/// `ddprof_ffi_Profile_free` when you are done with the profile. Returns null
+/// if creating the profile panicked, see `ddprof_ffi_take_last_error`.
 /// # Safety
 /// All slices must be have pointers that are suitably aligned for their type
 /// and must have the correct number of elements for the slice.
@@ -294,14 +304,70 @@ impl<'a> TryFrom<Sample<'a>> for profiles::api::Sample<'a> {
 pub unsafe extern "C" fn ddprof_ffi_Profile_new(
     sample_types: Slice<ValueType>,
     period: Option<&Period>,
-) -> Box<ddprof_profiles::Profile> {
-    let types: Vec<ddprof_profiles::api::ValueType> =
-        sample_types.into_slice().iter().map(Into::into).collect();
-    let builder = ddprof_profiles::Profile::builder()
-        .sample_types(types)
-        .period(period.map(Into::into));
+) -> Option<Box<ddprof_profiles::Profile>> {
+    catch_panic(|| {
+        let types: Vec<ddprof_profiles::api::ValueType> =
+            sample_types.into_slice().iter().map(Into::into).collect();
+        let builder = ddprof_profiles::Profile::builder()
+            .sample_types(types)
+            .period(period.map(Into::into));
+
+        Box::new(builder.build())
+    })
+    .ok()
+}
 
-    Box::new(builder.build())
+/// The most bytes gzipped pprof bytes may decompress to: a few KB of crafted
+/// gzip could otherwise expand to gigabytes before the profile is decoded.
+#[cfg(feature = "decode")]
+pub const PROFILE_MAX_DECOMPRESSED_SIZE: u64 = 64 << 20;
+
+/// Rebuilds a profile from encoded pprof bytes, gzipped or not, e.g. those
+/// of a child process, so it can be inspected or more samples added to it.
+/// Must call `ddprof_ffi_Profile_free` when you are done with the profile.
+/// Returns null if the bytes aren't a valid profile, or decompress to more
+/// than `PROFILE_MAX_DECOMPRESSED_SIZE` bytes, with the reason available
+/// from `ddprof_ffi_take_last_error`.
+///
+/// # Safety
+/// The `bytes` must be valid for the duration of this call. They aren't
+/// referenced by the profile afterwards.
+#[cfg(feature = "decode")]
+#[no_mangle]
+#[must_use]
+pub unsafe extern "C" fn ddprof_ffi_Profile_from_pprof(
+    bytes: crate::ByteSlice,
+) -> Option<Box<ddprof_profiles::Profile>> {
+    use std::io::Read;
+
+    let result = catch_panic(|| -> Result<_, Box<dyn Error>> {
+        let bytes = bytes.into_slice();
+        let profile = if bytes.starts_with(&[0x1f, 0x8b]) {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(bytes)
+                .take(PROFILE_MAX_DECOMPRESSED_SIZE + 1)
+                .read_to_end(&mut decompressed)?;
+            if decompressed.len() as u64 > PROFILE_MAX_DECOMPRESSED_SIZE {
+                return Err(format!(
+                    "decompresses to more than {} bytes",
+                    PROFILE_MAX_DECOMPRESSED_SIZE
+                )
+                .into());
+            }
+            ddprof_profiles::Profile::from_pprof(&decompressed)?
+        } else {
+            ddprof_profiles::Profile::from_pprof(bytes)?
+        };
+        Ok(Box::new(profile))
+    });
+    match result {
+        Ok(Ok(profile)) => Some(profile),
+        Ok(Err(err)) => {
+            set_last_error(format!("cannot load profile: {}", err));
+            None
+        }
+        Err(_) => None,
+    }
 }
 
 #[no_mangle]
@@ -312,23 +378,38 @@ pub extern "C" fn ddprof_ffi_Profile_free(profile: Box<ddprof_profiles::Profile>
     std::mem::drop(profile)
 }
 
-#[no_mangle]
+/// Returned by `ddprof_ffi_Profile_add` for a sample with a different number
+/// of values than the profile has sample types, which is a bug of the caller
+/// rather than a sample to drop.
+pub const PROFILE_ADD_VALUE_COUNT_MISMATCH: u64 = u64::MAX;
+
+pub(crate) fn add_sample(profile: &mut ddprof_profiles::Profile, sample: Sample) -> u64 {
+    match sample.try_into().map(|s| profile.add(s)) {
+        Ok(Ok(id)) => id.into(),
+        Ok(Err(err @ profiles::AddError::ValueCountMismatch { .. })) => {
+            set_last_error(err.to_string());
+            PROFILE_ADD_VALUE_COUNT_MISMATCH
+        }
+        _ => 0,
+    }
+}
+
+/// Adds the sample to the profile, returning its id. Returns 0 if the sample
+/// can't be added, or `ddprof_ffi_PROFILE_ADD_VALUE_COUNT_MISMATCH` if it
+/// doesn't have one value per sample type, with the details available from
+/// `ddprof_ffi_take_last_error`.
+///
 /// # Safety
 /// The `profile` ptr must point to a valid Profile object created by this
 /// module. All pointers inside the `sample` need to be valid for the duration
 /// of this call.
 /// This call is _NOT_ thread-safe.
+#[no_mangle]
 pub extern "C" fn ddprof_ffi_Profile_add(
     profile: &mut ddprof_profiles::Profile,
     sample: Sample,
 ) -> u64 {
-    match sample.try_into().map(|s| profile.add(s)) {
-        Ok(r) => match r {
-            Ok(id) => id.into(),
-            Err(_) => 0,
-        },
-        Err(_) => 0,
-    }
+    catch_panic(|| add_sample(profile, sample)).unwrap_or(0)
 }
 
 #[repr(C)]
@@ -336,6 +417,10 @@ pub struct EncodedProfile {
     start: Timespec,
     end: Timespec,
     buffer: crate::Vec<u8>,
+    /// The number of samples after aggregation.
+    samples_count: usize,
+    /// The number of distinct "trace endpoint" labels of the samples.
+    endpoints_count: usize,
 }
 
 impl TryFrom<ddprof_profiles::EncodedProfile> for EncodedProfile {
@@ -345,11 +430,19 @@ impl TryFrom<ddprof_profiles::EncodedProfile> for EncodedProfile {
         let start = value.start.try_into()?;
         let end = value.end.try_into()?;
         let buffer = value.buffer.into();
-        Ok(Self { start, end, buffer })
+        Ok(Self {
+            start,
+            end,
+            buffer,
+            samples_count: value.samples_count,
+            endpoints_count: value.endpoints_count,
+        })
     }
 }
 
+// The payloads are only read from C.
 #[repr(C)]
+#[allow(dead_code)]
 pub enum SerializeResult {
     Ok(EncodedProfile),
     Err(crate::Vec<u8>),
@@ -362,12 +455,69 @@ pub enum SerializeResult {
 pub extern "C" fn ddprof_ffi_Profile_serialize(
     profile: &ddprof_profiles::Profile,
 ) -> SerializeResult {
-    match || -> Result<EncodedProfile, Box<dyn Error>> { profile.serialize()?.try_into() }() {
-        Ok(ok) => SerializeResult::Ok(ok),
-        Err(err) => SerializeResult::Err(err.into()),
+    let result = catch_panic(|| -> Result<EncodedProfile, Box<dyn Error>> {
+        profile.serialize()?.try_into()
+    });
+    match result {
+        Ok(Ok(ok)) => SerializeResult::Ok(ok),
+        Ok(Err(err)) => SerializeResult::Err(err.into()),
+        Err(message) => SerializeResult::Err(message.into_bytes().into()),
+    }
+}
+
+#[repr(C)]
+pub struct TimeRange {
+    pub start: Timespec,
+    pub end: Timespec,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+pub enum FlushResult {
+    Ok(TimeRange),
+    Err(crate::Vec<u8>),
+}
+
+/// Serializes the profile into `buffer`, replacing its contents but reusing
+/// its allocation, then resets the profile with `start` as the start time of
+/// the next one. Returns the start and end times of the serialized profile.
+/// The profile isn't reset if serializing fails. Keep passing the same
+/// buffer to avoid allocating one per profile; a new one can be made with
+/// `ddprof_ffi_Vec_u8_new`, and freed with `ddprof_ffi_Vec_u8_drop`. The
+/// result must be freed with `ddprof_ffi_FlushResult_drop`.
+///
+/// # Safety
+/// The `profile` must point to a valid Profile object created by this module,
+/// and `buffer` to a Vec created by this module.
+/// This call is _NOT_ thread-safe.
+#[must_use]
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_Profile_flush(
+    profile: &mut ddprof_profiles::Profile,
+    start: Timespec,
+    buffer: &mut crate::Vec<u8>,
+) -> FlushResult {
+    let result = catch_panic(|| -> Result<TimeRange, Box<dyn Error>> {
+        // If this panics, the buffer is left empty rather than dangling
+        let mut buf: Vec<u8> = std::mem::take(buffer).into();
+        let flushed = profile.flush(DateTime::<Utc>::from(start).into(), &mut buf);
+        *buffer = buf.into();
+        let (start, end) = flushed?;
+        Ok(TimeRange {
+            start: start.try_into()?,
+            end: end.try_into()?,
+        })
+    });
+    match result {
+        Ok(Ok(range)) => FlushResult::Ok(range),
+        Ok(Err(err)) => FlushResult::Err(err.into()),
+        Err(message) => FlushResult::Err(message.into_bytes().into()),
     }
 }
 
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_FlushResult_drop(_: FlushResult) {}
+
 #[no_mangle]
 pub unsafe extern "C" fn ddprof_ffi_SerializeResult_drop(result: SerializeResult) {
     std::mem::drop(result)
@@ -375,7 +525,7 @@ pub unsafe extern "C" fn ddprof_ffi_SerializeResult_drop(result: SerializeResult
 
 #[must_use]
 #[no_mangle]
-pub unsafe extern "C" fn ddprof_ffi_Vec_u8_as_slice(vec: &crate::Vec<u8>) -> Slice<u8> {
+pub unsafe extern "C" fn ddprof_ffi_Vec_u8_as_slice(vec: &crate::Vec<u8>) -> Slice<'_, u8> {
     vec.as_slice()
 }
 
@@ -384,9 +534,121 @@ pub unsafe extern "C" fn ddprof_ffi_Vec_u8_as_slice(vec: &crate::Vec<u8>) -> Sli
 /// remains valid if false is returned.
 #[no_mangle]
 pub extern "C" fn ddprof_ffi_Profile_reset(profile: &mut ddprof_profiles::Profile) -> bool {
-    profile.reset().is_some()
+    catch_panic(|| profile.reset().is_some()).unwrap_or(false)
+}
+
+#[must_use]
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_Vec_u8_new() -> crate::Vec<u8> {
+    crate::Vec::default()
+}
+
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_Vec_u8_drop(_: crate::Vec<u8>) {}
+
+/// Renders the profile in the folded stack format used by flamegraph tools,
+/// for the sample type at `sample_type_index`. The returned buffer holds UTF-8
+/// text, one line per stack, and is empty if `sample_type_index` is out of
+/// range. The buffer is owned by the caller, who must free it with
+/// `ddprof_ffi_Vec_u8_drop`.
+///
+/// # Safety
+/// The `profile` must point to a valid Profile object created by this module.
+#[must_use]
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_Profile_to_folded(
+    profile: &ddprof_profiles::Profile,
+    sample_type_index: usize,
+) -> crate::Vec<u8> {
+    match catch_panic(|| profile.to_folded(sample_type_index)) {
+        Ok(Some(folded)) => folded.into_bytes().into(),
+        _ => crate::Vec::default(),
+    }
+}
+
+#[repr(C)]
+pub struct FunctionValue<'a> {
+    pub name: CharSlice<'a>,
+    pub value: i64,
+}
+
+/// Returns up to `limit` functions with the highest self value for the sample
+/// type at `sample_type_index`, in descending order of value. The result is
+/// empty if `sample_type_index` is out of range.
+///
+/// The vec is owned by the caller and must be freed with
+/// `ddprof_ffi_Vec_function_value_drop`, but the names inside it borrow from
+/// the `profile`: they are only valid until the profile is next modified,
+/// reset, or freed.
+///
+/// # Safety
+/// The `profile` must point to a valid Profile object created by this module.
+#[must_use]
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_Profile_top_functions(
+    profile: &ddprof_profiles::Profile,
+    sample_type_index: usize,
+    limit: usize,
+) -> crate::Vec<FunctionValue<'_>> {
+    match catch_panic(|| profile.top_functions(sample_type_index, limit)) {
+        Ok(Some(top)) => top
+            .into_iter()
+            .map(|(name, value)| FunctionValue {
+                name: name.into(),
+                value,
+            })
+            .collect::<Vec<_>>()
+            .into(),
+        _ => crate::Vec::default(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_Vec_function_value_drop(_: crate::Vec<FunctionValue>) {}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct ProfileStats {
+    /// The number of samples after aggregation.
+    pub sample_count: usize,
+    /// The sum of the values of each sample type, in the profile's order.
+    /// Totals saturate instead of overflowing.
+    pub totals: crate::Vec<i64>,
+    /// The length of the profile once serialized, in bytes.
+    pub encoded_len: usize,
+}
+
+/// Returns the sample count, the total of each sample type and the encoded
+/// length of the profile, all at once. The stats are all zero and the totals
+/// empty if computing them panicked.
+///
+/// The totals are owned by the caller, and the stats must be freed with
+/// `ddprof_ffi_ProfileStats_drop`. They don't borrow from the `profile`.
+///
+/// # Safety
+/// The `profile` must point to a valid Profile object created by this module.
+#[must_use]
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_Profile_stats(profile: &ddprof_profiles::Profile) -> ProfileStats {
+    catch_panic(|| {
+        let summary = profile.summary();
+        ProfileStats {
+            sample_count: summary.sample_count,
+            totals: summary
+                .sample_types
+                .iter()
+                .map(|sample_type| sample_type.total)
+                .collect::<Vec<_>>()
+                .into(),
+            encoded_len: profile.encoded_len(),
+        }
+    })
+    .unwrap_or_default()
 }
 
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_ProfileStats_drop(_: ProfileStats) {}
+
 #[cfg(test)]
 mod test {
     use crate::profiles::*;
@@ -396,7 +658,7 @@ mod test {
     fn ctor_and_dtor() {
         unsafe {
             let sample_type: *const ValueType = &ValueType::new("samples", "count");
-            let profile = ddprof_ffi_Profile_new(Slice::new(sample_type, 1), None);
+            let profile = ddprof_ffi_Profile_new(Slice::new(sample_type, 1), None).unwrap();
             ddprof_ffi_Profile_free(profile);
         }
     }
@@ -405,7 +667,7 @@ mod test {
     fn aggregate_samples() {
         unsafe {
             let sample_type: *const ValueType = &ValueType::new("samples", "count");
-            let mut profile = ddprof_ffi_Profile_new(Slice::new(sample_type, 1), None);
+            let mut profile = ddprof_ffi_Profile_new(Slice::new(sample_type, 1), None).unwrap();
 
             let lines = &vec![Line {
                 function: Function {
@@ -454,7 +716,7 @@ mod test {
 
     unsafe fn provide_distinct_locations_ffi() -> ddprof_profiles::Profile {
         let sample_type: *const ValueType = &ValueType::new("samples", "count");
-        let mut profile = ddprof_ffi_Profile_new(Slice::new(sample_type, 1), None);
+        let mut profile = ddprof_ffi_Profile_new(Slice::new(sample_type, 1), None).unwrap();
 
         let main_lines = vec![Line {
             function: Function {
@@ -528,4 +790,216 @@ mod test {
             provide_distinct_locations_ffi();
         }
     }
+
+    #[test]
+    fn panics_are_caught() {
+        use crate::error::{ddprof_ffi_take_last_error, INJECT_PANIC};
+
+        let mut profile = unsafe { provide_distinct_locations_ffi() };
+        let lines = vec![Line {
+            function: Function {
+                name: "{main}".into(),
+                ..Default::default()
+            },
+            line: 0,
+        }];
+        let locations = vec![Location {
+            lines: lines.as_slice().into(),
+            ..Default::default()
+        }];
+        let values: Vec<i64> = vec![1];
+        let sample = Sample {
+            locations: Slice::from(locations.as_slice()),
+            values: Slice::from(values.as_slice()),
+            labels: Slice::default(),
+        };
+
+        INJECT_PANIC.with(|inject| inject.set(true));
+        assert_eq!(ddprof_ffi_Profile_add(&mut profile, sample), 0);
+        let message: Vec<u8> = ddprof_ffi_take_last_error().into();
+        assert_eq!(message, b"panic: injected panic");
+
+        INJECT_PANIC.with(|inject| inject.set(true));
+        match ddprof_ffi_Profile_serialize(&profile) {
+            SerializeResult::Ok(_) => panic!("serialize to fail"),
+            SerializeResult::Err(message) => {
+                let message: Vec<u8> = message.into();
+                assert_eq!(message, b"panic: injected panic");
+            }
+        }
+        assert!(!ddprof_ffi_take_last_error().is_empty());
+
+        // The profile is still usable afterwards
+        assert_ne!(ddprof_ffi_Profile_add(&mut profile, sample), 0);
+        assert!(matches!(
+            ddprof_ffi_Profile_serialize(&profile),
+            SerializeResult::Ok(_)
+        ));
+        assert!(ddprof_ffi_take_last_error().is_empty());
+    }
+
+    #[test]
+    fn value_count_mismatch_ffi() {
+        use crate::error::ddprof_ffi_take_last_error;
+
+        let mut profile = unsafe { provide_distinct_locations_ffi() };
+        let values: Vec<i64> = vec![1, 2];
+        let sample = Sample {
+            locations: Slice::default(),
+            values: Slice::from(values.as_slice()),
+            labels: Slice::default(),
+        };
+        assert_eq!(
+            ddprof_ffi_Profile_add(&mut profile, sample),
+            PROFILE_ADD_VALUE_COUNT_MISMATCH
+        );
+        let message: Vec<u8> = ddprof_ffi_take_last_error().into();
+        assert_eq!(message, b"Expected 1 sample values but got 2");
+        assert_eq!(profile.summary().sample_count, 2);
+    }
+
+    #[test]
+    fn flush_ffi() {
+        let mut profile = unsafe { provide_distinct_locations_ffi() };
+        let mut buffer = ddprof_ffi_Vec_u8_new();
+
+        let start = Timespec {
+            seconds: 1_650_000_000,
+            nanoseconds: 5,
+        };
+        let range = match ddprof_ffi_Profile_flush(&mut profile, start, &mut buffer) {
+            FlushResult::Ok(range) => range,
+            FlushResult::Err(_) => panic!("flush to succeed"),
+        };
+        assert!(range.end.seconds >= range.start.seconds);
+        assert!(!buffer.is_empty());
+        assert!(ddprof_ffi_Profile_to_folded(&profile, 0).is_empty());
+
+        let range = match ddprof_ffi_Profile_flush(&mut profile, start, &mut buffer) {
+            FlushResult::Ok(range) => range,
+            FlushResult::Err(_) => panic!("flush to succeed"),
+        };
+        assert_eq!(range.start.seconds, start.seconds);
+        assert_eq!(range.start.nanoseconds, start.nanoseconds);
+        ddprof_ffi_Vec_u8_drop(buffer);
+    }
+
+    #[test]
+    fn folded_and_top_functions_ffi() {
+        let profile = unsafe { provide_distinct_locations_ffi() };
+
+        let folded: Vec<u8> = ddprof_ffi_Profile_to_folded(&profile, 0).into();
+        assert_eq!(folded.as_slice(), b"{main} 1\ntest 1\n");
+        assert!(ddprof_ffi_Profile_to_folded(&profile, 1).is_empty());
+
+        let top = ddprof_ffi_Profile_top_functions(&profile, 0, 10);
+        let top: Vec<(&str, i64)> = top
+            .iter()
+            .map(|f| (unsafe { f.name.try_to_utf8() }.unwrap(), f.value))
+            .collect();
+        assert_eq!(top, vec![("test", 1), ("{main}", 1)]);
+        assert!(ddprof_ffi_Profile_top_functions(&profile, 1, 10).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "decode")]
+    fn from_pprof_ffi() {
+        use crate::error::ddprof_ffi_take_last_error;
+        use std::io::Write;
+
+        let profile = unsafe { provide_distinct_locations_ffi() };
+        let encoded = profile.serialize().unwrap();
+
+        let parsed =
+            unsafe { ddprof_ffi_Profile_from_pprof(encoded.buffer.as_slice().into()) }.unwrap();
+        assert_eq!(ddprof_ffi_Profile_stats(&parsed).sample_count, 2);
+        ddprof_ffi_Profile_free(parsed);
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&encoded.buffer).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let parsed = unsafe { ddprof_ffi_Profile_from_pprof(gzipped.as_slice().into()) }.unwrap();
+        assert_eq!(ddprof_ffi_Profile_stats(&parsed).sample_count, 2);
+        ddprof_ffi_Profile_free(parsed);
+
+        let truncated = &encoded.buffer[..encoded.buffer.len() - 1];
+        assert!(unsafe { ddprof_ffi_Profile_from_pprof(truncated.into()) }.is_none());
+        let message: Vec<u8> = ddprof_ffi_take_last_error().into();
+        assert!(message.starts_with(b"cannot load profile: "));
+    }
+
+    #[test]
+    #[cfg(feature = "decode")]
+    fn from_pprof_ffi_decompression_bomb() {
+        use crate::error::ddprof_ffi_take_last_error;
+        use std::io::Write;
+
+        // Zeros compress about a thousand to one
+        let zeros = vec![0u8; 1 << 20];
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        for _ in 0..=PROFILE_MAX_DECOMPRESSED_SIZE >> 20 {
+            encoder.write_all(&zeros).unwrap();
+        }
+        let gzipped = encoder.finish().unwrap();
+        assert!(gzipped.len() < 1 << 20);
+
+        assert!(unsafe { ddprof_ffi_Profile_from_pprof(gzipped.as_slice().into()) }.is_none());
+        let message: Vec<u8> = ddprof_ffi_take_last_error().into();
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            format!(
+                "cannot load profile: decompresses to more than {} bytes",
+                PROFILE_MAX_DECOMPRESSED_SIZE
+            )
+        );
+    }
+
+    #[test]
+    fn serialize_counts_ffi() {
+        let mut profile = unsafe { provide_distinct_locations_ffi() };
+        let lines = vec![Line {
+            function: Function {
+                name: "handle".into(),
+                ..Default::default()
+            },
+            line: 0,
+        }];
+        let locations = vec![Location {
+            lines: lines.as_slice().into(),
+            ..Default::default()
+        }];
+        let values: Vec<i64> = vec![1];
+        for endpoint in ["GET /users", "GET /users", "POST /login"] {
+            let labels = vec![Label {
+                key: "trace endpoint".into(),
+                str: endpoint.into(),
+                ..Default::default()
+            }];
+            let sample = Sample {
+                locations: locations.as_slice().into(),
+                values: values.as_slice().into(),
+                labels: labels.as_slice().into(),
+            };
+            assert_ne!(ddprof_ffi_Profile_add(&mut profile, sample), 0);
+        }
+
+        match ddprof_ffi_Profile_serialize(&profile) {
+            SerializeResult::Ok(encoded) => {
+                assert_eq!(encoded.samples_count, 4);
+                assert_eq!(encoded.endpoints_count, 2);
+            }
+            SerializeResult::Err(_) => panic!("serialize to succeed"),
+        }
+    }
+
+    #[test]
+    fn stats_ffi() {
+        let profile = unsafe { provide_distinct_locations_ffi() };
+
+        let stats = ddprof_ffi_Profile_stats(&profile);
+        assert_eq!(stats.sample_count, 2);
+        assert_eq!(stats.totals.iter().copied().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(stats.encoded_len, profile.serialize().unwrap().buffer.len());
+        ddprof_ffi_ProfileStats_drop(stats);
+    }
 }
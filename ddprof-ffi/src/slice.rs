@@ -25,9 +25,7 @@ impl<'a, T: Debug> Debug for Slice<'a, T> {
 
 impl<'a, T: Eq> PartialEq<Self> for Slice<'a, T> {
     fn eq(&self, other: &Self) -> bool {
-        unsafe {
-            return self.as_slice() == other.as_slice();
-        }
+        unsafe { self.as_slice() == other.as_slice() }
     }
 }
 
@@ -40,6 +38,8 @@ pub type CharSlice<'a> = crate::Slice<'a, c_char>;
 pub type ByteSlice<'a> = crate::Slice<'a, u8>;
 
 /// This exists as an intrinsic, but it is private.
+// is_multiple_of is newer than our minimum supported Rust version.
+#[allow(clippy::manual_is_multiple_of)]
 pub fn is_aligned_and_not_null<T>(ptr: *const T) -> bool {
     !ptr.is_null() && ptr as usize % std::mem::align_of::<T>() == 0
 }
@@ -164,7 +164,7 @@ impl<'a, T> Default for Slice<'a, T> {
 impl<'a, T: 'a> From<&'a [T]> for Slice<'a, T> {
     fn from(s: &'a [T]) -> Self {
         // SAFETY: Rust slices meet all the invariants required for Slice::new.
-        unsafe { Slice::new(s.as_ptr() as *const T, s.len()) }
+        unsafe { Slice::new(s.as_ptr(), s.len()) }
     }
 }
 
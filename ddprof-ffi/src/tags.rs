@@ -1,9 +1,10 @@
 // Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2022-Present Datadog, Inc.
 
+use crate::error::catch_panic;
 use crate::{AsBytes, CharSlice};
-use ddprof_exporter::parse_tags;
 use ddprof_exporter::tag::Tag;
+use ddprof_exporter::{parse_tags, parse_tags_strict};
 
 #[must_use]
 #[no_mangle]
@@ -14,7 +15,33 @@ pub extern "C" fn ddprof_ffi_Vec_tag_new() -> crate::Vec<Tag> {
 #[no_mangle]
 pub extern "C" fn ddprof_ffi_Vec_tag_drop(_: crate::Vec<Tag>) {}
 
+/// Returns a copy of `vec`, with copies of its tags' strings. The copy is
+/// owned by the caller and must be dropped with `ddprof_ffi_Vec_tag_drop`,
+/// independently of `vec`.
+#[must_use]
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_Vec_tag_clone(vec: &crate::Vec<Tag>) -> crate::Vec<Tag> {
+    vec.iter().cloned().collect::<Vec<Tag>>().into()
+}
+
+/// Appends copies of the tags of `src` to `dst`. `dst` remains owned by the
+/// caller and `src` is left unchanged, so both still have to be dropped.
+///
+/// # Safety
+/// `dst` and `src` must be valid references to different vecs.
+#[no_mangle]
+pub unsafe extern "C" fn ddprof_ffi_Vec_tag_extend(
+    dst: &mut crate::Vec<Tag>,
+    src: &crate::Vec<Tag>,
+) {
+    for tag in src {
+        dst.push(tag.clone());
+    }
+}
+
+// The payloads are only read from C.
 #[repr(C)]
+#[allow(dead_code)]
 pub enum PushTagResult {
     Ok,
     Err(crate::Vec<u8>),
@@ -38,15 +65,18 @@ pub unsafe extern "C" fn ddprof_ffi_Vec_tag_push(
     key: CharSlice,
     value: CharSlice,
 ) -> PushTagResult {
-    let key = key.to_utf8_lossy().into_owned();
-    let value = value.to_utf8_lossy().into_owned();
-    match Tag::new(key, value) {
-        Ok(tag) => {
-            vec.push(tag);
-            PushTagResult::Ok
+    let result = catch_panic(|| {
+        let key = key.to_utf8_lossy().into_owned();
+        let value = value.to_utf8_lossy().into_owned();
+        match Tag::new(key, value) {
+            Ok(tag) => {
+                vec.push(tag);
+                PushTagResult::Ok
+            }
+            Err(err) => PushTagResult::Err(err.as_bytes().to_vec().into()),
         }
-        Err(err) => PushTagResult::Err(err.as_bytes().to_vec().into()),
-    }
+    });
+    result.unwrap_or_else(|message| PushTagResult::Err(message.into_bytes().into()))
 }
 
 #[repr(C)]
@@ -58,14 +88,40 @@ pub struct ParseTagsResult {
 #[must_use]
 #[no_mangle]
 pub unsafe extern "C" fn ddprof_ffi_Vec_tag_parse(string: CharSlice) -> ParseTagsResult {
-    let string = string.to_utf8_lossy();
-    let (tags, error) = parse_tags(string.as_ref());
+    let result = catch_panic(|| parse_tags(string.to_utf8_lossy().as_ref()));
+    let (tags, error) = result.unwrap_or_else(|message| (vec![], Some(message)));
     ParseTagsResult {
         tags: tags.into(),
         error_message: error.map(|message| Box::new(crate::Vec::from(message.into_bytes()))),
     }
 }
 
+#[repr(C)]
+#[allow(dead_code)]
+pub enum ParseTagsStrictResult {
+    Ok(crate::Vec<Tag>),
+    Err(crate::Vec<u8>),
+}
+
+#[no_mangle]
+pub extern "C" fn ddprof_ffi_ParseTagsStrictResult_drop(_: ParseTagsStrictResult) {}
+
+/// Parses tags like `ddprof_ffi_Vec_tag_parse`, but all or nothing: if any
+/// tag is malformed, no tags are returned, and the error lists every
+/// malformed tag. The result must be dropped with
+/// `ddprof_ffi_ParseTagsStrictResult_drop`.
+#[must_use]
+#[no_mangle]
+pub unsafe extern "C" fn ddprof_ffi_Vec_tag_parse_strict(
+    string: CharSlice,
+) -> ParseTagsStrictResult {
+    let result = catch_panic(|| parse_tags_strict(string.to_utf8_lossy().as_ref()));
+    match result.unwrap_or_else(Err) {
+        Ok(tags) => ParseTagsStrictResult::Ok(tags.into()),
+        Err(message) => ParseTagsStrictResult::Err(message.into_bytes().into()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::tags::*;
@@ -116,6 +172,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_clone_and_extend() {
+        let mut base = ddprof_ffi_Vec_tag_new();
+        for (key, value) in [("service", "php"), ("env", "staging")] {
+            let result = unsafe {
+                ddprof_ffi_Vec_tag_push(&mut base, CharSlice::from(key), CharSlice::from(value))
+            };
+            assert!(matches!(result, PushTagResult::Ok));
+        }
+
+        let copy = ddprof_ffi_Vec_tag_clone(&base);
+        ddprof_ffi_Vec_tag_drop(base);
+
+        let mut tags = ddprof_ffi_Vec_tag_new();
+        let result = unsafe {
+            ddprof_ffi_Vec_tag_push(&mut tags, CharSlice::from("host"), CharSlice::from("bits"))
+        };
+        assert!(matches!(result, PushTagResult::Ok));
+        unsafe { ddprof_ffi_Vec_tag_extend(&mut tags, &copy) };
+
+        let strings = |vec: &crate::Vec<Tag>| -> Vec<String> {
+            vec.iter().map(|tag| tag.to_string()).collect()
+        };
+        assert_eq!(strings(&copy), ["service:php", "env:staging"]);
+        assert_eq!(strings(&tags), ["host:bits", "service:php", "env:staging"]);
+    }
+
     #[test]
     fn test_parse() {
         let dd_tags = "env:staging:east, tags:, env_staging:east"; // contains an error
@@ -134,4 +217,30 @@ mod tests {
         let expected_error_message = b"Errors while parsing tags: tag 'tags:' ends with a colon";
         assert_eq!(expected_error_message, error_message.as_slice())
     }
+
+    #[test]
+    fn test_parse_strict() {
+        // SAFETY: CharSlices from Rust strings are safe.
+        let result = unsafe { ddprof_ffi_Vec_tag_parse_strict(CharSlice::from("ok:1,:bad")) };
+        match result {
+            ParseTagsStrictResult::Ok(tags) => panic!("expected an error, got {:?}", tags.len()),
+            ParseTagsStrictResult::Err(message) => {
+                let message: Vec<u8> = message.into();
+                assert_eq!(
+                    b"Errors while parsing tags: tag ':bad' begins with a colon",
+                    message.as_slice()
+                );
+            }
+        }
+
+        // SAFETY: CharSlices from Rust strings are safe.
+        let result = unsafe { ddprof_ffi_Vec_tag_parse_strict(CharSlice::from("ok:1")) };
+        match result {
+            ParseTagsStrictResult::Ok(tags) => {
+                assert_eq!(1, tags.len());
+                assert_eq!("ok:1", tags.get(0).unwrap().to_string());
+            }
+            ParseTagsStrictResult::Err(_) => panic!("expected the tags to parse"),
+        }
+    }
 }
@@ -42,7 +42,7 @@ impl<T> From<alloc::vec::Vec<T>> for Vec<T> {
             ptr: v.as_mut_ptr(),
             len: v.len(),
             capacity: v.capacity(),
-            _marker: PhantomData::default(),
+            _marker: PhantomData,
         }
     }
 }
@@ -91,14 +91,14 @@ impl<T> Vec<T> {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.len > 0
+        self.len == 0
     }
 
-    pub fn as_slice(&self) -> Slice<T> {
+    pub fn as_slice(&self) -> Slice<'_, T> {
         unsafe { Slice::new(self.ptr, self.len) }
     }
 
-    pub fn iter(&self) -> std::slice::Iter<T> {
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
         unsafe { self.as_slice().into_slice() }.iter()
     }
 
@@ -154,7 +154,7 @@ mod test {
         assert!(ffi_vec.capacity >= 2);
 
         let slice = unsafe { ffi_vec.as_slice().as_slice() };
-        let first = slice.get(0).unwrap();
+        let first = slice.first().unwrap();
         let second = slice.get(1).unwrap();
         assert_eq!(first, &1);
         assert_eq!(second, &2);
@@ -165,7 +165,7 @@ mod test {
         let vec = vec![0, 2, 4, 6];
         let ffi_vec: Vec<u8> = Vec::from(vec.clone());
 
-        for (a, b) in vec.iter().zip(ffi_vec.into_iter()) {
+        for (a, b) in vec.iter().zip(&ffi_vec) {
             assert_eq!(a, b)
         }
     }
@@ -0,0 +1,131 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use ddprof_profiles::{api, Profile, ProfileBuilder};
+
+fn samples() -> Vec<api::Sample<'static>> {
+    const FUNCTIONS: [&str; 4] = ["main", "run", "dispatch", "handle"];
+    const MAPPINGS: [&str; 2] = ["/usr/local/bin/php", "/usr/lib/libc.so.6"];
+
+    (0..FUNCTIONS.len())
+        .map(|leaf| api::Sample {
+            locations: FUNCTIONS[..=leaf]
+                .iter()
+                .rev()
+                .enumerate()
+                .map(|(depth, name)| api::Location {
+                    mapping: api::Mapping {
+                        filename: MAPPINGS[depth % MAPPINGS.len()],
+                        ..Default::default()
+                    },
+                    lines: vec![api::Line {
+                        function: api::Function {
+                            name,
+                            filename: "index.php",
+                            ..Default::default()
+                        },
+                        line: 0,
+                    }],
+                    ..Default::default()
+                })
+                .collect(),
+            values: vec![1],
+            labels: vec![],
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn add_samples(builder: fn() -> ProfileBuilder<'static>) -> Profile {
+    let mut profile = builder().build();
+    for sample in samples() {
+        profile.add(sample).expect("add to succeed");
+    }
+    profile
+}
+
+/// A heap profiler's samples, each carrying the same few label keys.
+fn labeled_batch() -> Vec<api::Sample<'static>> {
+    (0..1000)
+        .flat_map(|i| {
+            samples().into_iter().map(move |mut sample| {
+                sample.labels = vec![
+                    api::Label::num("allocation size", 64 * (i % 8), Some("bytes")),
+                    api::Label::str("thread name", "main"),
+                    api::Label::num("thread id", 1, None),
+                ];
+                sample
+            })
+        })
+        .collect()
+}
+
+/// A ring buffer's worth of samples, mostly aggregated with each other.
+fn batch() -> Vec<api::Sample<'static>> {
+    (0..250).flat_map(|_| samples()).collect()
+}
+
+fn sample_types() -> Vec<api::ValueType<'static>> {
+    vec![api::ValueType {
+        r#type: "samples",
+        unit: "count",
+    }]
+}
+
+fn bench_add(c: &mut Criterion) {
+    c.bench_function("add with mappings", |b| {
+        b.iter(|| {
+            black_box(add_samples(|| {
+                Profile::builder().sample_types(sample_types())
+            }))
+        })
+    });
+    c.bench_function("add with no_mappings", |b| {
+        b.iter(|| {
+            black_box(add_samples(|| {
+                Profile::builder()
+                    .sample_types(sample_types())
+                    .no_mappings()
+            }))
+        })
+    });
+    c.bench_function("add a batch one by one", |b| {
+        b.iter_batched(
+            batch,
+            |batch| {
+                let mut profile = Profile::builder().sample_types(sample_types()).build();
+                for sample in batch {
+                    profile.add(sample).expect("add to succeed");
+                }
+                black_box(profile)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("add_all a batch", |b| {
+        b.iter_batched(
+            batch,
+            |batch| {
+                let mut profile = Profile::builder().sample_types(sample_types()).build();
+                profile.add_all(&batch).expect("add to succeed");
+                black_box(profile)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("add_all labeled samples", |b| {
+        b.iter_batched(
+            labeled_batch,
+            |batch| {
+                let mut profile = Profile::builder().sample_types(sample_types()).build();
+                profile.add_all(&batch).expect("add to succeed");
+                black_box(profile)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_add);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ddprof_profiles::{api, Profile};
+
+const SAMPLES: usize = 1_000_000;
+
+fn location(name: &str) -> api::Location<'_> {
+    api::Location {
+        mapping: api::Mapping {
+            filename: "/usr/local/bin/php",
+            ..Default::default()
+        },
+        lines: vec![api::Line {
+            function: api::Function {
+                name,
+                filename: "index.php",
+                ..Default::default()
+            },
+            line: 0,
+        }],
+        ..Default::default()
+    }
+}
+
+/// Adds a million samples spread over a thousand functions and a hundred
+/// endpoint labels, so most of the time goes into interning.
+fn add_million(functions: &[String], endpoints: &[String]) -> Profile {
+    let mut profile = Profile::builder()
+        .sample_types(vec![api::ValueType {
+            r#type: "wall-time",
+            unit: "nanoseconds",
+        }])
+        .build();
+    for i in 0..SAMPLES {
+        let sample = api::Sample {
+            locations: vec![
+                location(&functions[i % functions.len()]),
+                location(&functions[i / 7 % functions.len()]),
+                location("main"),
+            ],
+            values: vec![10_000],
+            labels: vec![api::Label::str("endpoint", &endpoints[i % endpoints.len()])],
+            ..Default::default()
+        };
+        profile.add(sample).expect("add to succeed");
+    }
+    profile
+}
+
+fn bench_intern(c: &mut Criterion) {
+    let functions: Vec<String> = (0..1000)
+        .map(|i| format!("App\\Handler::run{}", i))
+        .collect();
+    let endpoints: Vec<String> = (0..100)
+        .map(|i| format!("GET /api/v1/items/{}", i))
+        .collect();
+
+    let mut group = c.benchmark_group("intern");
+    group.sample_size(10);
+    group.bench_function("add a million samples", |b| {
+        b.iter(|| black_box(add_million(&functions, &endpoints)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_intern);
+criterion_main!(benches);
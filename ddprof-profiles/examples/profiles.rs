@@ -57,6 +57,7 @@ fn main() {
         ],
         values: vec![1, 10000],
         labels: vec![],
+        ..Default::default()
     };
 
     let mut profile: Profile = Profile::builder()
@@ -0,0 +1,24 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+#![no_main]
+
+use ddprof_profiles::Profile;
+use libfuzzer_sys::fuzz_target;
+
+// Decoding must reject malformed bytes with an error, and whatever it
+// accepts must be a profile every other operation can handle.
+fuzz_target!(|bytes: &[u8]| {
+    let mut profile = match Profile::from_pprof(bytes) {
+        Ok(profile) => profile,
+        Err(_) => return,
+    };
+    let encoded = profile.serialize().expect("a decoded profile to encode");
+    let mut merged = Profile::from_pprof(&encoded.buffer).expect("an encoded profile to decode");
+    merged
+        .merge_encoded(bytes)
+        .expect("a profile to merge into its copy");
+    profile.coalesce_functions(|function| (function.name.to_owned(), function.filename.to_owned()));
+    profile.compact();
+    profile.reset().expect("sample types to be interned");
+});
@@ -1,6 +1,10 @@
 // Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
 
+use std::fmt;
+
+use crate::{AddError, MappingId, PProfId, Profile};
+
 #[derive(Copy, Clone)]
 pub struct ValueType<'a> {
     pub r#type: &'a str,
@@ -12,6 +16,20 @@ pub struct Period<'a> {
     pub value: i64,
 }
 
+/// Renders as `type/unit`, e.g. `wall-time/nanoseconds`.
+impl<'a> fmt::Display for ValueType<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.r#type, self.unit)
+    }
+}
+
+/// Renders as `type/unit=value`, e.g. `wall-time/nanoseconds=10000000`.
+impl<'a> fmt::Display for Period<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.r#type, self.value)
+    }
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct Mapping<'a> {
     /// Address at which the binary (or DLL) is loaded into memory.
@@ -32,6 +50,12 @@ pub struct Mapping<'a> {
     /// with high probability. E.g., for binaries generated by GNU tools,
     /// it could be the contents of the .note.gnu.build-id field.
     pub build_id: &'a str,
+
+    /// Where to find the debug info of the binary, as a path or url, for
+    /// symbolizing it on the backend. pprof mappings have no field for this,
+    /// so it is serialized as a profile comment of the form
+    /// `debug_file:<mapping id>:<debug_file>`. Empty if unknown.
+    pub debug_file: &'a str,
 }
 
 #[derive(Copy, Clone, Default)]
@@ -76,6 +100,10 @@ pub struct Location<'a> {
     /// E.g., if memcpy() is inlined into printf:
     ///    line[0].function_name == "memcpy"
     ///    line[1].function_name == "printf"
+    ///
+    /// Like `Sample::locations`, this goes from the leaf to the callers. The
+    /// order can't be checked, and getting it wrong turns the inlined frames
+    /// upside down; `Location::with_inlined` spells it out.
     pub lines: Vec<Line<'a>>,
 
     /// Provides an indication that multiple symbols map to this location's
@@ -86,6 +114,88 @@ pub struct Location<'a> {
     pub is_folded: bool,
 }
 
+/// A location in a mapping added beforehand with `Profile::add_mapping`, so
+/// the mapping isn't interned again for each location, see
+/// `Profile::add_mapped_location`.
+pub struct MappedLocation<'a> {
+    pub mapping_id: MappingId,
+
+    /// Same as `Location::address`.
+    pub address: u64,
+
+    /// Same as `Location::lines`.
+    pub lines: Vec<Line<'a>>,
+
+    /// Same as `Location::is_folded`.
+    pub is_folded: bool,
+}
+
+/// Same as `Mapping`, with paths which may not be valid UTF-8, see
+/// `Profile::add_bytes_mapping`.
+#[derive(Copy, Clone, Default)]
+pub struct BytesMapping<'a> {
+    pub memory_start: u64,
+    pub memory_limit: u64,
+    pub file_offset: u64,
+    pub filename: &'a [u8],
+    pub build_id: &'a [u8],
+    pub debug_file: &'a [u8],
+}
+
+/// Same as `Function`, with names and paths which may not be valid UTF-8,
+/// such as raw symbols from native stack walkers.
+#[derive(Copy, Clone, Default)]
+pub struct BytesFunction<'a> {
+    pub name: &'a [u8],
+    pub system_name: &'a [u8],
+    pub filename: &'a [u8],
+    pub start_line: i64,
+}
+
+pub struct BytesLine<'a> {
+    pub function: BytesFunction<'a>,
+    pub line: i64,
+}
+
+/// Same as `Location`, with names and paths which may not be valid UTF-8,
+/// see `Profile::add_bytes_location`.
+#[derive(Default)]
+pub struct BytesLocation<'a> {
+    pub mapping: BytesMapping<'a>,
+    pub address: u64,
+    pub lines: Vec<BytesLine<'a>>,
+    pub is_folded: bool,
+}
+
+/// The symbol an address resolves to, see `Profile::resymbolize` and
+/// `Profile::symbolize_pending`.
+#[derive(Copy, Clone, Default)]
+pub struct ResolvedSymbol<'a> {
+    /// Name of the function, in human-readable form if available.
+    pub name: &'a str,
+
+    /// Name of the function, as identified by the system.
+    pub system_name: &'a str,
+
+    /// Source file containing the function.
+    pub filename: &'a str,
+
+    /// Line number in source code.
+    pub line: i64,
+}
+
+impl<'a> Location<'a> {
+    /// Creates a location for the given lines, starting with the innermost
+    /// inlined function and ending with the function they were all inlined
+    /// into.
+    pub fn with_inlined(leaf_first: Vec<Line<'a>>) -> Self {
+        Self {
+            lines: leaf_first,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct Label<'a> {
     pub key: &'a str,
@@ -104,6 +214,30 @@ pub struct Label<'a> {
     pub num_unit: Option<&'a str>,
 }
 
+impl<'a> Label<'a> {
+    /// Creates a label which only carries a string value.
+    pub fn str(key: &'a str, value: &'a str) -> Self {
+        Self {
+            key,
+            str: Some(value),
+            num: 0,
+            num_unit: None,
+        }
+    }
+
+    /// Creates a label which only carries a numeric value, optionally with
+    /// the unit of that value.
+    pub fn num(key: &'a str, value: i64, unit: Option<&'a str>) -> Self {
+        Self {
+            key,
+            str: None,
+            num: value,
+            num_unit: unit,
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Sample<'a> {
     /// The leaf is at locations[0].
     pub locations: Vec<Location<'a>>,
@@ -119,4 +253,47 @@ pub struct Sample<'a> {
     /// label includes additional context for this sample. It can include
     /// things like a thread id, allocation size, etc
     pub labels: Vec<Label<'a>>,
+
+    /// Locations which were added with `Profile::add_location`, to avoid
+    /// passing stacks shared by many samples again each time. They come
+    /// after `locations` in the stack, so either one can be left empty, or
+    /// `locations` can hold the leaves of a stack shared through these.
+    pub location_ids: Vec<PProfId>,
+
+    /// Set when the stack is known to be incomplete, for instance because
+    /// the unwinder gave up at its depth limit. A "[truncated]" location is
+    /// then added as the root, so the missing frames are visible.
+    pub truncated: bool,
+}
+
+impl<'a> Sample<'a> {
+    /// Creates a sample without locations nor labels, whose values are laid
+    /// out for the sample types of `profile` from pairs of sample type index
+    /// and value, e.g. `&[(cpu, 10), (wall, 20)]`. The values of sample types
+    /// which aren't given are 0, and those given more than once are summed,
+    /// saturating at the bounds of i64.
+    pub fn with_values(profile: &Profile, values: &[(usize, i64)]) -> Result<Self, AddError> {
+        let mut laid_out = vec![0i64; profile.sample_types.len()];
+        for &(index, value) in values {
+            let column = laid_out
+                .get_mut(index)
+                .ok_or(AddError::UnknownSampleType(index))?;
+            *column = column.saturating_add(value);
+        }
+        Ok(Self {
+            locations: vec![],
+            values: laid_out,
+            labels: vec![],
+            ..Default::default()
+        })
+    }
+
+    /// Creates a sample without locations nor labels, for `count`
+    /// allocations of `bytes` in total, in the alloc-samples and alloc-space
+    /// sample types of `profile`. Returns None if it doesn't have both, see
+    /// `Profile::allocation_sample_types`.
+    pub fn with_allocation(profile: &Profile, count: i64, bytes: i64) -> Option<Self> {
+        let (count_index, space_index) = profile.allocation_sample_types()?;
+        Self::with_values(profile, &[(count_index, count), (space_index, bytes)]).ok()
+    }
 }
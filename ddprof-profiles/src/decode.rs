@@ -0,0 +1,853 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Decoding of encoded pprof profiles which may come from untrusted sources,
+//! such as other processes. The number of entries in the main repeated fields
+//! is checked against limits before anything is allocated for them. Only built
+//! with the `decode` feature.
+
+use core::fmt;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime};
+
+use prost::Message;
+
+use crate::{
+    pprof, DedupExt, Function, Label, Line, Location, Mapping, PProfId, Profile, Sample, ValueType,
+};
+
+/// Maximum number of entries accepted in a decoded profile.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DecodeLimits {
+    pub max_strings: usize,
+    pub max_samples: usize,
+    pub max_locations: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_strings: 1 << 20,
+            max_samples: 1 << 20,
+            max_locations: 1 << 20,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The message has more entries of the named field than allowed.
+    LimitExceeded { field: &'static str, limit: usize },
+    /// The message isn't a valid pprof profile.
+    Invalid(prost::DecodeError),
+    /// The profile has a sample type, given as type and unit, which the
+    /// profile it is merged into doesn't have.
+    UnknownSampleType(String, String),
+    /// An id or string index of the profile refers to nothing, or an item
+    /// has an id of 0 or the id of another item. `field` names the message
+    /// and field, as in profile.proto.
+    CorruptReference { field: &'static str },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::LimitExceeded { field, limit } => {
+                write!(f, "profile has more than {} {}", limit, field)
+            }
+            DecodeError::Invalid(err) => write!(f, "invalid profile: {}", err),
+            DecodeError::UnknownSampleType(r#type, unit) => {
+                write!(f, "unknown sample type {} ({})", r#type, unit)
+            }
+            DecodeError::CorruptReference { field } => {
+                write!(f, "invalid profile: corrupt reference in {}", field)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::LimitExceeded { .. }
+            | DecodeError::UnknownSampleType(..)
+            | DecodeError::CorruptReference { .. } => None,
+            DecodeError::Invalid(err) => Some(err),
+        }
+    }
+}
+
+// Field numbers of pprof::Profile, see profile.proto.
+const SAMPLE: u64 = 2;
+const LOCATION: u64 = 4;
+const STRING_TABLE: u64 = 6;
+
+/// Decodes an encoded pprof profile, failing with `LimitExceeded` if it has
+/// more strings, samples or locations than `limits` allow.
+pub fn decode(buffer: &[u8], limits: &DecodeLimits) -> Result<pprof::Profile, DecodeError> {
+    let checks = [
+        (STRING_TABLE, "strings", limits.max_strings),
+        (SAMPLE, "samples", limits.max_samples),
+        (LOCATION, "locations", limits.max_locations),
+    ];
+    let mut counts = [0usize; 3];
+
+    // Protobuf has no declared lengths for repeated fields, every entry is
+    // its own field, so they are counted by walking the top level of the
+    // message. A malformed message stops the walk early and is then reported
+    // by prost, which never allocates more than the buffer holds.
+    for field in (FieldIter { buffer }) {
+        for ((number, name, limit), count) in checks.iter().zip(counts.iter_mut()) {
+            if field == *number {
+                *count += 1;
+                if *count > *limit {
+                    return Err(DecodeError::LimitExceeded {
+                        field: name,
+                        limit: *limit,
+                    });
+                }
+            }
+        }
+    }
+
+    pprof::Profile::decode(buffer).map_err(DecodeError::Invalid)
+}
+
+fn invalid(description: &'static str) -> DecodeError {
+    DecodeError::Invalid(prost::DecodeError::new(description))
+}
+
+/// Splits a `debug_file:<mapping id>:<file>` comment, as added when a mapping
+/// with a debug file is interned.
+fn parse_debug_file(comment: &str) -> Option<(u64, &str)> {
+    let (id, file) = comment.strip_prefix("debug_file:")?.split_once(':')?;
+    Some((id.parse().ok()?, file))
+}
+
+/// Collects the ids of `items`, which must be distinct and not 0.
+fn item_ids<T>(
+    items: &[T],
+    id: fn(&T) -> u64,
+    field: &'static str,
+) -> Result<HashSet<u64>, DecodeError> {
+    let mut ids = HashSet::with_capacity(items.len());
+    for item in items {
+        let id = id(item);
+        if id == 0 || !ids.insert(id) {
+            return Err(DecodeError::CorruptReference { field });
+        }
+    }
+    Ok(ids)
+}
+
+/// Checks every id and string index of a decoded profile before anything is
+/// built from it: they must refer to items the profile has, and only those
+/// which are optional may be 0. The first string must be the empty one, as
+/// index 0 stands for no string.
+fn validate(profile: &pprof::Profile) -> Result<(), DecodeError> {
+    let corrupt = |field| DecodeError::CorruptReference { field };
+    let strings = profile.string_table.len() as u64;
+    if profile.string_table.first().map(String::as_str) != Some("") {
+        return Err(corrupt("string_table"));
+    }
+    let string = |index: i64, field| match index {
+        index if index >= 0 && (index as u64) < strings => Ok(()),
+        _ => Err(corrupt(field)),
+    };
+    let reference = |ids: &HashSet<u64>, id: u64, optional: bool, field| {
+        if (optional && id == 0) || ids.contains(&id) {
+            Ok(())
+        } else {
+            Err(corrupt(field))
+        }
+    };
+
+    for value_type in profile.sample_type.iter().chain(&profile.period_type) {
+        string(value_type.r#type, "ValueType.type")?;
+        string(value_type.unit, "ValueType.unit")?;
+    }
+    string(profile.drop_frames, "Profile.drop_frames")?;
+    string(profile.keep_frames, "Profile.keep_frames")?;
+    string(profile.default_sample_type, "Profile.default_sample_type")?;
+    for comment in profile.comment.iter() {
+        string(*comment, "Profile.comment")?;
+    }
+
+    let mappings = item_ids(&profile.mapping, |mapping| mapping.id, "Mapping.id")?;
+    for mapping in profile.mapping.iter() {
+        string(mapping.filename, "Mapping.filename")?;
+        string(mapping.build_id, "Mapping.build_id")?;
+    }
+    let functions = item_ids(&profile.function, |function| function.id, "Function.id")?;
+    for function in profile.function.iter() {
+        string(function.name, "Function.name")?;
+        string(function.system_name, "Function.system_name")?;
+        string(function.filename, "Function.filename")?;
+    }
+    let locations = item_ids(&profile.location, |location| location.id, "Location.id")?;
+    for location in profile.location.iter() {
+        reference(&mappings, location.mapping_id, true, "Location.mapping_id")?;
+        for line in location.line.iter() {
+            reference(&functions, line.function_id, false, "Line.function_id")?;
+        }
+    }
+
+    for sample in profile.sample.iter() {
+        if sample.value.len() != profile.sample_type.len() {
+            return Err(invalid("sample values don't match the sample types"));
+        }
+        for id in sample.location_id.iter() {
+            reference(&locations, *id, false, "Sample.location_id")?;
+        }
+        for label in sample.label.iter() {
+            string(label.key, "Label.key")?;
+            string(label.str, "Label.str")?;
+            string(label.num_unit, "Label.num_unit")?;
+        }
+    }
+    Ok(())
+}
+
+impl Profile {
+    /// Rebuilds a profile from an encoded pprof profile, e.g. one handed over
+    /// by another process, so more samples can be added to it. The buffer is
+    /// decoded with the default `DecodeLimits`, see `from_pprof_with_limits`
+    /// to set others. Items with the same content
+    /// are merged, and ids are reassigned, but a profile serialized by this
+    /// crate comes back with the same ids. The start time and duration are
+    /// kept, and so are the process info and the mapping debug files of the
+    /// comments.
+    ///
+    /// The bytes may be malformed or even crafted: every id and string index
+    /// is checked before the profile is built, failing with
+    /// `CorruptReference` if one refers to nothing.
+    pub fn from_pprof(bytes: &[u8]) -> Result<Profile, DecodeError> {
+        Profile::from_pprof_with_limits(bytes, &DecodeLimits::default())
+    }
+
+    /// Same as `from_pprof`, failing with `LimitExceeded` if the buffer has
+    /// more entries than `limits` allow.
+    pub fn from_pprof_with_limits(
+        bytes: &[u8],
+        limits: &DecodeLimits,
+    ) -> Result<Profile, DecodeError> {
+        let decoded = decode(bytes, limits)?;
+        validate(&decoded)?;
+        let mut profile = Profile::new();
+
+        // Nothing below can fail, as the references were validated.
+        let strings: Vec<PProfId> = decoded
+            .string_table
+            .iter()
+            .map(|string| profile.intern(string))
+            .collect();
+        let string = |index: i64| strings[index as usize];
+        let value_type = |value_type: &pprof::ValueType| ValueType {
+            type_: string(value_type.r#type),
+            unit: string(value_type.unit),
+        };
+        // Id 0 stands for none, and is kept as is
+        let lookup = |ids: &HashMap<u64, PProfId>, id: u64| match id {
+            0 => PProfId(0),
+            id => ids[&id],
+        };
+
+        profile.sample_types = decoded.sample_type.iter().map(value_type).collect();
+        profile.allocation_sample_types = profile.find_allocation_sample_types();
+        profile.period_type = decoded.period_type.as_ref().map(value_type);
+        profile.period = decoded.period;
+        profile.drop_frames = string(decoded.drop_frames);
+        profile.keep_frames = string(decoded.keep_frames);
+        profile.default_sample_type = string(decoded.default_sample_type);
+
+        // The first comment naming a mapping's debug file wins
+        let mut debug_files = HashMap::new();
+        for comment in decoded.comment.iter() {
+            if let Some((id, file)) = parse_debug_file(&decoded.string_table[*comment as usize]) {
+                debug_files.entry(id).or_insert(file);
+            }
+        }
+
+        let mut mapping_ids = HashMap::with_capacity(decoded.mapping.len());
+        for mapping in decoded.mapping.iter() {
+            let debug_file = match debug_files.get(&mapping.id) {
+                Some(file) => profile.intern(file),
+                None => PProfId(0),
+            };
+            let index = profile.mappings.dedup(Mapping {
+                memory_start: mapping.memory_start,
+                memory_limit: mapping.memory_limit,
+                file_offset: mapping.file_offset,
+                filename: string(mapping.filename),
+                build_id: string(mapping.build_id),
+                debug_file,
+            });
+            mapping_ids.insert(mapping.id, PProfId(index + 1));
+        }
+
+        let mut function_ids = HashMap::with_capacity(decoded.function.len());
+        for function in decoded.function.iter() {
+            let index = profile.functions.dedup(Function {
+                name: string(function.name),
+                system_name: string(function.system_name),
+                filename: string(function.filename),
+                start_line: function.start_line,
+            });
+            function_ids.insert(function.id, PProfId(index + 1));
+        }
+
+        let mut location_ids = HashMap::with_capacity(decoded.location.len());
+        for location in decoded.location.iter() {
+            let lines = location
+                .line
+                .iter()
+                .map(|line| Line {
+                    function_id: lookup(&function_ids, line.function_id),
+                    line: line.line,
+                })
+                .collect();
+            let index = profile.locations.dedup(Location {
+                mapping_id: lookup(&mapping_ids, location.mapping_id),
+                address: location.address.try_into().unwrap_or(0),
+                lines,
+                is_folded: location.is_folded,
+            });
+            location_ids.insert(location.id, PProfId(index + 1));
+        }
+
+        for sample in decoded.sample.iter() {
+            let locations = sample
+                .location_id
+                .iter()
+                .map(|id| lookup(&location_ids, *id))
+                .collect();
+            let labels = sample
+                .label
+                .iter()
+                .map(|label| Label {
+                    key: string(label.key),
+                    str: string(label.str),
+                    num: label.num,
+                    num_unit: string(label.num_unit),
+                })
+                .collect();
+            profile.merge_sample(Sample { locations, labels }, sample.value.clone());
+        }
+
+        let mut process_id = None;
+        let mut runtime_id = None;
+        let mut commented_mappings = HashSet::new();
+        for comment in decoded.comment.iter() {
+            let comment = string(*comment);
+            let debug_file = parse_debug_file(&profile.strings[comment.0])
+                .and_then(|(id, _)| mapping_ids.get(&id).copied());
+            if let Some(mapping_id) = debug_file {
+                // The mapping may have a new id, or have been merged with one
+                // which already got its comment
+                if commented_mappings.insert(mapping_id) {
+                    let mapping = &profile.mappings[mapping_id.0 - 1];
+                    let comment = format!(
+                        "debug_file:{}:{}",
+                        mapping_id.0, profile.strings[mapping.debug_file.0]
+                    );
+                    let comment = profile.intern(&comment);
+                    profile.comments.push(comment);
+                }
+                continue;
+            }
+            profile.comments.push(comment);
+            let text = &profile.strings[comment.0];
+            if let Some(pid) = text.strip_prefix("process_id:") {
+                process_id = pid.parse().ok();
+            } else if let Some(id) = text.strip_prefix("runtime-id:") {
+                runtime_id = Some(id.to_owned());
+            } else if !text.starts_with("debug_file:") {
+                profile.free_comments.push(comment);
+            }
+        }
+        if let (Some(pid), Some(runtime_id)) = (process_id, runtime_id) {
+            profile.process_info = Some((pid, runtime_id));
+        }
+
+        let start_time = if decoded.time_nanos > 0 {
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(decoded.time_nanos as u64)
+        } else {
+            profile.start_time
+        };
+        let duration = Duration::from_nanos(decoded.duration_nanos.max(0) as u64);
+        let (_, now) = (profile.clock)();
+        profile.set_window(start_time, now, Ok(duration));
+        Ok(profile)
+    }
+
+    /// Adds the samples of an encoded pprof profile, e.g. one from Go's
+    /// runtime/pprof, to this profile, aggregating them with its own. Their
+    /// labels, string or numeric, are kept, with their strings interned into
+    /// this profile's table. The values are laid out for this profile's
+    /// sample types, matched by type and unit, and are 0 for those the
+    /// encoded profile doesn't have. Fails with `UnknownSampleType` if the
+    /// encoded profile has sample types this one doesn't, leaving this
+    /// profile unchanged, as it does for other errors. The buffer is decoded
+    /// with the default `DecodeLimits`, see `merge_encoded_with_limits`.
+    pub fn merge_encoded(&mut self, bytes: &[u8]) -> Result<(), DecodeError> {
+        self.merge_encoded_with_limits(bytes, &DecodeLimits::default())
+    }
+
+    /// Same as `merge_encoded`, failing with `LimitExceeded` if the buffer
+    /// has more entries than `limits` allow.
+    pub fn merge_encoded_with_limits(
+        &mut self,
+        bytes: &[u8],
+        limits: &DecodeLimits,
+    ) -> Result<(), DecodeError> {
+        let other = Profile::from_pprof_with_limits(bytes, limits)?;
+        let resolve = |profile: &Profile, sample_type: &ValueType| {
+            (
+                profile.strings[sample_type.type_.0].clone(),
+                profile.strings[sample_type.unit.0].clone(),
+            )
+        };
+        let ours: Vec<(String, String)> = self
+            .sample_types
+            .iter()
+            .map(|sample_type| resolve(self, sample_type))
+            .collect();
+        let columns = other
+            .sample_types
+            .iter()
+            .map(|sample_type| {
+                let resolved = resolve(&other, sample_type);
+                ours.iter()
+                    .position(|sample_type| *sample_type == resolved)
+                    .ok_or(DecodeError::UnknownSampleType(resolved.0, resolved.1))
+            })
+            .collect::<Result<Vec<usize>, _>>()?;
+
+        let mut ids = other.copied_ids();
+        for (sample, values) in other.samples.iter() {
+            let mut laid_out = vec![0i64; ours.len()];
+            for (column, value) in columns.iter().zip(values) {
+                laid_out[*column] = laid_out[*column].saturating_add(*value);
+            }
+            other.copy_sample(self, &mut ids, sample, &laid_out);
+        }
+        Ok(())
+    }
+}
+
+/// Walks the top level fields of a protobuf message, yielding their numbers.
+struct FieldIter<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> FieldIter<'a> {
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for (index, byte) in self.buffer.iter().take(10).enumerate() {
+            value |= u64::from(byte & 0x7f) << (7 * index);
+            if byte & 0x80 == 0 {
+                self.buffer = &self.buffer[index + 1..];
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn skip(&mut self, len: u64) -> Option<()> {
+        if len > self.buffer.len() as u64 {
+            return None;
+        }
+        self.buffer = &self.buffer[len as usize..];
+        Some(())
+    }
+}
+
+impl<'a> Iterator for FieldIter<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let key = self.varint()?;
+        match key & 0x7 {
+            0 => {
+                self.varint()?;
+            }
+            1 => self.skip(8)?,
+            2 => {
+                let len = self.varint()?;
+                self.skip(len)?
+            }
+            5 => self.skip(4)?,
+            // Groups are deprecated and aren't used by pprof.
+            _ => return None,
+        }
+        Some(key >> 3)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(profile: &pprof::Profile) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        profile.encode(&mut buffer).unwrap();
+        buffer
+    }
+
+    /// The key of a length-delimited field.
+    fn key(field: u64) -> u8 {
+        ((field << 3) | 2) as u8
+    }
+
+    #[test]
+    fn decode_within_limits() {
+        let profile = pprof::Profile {
+            sample: vec![pprof::Sample {
+                location_id: vec![1],
+                value: vec![1],
+                label: vec![],
+            }],
+            location: vec![pprof::Location {
+                id: 1,
+                ..Default::default()
+            }],
+            string_table: vec!["".into(), "samples".into(), "count".into()],
+            ..Default::default()
+        };
+
+        let decoded = decode(&encode(&profile), &DecodeLimits::default()).unwrap();
+        assert_eq!(decoded, profile);
+    }
+
+    #[test]
+    fn decode_limit_exceeded() {
+        // Many empty strings encode to two bytes each, so a small message can
+        // declare a huge string table.
+        let buffer: Vec<u8> = [key(STRING_TABLE), 0].repeat(10_000);
+        let limits = DecodeLimits {
+            max_strings: 1000,
+            ..Default::default()
+        };
+
+        match decode(&buffer, &limits) {
+            Err(DecodeError::LimitExceeded { field, limit }) => {
+                assert_eq!(field, "strings");
+                assert_eq!(limit, 1000);
+            }
+            other => panic!("expected a limit error, got {:?}", other),
+        }
+
+        let limits = DecodeLimits {
+            max_samples: 1,
+            ..Default::default()
+        };
+        let buffer: Vec<u8> = [key(SAMPLE), 0].repeat(2);
+        assert!(matches!(
+            decode(&buffer, &limits),
+            Err(DecodeError::LimitExceeded {
+                field: "samples",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn from_pprof_with_limits() {
+        let buffer = encode(&go_profile());
+        let limits = DecodeLimits {
+            max_locations: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Profile::from_pprof_with_limits(&buffer, &limits),
+            Err(DecodeError::LimitExceeded {
+                field: "locations",
+                limit: 1
+            })
+        ));
+
+        let sample_types = vec![
+            crate::api::ValueType {
+                r#type: "cpu",
+                unit: "nanoseconds",
+            },
+            crate::api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        assert!(matches!(
+            profile.merge_encoded_with_limits(&buffer, &limits),
+            Err(DecodeError::LimitExceeded {
+                field: "locations",
+                limit: 1
+            })
+        ));
+        assert!(profile.samples.is_empty());
+        profile
+            .merge_encoded_with_limits(&buffer, &Default::default())
+            .unwrap();
+        assert!(!profile.samples.is_empty());
+    }
+
+    /// A cpu profile laid out like those of Go's runtime/pprof: strings in
+    /// their own order, ids which aren't indexes, and pprof labels.
+    fn go_profile() -> pprof::Profile {
+        let strings = [
+            "",
+            "samples",
+            "count",
+            "cpu",
+            "nanoseconds",
+            "main.work",
+            "main.go",
+            "bytes",
+            "request",
+            "checkout",
+            "main.main",
+            "/usr/bin/app",
+            "",
+        ];
+        let function = |id, name| pprof::Function {
+            id,
+            name,
+            system_name: name,
+            filename: 6,
+            start_line: 10,
+        };
+        let location = |id, function_id| pprof::Location {
+            id,
+            mapping_id: 3,
+            address: 0x1000 + id,
+            line: vec![pprof::Line {
+                function_id,
+                line: 12,
+            }],
+            is_folded: false,
+        };
+        pprof::Profile {
+            sample_type: vec![
+                pprof::ValueType { r#type: 1, unit: 2 },
+                pprof::ValueType { r#type: 3, unit: 4 },
+            ],
+            sample: vec![pprof::Sample {
+                location_id: vec![7, 9],
+                value: vec![2, 20_000_000],
+                label: vec![
+                    pprof::Label {
+                        key: 8,
+                        str: 9,
+                        ..Default::default()
+                    },
+                    pprof::Label {
+                        key: 7,
+                        num: 4096,
+                        num_unit: 7,
+                        ..Default::default()
+                    },
+                ],
+            }],
+            mapping: vec![pprof::Mapping {
+                id: 3,
+                memory_start: 0x1000,
+                memory_limit: 0x2000,
+                filename: 11,
+                ..Default::default()
+            }],
+            location: vec![location(7, 20), location(9, 21)],
+            function: vec![function(20, 5), function(21, 10)],
+            string_table: strings.iter().map(|s| s.to_string()).collect(),
+            period_type: Some(pprof::ValueType { r#type: 3, unit: 4 }),
+            period: 10_000_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_go_labels() {
+        let sample_types = vec![
+            crate::api::ValueType {
+                r#type: "cpu",
+                unit: "nanoseconds",
+            },
+            crate::api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        // Strings which the Go profile has at other indexes
+        let location = crate::api::Location {
+            lines: vec![crate::api::Line {
+                function: crate::api::Function {
+                    name: "main.main",
+                    ..Default::default()
+                },
+                line: 0,
+            }],
+            ..Default::default()
+        };
+        profile
+            .add(crate::api::Sample {
+                locations: vec![location],
+                values: vec![5, 1],
+                labels: vec![crate::api::Label::str("request", "login")],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let bytes = encode(&go_profile());
+        profile.merge_encoded(&bytes).unwrap();
+        profile.merge_encoded(&bytes).unwrap();
+
+        let decoded = decode(
+            &profile.serialize().unwrap().buffer,
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+        let string = |id: i64| decoded.string_table[id as usize].as_str();
+        let mut samples: Vec<(Vec<String>, Vec<i64>)> = decoded
+            .sample
+            .iter()
+            .map(|sample| {
+                let labels = sample
+                    .label
+                    .iter()
+                    .map(|label| match label.str {
+                        0 => format!(
+                            "{}={} {}",
+                            string(label.key),
+                            label.num,
+                            string(label.num_unit)
+                        ),
+                        str => format!("{}={}", string(label.key), string(str)),
+                    })
+                    .collect();
+                (labels, sample.value.clone())
+            })
+            .collect();
+        samples.sort();
+        assert_eq!(
+            samples,
+            vec![
+                (
+                    vec!["request=checkout".to_owned(), "bytes=4096 bytes".to_owned()],
+                    vec![40_000_000, 4]
+                ),
+                (vec!["request=login".to_owned()], vec![5, 1]),
+            ]
+        );
+        assert_eq!(
+            profile.to_folded(0).unwrap(),
+            "main.main 5\nmain.main;main.work 40000000\n"
+        );
+    }
+
+    #[test]
+    fn from_pprof_allocation_sample_types() {
+        let sample_types = vec![
+            crate::api::ValueType {
+                r#type: "alloc-samples",
+                unit: "count",
+            },
+            crate::api::ValueType {
+                r#type: "alloc-space",
+                unit: "bytes",
+            },
+        ];
+        let profile = Profile::builder().sample_types(sample_types).build();
+        let decoded = Profile::from_pprof(&profile.serialize().unwrap().buffer).unwrap();
+        assert_eq!(decoded.allocation_sample_types(), Some((0, 1)));
+    }
+
+    #[test]
+    fn merge_unknown_sample_type() {
+        let sample_types = vec![crate::api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        match profile.merge_encoded(&encode(&go_profile())) {
+            Err(DecodeError::UnknownSampleType(r#type, unit)) => {
+                assert_eq!((r#type.as_str(), unit.as_str()), ("cpu", "nanoseconds"));
+            }
+            other => panic!("expected an unknown sample type, got {:?}", other),
+        }
+        assert_eq!(profile.summary().sample_count, 0);
+    }
+
+    #[test]
+    fn from_pprof_truncated() {
+        let bytes = encode(&go_profile());
+        assert!(Profile::from_pprof(&bytes).is_ok());
+        // Most prefixes are rejected, those which aren't must be usable
+        for len in 0..bytes.len() {
+            if let Ok(mut profile) = Profile::from_pprof(&bytes[..len]) {
+                profile.serialize().unwrap();
+                profile.compact();
+                profile.coalesce_functions(|function| function.name.to_owned());
+            }
+        }
+    }
+
+    #[test]
+    fn from_pprof_corrupt_references() {
+        type Corrupt = fn(&mut pprof::Profile);
+        let cases: [(&str, Corrupt); 11] = [
+            ("string_table", |p| p.string_table[0] = "main".into()),
+            ("ValueType.unit", |p| p.sample_type[1].unit = -1),
+            ("Mapping.id", |p| p.mapping[0].id = 0),
+            // Ids which refer to another item of the same kind
+            ("Function.id", |p| p.function[1].id = 20),
+            ("Location.id", |p| p.location[1].id = 7),
+            ("Location.mapping_id", |p| p.location[0].mapping_id = 7),
+            ("Line.function_id", |p| {
+                p.location[0].line[0].function_id = 0
+            }),
+            ("Sample.location_id", |p| p.sample[0].location_id.push(0)),
+            ("Label.num_unit", |p| {
+                p.sample[0].label[1].num_unit = 1 << 40
+            }),
+            ("Profile.comment", |p| {
+                p.comment.push(p.string_table.len() as i64)
+            }),
+            ("Profile.default_sample_type", |p| {
+                p.default_sample_type = i64::MIN
+            }),
+        ];
+        for (field, corrupt) in cases.iter() {
+            let mut profile = go_profile();
+            corrupt(&mut profile);
+            match Profile::from_pprof(&encode(&profile)) {
+                Err(DecodeError::CorruptReference { field: actual }) => assert_eq!(actual, *field),
+                Err(err) => panic!("{}: expected a corrupt reference, got {}", field, err),
+                Ok(_) => panic!("{}: expected a corrupt reference", field),
+            }
+        }
+    }
+
+    #[test]
+    fn from_pprof_saturates() {
+        let mut profile = go_profile();
+        profile.sample[0].value = vec![i64::MAX, i64::MAX];
+        profile.sample.push(profile.sample[0].clone());
+        let profile = Profile::from_pprof(&encode(&profile)).unwrap();
+        let values: Vec<&[i64]> = profile.samples_iter().map(|(_, values)| values).collect();
+        assert_eq!(values, vec![&[i64::MAX, i64::MAX]]);
+    }
+
+    #[test]
+    fn decode_invalid() {
+        // A string which claims to be longer than the message
+        let buffer = [key(STRING_TABLE), 0xff, 0xff, 0xff, 0xff, 0x0f];
+        assert!(matches!(
+            decode(&buffer, &DecodeLimits::default()),
+            Err(DecodeError::Invalid(_))
+        ));
+    }
+}
@@ -3,18 +3,35 @@
 
 use core::fmt;
 use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::hash::Hash;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash};
+use std::io;
 use std::ops::AddAssign;
-use std::time::{Instant, SystemTime};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use indexmap::{IndexMap, IndexSet};
 use prost::{EncodeError, Message};
-use ux::u63;
+use rustc_hash::FxHasher;
 
 pub mod api;
+#[cfg(feature = "decode")]
+pub mod decode;
+mod location_cache;
+pub mod perf;
 pub mod pprof;
 
+pub use location_cache::LocationCache;
+
+/// The tables of a profile hash with FxHash, which is much faster than the
+/// default SipHash on the short keys they intern, `Profile::add` interning
+/// every string of every sample. It is not resistant to collision attacks,
+/// on purpose: the tables only hold what the profiled process itself
+/// reports, never untrusted input.
+type FxIndexMap<K, V> = IndexMap<K, V, BuildHasherDefault<FxHasher>>;
+type FxIndexSet<T> = IndexSet<T, BuildHasherDefault<FxHasher>>;
+
 #[derive(Eq, PartialEq, Hash)]
 struct Mapping {
     /// Address at which the binary (or DLL) is loaded into memory.
@@ -33,6 +50,10 @@ struct Mapping {
     /// with high probability. E.g., for binaries generated by GNU tools,
     /// it could be the contents of the .note.gnu.build-id field.
     pub build_id: PProfId,
+
+    /// Where to find the debug info of the binary, see
+    /// `api::Mapping::debug_file`.
+    pub debug_file: PProfId,
 }
 
 #[derive(Eq, PartialEq, Hash)]
@@ -47,8 +68,9 @@ struct Function {
     /// Source file containing the function.
     pub filename: PProfId,
 
-    /// Line number in source file.
-    pub start_line: u63,
+    /// Line number in source file. Kept as is when negative, which some
+    /// runtimes use as a sentinel.
+    pub start_line: i64,
 }
 
 #[derive(Eq, PartialEq, Hash)]
@@ -165,20 +187,104 @@ impl From<&ValueType> for pprof::ValueType {
 
 pub struct Profile {
     sample_types: Vec<ValueType>,
-    samples: IndexMap<Sample, Vec<i64>>,
-    mappings: IndexSet<Mapping>,
-    locations: IndexSet<Location>,
-    functions: IndexSet<Function>,
-    strings: IndexSet<String>,
+    samples: FxIndexMap<Sample, Vec<i64>>,
+    mappings: FxIndexSet<Mapping>,
+    locations: FxIndexSet<Location>,
+    functions: FxIndexSet<Function>,
+    strings: FxIndexSet<String>,
+    /// Freeform comments of the profile, which hold the process info, the
+    /// debug files of the mappings and the free-form comments.
+    comments: Vec<PProfId>,
+    /// The comments given to `ProfileBuilder::comments` or `add_comment`,
+    /// which are also in `comments`, to keep them across resets.
+    free_comments: Vec<PProfId>,
+    /// The pid and runtime id, see `ProfileBuilder::process_info`.
+    process_info: Option<(u32, String)>,
+    /// The counts given to `add_endpoint_count`, by endpoint.
+    endpoint_counts: EndpointCounts,
+    /// The length strings are truncated to, see
+    /// `ProfileBuilder::max_string_len`, and the number of truncations.
+    max_string_len: Option<usize>,
+    truncated_strings: u64,
+    /// The number of samples refused for going over a soft limit, and the
+    /// sum of their values, one per sample type.
+    dropped_samples: u64,
+    dropped_values: Vec<i64>,
     started_at: Instant,
     start_time: SystemTime,
+    /// What the start time, the duration and the end are read from, see
+    /// `ProfileBuilder::clock`.
+    clock: Clock,
     period: i64,
     period_type: Option<ValueType>,
+    /// Regexes of the frames for tools to drop and keep, see
+    /// `ProfileBuilder::drop_frames`. The empty string when unset.
+    drop_frames: PProfId,
+    keep_frames: PProfId,
+    /// The type of the sample type viewers show first, see
+    /// `ProfileBuilder::default_sample_type`. The empty string when unset.
+    default_sample_type: PProfId,
+    /// Index into sample_types of the column to infer the period from when
+    /// no period has been set.
+    infer_period_from: Option<usize>,
+    /// The indexes into sample_types of the alloc-samples and alloc-space
+    /// columns, found whenever the sample types are set, see
+    /// `allocation_sample_types`.
+    allocation_sample_types: Option<(usize, usize)>,
+    ignore_location_addresses: bool,
+    no_mappings: bool,
+    omit_zero_samples: bool,
+    validate_lines: bool,
+    process_location_for_empty_stacks: bool,
+    stack_hash_labels: bool,
+    compatibility_mode: CompatibilityMode,
+    soft_limits: SoftLimits,
+    /// The id of the key of timestamp labels, see `timestamp_key`.
+    timestamp_key: Option<PProfId>,
+    /// Changes whenever location ids are renumbered, see `locations_epoch`.
+    locations_epoch: u64,
+    /// The ids of recently seen label keys, by the address and length of the
+    /// `&str` they were given as, see `intern_label_key`.
+    label_keys: [(usize, usize, PProfId); LABEL_KEY_CACHE_SIZE],
+    /// The keys of the labels samples are aggregated by, if not all of them,
+    /// see `ProfileBuilder::aggregate_by_labels`.
+    aggregate_by_labels: Option<Vec<PProfId>>,
+    /// When aggregating by some labels, the index of the sample each stack
+    /// and set of those labels was folded into.
+    folded: HashMap<FoldKey, usize>,
+}
+
+/// A stack, and the labels of a sample which are aggregated by.
+type FoldKey = (Vec<PProfId>, Vec<Label>);
+
+/// Reads the wall clock and the monotonic clock, see `ProfileBuilder::clock`.
+pub type Clock = fn() -> (SystemTime, Instant);
+
+fn system_clock() -> (SystemTime, Instant) {
+    (SystemTime::now(), Instant::now())
 }
 
 pub struct ProfileBuilder<'a> {
     sample_types: Vec<api::ValueType<'a>>,
     period: Option<api::Period<'a>>,
+    infer_period_from: Option<api::ValueType<'a>>,
+    ignore_location_addresses: bool,
+    no_mappings: bool,
+    omit_zero_samples: bool,
+    validate_lines: bool,
+    process_location_for_empty_stacks: bool,
+    stack_hash_labels: bool,
+    compatibility_mode: CompatibilityMode,
+    soft_limits: SoftLimits,
+    process_info: Option<(u32, &'a str)>,
+    drop_frames: Option<&'a str>,
+    keep_frames: Option<&'a str>,
+    default_sample_type: Option<&'a str>,
+    comments: Vec<&'a str>,
+    start_time: Option<SystemTime>,
+    clock: Option<Clock>,
+    aggregate_by_labels: Option<Vec<&'a str>>,
+    max_string_len: Option<usize>,
 }
 
 impl<'a> ProfileBuilder<'a> {
@@ -186,6 +292,24 @@ impl<'a> ProfileBuilder<'a> {
         ProfileBuilder {
             sample_types: vec![],
             period: None,
+            infer_period_from: None,
+            ignore_location_addresses: false,
+            no_mappings: false,
+            omit_zero_samples: false,
+            validate_lines: false,
+            process_location_for_empty_stacks: false,
+            stack_hash_labels: false,
+            compatibility_mode: CompatibilityMode::default(),
+            soft_limits: SoftLimits::default(),
+            process_info: None,
+            drop_frames: None,
+            keep_frames: None,
+            default_sample_type: None,
+            comments: vec![],
+            start_time: None,
+            clock: None,
+            aggregate_by_labels: None,
+            max_string_len: None,
         }
     }
 
@@ -199,6 +323,180 @@ impl<'a> ProfileBuilder<'a> {
         self
     }
 
+    /// When the profile has no period, infer one at serialization time from
+    /// the given sample type: the period type is the sample type itself, and
+    /// the period is the column's average value per sample. If one of the
+    /// sample types has a "count" unit, it's used as the number of samples,
+    /// otherwise each aggregated sample counts as one.
+    ///
+    /// By default, no period is inferred and a profile without a period is
+    /// serialized without one. This is ignored if `sample_type` isn't one of
+    /// the profile's sample types, or if a period was set explicitly.
+    pub fn infer_period_from(mut self, sample_type: Option<api::ValueType<'a>>) -> Self {
+        self.infer_period_from = sample_type;
+        self
+    }
+
+    /// When enabled, locations which have lines are identified by their
+    /// mapping and lines only; their addresses are dropped, so that stacks
+    /// which only differ by instruction address aggregate together. Only use
+    /// this when addresses carry no meaning for the profile, such as for
+    /// interpreted languages which symbolize on the fly. Locations without
+    /// lines keep their address. Disabled by default.
+    pub fn ignore_location_addresses(mut self, ignore: bool) -> Self {
+        self.ignore_location_addresses = ignore;
+        self
+    }
+
+    /// Builds a profile which never has mappings, for profiles where they
+    /// carry no meaning, such as ones for interpreted languages. The mappings
+    /// of the locations added to it are ignored and every location uses the
+    /// "no mapping" id, which skips the work of interning them.
+    pub fn no_mappings(mut self) -> Self {
+        self.no_mappings = true;
+        self
+    }
+
+    /// When enabled, samples whose values are all zero are left out when the
+    /// profile is serialized. This only affects serialization: the samples are
+    /// still aggregated by the profile. Disabled by default.
+    pub fn omit_zero_samples(mut self, omit: bool) -> Self {
+        self.omit_zero_samples = omit;
+        self
+    }
+
+    /// When enabled, `add` rejects locations whose lines don't fit their
+    /// functions, see `AddError::ImplausibleLines`. Disabled by default.
+    pub fn validate_lines(mut self, validate: bool) -> Self {
+        self.validate_lines = validate;
+        self
+    }
+
+    /// Samples without any location are allowed, for values which aren't
+    /// tied to a stack such as GC pause totals, but some consumers reject
+    /// samples with an empty stack. When enabled, such samples get a single
+    /// location for a "[process]" function instead. Disabled by default, in
+    /// which case they are serialized without locations.
+    pub fn process_location_for_empty_stacks(mut self, enabled: bool) -> Self {
+        self.process_location_for_empty_stacks = enabled;
+        self
+    }
+
+    /// When enabled, `add` gives each sample a numeric "stack_hash" label, a
+    /// hash of its stack which is the same for the same functions, files,
+    /// lines and addresses in any profile, so stacks can be matched across
+    /// profiles without comparing them. Stacks of raw addresses only hash the
+    /// same as long as the addresses are. Disabled by default.
+    pub fn stack_hash_labels(mut self, enabled: bool) -> Self {
+        self.stack_hash_labels = enabled;
+        self
+    }
+
+    /// Selects which pprof fields serialization writes, see
+    /// `CompatibilityMode`. Defaults to `CompatibilityMode::Current`.
+    pub fn compatibility_mode(mut self, mode: CompatibilityMode) -> Self {
+        self.compatibility_mode = mode;
+        self
+    }
+
+    /// Limits the number of distinct items of the profile well below what it
+    /// can hold, see `SoftLimits`. No limits by default.
+    pub fn soft_limits(mut self, limits: SoftLimits) -> Self {
+        self.soft_limits = limits;
+        self
+    }
+
+    /// Identifies the process the profile is from, to correlate it with
+    /// traces and host metrics. Rather than labelling every sample, the
+    /// values are serialized once, as the profile comments
+    /// `process_id:<pid>` and `runtime-id:<runtime_id>`.
+    pub fn process_info(mut self, pid: u32, runtime_id: &'a str) -> Self {
+        self.process_info = Some((pid, runtime_id));
+        self
+    }
+
+    /// Starts the profile at `start_time` rather than when it's built, for
+    /// embedders which buffer samples before making the profile. The
+    /// duration is measured from the same point in time.
+    pub fn start_time(mut self, start_time: SystemTime) -> Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    /// Reads the time from `clock` instead of the system clocks, for the
+    /// start time, and the duration and end when serializing, including
+    /// after a reset. Meant for tests, e.g. to step the wall clock back
+    /// while a profile is running.
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Free-form notes about the capture, such as the profiler version or
+    /// runtime flags, serialized as profile comments. Unlike samples, they
+    /// are kept when the profile is reset. See also `Profile::add_comment`.
+    pub fn comments(mut self, comments: Vec<&'a str>) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    /// Sets the pprof `drop_frames` regex: tools showing the profile drop the
+    /// frames whose function name matches it, along with the frames above
+    /// them, e.g. the frames of the profiler itself. Unset by default.
+    pub fn drop_frames(mut self, regex: &'a str) -> Self {
+        self.drop_frames = Some(regex);
+        self
+    }
+
+    /// Sets the pprof `keep_frames` regex: frames matching it are kept even
+    /// if they match `drop_frames`. Unset by default.
+    pub fn keep_frames(mut self, regex: &'a str) -> Self {
+        self.keep_frames = Some(regex);
+        self
+    }
+
+    /// Aggregates samples with the same stack by the labels with the given
+    /// keys only, rather than by all their labels. The other labels are kept
+    /// but don't keep samples apart: an aggregated sample keeps those of the
+    /// first sample folded into it, e.g. a representative "thread name",
+    /// and those of later samples are dropped. With no keys, samples are
+    /// aggregated by stack alone.
+    pub fn aggregate_by_labels(mut self, keys: Vec<&'a str>) -> Self {
+        self.aggregate_by_labels = Some(keys);
+        self
+    }
+
+    /// Sets the sample type viewers show first, by its type, e.g. "wall-time"
+    /// for a profile with both CPU and wall time. It must be the type of one
+    /// of the sample types: `try_build` fails otherwise, and `build` leaves
+    /// it unset. Unset by default, where viewers pick the last sample type.
+    pub fn default_sample_type(mut self, r#type: &'a str) -> Self {
+        self.default_sample_type = Some(r#type);
+        self
+    }
+
+    /// Truncates the strings interned while adding to the profile, such as
+    /// function names and label values, to their first `max_len` bytes,
+    /// rounded down to a character boundary, followed by "…". This keeps a
+    /// pathological string, like a generated name megabytes long, from
+    /// bloating the profile. The strings given to the builder, including the
+    /// sample types and period type, are kept whole. Unlimited by default.
+    pub fn max_string_len(mut self, max_len: usize) -> Self {
+        self.max_string_len = Some(max_len);
+        self
+    }
+
+    /// Same as `build`, but fails rather than ignoring options which don't
+    /// match the sample types.
+    pub fn try_build(self) -> Result<Profile, BuildError> {
+        if let Some(r#type) = self.default_sample_type {
+            if !self.sample_types.iter().any(|st| st.r#type == r#type) {
+                return Err(BuildError::UnknownDefaultSampleType(r#type.to_owned()));
+            }
+        }
+        Ok(self.build())
+    }
+
     pub fn build(self) -> Profile {
         let mut profile = Profile::new();
         profile.sample_types = self
@@ -209,8 +507,9 @@ impl<'a> ProfileBuilder<'a> {
                 unit: profile.intern(vt.unit),
             })
             .collect();
+        profile.allocation_sample_types = profile.find_allocation_sample_types();
 
-        if let Some(p) = self.period {
+        if let Some(p) = &self.period {
             profile.period = p.value;
             profile.period_type = Some(ValueType {
                 type_: profile.intern(p.r#type.r#type),
@@ -218,6 +517,60 @@ impl<'a> ProfileBuilder<'a> {
             });
         };
 
+        profile.infer_period_from = self.infer_period_from.and_then(|vt| {
+            self.sample_types
+                .iter()
+                .position(|st| st.r#type == vt.r#type && st.unit == vt.unit)
+        });
+        profile.ignore_location_addresses = self.ignore_location_addresses;
+        profile.no_mappings = self.no_mappings;
+        profile.omit_zero_samples = self.omit_zero_samples;
+        profile.validate_lines = self.validate_lines;
+        profile.process_location_for_empty_stacks = self.process_location_for_empty_stacks;
+        profile.stack_hash_labels = self.stack_hash_labels;
+        profile.compatibility_mode = self.compatibility_mode;
+        profile.soft_limits = self.soft_limits;
+        if let Some(regex) = self.drop_frames {
+            profile.drop_frames = profile.intern(regex);
+        }
+        if let Some(regex) = self.keep_frames {
+            profile.keep_frames = profile.intern(regex);
+        }
+        if let Some(r#type) = self.default_sample_type {
+            if self.sample_types.iter().any(|st| st.r#type == r#type) {
+                profile.default_sample_type = profile.intern(r#type);
+            }
+        }
+        if let Some(keys) = self.aggregate_by_labels {
+            let keys = keys.into_iter().map(|key| profile.intern(key)).collect();
+            profile.aggregate_by_labels = Some(keys);
+        }
+
+        if let Some((pid, runtime_id)) = self.process_info {
+            profile.process_info = Some((pid, runtime_id.to_owned()));
+            for comment in [
+                format!("process_id:{}", pid),
+                format!("runtime-id:{}", runtime_id),
+            ] {
+                let comment = profile.intern(&comment);
+                profile.comments.push(comment);
+            }
+        }
+        for comment in self.comments {
+            profile.add_comment(comment);
+        }
+        if let Some(clock) = self.clock {
+            let (start_time, started_at) = clock();
+            profile.clock = clock;
+            profile.start_time = start_time;
+            profile.started_at = started_at;
+        }
+        if let Some(start_time) = self.start_time {
+            profile.set_start_time(start_time);
+        }
+        // Only now, so the strings above are never truncated
+        profile.max_string_len = self.max_string_len;
+
         profile
     }
 }
@@ -228,9 +581,24 @@ impl<'a> Default for ProfileBuilder<'a> {
     }
 }
 
+/// Ids of the items in a profile. Strings use their index in the string
+/// table, where 0 is the empty string. Everything else uses its index + 1,
+/// because pprof reserves id 0 to mean "none": this goes for mappings,
+/// locations and functions, as well as the ids `Profile::add` returns for
+/// samples.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct PProfId(usize);
 
+/// The id of a mapping added with `Profile::add_mapping`, for use in
+/// `api::MappedLocation`. It's only valid in the profile which returned it,
+/// until that profile is reset.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct MappingId(PProfId);
+
+/// The id of a function added with `Profile::add_function`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct FunctionId(PProfId);
+
 impl From<&PProfId> for u64 {
     fn from(id: &PProfId) -> Self {
         id.0 as u64
@@ -264,7 +632,7 @@ trait DedupExt<T: Eq + Hash> {
         Q: Eq + Hash + ?Sized;
 }
 
-impl<T: Sized + Hash + Eq> DedupExt<T> for IndexSet<T> {
+impl<T: Sized + Hash + Eq, S: BuildHasher> DedupExt<T> for IndexSet<T, S> {
     fn dedup(&mut self, item: T) -> usize {
         let (id, _) = self.insert_full(item);
         id
@@ -301,14 +669,342 @@ impl fmt::Display for FullError {
 /// Since the ids are index + 1, we need to take 1 off the size. I also want
 /// to restrict the maximum to a 32 bit value; we're gathering way too much
 /// data if we ever exceed this in a single profile.
+#[cfg(not(test))]
 const CONTAINER_MAX: usize = (u32::MAX - 1) as usize;
 
+/// Low enough for tests to reach.
+#[cfg(test)]
+const CONTAINER_MAX: usize = 4096;
+
+/// The key of the label `Profile::add_with_timestamp` adds.
+const TIMESTAMP_KEY: &str = "end_timestamp_ns";
+
+/// The key of the label of the sample reporting the samples refused by the
+/// soft limits.
+const DROPPED_SAMPLES_KEY: &str = "dropped_samples";
+
+/// Epochs are unique across profiles, so a `LocationCache` used with another
+/// profile doesn't take its ids for those of the first.
+static LOCATIONS_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+fn next_locations_epoch() -> u64 {
+    LOCATIONS_EPOCH.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The number of label keys `Profile::intern_label_key` remembers. Samples
+/// rarely carry more distinct keys than this.
+const LABEL_KEY_CACHE_SIZE: usize = 8;
+
+/// Appended to strings truncated by `ProfileBuilder::max_string_len`.
+const TRUNCATION_MARKER: &str = "\u{2026}";
+
 impl std::error::Error for FullError {}
 
+/// A sample type index which the profile doesn't have.
+#[derive(Debug, Eq, PartialEq)]
+pub struct UnknownSampleTypeError(pub usize);
+
+impl fmt::Display for UnknownSampleTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown sample type {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownSampleTypeError {}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum AddError {
+    /// The profile has reached the maximum number of some of its items.
+    Full,
+    /// A sample refers to a location id which the profile doesn't have.
+    InvalidLocationId(PProfId),
+    /// A location refers to a mapping id which the profile doesn't have, see
+    /// `api::MappedLocation`.
+    InvalidMappingId(MappingId),
+    /// A location has a line which comes before the start of its function,
+    /// which usually means lines and functions were paired up in different
+    /// orders. Only checked if the profile was built with `validate_lines`.
+    ImplausibleLines,
+    /// The item would take the profile over one of its `SoftLimits`. The
+    /// profile is left as it was, so it can be serialized and reset.
+    SoftLimitExceeded { kind: ItemKind, limit: usize },
+    /// A sample type index which the profile doesn't have, see
+    /// `api::Sample::with_values`.
+    UnknownSampleType(usize),
+    /// A sample of a profile with both alloc-samples and alloc-space sample
+    /// types has a size but no allocations, see
+    /// `api::Sample::with_allocation`. Allocations of 0 bytes are fine.
+    PartialAllocation,
+    /// A sample has a different number of values than the profile has sample
+    /// types.
+    ValueCountMismatch { expected: usize, actual: usize },
+    /// The sample at `index` of those given to `Profile::add_all` couldn't
+    /// be added, because of `error`.
+    InBatch { index: usize, error: Box<AddError> },
+}
+
+impl From<FullError> for AddError {
+    fn from(_: FullError) -> Self {
+        AddError::Full
+    }
+}
+
+impl fmt::Display for AddError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddError::Full => write!(f, "Full"),
+            AddError::InvalidLocationId(id) => write!(f, "Invalid location id {}", id.0),
+            AddError::InvalidMappingId(id) => write!(f, "Invalid mapping id {}", id.0 .0),
+            AddError::ImplausibleLines => write!(f, "Line before the start of its function"),
+            AddError::SoftLimitExceeded { kind, limit } => {
+                write!(f, "More than {} {:?}", limit, kind)
+            }
+            AddError::UnknownSampleType(index) => write!(f, "Unknown sample type {}", index),
+            AddError::PartialAllocation => {
+                write!(f, "Allocation size set without an allocation count")
+            }
+            AddError::ValueCountMismatch { expected, actual } => {
+                write!(f, "Expected {} sample values but got {}", expected, actual)
+            }
+            AddError::InBatch { index, error } => write!(f, "Sample {}: {}", index, error),
+        }
+    }
+}
+
+impl std::error::Error for AddError {}
+
+/// Items of a profile which can have a soft limit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ItemKind {
+    Locations,
+    Functions,
+    Strings,
+    Samples,
+}
+
+/// Maximum numbers of distinct items in a profile, for callers which would
+/// rather serialize and reset a profile than let it grow until it's full. An
+/// add which would go over a limit fails with `AddError::SoftLimitExceeded`.
+/// The strings include the empty string and those of the sample types.
+/// Samples refused this way are counted, and serialized as a single sample
+/// labelled "dropped_samples", see `Profile::dropped_samples`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SoftLimits {
+    pub locations: Option<usize>,
+    pub functions: Option<usize>,
+    pub strings: Option<usize>,
+    /// Samples after aggregation: samples adding to an existing one are
+    /// always accepted.
+    pub samples: Option<usize>,
+}
+
+/// The ids in a profile which items of another one were copied to, indexed
+/// by their id - 1 in the other profile, see `Profile::partition_by_label`
+/// and `Profile::merge_encoded` with the `decode` feature.
+/// Items not copied yet have id 0.
+struct CopiedIds {
+    mappings: Vec<PProfId>,
+    functions: Vec<PProfId>,
+    locations: Vec<PProfId>,
+}
+
+/// The number of items of each kind in a profile, to undo the interning done
+/// by an add which fails.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct ItemCounts {
+    mappings: usize,
+    locations: usize,
+    functions: usize,
+    strings: usize,
+    comments: usize,
+}
+
+/// 64-bit FNV-1a, for hashes which must not change across runs or Rust
+/// versions, unlike those of the standard library's hashers.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    /// Writes the length first, so that ("ab", "c") and ("a", "bc") differ.
+    fn write_str(&mut self, value: &str) {
+        self.write_u64(value.len() as u64);
+        self.write(value.as_bytes());
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Which pprof fields a profile is serialized with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CompatibilityMode {
+    /// Every field the profile has data for.
+    Current,
+    /// For older consumers which choke on fields added to pprof later. These
+    /// are written with their default value, which protobuf leaves out:
+    ///  - `Label.num_unit`, so numeric labels have no unit;
+    ///  - `Location.is_folded`, so folded locations look like others.
+    ///
+    /// Everything else is written as usual. Ids already are what older tools
+    /// expect in both modes: they start at 1 and have no gaps, and the first
+    /// string of the string table is the empty string.
+    Legacy,
+}
+
+// #[default] on enum variants is newer than our minimum supported Rust version.
+#[allow(clippy::derivable_impls)]
+impl Default for CompatibilityMode {
+    fn default() -> Self {
+        CompatibilityMode::Current
+    }
+}
+
+/// The counts of a profile's endpoints, see `Profile::add_endpoint_count`.
+pub type EndpointCounts = HashMap<String, i64>;
+
+/// What `Profile::reset_keeping` does with the endpoint counts.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EndpointCountsOnReset {
+    /// The next profile starts without counts, and the previous one keeps
+    /// them. This is the default, and what `reset` does.
+    Reset,
+    /// The next profile starts with the counts of the previous one, which
+    /// keeps them too, so they accumulate across resets.
+    CarryOver,
+    /// The next profile starts without counts, and the counts are taken out
+    /// of the previous one to be returned on their own.
+    Return,
+}
+
+// #[default] on enum variants is newer than our minimum supported Rust version.
+#[allow(clippy::derivable_impls)]
+impl Default for EndpointCountsOnReset {
+    fn default() -> Self {
+        EndpointCountsOnReset::Reset
+    }
+}
+
+/// The options of `Profile::reset_keeping`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ResetOptions {
+    pub endpoint_counts: EndpointCountsOnReset,
+}
+
 pub struct EncodedProfile {
     pub start: SystemTime,
     pub end: SystemTime,
     pub buffer: Vec<u8>,
+    /// The number of samples after aggregation, not counting the one
+    /// standing for dropped samples.
+    pub samples_count: usize,
+    /// The number of distinct "trace endpoint" labels of the samples.
+    pub endpoints_count: usize,
+}
+
+/// Why `ProfileBuilder::try_build` failed.
+#[derive(Debug, Eq, PartialEq)]
+pub enum BuildError {
+    /// The default sample type isn't the type of any of the sample types.
+    UnknownDefaultSampleType(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::UnknownDefaultSampleType(r#type) => {
+                write!(f, "no sample type {} to default to", r#type)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Why `Profile::serialize_compressed` or `Profile::serialize_and_reset`
+/// failed.
+#[derive(Debug)]
+pub enum SerializeError {
+    /// The profile couldn't be encoded to pprof.
+    Encode(EncodeError),
+    /// The encoded profile couldn't be gzipped.
+    Compress(io::Error),
+    /// The transform of `serialize_with_transform` failed.
+    Transform(io::Error),
+}
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerializeError::Encode(err) => write!(f, "cannot encode profile: {}", err),
+            SerializeError::Compress(err) => write!(f, "cannot compress profile: {}", err),
+            SerializeError::Transform(err) => write!(f, "cannot transform profile: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SerializeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SerializeError::Encode(err) => Some(err),
+            SerializeError::Compress(err) => Some(err),
+            SerializeError::Transform(err) => Some(err),
+        }
+    }
+}
+
+impl From<EncodeError> for SerializeError {
+    fn from(err: EncodeError) -> Self {
+        SerializeError::Encode(err)
+    }
+}
+
+/// Checks `bytes` against the checksum `Profile::serialize_with_checksum`
+/// returned for them, to detect corruption, e.g. of profiles buffered on
+/// disk before being uploaded.
+pub fn verify_checksum(bytes: &[u8], checksum: u32) -> bool {
+    crc32fast::hash(bytes) == checksum
+}
+
+/// An aggregated sample of a profile, with the ids of its locations and
+/// labels resolved to what they refer to, see `Profile::samples_iter`.
+pub struct SampleView<'a> {
+    /// Leaf first, like `api::Sample::locations`.
+    pub locations: Vec<api::Location<'a>>,
+    pub labels: Vec<api::Label<'a>>,
+}
+
+/// The totals of a profile's samples, see `Profile::summary`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ProfileSummary<'a> {
+    /// One entry per sample type, in the profile's order.
+    pub sample_types: Vec<SampleTypeSummary<'a>>,
+
+    /// The number of samples after aggregation.
+    pub sample_count: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct SampleTypeSummary<'a> {
+    pub r#type: &'a str,
+    pub unit: &'a str,
+
+    /// The sum of this sample type's values over all samples.
+    pub total: i64,
 }
 
 impl Profile {
@@ -326,10 +1022,37 @@ impl Profile {
             locations: Default::default(),
             functions: Default::default(),
             strings: Default::default(),
+            comments: vec![],
+            free_comments: vec![],
+            process_info: None,
+            endpoint_counts: EndpointCounts::new(),
+            max_string_len: None,
+            truncated_strings: 0,
+            dropped_samples: 0,
+            dropped_values: vec![],
             started_at: Instant::now(),
             start_time: SystemTime::now(),
+            clock: system_clock,
             period: 0,
             period_type: None,
+            drop_frames: PProfId(0),
+            keep_frames: PProfId(0),
+            default_sample_type: PProfId(0),
+            infer_period_from: None,
+            allocation_sample_types: None,
+            ignore_location_addresses: false,
+            no_mappings: false,
+            omit_zero_samples: false,
+            validate_lines: false,
+            process_location_for_empty_stacks: false,
+            stack_hash_labels: false,
+            compatibility_mode: CompatibilityMode::default(),
+            soft_limits: SoftLimits::default(),
+            timestamp_key: None,
+            locations_epoch: next_locations_epoch(),
+            label_keys: [(0, 0, PProfId(0)); LABEL_KEY_CACHE_SIZE],
+            aggregate_by_labels: None,
+            folded: HashMap::new(),
         };
 
         profile.intern("");
@@ -337,33 +1060,115 @@ impl Profile {
     }
 
     /// Interns the `str` as a string, returning the id in the string table.
+    /// Strings longer than `max_string_len` are truncated first, see
+    /// `ProfileBuilder::max_string_len`.
     fn intern(&mut self, str: &str) -> PProfId {
+        match self.max_string_len {
+            Some(max_len) if str.len() > max_len => {
+                let mut end = max_len;
+                while !str.is_char_boundary(end) {
+                    end -= 1;
+                }
+                self.truncated_strings += 1;
+                let truncated = format!("{}{}", &str[..end], TRUNCATION_MARKER);
+                self.intern_whole(&truncated)
+            }
+            _ => self.intern_whole(str),
+        }
+    }
+
+    /// Interns the `str` as it is, whatever its length.
+    fn intern_whole(&mut self, str: &str) -> PProfId {
         // strings are special because the empty string is actually allowed at
         // index 0; most other 0's are reserved and cannot exist
         let id = self.strings.dedup_ref(str);
         PProfId(id)
     }
 
+    /// Interns bytes which may not be valid UTF-8, such as symbol names from
+    /// native stack walkers or latin-1 paths, returning the id in the string
+    /// table. The pprof string table holds protobuf strings, which must be
+    /// UTF-8 for decoders to accept the profile, so invalid sequences are
+    /// replaced with U+FFFD here rather than kept: bytes which only differ
+    /// in their invalid sequences get the same id. Valid UTF-8 is interned
+    /// unchanged, with the same id as the `&str`. `add_bytes_mapping` and
+    /// `add_bytes_location` intern their strings the same way.
+    pub fn intern_bytes(&mut self, bytes: &[u8]) -> PProfId {
+        self.intern(&String::from_utf8_lossy(bytes))
+    }
+
     pub fn builder<'a>() -> ProfileBuilder<'a> {
         ProfileBuilder::new()
     }
 
-    fn add_mapping(&mut self, mapping: &api::Mapping) -> Result<PProfId, FullError> {
+    /// Adds a free-form comment to the profile, see
+    /// `ProfileBuilder::comments`.
+    pub fn add_comment(&mut self, comment: &str) {
+        let comment = self.intern(comment);
+        self.comments.push(comment);
+        self.free_comments.push(comment);
+    }
+
+    /// Adds a mapping on its own, such as a shared object as it is loaded,
+    /// before any of its locations are known. Adding the same mapping again
+    /// returns the same id. Locations can then refer to it through
+    /// `api::MappedLocation` instead of repeating it.
+    pub fn add_mapping(&mut self, mapping: &api::Mapping) -> Result<MappingId, FullError> {
+        self.intern_mapping(mapping).map(MappingId)
+    }
+
+    /// Same as `add_mapping`, for paths which may not be valid UTF-8, whose
+    /// invalid sequences are replaced as `intern_bytes` does.
+    pub fn add_bytes_mapping(
+        &mut self,
+        mapping: &api::BytesMapping,
+    ) -> Result<MappingId, FullError> {
+        let filename = String::from_utf8_lossy(mapping.filename);
+        let build_id = String::from_utf8_lossy(mapping.build_id);
+        let debug_file = String::from_utf8_lossy(mapping.debug_file);
+        self.add_mapping(&api::Mapping {
+            memory_start: mapping.memory_start,
+            memory_limit: mapping.memory_limit,
+            file_offset: mapping.file_offset,
+            filename: &filename,
+            build_id: &build_id,
+            debug_file: &debug_file,
+        })
+    }
+
+    /// Adds a function on its own, returning the same id for the same
+    /// function. Unlike `add`, this isn't subject to the soft limits.
+    pub fn add_function(&mut self, function: &api::Function) -> Result<FunctionId, FullError> {
+        self.intern_function(function).map(FunctionId)
+    }
+
+    fn intern_mapping(&mut self, mapping: &api::Mapping) -> Result<PProfId, FullError> {
+        if self.no_mappings {
+            return Ok(PProfId(0));
+        }
+
         // todo: do full checks as part of intern/dedup
-        if self.strings.len() >= CONTAINER_MAX as usize || self.mappings.len() >= CONTAINER_MAX {
+        if self.strings.len() >= CONTAINER_MAX || self.mappings.len() >= CONTAINER_MAX {
             return Err(FullError);
         }
 
         let filename = self.intern(mapping.filename);
         let build_id = self.intern(mapping.build_id);
+        let debug_file = self.intern(mapping.debug_file);
 
-        let index = self.mappings.dedup(Mapping {
+        let (index, inserted) = self.mappings.insert_full(Mapping {
             memory_start: mapping.memory_start,
             memory_limit: mapping.memory_limit,
             file_offset: mapping.file_offset,
             filename,
             build_id,
+            debug_file,
         });
+        if inserted && debug_file.0 != 0 {
+            let comment = format!("debug_file:{}:{}", index + 1, mapping.debug_file);
+            let comment = self.intern(&comment);
+            self.comments.push(comment);
+        }
 
         /* PProf reserves mapping 0 for "no mapping", and it won't let you put
          * one in there with all "zero" data either, so we shift the ids.
@@ -371,7 +1176,11 @@ impl Profile {
         Ok(PProfId(index + 1))
     }
 
-    fn add_function(&mut self, function: &api::Function) -> PProfId {
+    fn intern_function(&mut self, function: &api::Function) -> Result<PProfId, FullError> {
+        if self.strings.len() >= CONTAINER_MAX || self.functions.len() >= CONTAINER_MAX {
+            return Err(FullError);
+        }
+
         let name = self.intern(function.name);
         let system_name = self.intern(function.system_name);
         let filename = self.intern(function.filename);
@@ -380,91 +1189,611 @@ impl Profile {
             name,
             system_name,
             filename,
-            start_line: if function.start_line < 0 {
-                u63::new(0)
-            } else {
-                u63::new(function.start_line as u64)
-            },
+            start_line: function.start_line,
         });
 
         /* PProf reserves function 0 for "no function", and it won't let you put
          * one in there with all "zero" data either, so we shift the ids.
          */
-        PProfId(index + 1)
+        Ok(PProfId(index + 1))
     }
 
-    pub fn add(&mut self, sample: api::Sample) -> Result<PProfId, FullError> {
-        if sample.values.len() != self.sample_types.len() {
-            return Ok(PProfId(0));
-        }
+    /// Interns a location standing for something other than code, such as
+    /// "[truncated]", with just a function name.
+    fn intern_synthetic_location(&mut self, name: &str) -> Result<PProfId, FullError> {
+        let location = api::Location::with_inlined(vec![api::Line {
+            function: api::Function {
+                name,
+                ..Default::default()
+            },
+            line: 0,
+        }]);
+        self.intern_location(&location)
+    }
 
-        let values = sample.values.clone();
-        let labels = sample
-            .labels
-            .iter()
-            .map(|label| {
-                let key = self.intern(label.key);
-                let str = label.str.map(|s| self.intern(s)).unwrap_or(PProfId(0));
-                let num_unit = label.num_unit.map(|s| self.intern(s)).unwrap_or(PProfId(0));
+    fn intern_location(&mut self, location: &api::Location) -> Result<PProfId, FullError> {
+        let mapping_id = self.intern_mapping(&location.mapping)?;
+        self.intern_location_in(
+            mapping_id,
+            location.address,
+            &location.lines,
+            location.is_folded,
+        )
+    }
 
-                Label {
-                    key,
-                    str,
-                    num: label.num,
-                    num_unit,
-                }
+    fn intern_location_in(
+        &mut self,
+        mapping_id: PProfId,
+        address: u64,
+        lines: &[api::Line],
+        is_folded: bool,
+    ) -> Result<PProfId, FullError> {
+        if self.locations.len() >= CONTAINER_MAX {
+            return Err(FullError);
+        }
+        let lines = lines
+            .iter()
+            .map(|line| {
+                let function_id = self.intern_function(&line.function)?;
+                Ok(Line {
+                    function_id,
+                    line: line.line,
+                })
             })
-            .collect();
+            .collect::<Result<Vec<_>, FullError>>()?;
 
-        let mut locations: Vec<PProfId> = Vec::with_capacity(sample.locations.len());
-        for location in sample.locations.iter() {
-            let mapping_id = self.add_mapping(&location.mapping)?;
-            let lines: Vec<Line> = location
-                .lines
-                .iter()
-                .map(|line| {
-                    let function_id = self.add_function(&line.function);
-                    Line {
-                        function_id,
-                        line: line.line,
-                    }
-                })
-                .collect();
+        let address = if self.ignore_location_addresses && !lines.is_empty() {
+            0
+        } else {
+            address.try_into().unwrap_or(0)
+        };
 
-            let index = self.locations.dedup(Location {
-                mapping_id,
-                address: location.address.try_into().unwrap_or(0),
-                lines,
-                is_folded: location.is_folded,
-            });
+        let index = self.locations.dedup(Location {
+            mapping_id,
+            address,
+            lines,
+            is_folded,
+        });
 
-            /* PProf reserves location 0. Based on this pattern in other
-             * situations, this would be "no location", but I'm not sure how
-             * this is logical?
-             */
-            locations.push(PProfId(index + 1))
-        }
+        /* PProf reserves location 0. Based on this pattern in other
+         * situations, this would be "no location", but I'm not sure how
+         * this is logical?
+         */
+        Ok(PProfId(index + 1))
+    }
+
+    /// Hashes what the locations resolve to, rather than their ids, which
+    /// depend on the order locations were added in.
+    fn stack_hash(&self, locations: &[PProfId]) -> u64 {
+        let mut hasher = StableHasher::new();
+        for id in locations {
+            let location = self
+                .locations
+                .get_index(id.0 - 1)
+                .expect("location to exist");
+            hasher.write_u64(location.address as u64);
+            hasher.write_u64(location.lines.len() as u64);
+            for line in location.lines.iter() {
+                let function = self
+                    .functions
+                    .get_index(line.function_id.0 - 1)
+                    .expect("function to exist");
+                for string in [function.name, function.system_name, function.filename] {
+                    hasher.write_str(self.strings.get_index(string.0).expect("string to exist"));
+                }
+                hasher.write_u64(line.line as u64);
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Checks that no line of the location comes before the start of its
+    /// function, when both are known. The order of the lines themselves can't
+    /// be checked, see `api::Location::lines` for the expected order.
+    fn check_lines(&self, lines: &[api::Line]) -> Result<(), AddError> {
+        if !self.validate_lines {
+            return Ok(());
+        }
+        let implausible = lines.iter().any(|line| {
+            line.line > 0 && line.function.start_line > 0 && line.line < line.function.start_line
+        });
+        if implausible {
+            Err(AddError::ImplausibleLines)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn item_counts(&self) -> ItemCounts {
+        ItemCounts {
+            mappings: self.mappings.len(),
+            locations: self.locations.len(),
+            functions: self.functions.len(),
+            strings: self.strings.len(),
+            comments: self.comments.len(),
+        }
+    }
+
+    /// Checks the soft limits, and when one is exceeded, drops the items
+    /// interned since `before`. Items are only ever appended and only refer to
+    /// earlier ones, so this restores the profile as it was.
+    fn check_soft_limits(&mut self, before: ItemCounts) -> Result<(), AddError> {
+        let counts = [
+            (
+                ItemKind::Locations,
+                self.soft_limits.locations,
+                self.locations.len(),
+            ),
+            (
+                ItemKind::Functions,
+                self.soft_limits.functions,
+                self.functions.len(),
+            ),
+            (
+                ItemKind::Strings,
+                self.soft_limits.strings,
+                self.strings.len(),
+            ),
+        ];
+        for (kind, limit, count) in counts {
+            match limit {
+                Some(limit) if count > limit => {
+                    self.drop_items_since(before);
+                    return Err(AddError::SoftLimitExceeded { kind, limit });
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the items interned since `before` when the profile got full, so
+    /// it's left as it was.
+    fn undo_if_full<T>(
+        &mut self,
+        before: ItemCounts,
+        result: Result<T, FullError>,
+    ) -> Result<T, AddError> {
+        if result.is_err() {
+            self.drop_items_since(before);
+        }
+        Ok(result?)
+    }
+
+    fn drop_items_since(&mut self, before: ItemCounts) {
+        self.mappings.truncate(before.mappings);
+        self.locations.truncate(before.locations);
+        self.functions.truncate(before.functions);
+        self.strings.truncate(before.strings);
+        self.comments.truncate(before.comments);
+    }
 
-        let s = Sample { locations, labels };
+    /// Counts a sample refused for going over a soft limit.
+    fn record_dropped(&mut self, values: &[i64]) {
+        self.dropped_samples += 1;
+        self.dropped_values.resize(values.len(), 0);
+        for (total, value) in self.dropped_values.iter_mut().zip(values) {
+            *total = total.saturating_add(*value);
+        }
+    }
 
-        let id = match self.samples.get_index_of(&s) {
+    /// Adds `value` to the count of `endpoint`, such as the number of
+    /// requests it served, for correlating profiles with endpoints. The
+    /// count saturates at the bounds of i64.
+    pub fn add_endpoint_count(&mut self, endpoint: &str, value: i64) {
+        match self.endpoint_counts.get_mut(endpoint) {
+            Some(count) => *count = count.saturating_add(value),
             None => {
-                self.samples.insert(s, values);
-                PProfId(self.samples.len())
+                self.endpoint_counts.insert(endpoint.to_owned(), value);
+            }
+        }
+    }
+
+    /// The counts given to `add_endpoint_count` since the profile was
+    /// created or reset, see `reset_keeping` to keep them across resets.
+    pub fn endpoint_counts(&self) -> &EndpointCounts {
+        &self.endpoint_counts
+    }
+
+    /// The number of strings truncated for being longer than the limit of
+    /// `ProfileBuilder::max_string_len` since the profile was created or
+    /// reset. A string is counted each time it's truncated.
+    pub fn truncated_strings(&self) -> u64 {
+        self.truncated_strings
+    }
+
+    /// The number of samples `add` refused for going over a soft limit since
+    /// the profile was created or reset.
+    pub fn dropped_samples(&self) -> u64 {
+        self.dropped_samples
+    }
+
+    /// The sum of the values of the samples `add` refused for going over a
+    /// soft limit, one per sample type, or empty if none were.
+    pub fn dropped_values(&self) -> &[i64] {
+        &self.dropped_values
+    }
+
+    /// Multiplies the values of the sample type at `sample_type_index` by
+    /// `factor`, in every sample including the dropped ones, e.g. to
+    /// extrapolate sampled values to estimated totals. Results are rounded
+    /// to the nearest integer, and saturate at the bounds of i64.
+    pub fn scale_values(
+        &mut self,
+        sample_type_index: usize,
+        factor: f64,
+    ) -> Result<(), UnknownSampleTypeError> {
+        if sample_type_index >= self.sample_types.len() {
+            return Err(UnknownSampleTypeError(sample_type_index));
+        }
+        // Casts from f64 saturate, which is the behavior we want
+        let scale = |value: &mut i64| *value = (*value as f64 * factor).round() as i64;
+        for values in self.samples.values_mut() {
+            scale(&mut values[sample_type_index]);
+        }
+        if let Some(value) = self.dropped_values.get_mut(sample_type_index) {
+            scale(value);
+        }
+        Ok(())
+    }
+
+    /// Sets the period and its type, replacing any set by the builder or an
+    /// earlier call, and taking precedence over `infer_period_from`. Samples
+    /// already added are left as they are, and the period is kept on reset.
+    pub fn set_period(&mut self, period: i64, period_type: api::ValueType) {
+        self.period = period;
+        self.period_type = Some(ValueType {
+            type_: self.intern_whole(period_type.r#type),
+            unit: self.intern_whole(period_type.unit),
+        });
+    }
+
+    /// Adds a location on its own, returning its id. Samples can then refer
+    /// to it through `api::Sample::location_ids` instead of passing it again.
+    /// The ids are those of the profile's location table, so they no longer
+    /// hold once `resymbolize`, `coalesce_functions`, `mark_folded`,
+    /// `symbolize_pending` or `compact` rewrote it; add the locations again
+    /// after calling any of those.
+    pub fn add_location(&mut self, location: api::Location) -> Result<PProfId, AddError> {
+        self.check_lines(&location.lines)?;
+        let before = self.item_counts();
+        let id = self.intern_location(&location);
+        let id = self.undo_if_full(before, id)?;
+        self.check_soft_limits(before)?;
+        Ok(id)
+    }
+
+    /// Same as `add_location`, for names and paths which may not be valid
+    /// UTF-8, whose invalid sequences are replaced as `intern_bytes` does.
+    /// Samples refer to the location through its id.
+    pub fn add_bytes_location(
+        &mut self,
+        location: &api::BytesLocation,
+    ) -> Result<PProfId, AddError> {
+        let mapping = location.mapping;
+        let mapping_strings =
+            [mapping.filename, mapping.build_id, mapping.debug_file].map(String::from_utf8_lossy);
+        let function_strings: Vec<_> = location
+            .lines
+            .iter()
+            .map(|line| {
+                let function = line.function;
+                [function.name, function.system_name, function.filename]
+                    .map(String::from_utf8_lossy)
+            })
+            .collect();
+        let [filename, build_id, debug_file] = &mapping_strings;
+        let lines = location
+            .lines
+            .iter()
+            .zip(function_strings.iter())
+            .map(|(line, [name, system_name, filename])| api::Line {
+                function: api::Function {
+                    name,
+                    system_name,
+                    filename,
+                    start_line: line.function.start_line,
+                },
+                line: line.line,
+            })
+            .collect();
+        self.add_location(api::Location {
+            mapping: api::Mapping {
+                memory_start: mapping.memory_start,
+                memory_limit: mapping.memory_limit,
+                file_offset: mapping.file_offset,
+                filename,
+                build_id,
+                debug_file,
+            },
+            address: location.address,
+            lines,
+            is_folded: location.is_folded,
+        })
+    }
+
+    /// Identifies the numbering of the locations of the profile: two calls
+    /// returning the same epoch mean the location ids of the first are still
+    /// valid. It changes when the profile is reset, or its locations merged.
+    pub(crate) fn locations_epoch(&self) -> u64 {
+        self.locations_epoch
+    }
+
+    /// Adds a location in a mapping added with `add_mapping`, returning its
+    /// id like `add_location` does.
+    pub fn add_mapped_location(
+        &mut self,
+        location: api::MappedLocation,
+    ) -> Result<PProfId, AddError> {
+        let MappingId(mapping_id) = location.mapping_id;
+        if mapping_id.0 > self.mappings.len() {
+            return Err(AddError::InvalidMappingId(location.mapping_id));
+        }
+        self.check_lines(&location.lines)?;
+        let before = self.item_counts();
+        let id = self.intern_location_in(
+            mapping_id,
+            location.address,
+            &location.lines,
+            location.is_folded,
+        );
+        let id = self.undo_if_full(before, id)?;
+        self.check_soft_limits(before)?;
+        Ok(id)
+    }
+
+    /// Interns the locations of the sample, replacing the contents of
+    /// `locations` with the ids of its stack. When the profile is full, the
+    /// locations interned so far are left behind, for the caller to drop.
+    fn intern_stack(
+        &mut self,
+        sample: &api::Sample,
+        locations: &mut Vec<PProfId>,
+    ) -> Result<(), FullError> {
+        locations.clear();
+        locations.reserve(sample.locations.len() + sample.location_ids.len());
+        for location in sample.locations.iter() {
+            locations.push(self.intern_location(location)?);
+        }
+        locations.extend_from_slice(&sample.location_ids);
+        if sample.truncated {
+            locations.push(self.intern_synthetic_location("[truncated]")?);
+        }
+        if locations.is_empty() && self.process_location_for_empty_stacks {
+            locations.push(self.intern_synthetic_location("[process]")?);
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, sample: api::Sample) -> Result<PProfId, AddError> {
+        self.add_labeled(&sample, None, &mut Vec::new())
+    }
+
+    /// Adds the samples in order, like calling `add` for each but sharing the
+    /// buffer their stacks are built in, which is only allocated again when a
+    /// sample isn't aggregated with an earlier one. Stops at the first sample
+    /// which can't be added, returning `AddError::InBatch` with its index;
+    /// the samples before it are kept.
+    pub fn add_all(&mut self, samples: &[api::Sample]) -> Result<(), AddError> {
+        let mut locations = Vec::new();
+        for (index, sample) in samples.iter().enumerate() {
+            if let Err(error) = self.add_labeled(sample, None, &mut locations) {
+                return Err(AddError::InBatch {
+                    index,
+                    error: Box::new(error),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the sample with a numeric "end_timestamp_ns" label holding
+    /// `timestamp_ns`, for tools showing samples on a timeline. The label is
+    /// part of the sample, so samples are only aggregated with those of the
+    /// same timestamp.
+    pub fn add_with_timestamp(
+        &mut self,
+        sample: api::Sample,
+        timestamp_ns: i64,
+    ) -> Result<PProfId, AddError> {
+        self.add_labeled(&sample, Some(timestamp_ns), &mut Vec::new())
+    }
+
+    /// The id of the "end_timestamp_ns" string, interned on first use.
+    fn timestamp_key(&mut self) -> PProfId {
+        match self.timestamp_key {
+            // The string table may have been truncated or remapped since
+            Some(id) if self.strings.get_index(id.0).map(String::as_str) == Some(TIMESTAMP_KEY) => {
+                id
+            }
+            _ => {
+                let id = self.intern(TIMESTAMP_KEY);
+                self.timestamp_key = Some(id);
+                id
+            }
+        }
+    }
+
+    /// Interns a label key, skipping the hash lookup when the same `&str`
+    /// was interned recently: keys are usually literals or long-lived
+    /// strings, given again with every sample. A cached id is only used if
+    /// the string table still holds the key at that id, as the table may
+    /// have been reset or compacted since, and the memory of the `&str` may
+    /// hold another key now.
+    fn intern_label_key(&mut self, key: &str) -> PProfId {
+        let address = key.as_ptr() as usize;
+        let slot = (address ^ (address >> 7) ^ key.len()) % LABEL_KEY_CACHE_SIZE;
+        let (cached_address, cached_len, id) = self.label_keys[slot];
+        if cached_address == address
+            && cached_len == key.len()
+            && self.strings.get_index(id.0).map(String::as_str) == Some(key)
+        {
+            return id;
+        }
+        let id = self.intern(key);
+        self.label_keys[slot] = (address, key.len(), id);
+        id
+    }
+
+    /// Adds the sample, building its stack in `scratch`, which is left with
+    /// an allocation to reuse when the sample is aggregated with another.
+    fn add_labeled(
+        &mut self,
+        sample: &api::Sample,
+        timestamp_ns: Option<i64>,
+        scratch: &mut Vec<PProfId>,
+    ) -> Result<PProfId, AddError> {
+        if sample.values.len() != self.sample_types.len() {
+            return Err(AddError::ValueCountMismatch {
+                expected: self.sample_types.len(),
+                actual: sample.values.len(),
+            });
+        }
+        if let Some((count, space)) = self.allocation_sample_types {
+            if sample.values[count] == 0 && sample.values[space] != 0 {
+                return Err(AddError::PartialAllocation);
             }
+        }
+        if let Some(id) = sample
+            .location_ids
+            .iter()
+            .find(|id| id.0 == 0 || id.0 > self.locations.len())
+        {
+            return Err(AddError::InvalidLocationId(*id));
+        }
+        for location in sample.locations.iter() {
+            self.check_lines(&location.lines)?;
+        }
+
+        let before = self.item_counts();
+        let values = &sample.values;
+        let mut labels: Vec<Label> = sample
+            .labels
+            .iter()
+            .map(|label| {
+                let key = self.intern_label_key(label.key);
+                let str = label.str.map(|s| self.intern(s)).unwrap_or(PProfId(0));
+                let num_unit = label.num_unit.map(|s| self.intern(s)).unwrap_or(PProfId(0));
+
+                Label {
+                    key,
+                    str,
+                    num: label.num,
+                    num_unit,
+                }
+            })
+            .collect();
+        if let Some(timestamp_ns) = timestamp_ns {
+            labels.push(Label {
+                key: self.timestamp_key(),
+                str: PProfId(0),
+                num: timestamp_ns,
+                num_unit: PProfId(0),
+            });
+        }
+
+        let interned = self.intern_stack(sample, scratch);
+        self.undo_if_full(before, interned)?;
+        if self.stack_hash_labels {
+            labels.push(Label {
+                key: self.intern("stack_hash"),
+                str: PProfId(0),
+                num: self.stack_hash(scratch) as i64,
+                num_unit: PProfId(0),
+            });
+        }
+        if let Err(err) = self.check_soft_limits(before) {
+            self.record_dropped(values);
+            return Err(err);
+        }
+
+        let (s, fold_key) = self.fold(Sample {
+            locations: std::mem::take(scratch),
+            labels,
+        });
+
+        let index = match self.samples.get_index_of(&s) {
+            None => match self.soft_limits.samples {
+                Some(limit) if self.samples.len() >= limit => {
+                    self.drop_items_since(before);
+                    self.record_dropped(values);
+                    return Err(AddError::SoftLimitExceeded {
+                        kind: ItemKind::Samples,
+                        limit,
+                    });
+                }
+                _ => {
+                    let index = self.samples.insert_full(s, values.clone()).0;
+                    if let Some(key) = fold_key {
+                        self.folded.insert(key, index);
+                    }
+                    index
+                }
+            },
             Some(index) => {
                 let (_, existing_values) =
                     self.samples.get_index_mut(index).expect("index to exist");
                 for (a, b) in existing_values.iter_mut().zip(values) {
-                    a.add_assign(b)
+                    a.add_assign(*b)
                 }
-                PProfId(index + 1)
+                *scratch = s.locations;
+                index
             }
         };
-        Ok(id)
+        Ok(PProfId(index + 1))
+    }
+
+    /// Returns the indexes of the alloc-samples and alloc-space sample types,
+    /// if the profile has both. Heap profilers report the number and size of
+    /// allocations together, so samples can't have a size without a count,
+    /// though a count of allocations of 0 bytes is fine.
+    pub fn allocation_sample_types(&self) -> Option<(usize, usize)> {
+        self.allocation_sample_types
+    }
+
+    /// Looks up `allocation_sample_types` among the sample types.
+    fn find_allocation_sample_types(&self) -> Option<(usize, usize)> {
+        let index_of = |names: [&str; 2]| {
+            self.sample_types.iter().position(|sample_type| {
+                let type_ = self.strings[sample_type.type_.0].as_str();
+                names.contains(&type_)
+            })
+        };
+        let count = index_of(["alloc-samples", "alloc_samples"])?;
+        let space = index_of(["alloc-space", "alloc_space"])?;
+        Some((count, space))
+    }
+
+    /// Returns the period to serialize, which is either the one that was set
+    /// or the one inferred from samples if the profile was built to do so.
+    fn effective_period(&self) -> (i64, Option<ValueType>) {
+        if self.period_type.is_some() {
+            return (self.period, self.period_type);
+        }
+
+        let index = match self.infer_period_from {
+            Some(index) => index,
+            None => return (self.period, None),
+        };
+
+        let count_unit = self.strings.get_index_of("count");
+        let count_index = self
+            .sample_types
+            .iter()
+            .position(|st| Some(st.unit.0) == count_unit);
+
+        let mut total: i64 = 0;
+        let mut count: i64 = 0;
+        for values in self.samples.values() {
+            total = total.saturating_add(values[index]);
+            count = count.saturating_add(count_index.map_or(1, |i| values[i]));
+        }
+
+        let period = if count > 0 { total / count } else { 0 };
+        (period, Some(self.sample_types[index]))
     }
 
-    fn extract_api_sample_types(&self) -> Option<Vec<api::ValueType>> {
+    fn extract_api_sample_types(&self) -> Option<Vec<api::ValueType<'_>>> {
         let mut sample_types: Vec<api::ValueType> = Vec::with_capacity(self.sample_types.len());
         for sample_type in self.sample_types.iter() {
             sample_types.push(api::ValueType {
@@ -478,14 +1807,53 @@ impl Profile {
     /// Resets all data except the sample types and period. Returns the
     /// previous Profile on success.
     pub fn reset(&mut self) -> Option<Profile> {
+        self.reset_keeping(ResetOptions::default())
+            .map(|(profile, _)| profile)
+    }
+
+    /// Resets like `reset`, with the endpoint counts handled as `options`
+    /// says. The sample types and period, including one given to
+    /// `set_period`, are kept whatever the options, as they describe the
+    /// values rather than hold any. Returns the previous Profile on success,
+    /// along with its endpoint counts for `EndpointCountsOnReset::Return`,
+    /// or else empty counts.
+    pub fn reset_keeping(&mut self, options: ResetOptions) -> Option<(Profile, EndpointCounts)> {
+        let mut profile = self.empty_like()?;
+        if options.endpoint_counts == EndpointCountsOnReset::CarryOver {
+            profile.endpoint_counts = self.endpoint_counts.clone();
+        }
+        std::mem::swap(&mut *self, &mut profile);
+        let counts = match options.endpoint_counts {
+            EndpointCountsOnReset::Return => std::mem::take(&mut profile.endpoint_counts),
+            EndpointCountsOnReset::Reset | EndpointCountsOnReset::CarryOver => {
+                EndpointCounts::new()
+            }
+        };
+        Some((profile, counts))
+    }
+
+    /// Builds a profile without samples, with the same sample types, period
+    /// and options as this one, starting now.
+    fn empty_like(&self) -> Option<Profile> {
         /* We have to map over the types because the order of the strings is
          * not generally guaranteed, so we can't just copy the underlying
          * structures.
          */
         let sample_types: Vec<api::ValueType> = self.extract_api_sample_types()?;
 
-        let mut profile = ProfileBuilder::new()
+        let infer_period_from = self.infer_period_from.map(|index| sample_types[index]);
+
+        let mut builder = ProfileBuilder::new()
             .sample_types(sample_types)
+            .clock(self.clock)
+            .infer_period_from(infer_period_from)
+            .ignore_location_addresses(self.ignore_location_addresses)
+            .omit_zero_samples(self.omit_zero_samples)
+            .validate_lines(self.validate_lines)
+            .process_location_for_empty_stacks(self.process_location_for_empty_stacks)
+            .stack_hash_labels(self.stack_hash_labels)
+            .compatibility_mode(self.compatibility_mode)
+            .soft_limits(self.soft_limits)
             .period(match &self.period_type {
                 Some(t) => Some(api::Period {
                     r#type: api::ValueType {
@@ -495,135 +1863,4397 @@ impl Profile {
                     value: self.period,
                 }),
                 None => None,
-            })
-            .build();
+            });
+        if self.no_mappings {
+            builder = builder.no_mappings();
+        }
+        if let Some((pid, runtime_id)) = &self.process_info {
+            builder = builder.process_info(*pid, runtime_id);
+        }
+        if self.drop_frames.0 != 0 {
+            builder = builder.drop_frames(self.strings.get_index(self.drop_frames.0)?);
+        }
+        if self.keep_frames.0 != 0 {
+            builder = builder.keep_frames(self.strings.get_index(self.keep_frames.0)?);
+        }
+        if self.default_sample_type.0 != 0 {
+            builder =
+                builder.default_sample_type(self.strings.get_index(self.default_sample_type.0)?);
+        }
+        if let Some(keys) = &self.aggregate_by_labels {
+            let keys = keys
+                .iter()
+                .map(|key| self.strings.get_index(key.0).map(String::as_str))
+                .collect::<Option<Vec<_>>>()?;
+            builder = builder.aggregate_by_labels(keys);
+        }
+        if let Some(max_len) = self.max_string_len {
+            builder = builder.max_string_len(max_len);
+        }
+        let mut comments = Vec::with_capacity(self.free_comments.len());
+        for comment in self.free_comments.iter() {
+            comments.push(self.strings.get_index(comment.0)?.as_str());
+        }
+        Some(builder.comments(comments).build())
+    }
 
-        std::mem::swap(&mut *self, &mut profile);
-        Some(profile)
+    /// Sets the start time of the profile, and the instant the duration is
+    /// measured from to match it, offset from the current instant by as much
+    /// as `start_time` is from the current time.
+    fn set_start_time(&mut self, start_time: SystemTime) {
+        let (now, instant) = (self.clock)();
+        let elapsed = now.duration_since(start_time).map_err(|err| err.duration());
+        self.set_window(start_time, instant, elapsed);
+    }
+
+    /// Sets the start time of the profile, and the instant the duration is
+    /// measured from: `elapsed` before `instant`, or, for a start in the
+    /// future, the `Err` duration after it.
+    fn set_window(
+        &mut self,
+        start_time: SystemTime,
+        instant: Instant,
+        elapsed: Result<Duration, Duration>,
+    ) {
+        let started_at = match elapsed {
+            Ok(elapsed) => instant.checked_sub(elapsed),
+            Err(ahead) => instant.checked_add(ahead),
+        };
+        self.start_time = start_time;
+        self.started_at = started_at.unwrap_or(instant);
+    }
+
+    /// Returns the duration and end time of the profile if it ended `now`.
+    /// The duration is measured on the monotonic clock and the end derived
+    /// from it, as the wall clock can be stepped, e.g. by NTP, which would
+    /// otherwise make profiles end before they start.
+    fn window(&self, now: Instant) -> (Duration, SystemTime) {
+        let duration = now.saturating_duration_since(self.started_at);
+        let end = self
+            .start_time
+            .checked_add(duration)
+            .unwrap_or(self.start_time);
+        (duration, end)
     }
 
     /// Serialize the aggregated profile.
     pub fn serialize(&self) -> Result<EncodedProfile, EncodeError> {
-        let profile: pprof::Profile = self.into();
         let mut buffer: Vec<u8> = Vec::new();
-        profile.encode(&mut buffer)?;
+        let (start, end) = self
+            .serialize_into(&mut buffer)
+            .expect("writing to a Vec to succeed");
         Ok(EncodedProfile {
-            start: self.start_time,
-            end: SystemTime::now(),
+            start,
+            end,
             buffer,
+            samples_count: self.sample_count(),
+            endpoints_count: self.endpoints_count(),
         })
     }
 
-    pub fn get_string(&self, id: PProfId) -> Option<&String> {
-        self.strings.get_index(id.0)
+    /// Serializes the profile like `serialize`, then gzips the buffer with
+    /// the given compression level, from 0 for none to 9 for the best; higher
+    /// levels are taken as 9. The intake accepts gzipped pprof as is.
+    pub fn serialize_compressed(&self, level: u32) -> Result<EncodedProfile, SerializeError> {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let encoded = self.serialize()?;
+        let mut encoder = GzEncoder::new(
+            Vec::with_capacity(encoded.buffer.len() / 4),
+            flate2::Compression::new(level.min(9)),
+        );
+        encoder
+            .write_all(&encoded.buffer)
+            .map_err(SerializeError::Compress)?;
+        let buffer = encoder.finish().map_err(SerializeError::Compress)?;
+        Ok(EncodedProfile { buffer, ..encoded })
     }
-}
 
-impl Default for Profile {
-    fn default() -> Self {
-        Self::new()
+    /// Serializes the profile like `serialize`, then passes the buffer through
+    /// `transform`, e.g. to encrypt or sign it, and returns the transformed
+    /// bytes in its place. The transform takes ownership of the encoded
+    /// bytes, so it can wipe them once done.
+    pub fn serialize_with_transform<F>(
+        &self,
+        transform: F,
+    ) -> Result<EncodedProfile, SerializeError>
+    where
+        F: FnOnce(Vec<u8>) -> io::Result<Vec<u8>>,
+    {
+        let mut encoded = self.serialize()?;
+        let buffer = std::mem::take(&mut encoded.buffer);
+        encoded.buffer = transform(buffer).map_err(SerializeError::Transform)?;
+        Ok(encoded)
     }
-}
 
-impl From<&Profile> for pprof::Profile {
-    fn from(profile: &Profile) -> Self {
-        pprof::Profile {
-            sample_type: profile.sample_types.iter().map(Into::into).collect(),
-            sample: profile
-                .samples
-                .iter()
-                .map(|(sample, values)| pprof::Sample {
-                    location_id: sample.locations.iter().map(Into::into).collect(),
-                    value: values.to_vec(),
-                    label: sample.labels.iter().map(Into::into).collect(),
-                })
-                .collect(),
-            mapping: profile
+    /// Serializes the profile into `writer` a few items at a time, rather
+    /// than into a buffer holding the whole encoded profile. The bytes are
+    /// the same as those of `serialize`. Returns the start and end times of
+    /// the profile. Only writing can fail, as encoding into a Vec can't.
+    pub fn serialize_into<W: io::Write>(
+        &self,
+        writer: &mut W,
+    ) -> io::Result<(SystemTime, SystemTime)> {
+        let (_, now) = (self.clock)();
+        self.encode_into(now, writer)?;
+        Ok((self.start_time, self.window(now).1))
+    }
+
+    /// Serializes the profile like `serialize`, along with the CRC-32 of the
+    /// buffer, to be checked with `verify_checksum`.
+    pub fn serialize_with_checksum(&self) -> Result<(EncodedProfile, u32), EncodeError> {
+        let encoded = self.serialize()?;
+        let checksum = crc32fast::hash(&encoded.buffer);
+        Ok((encoded, checksum))
+    }
+
+    /// The length in bytes that `serialize` would encode the profile to, if
+    /// called now, e.g. to split or drop a profile too large to upload before
+    /// paying for encoding and compressing it. This builds the intermediate
+    /// `pprof::Profile`, copying every table, but doesn't allocate the
+    /// encoded buffer. The duration grows with time, so a later `serialize`
+    /// can take a byte or two more.
+    pub fn encoded_len(&self) -> usize {
+        let profile: pprof::Profile = self.into();
+        profile.encoded_len()
+    }
+
+    /// Serializes the profile into `buf`, replacing its contents but reusing
+    /// its allocation, then resets the profile like `reset` does, with
+    /// `start` as the start time of the next profile. Returns the start and
+    /// end times of the serialized profile. If serializing fails, the profile
+    /// isn't reset.
+    pub fn flush(
+        &mut self,
+        start: SystemTime,
+        buf: &mut Vec<u8>,
+    ) -> Result<(SystemTime, SystemTime), EncodeError> {
+        buf.clear();
+        let flushed = self
+            .serialize_into(buf)
+            .expect("writing to a Vec to succeed");
+
+        // Resetting only fails if the sample type strings are missing
+        self.reset().expect("sample types to be interned");
+        self.set_start_time(start);
+        Ok(flushed)
+    }
+
+    /// Serializes the profile, then resets it like `reset`, under the same
+    /// borrow: a sample added after this returns can only land in the next
+    /// profile, and one added before it in the returned one. The profile isn't
+    /// reset if serializing fails. See `flush` to reuse a buffer instead.
+    pub fn serialize_and_reset(&mut self) -> Result<EncodedProfile, SerializeError> {
+        let encoded = self.serialize()?;
+        // Resetting only fails if the sample type strings are missing
+        self.reset().expect("sample types to be interned");
+        Ok(encoded)
+    }
+
+    /// Symbolizes the locations which have an address, for profiles which
+    /// were collected with raw addresses and placeholder functions. `resolve`
+    /// is called with each such address; when it returns a symbol, the
+    /// location's lines are replaced by a single line for that symbol, and
+    /// when it returns None the location is left as it is.
+    ///
+    /// Functions which end up with the same symbol are merged, and functions
+    /// no longer used by any location are removed. If locations end up
+    /// identical, they are merged along with the samples using them.
+    pub fn resymbolize<'a, F>(&mut self, mut resolve: F)
+    where
+        F: FnMut(u64) -> Option<api::ResolvedSymbol<'a>>,
+    {
+        let mut locations: Vec<Location> =
+            std::mem::take(&mut self.locations).into_iter().collect();
+        for location in locations.iter_mut().filter(|l| l.address != 0) {
+            if let Some(symbol) = resolve(location.address as u64) {
+                // A full profile keeps the location as it was
+                if let Ok(function_id) = self.intern_function(&api::Function {
+                    name: symbol.name,
+                    system_name: symbol.system_name,
+                    filename: symbol.filename,
+                    start_line: 0,
+                }) {
+                    location.lines = vec![Line {
+                        function_id,
+                        line: symbol.line,
+                    }];
+                }
+            }
+        }
+
+        // Drop the functions which were replaced, shifting the ids of the
+        // remaining ones.
+        let mut used = vec![false; self.functions.len()];
+        for line in locations.iter().flat_map(|l| l.lines.iter()) {
+            used[line.function_id.0 - 1] = true;
+        }
+        let mut function_ids = Vec::with_capacity(used.len());
+        for (function, used) in std::mem::take(&mut self.functions).into_iter().zip(used) {
+            function_ids.push(if used {
+                PProfId(self.functions.dedup(function) + 1)
+            } else {
+                PProfId(0)
+            });
+        }
+
+        self.remap_functions(locations, &function_ids);
+    }
+
+    /// Symbolizes the pending locations, those added with an address but no
+    /// lines, so profilers can add samples without symbolizing on the hot
+    /// path, and do it once before serializing. `resolve` is called with the
+    /// mapping and address of each pending location, and returns its
+    /// symbols, leaf first when functions were inlined. The location gets a
+    /// line for each; when there are none, the location stays pending, with
+    /// just its address. Locations which were already symbolized are left
+    /// alone, see `resymbolize` for those.
+    pub fn symbolize_pending<'a, F>(&mut self, mut resolve: F)
+    where
+        F: FnMut(&api::Mapping, u64) -> Vec<api::ResolvedSymbol<'a>>,
+    {
+        let mut locations: Vec<Location> =
+            std::mem::take(&mut self.locations).into_iter().collect();
+        for location in locations
+            .iter_mut()
+            .filter(|l| l.lines.is_empty() && l.address != 0)
+        {
+            let symbols = match self
                 .mappings
+                .get_index(location.mapping_id.0.wrapping_sub(1))
+            {
+                Some(mapping) => {
+                    let string = |id: PProfId| self.strings[id.0].as_str();
+                    let mapping = api::Mapping {
+                        memory_start: mapping.memory_start,
+                        memory_limit: mapping.memory_limit,
+                        file_offset: mapping.file_offset,
+                        filename: string(mapping.filename),
+                        build_id: string(mapping.build_id),
+                        debug_file: string(mapping.debug_file),
+                    };
+                    resolve(&mapping, location.address as u64)
+                }
+                None => resolve(&api::Mapping::default(), location.address as u64),
+            };
+            // A full profile leaves the location pending
+            let lines = symbols
                 .iter()
-                .enumerate()
-                .map(|(index, mapping)| pprof::Mapping {
-                    id: (index + 1) as u64,
-                    memory_start: mapping.memory_start,
-                    memory_limit: mapping.memory_limit,
-                    file_offset: mapping.file_offset,
-                    filename: mapping.filename.into(),
-                    build_id: mapping.build_id.into(),
-                    ..Default::default() // todo: support detailed Mapping info
-                })
-                .collect(),
-            location: profile
-                .locations
-                .iter()
-                .enumerate()
-                .map(|(index, location)| pprof::Location {
-                    id: (index + 1) as u64,
-                    mapping_id: location.mapping_id.into(),
-                    address: location.address as u64,
-                    line: location.lines.iter().map(Into::into).collect(),
-                    is_folded: location.is_folded,
-                })
-                .collect(),
-            function: profile
-                .functions
-                .iter()
-                .enumerate()
-                .map(|(index, function)| {
-                    let start_line: u64 = function.start_line.into();
-                    pprof::Function {
-                        id: (index + 1) as u64,
-                        name: function.name.into(),
-                        system_name: function.system_name.into(),
-                        filename: function.filename.into(),
-                        start_line: start_line.try_into().unwrap_or(0),
-                    }
+                .map(|symbol| {
+                    Ok(Line {
+                        function_id: self.intern_function(&api::Function {
+                            name: symbol.name,
+                            system_name: symbol.system_name,
+                            filename: symbol.filename,
+                            start_line: 0,
+                        })?,
+                        line: symbol.line,
+                    })
                 })
-                .collect(),
-            string_table: profile.strings.iter().map(Into::into).collect(),
-            time_nanos: profile
-                .start_time
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map_or(0, |d| d.as_nanos() as i64),
-            duration_nanos: profile
-                .started_at
-                .elapsed()
-                .as_nanos()
-                .try_into()
-                .unwrap_or(0),
-            period: profile.period,
-            period_type: profile.period_type.as_ref().map(Into::into),
-            ..Default::default()
+                .collect::<Result<Vec<_>, FullError>>();
+            if let Ok(lines) = lines {
+                location.lines = lines;
+            }
         }
+
+        // Functions are only added, so their ids don't change
+        let function_ids: Vec<PProfId> = (1..=self.functions.len()).map(PProfId).collect();
+        self.remap_functions(locations, &function_ids);
     }
-}
 
-#[cfg(test)]
-mod api_test {
-    use crate::{api, pprof, PProfId, Profile};
+    /// Merges the functions for which `key` returns the same key, e.g. the
+    /// functions with the same name and filename, which `resymbolize` can
+    /// leave behind when symbols for the same function differ in their
+    /// system name or start line because they come from different sources.
+    /// The functions only merge if the key says so: include all the fields
+    /// of `api::Function` which tell functions apart. The first of the
+    /// functions is kept, with the first known start line. Locations which
+    /// end up identical are merged, along with the samples using them.
+    pub fn coalesce_functions<K, F>(&mut self, mut key: F)
+    where
+        K: Eq + Hash,
+        F: FnMut(&api::Function) -> K,
+    {
+        let mut kept: HashMap<K, usize> = HashMap::new();
+        let mut functions: Vec<Function> = Vec::with_capacity(self.functions.len());
+        let mut function_ids = Vec::with_capacity(self.functions.len());
+        for function in std::mem::take(&mut self.functions) {
+            let index = *kept
+                .entry(key(&api::Function {
+                    name: &self.strings[function.name.0],
+                    system_name: &self.strings[function.system_name.0],
+                    filename: &self.strings[function.filename.0],
+                    start_line: function.start_line,
+                }))
+                .or_insert(functions.len());
+            if index == functions.len() {
+                functions.push(function);
+            } else if functions[index].start_line == 0 {
+                functions[index].start_line = function.start_line;
+            }
+            function_ids.push(PProfId(index + 1));
+        }
+        // The functions have different keys, so none are deduplicated
+        self.functions = functions.into_iter().collect();
 
-    #[test]
-    fn interning() {
-        let sample_types = vec![api::ValueType {
+        let locations: Vec<Location> = std::mem::take(&mut self.locations).into_iter().collect();
+        self.remap_functions(locations, &function_ids);
+    }
+
+    /// Recomputes `is_folded` on every location, from whether `predicate`
+    /// holds for its lines, leaf first. This is for after identical code
+    /// folding has been detected, e.g. when symbolization finds several
+    /// symbols at one address. Locations which end up identical are merged,
+    /// along with the samples using them.
+    pub fn mark_folded<F>(&mut self, mut predicate: F)
+    where
+        F: FnMut(&[api::Line]) -> bool,
+    {
+        let mut locations: Vec<Location> =
+            std::mem::take(&mut self.locations).into_iter().collect();
+        for location in locations.iter_mut() {
+            let lines: Vec<api::Line> = location
+                .lines
+                .iter()
+                .map(|line| {
+                    let function = &self.functions[line.function_id.0 - 1];
+                    api::Line {
+                        function: api::Function {
+                            name: &self.strings[function.name.0],
+                            system_name: &self.strings[function.system_name.0],
+                            filename: &self.strings[function.filename.0],
+                            start_line: function.start_line,
+                        },
+                        line: line.line,
+                    }
+                })
+                .collect();
+            location.is_folded = predicate(&lines);
+        }
+
+        let function_ids: Vec<PProfId> = (1..=self.functions.len()).map(PProfId).collect();
+        self.remap_functions(locations, &function_ids);
+    }
+
+    /// Puts back `locations`, which were taken out of the profile, after
+    /// replacing their function ids with the new ones in `function_ids`,
+    /// indexed by old id - 1. Then the samples are updated to use the new
+    /// location ids, merging the ones which become identical.
+    fn remap_functions(&mut self, locations: Vec<Location>, function_ids: &[PProfId]) {
+        self.locations_epoch = next_locations_epoch();
+        let mut location_ids = Vec::with_capacity(locations.len());
+        for mut location in locations {
+            for line in location.lines.iter_mut() {
+                line.function_id = function_ids[line.function_id.0 - 1];
+            }
+            location_ids.push(PProfId(self.locations.dedup(location) + 1));
+        }
+
+        self.folded.clear();
+        for (mut sample, values) in std::mem::take(&mut self.samples) {
+            for id in sample.locations.iter_mut() {
+                *id = location_ids[id.0 - 1];
+            }
+            self.merge_sample(sample, values);
+        }
+    }
+
+    /// Puts back a sample which was taken out of the profile, adding its
+    /// values to those of an identical one if there is one.
+    fn merge_sample(&mut self, sample: Sample, values: Vec<i64>) {
+        let (sample, fold_key) = self.fold(sample);
+        match self.samples.get_mut(&sample) {
+            Some(existing_values) => {
+                // Saturating, as decoded profiles may have any values
+                for (a, b) in existing_values.iter_mut().zip(values) {
+                    *a = a.saturating_add(b);
+                }
+            }
+            None => {
+                let (index, _) = self.samples.insert_full(sample, values);
+                if let Some(key) = fold_key {
+                    self.folded.insert(key, index);
+                }
+            }
+        }
+    }
+
+    /// When aggregating by some labels only, gives the sample the labels of
+    /// the sample it folds into, if there is one already. Otherwise returns
+    /// the key to fold later samples into this one, once it's inserted.
+    fn fold(&self, mut sample: Sample) -> (Sample, Option<FoldKey>) {
+        let keys = match &self.aggregate_by_labels {
+            Some(keys) => keys,
+            None => return (sample, None),
+        };
+        let key = (
+            sample.locations.clone(),
+            sample
+                .labels
+                .iter()
+                .filter(|label| keys.contains(&label.key))
+                .copied()
+                .collect(),
+        );
+        match self.folded.get(&key) {
+            Some(index) => {
+                let (existing, _) = self.samples.get_index(*index).expect("index to exist");
+                sample.labels = existing.labels.clone();
+                (sample, None)
+            }
+            None => (sample, Some(key)),
+        }
+    }
+
+    /// Rebuilds the index of folded samples after their ids changed.
+    fn refold(&mut self) {
+        self.folded.clear();
+        if self.aggregate_by_labels.is_none() {
+            return;
+        }
+        for index in 0..self.samples.len() {
+            let (sample, _) = self.samples.get_index(index).expect("index to exist");
+            let sample = Sample {
+                locations: sample.locations.clone(),
+                labels: sample.labels.clone(),
+            };
+            if let (_, Some(key)) = self.fold(sample) {
+                self.folded.insert(key, index);
+            }
+        }
+    }
+
+    /// Replaces the values of string labels for which `f`, called with the
+    /// label's key and value, returns a replacement, e.g. "[redacted]" for
+    /// values which look like emails or tokens. `f` is called once for each
+    /// distinct key and value, and numeric labels are left alone. Values
+    /// which end up unused are removed from the string table, so they aren't
+    /// serialized.
+    ///
+    /// Samples which only differed by redacted values become identical, and
+    /// are merged by summing their values.
+    pub fn redact_labels<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, &str) -> Option<String>,
+    {
+        let mut replacements: HashMap<(PProfId, PProfId), PProfId> = HashMap::new();
+        self.folded.clear();
+        for (mut sample, values) in std::mem::take(&mut self.samples) {
+            for label in sample.labels.iter_mut().filter(|label| label.str.0 != 0) {
+                let value = match replacements.get(&(label.key, label.str)) {
+                    Some(value) => *value,
+                    None => {
+                        let replacement = f(&self.strings[label.key.0], &self.strings[label.str.0]);
+                        let value = match replacement {
+                            Some(replacement) => self.intern(&replacement),
+                            None => label.str,
+                        };
+                        replacements.insert((label.key, label.str), value);
+                        value
+                    }
+                };
+                label.str = value;
+            }
+            self.merge_sample(sample, values);
+        }
+        self.compact();
+    }
+
+    /// Removes the strings which nothing refers to anymore, shifting the ids
+    /// of the remaining ones, e.g. for a long-lived profile which interned
+    /// many one-off strings, such as labels of failed samples. Strings are
+    /// kept as long as a sample, location, function, mapping, comment or
+    /// sample type refers to them.
+    pub fn compact(&mut self) {
+        let mut used = vec![false; self.strings.len()];
+        let mut mark = |id: PProfId| used[id.0] = true;
+        mark(PProfId(0));
+        for value_type in self.sample_types.iter().chain(self.period_type.iter()) {
+            mark(value_type.type_);
+            mark(value_type.unit);
+        }
+        for mapping in self.mappings.iter() {
+            mark(mapping.filename);
+            mark(mapping.build_id);
+            mark(mapping.debug_file);
+        }
+        for function in self.functions.iter() {
+            mark(function.name);
+            mark(function.system_name);
+            mark(function.filename);
+        }
+        for label in self.samples.keys().flat_map(|sample| sample.labels.iter()) {
+            mark(label.key);
+            mark(label.str);
+            mark(label.num_unit);
+        }
+        for comment in self.comments.iter() {
+            mark(*comment);
+        }
+        mark(self.drop_frames);
+        mark(self.keep_frames);
+        mark(self.default_sample_type);
+        for key in self.aggregate_by_labels.iter().flatten() {
+            mark(*key);
+        }
+        if used.iter().all(|used| *used) {
+            return;
+        }
+
+        let mut string_ids = Vec::with_capacity(used.len());
+        for (string, used) in std::mem::take(&mut self.strings).into_iter().zip(used) {
+            string_ids.push(if used {
+                PProfId(self.strings.insert_full(string).0)
+            } else {
+                PProfId(0)
+            });
+        }
+        let remap = |id: &mut PProfId| *id = string_ids[id.0];
+
+        for value_type in self
+            .sample_types
+            .iter_mut()
+            .chain(self.period_type.iter_mut())
+        {
+            remap(&mut value_type.type_);
+            remap(&mut value_type.unit);
+        }
+        self.comments.iter_mut().for_each(remap);
+        self.free_comments.iter_mut().for_each(remap);
+        remap(&mut self.drop_frames);
+        remap(&mut self.keep_frames);
+        remap(&mut self.default_sample_type);
+        self.aggregate_by_labels
+            .iter_mut()
+            .flatten()
+            .for_each(remap);
+        // The remapping keeps distinct items distinct, so none are merged and
+        // the ids of mappings and functions don't change.
+        self.mappings = std::mem::take(&mut self.mappings)
+            .into_iter()
+            .map(|mut mapping| {
+                remap(&mut mapping.filename);
+                remap(&mut mapping.build_id);
+                remap(&mut mapping.debug_file);
+                mapping
+            })
+            .collect();
+        self.functions = std::mem::take(&mut self.functions)
+            .into_iter()
+            .map(|mut function| {
+                remap(&mut function.name);
+                remap(&mut function.system_name);
+                remap(&mut function.filename);
+                function
+            })
+            .collect();
+        self.samples = std::mem::take(&mut self.samples)
+            .into_iter()
+            .map(|(mut sample, values)| {
+                for label in sample.labels.iter_mut() {
+                    remap(&mut label.key);
+                    remap(&mut label.str);
+                    remap(&mut label.num_unit);
+                }
+                (sample, values)
+            })
+            .collect();
+        self.refold();
+    }
+
+    /// The number of samples after aggregation.
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn endpoints_count(&self) -> usize {
+        let key = match self.strings.get_index_of("trace endpoint") {
+            Some(key) => PProfId(key),
+            None => return 0,
+        };
+        let endpoints: HashSet<PProfId> = self
+            .samples
+            .keys()
+            .flat_map(|sample| sample.labels.iter())
+            .filter(|label| label.key == key && label.str.0 != 0)
+            .map(|label| label.str)
+            .collect();
+        endpoints.len()
+    }
+
+    pub fn location_count(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn function_count(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn mapping_count(&self) -> usize {
+        self.mappings.len()
+    }
+
+    /// The number of strings, including the empty string.
+    pub fn string_count(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn get_string(&self, id: PProfId) -> Option<&String> {
+        self.strings.get_index(id.0)
+    }
+
+    /// Iterates over the aggregated samples, in the order they were first
+    /// added, with their values, e.g. to check what a profile holds without
+    /// serializing it.
+    pub fn samples_iter(&self) -> impl Iterator<Item = (SampleView<'_>, &[i64])> {
+        self.samples
+            .iter()
+            .map(move |(sample, values)| (self.view_sample(sample), values.as_slice()))
+    }
+
+    fn view_sample(&self, sample: &Sample) -> SampleView<'_> {
+        let string = |id: PProfId| self.get_string(id).map(String::as_str).unwrap_or("");
+        let optional = |id: PProfId| if id.0 == 0 { None } else { Some(string(id)) };
+        let locations = sample
+            .locations
+            .iter()
+            .filter_map(|id| self.locations.get_index(id.0.wrapping_sub(1)))
+            .map(|location| api::Location {
+                mapping: match self
+                    .mappings
+                    .get_index(location.mapping_id.0.wrapping_sub(1))
+                {
+                    Some(mapping) => api::Mapping {
+                        memory_start: mapping.memory_start,
+                        memory_limit: mapping.memory_limit,
+                        file_offset: mapping.file_offset,
+                        filename: string(mapping.filename),
+                        build_id: string(mapping.build_id),
+                        debug_file: string(mapping.debug_file),
+                    },
+                    None => api::Mapping::default(),
+                },
+                address: location.address as u64,
+                lines: location
+                    .lines
+                    .iter()
+                    .filter_map(|line| {
+                        let function = self.functions.get_index(line.function_id.0 - 1)?;
+                        Some(api::Line {
+                            function: api::Function {
+                                name: string(function.name),
+                                system_name: string(function.system_name),
+                                filename: string(function.filename),
+                                start_line: function.start_line,
+                            },
+                            line: line.line,
+                        })
+                    })
+                    .collect(),
+                is_folded: location.is_folded,
+            })
+            .collect();
+        let labels = sample
+            .labels
+            .iter()
+            .map(|label| api::Label {
+                key: string(label.key),
+                str: optional(label.str),
+                num: label.num,
+                num_unit: optional(label.num_unit),
+            })
+            .collect();
+        SampleView { locations, labels }
+    }
+
+    /// Resolves the function names of a sample's stack, from the root to the
+    /// leaf. Inlined functions are expanded in place, caller first.
+    fn resolve_frames(&self, sample: &Sample) -> Vec<&str> {
+        let mut frames = Vec::with_capacity(sample.locations.len());
+        for location_id in sample.locations.iter().rev() {
+            let location = match self.locations.get_index(location_id.0 - 1) {
+                Some(location) => location,
+                None => continue,
+            };
+            for line in location.lines.iter().rev() {
+                if let Some(function) = self.functions.get_index(line.function_id.0 - 1) {
+                    frames.push(self.strings[function.name.0].as_str());
+                }
+            }
+        }
+        frames
+    }
+
+    /// Summarizes the profile: the total of each sample type and the number of
+    /// samples. Totals saturate instead of overflowing.
+    pub fn summary(&self) -> ProfileSummary<'_> {
+        let mut totals = vec![0i64; self.sample_types.len()];
+        for values in self.samples.values() {
+            for (total, value) in totals.iter_mut().zip(values) {
+                *total = total.saturating_add(*value);
+            }
+        }
+
+        let sample_types = self
+            .sample_types
+            .iter()
+            .zip(totals)
+            .map(|(sample_type, total)| SampleTypeSummary {
+                r#type: self.strings[sample_type.type_.0].as_str(),
+                unit: self.strings[sample_type.unit.0].as_str(),
+                total,
+            })
+            .collect();
+
+        ProfileSummary {
+            sample_types,
+            sample_count: self.samples.len(),
+        }
+    }
+
+    /// Renders the profile in the folded stack format used by flamegraph
+    /// tools: one line per distinct stack, with frames separated by `;` from
+    /// the root to the leaf, followed by a space and the stack's value for the
+    /// given sample type. Stacks with a zero value are omitted.
+    /// Returns None if `sample_type_index` is out of range.
+    pub fn to_folded(&self, sample_type_index: usize) -> Option<String> {
+        if sample_type_index >= self.sample_types.len() {
+            return None;
+        }
+
+        // Samples which only differ by labels share a line.
+        let mut stacks: IndexMap<Vec<&str>, i64> = IndexMap::new();
+        for (sample, values) in self.samples.iter() {
+            let total = stacks.entry(self.resolve_frames(sample)).or_insert(0);
+            *total = total.saturating_add(values[sample_type_index]);
+        }
+
+        let mut folded = String::new();
+        for (frames, value) in stacks {
+            if value != 0 {
+                folded += &format!("{} {}\n", frames.join(";"), value);
+            }
+        }
+        Some(folded)
+    }
+
+    /// Returns up to `limit` functions with the highest self value for the
+    /// given sample type, in descending order of value. A function's self
+    /// value is the sum over the samples it is the leaf of; functions with the
+    /// same name are merged. Returns None if `sample_type_index` is out of
+    /// range.
+    pub fn top_functions(
+        &self,
+        sample_type_index: usize,
+        limit: usize,
+    ) -> Option<Vec<(&str, i64)>> {
+        if sample_type_index >= self.sample_types.len() {
+            return None;
+        }
+
+        let mut totals: IndexMap<&str, i64> = IndexMap::new();
+        for (sample, values) in self.samples.iter() {
+            if let Some(leaf) = self.resolve_frames(sample).last() {
+                let total = totals.entry(leaf).or_insert(0);
+                *total = total.saturating_add(values[sample_type_index]);
+            }
+        }
+
+        let mut top: Vec<(&str, i64)> = totals.into_iter().collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        top.truncate(limit);
+        Some(top)
+    }
+
+    /// Sums the given sample type over the samples grouped by their value for
+    /// `label_key`, in descending order of total. Numeric labels are grouped
+    /// by their number, and samples without the label under "". Returns an
+    /// empty breakdown if `sample_type_index` is out of range.
+    pub fn breakdown_by_label(
+        &self,
+        label_key: &str,
+        sample_type_index: usize,
+    ) -> Vec<(String, i64)> {
+        if sample_type_index >= self.sample_types.len() {
+            return vec![];
+        }
+
+        let key = self.strings.get_index_of(label_key).map(PProfId);
+        let mut totals: IndexMap<String, i64> = IndexMap::new();
+        for (sample, values) in self.samples.iter() {
+            let label = sample.labels.iter().find(|label| Some(label.key) == key);
+            let value = match label {
+                Some(label) if label.str.0 != 0 => self.strings[label.str.0].clone(),
+                Some(label) => label.num.to_string(),
+                None => String::new(),
+            };
+            let total = totals.entry(value).or_insert(0);
+            *total = total.saturating_add(values[sample_type_index]);
+        }
+
+        let mut breakdown: Vec<(String, i64)> = totals.into_iter().collect();
+        breakdown.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        breakdown
+    }
+
+    /// Splits the profile into one profile per value of the label `key`,
+    /// e.g. per tenant, in the order the values first appear. Numeric labels
+    /// are split by their number, and samples without the label go to an
+    /// "(unlabeled)" profile. Each profile has the same sample types, period,
+    /// options and start time as this one, and only the items its samples
+    /// use. The samples keep their labels, including `key`.
+    pub fn partition_by_label(&self, key: &str) -> Vec<(String, Profile)> {
+        let key = self.strings.get_index_of(key).map(PProfId);
+        let mut partitions: IndexMap<String, (Profile, CopiedIds)> = IndexMap::new();
+        for (sample, values) in self.samples.iter() {
+            let label = sample.labels.iter().find(|label| Some(label.key) == key);
+            let value = match label {
+                Some(label) if label.str.0 != 0 => self.strings[label.str.0].clone(),
+                Some(label) => label.num.to_string(),
+                None => "(unlabeled)".to_owned(),
+            };
+            let (profile, ids) = partitions.entry(value).or_insert_with(|| {
+                // Building only fails if the sample type strings are missing
+                let mut profile = self.empty_like().expect("sample types to be interned");
+                profile.start_time = self.start_time;
+                profile.started_at = self.started_at;
+                (profile, self.copied_ids())
+            });
+            self.copy_sample(profile, ids, sample, values);
+        }
+        partitions
+            .into_iter()
+            .map(|(value, (profile, _))| (value, profile))
+            .collect()
+    }
+
+    /// Ids for copying the items of this profile into another, before any
+    /// were copied.
+    fn copied_ids(&self) -> CopiedIds {
+        CopiedIds {
+            mappings: vec![PProfId(0); self.mappings.len()],
+            functions: vec![PProfId(0); self.functions.len()],
+            locations: vec![PProfId(0); self.locations.len()],
+        }
+    }
+
+    /// Adds `sample` of this profile to `into`, along with the items it uses
+    /// which weren't copied yet.
+    fn copy_sample(
+        &self,
+        into: &mut Profile,
+        ids: &mut CopiedIds,
+        sample: &Sample,
+        values: &[i64],
+    ) {
+        let locations = sample
+            .locations
+            .iter()
+            .map(|id| self.copy_location(into, ids, *id))
+            .collect();
+        let labels = sample
+            .labels
+            .iter()
+            .map(|label| Label {
+                key: into.intern(&self.strings[label.key.0]),
+                str: into.intern(&self.strings[label.str.0]),
+                num: label.num,
+                num_unit: into.intern(&self.strings[label.num_unit.0]),
+            })
+            .collect();
+        into.merge_sample(Sample { locations, labels }, values.to_vec());
+    }
+
+    fn copy_location(&self, into: &mut Profile, ids: &mut CopiedIds, id: PProfId) -> PProfId {
+        if ids.locations[id.0 - 1].0 != 0 {
+            return ids.locations[id.0 - 1];
+        }
+        let location = &self.locations[id.0 - 1];
+        let mapping_id = match location.mapping_id {
+            PProfId(0) => PProfId(0),
+            mapping_id => self.copy_mapping(into, ids, mapping_id),
+        };
+        let lines = location
+            .lines
+            .iter()
+            .map(|line| Line {
+                function_id: self.copy_function(into, ids, line.function_id),
+                line: line.line,
+            })
+            .collect();
+        let copied = PProfId(
+            into.locations.dedup(Location {
+                mapping_id,
+                address: location.address,
+                lines,
+                is_folded: location.is_folded,
+            }) + 1,
+        );
+        ids.locations[id.0 - 1] = copied;
+        copied
+    }
+
+    fn copy_mapping(&self, into: &mut Profile, ids: &mut CopiedIds, id: PProfId) -> PProfId {
+        if ids.mappings[id.0 - 1].0 != 0 {
+            return ids.mappings[id.0 - 1];
+        }
+        let mapping = &self.mappings[id.0 - 1];
+        let debug_file = &self.strings[mapping.debug_file.0];
+        let mapping = Mapping {
+            memory_start: mapping.memory_start,
+            memory_limit: mapping.memory_limit,
+            file_offset: mapping.file_offset,
+            filename: into.intern(&self.strings[mapping.filename.0]),
+            build_id: into.intern(&self.strings[mapping.build_id.0]),
+            debug_file: into.intern(debug_file),
+        };
+        let has_debug_file = mapping.debug_file.0 != 0;
+        let (index, inserted) = into.mappings.insert_full(mapping);
+        // The comment refers to the mapping by its id in `into`
+        if inserted && has_debug_file {
+            let comment = format!("debug_file:{}:{}", index + 1, debug_file);
+            let comment = into.intern(&comment);
+            into.comments.push(comment);
+        }
+        ids.mappings[id.0 - 1] = PProfId(index + 1);
+        PProfId(index + 1)
+    }
+
+    fn copy_function(&self, into: &mut Profile, ids: &mut CopiedIds, id: PProfId) -> PProfId {
+        if ids.functions[id.0 - 1].0 != 0 {
+            return ids.functions[id.0 - 1];
+        }
+        let function = &self.functions[id.0 - 1];
+        let function = Function {
+            name: into.intern(&self.strings[function.name.0]),
+            system_name: into.intern(&self.strings[function.system_name.0]),
+            filename: into.intern(&self.strings[function.filename.0]),
+            start_line: function.start_line,
+        };
+        let copied = PProfId(into.functions.dedup(function) + 1);
+        ids.functions[id.0 - 1] = copied;
+        copied
+    }
+
+    /// Writes the samples as newline-delimited JSON, one object per sample
+    /// with its stack from the root to the leaf, its labels and its value for
+    /// the given sample type:
+    ///
+    /// `{"frames":["main","work"],"labels":[{"key":"thread id","num":7,"num_unit":""}],"value":3}`
+    ///
+    /// String labels have a "str" instead of "num" and "num_unit". Fails with
+    /// `InvalidInput` if `sample_type_index` is out of range.
+    pub fn write_ndjson<W: io::Write>(
+        &self,
+        w: &mut W,
+        sample_type_index: usize,
+    ) -> io::Result<()> {
+        if sample_type_index >= self.sample_types.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no sample type at index {}", sample_type_index),
+            ));
+        }
+
+        for (sample, values) in self.samples.iter() {
+            let labels: Vec<serde_json::Value> = sample
+                .labels
+                .iter()
+                .map(|label| {
+                    let key = self.strings[label.key.0].as_str();
+                    if label.str.0 != 0 {
+                        serde_json::json!({ "key": key, "str": self.strings[label.str.0] })
+                    } else {
+                        serde_json::json!({
+                            "key": key,
+                            "num": label.num,
+                            "num_unit": self.strings[label.num_unit.0],
+                        })
+                    }
+                })
+                .collect();
+            let line = serde_json::json!({
+                "frames": self.resolve_frames(sample),
+                "labels": labels,
+                "value": values[sample_type_index],
+            });
+            writeln!(w, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    /// The pprof message of the profile, as of `now`.
+    fn to_pprof(&self, now: Instant) -> pprof::Profile {
+        let mut string_table: Vec<String> = self.strings.iter().map(Into::into).collect();
+        if let Some((key, true)) = self.dropped_samples_key() {
+            debug_assert_eq!(key, string_table.len());
+            string_table.push(DROPPED_SAMPLES_KEY.to_owned());
+        }
+        pprof::Profile {
+            sample: self.pprof_samples().collect(),
+            mapping: self.pprof_mappings().collect(),
+            location: self.pprof_locations().collect(),
+            function: self.pprof_functions().collect(),
+            string_table,
+            ..self.pprof_fields(now)
+        }
+    }
+
+    /// Encodes the profile as of `now` into `writer`, the same as encoding
+    /// `to_pprof` would, but converting the items of its tables one at a
+    /// time rather than copying them all first, and writing through a
+    /// buffer of about `CHUNK` bytes.
+    fn encode_into<W: io::Write>(&self, now: Instant, writer: &mut W) -> io::Result<()> {
+        use prost::encoding::{message, string};
+        const CHUNK: usize = 32 * 1024;
+
+        let mut buf: Vec<u8> = Vec::with_capacity(CHUNK);
+        let mut flush = |buf: &mut Vec<u8>, force: bool| -> io::Result<()> {
+            if force || buf.len() >= CHUNK {
+                writer.write_all(buf)?;
+                buf.clear();
+            }
+            Ok(())
+        };
+
+        // Messages encode their fields in the order of their tags, and the
+        // tables are fields 2 to 6, so the other fields go around them.
+        let mut fields = self.pprof_fields(now);
+        let head = pprof::Profile {
+            sample_type: std::mem::take(&mut fields.sample_type),
+            ..Default::default()
+        };
+        head.encode_raw(&mut buf);
+
+        for sample in self.pprof_samples() {
+            message::encode(2, &sample, &mut buf);
+            flush(&mut buf, false)?;
+        }
+        for mapping in self.pprof_mappings() {
+            message::encode(3, &mapping, &mut buf);
+            flush(&mut buf, false)?;
+        }
+        for location in self.pprof_locations() {
+            message::encode(4, &location, &mut buf);
+            flush(&mut buf, false)?;
+        }
+        for function in self.pprof_functions() {
+            message::encode(5, &function, &mut buf);
+            flush(&mut buf, false)?;
+        }
+        for string in self.strings.iter() {
+            string::encode(6, string, &mut buf);
+            flush(&mut buf, false)?;
+        }
+        if let Some((_, true)) = self.dropped_samples_key() {
+            string::encode(6, &DROPPED_SAMPLES_KEY.to_owned(), &mut buf);
+        }
+
+        fields.encode_raw(&mut buf);
+        flush(&mut buf, true)
+    }
+
+    /// The fields of the pprof message of the profile as of `now`, without
+    /// its samples, mappings, locations, functions and strings.
+    fn pprof_fields(&self, now: Instant) -> pprof::Profile {
+        let (period, period_type) = self.effective_period();
+        pprof::Profile {
+            sample_type: self.sample_types.iter().map(Into::into).collect(),
+            time_nanos: self
+                .start_time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as i64),
+            duration_nanos: self.window(now).0.as_nanos().try_into().unwrap_or(0),
+            period,
+            period_type: period_type.as_ref().map(Into::into),
+            comment: self.comments.iter().map(Into::into).collect(),
+            drop_frames: self.drop_frames.into(),
+            keep_frames: self.keep_frames.into(),
+            default_sample_type: self.default_sample_type.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The string id of the label of the sample of dropped samples, if there
+    /// were any, and whether it's appended to the strings of the profile.
+    fn dropped_samples_key(&self) -> Option<(usize, bool)> {
+        if self.dropped_samples == 0 {
+            return None;
+        }
+        Some(match self.strings.get_index_of(DROPPED_SAMPLES_KEY) {
+            Some(index) => (index, false),
+            None => (self.strings.len(), true),
+        })
+    }
+
+    fn pprof_samples(&self) -> impl Iterator<Item = pprof::Sample> + '_ {
+        let legacy = self.compatibility_mode == CompatibilityMode::Legacy;
+        let samples = self
+            .samples
+            .iter()
+            .filter(move |(_, values)| {
+                !self.omit_zero_samples || values.iter().any(|value| *value != 0)
+            })
+            .map(move |(sample, values)| pprof::Sample {
+                location_id: sample.locations.iter().map(Into::into).collect(),
+                value: values.to_vec(),
+                label: sample
+                    .labels
+                    .iter()
+                    .map(|label| {
+                        let mut label: pprof::Label = label.into();
+                        if legacy {
+                            label.num_unit = 0;
+                        }
+                        label
+                    })
+                    .collect(),
+            });
+
+        // The samples refused by the soft limits are reported as one sample
+        // without a stack, so consumers can correct their statistics.
+        let dropped = self.dropped_samples_key().map(|(key, _)| pprof::Sample {
+            location_id: vec![],
+            value: self.dropped_values.clone(),
+            label: vec![pprof::Label {
+                key: key as i64,
+                num: self.dropped_samples as i64,
+                ..Default::default()
+            }],
+        });
+        samples.chain(dropped)
+    }
+
+    fn pprof_mappings(&self) -> impl Iterator<Item = pprof::Mapping> + '_ {
+        self.mappings
+            .iter()
+            .enumerate()
+            .map(|(index, mapping)| pprof::Mapping {
+                id: (index + 1) as u64,
+                memory_start: mapping.memory_start,
+                memory_limit: mapping.memory_limit,
+                file_offset: mapping.file_offset,
+                filename: mapping.filename.into(),
+                build_id: mapping.build_id.into(),
+                ..Default::default() // todo: support detailed Mapping info
+            })
+    }
+
+    fn pprof_locations(&self) -> impl Iterator<Item = pprof::Location> + '_ {
+        let legacy = self.compatibility_mode == CompatibilityMode::Legacy;
+        self.locations
+            .iter()
+            .enumerate()
+            .map(move |(index, location)| pprof::Location {
+                id: (index + 1) as u64,
+                mapping_id: location.mapping_id.into(),
+                address: location.address as u64,
+                line: location.lines.iter().map(Into::into).collect(),
+                is_folded: location.is_folded && !legacy,
+            })
+    }
+
+    fn pprof_functions(&self) -> impl Iterator<Item = pprof::Function> + '_ {
+        self.functions
+            .iter()
+            .enumerate()
+            .map(|(index, function)| pprof::Function {
+                id: (index + 1) as u64,
+                name: function.name.into(),
+                system_name: function.system_name.into(),
+                filename: function.filename.into(),
+                start_line: function.start_line,
+            })
+    }
+}
+
+impl From<&Profile> for pprof::Profile {
+    fn from(profile: &Profile) -> Self {
+        profile.to_pprof((profile.clock)().1)
+    }
+}
+
+#[cfg(test)]
+mod api_test {
+    use crate::{
+        api, pprof, AddError, BuildError, CompatibilityMode, EncodedProfile, EndpointCounts,
+        EndpointCountsOnReset, FunctionId, ItemKind, LocationCache, MappingId, PProfId, Profile,
+        ProfileSummary, ResetOptions, SampleTypeSummary, SerializeError, SoftLimits,
+        UnknownSampleTypeError, CONTAINER_MAX,
+    };
+    use prost::Message;
+    use std::cell::Cell;
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[test]
+    fn interning() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profiles = Profile::builder().sample_types(sample_types).build();
+
+        /* There have been 3 strings: "", "samples", and "count". Since the interning index starts at
+         * zero, this means the next string will be 3.
+         */
+        const EXPECTED_ID: PProfId = PProfId(3);
+
+        let string = "a";
+        let id1 = profiles.intern(string);
+        let id2 = profiles.intern(string);
+
+        assert_eq!(id1, id2);
+        assert_eq!(id1, EXPECTED_ID);
+    }
+
+    #[test]
+    fn api() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+
+        let mapping = api::Mapping {
+            filename: "php",
+            ..Default::default()
+        };
+
+        let index = api::Function {
+            filename: "index.php",
+            ..Default::default()
+        };
+
+        let locations = vec![
+            api::Location {
+                mapping,
+                lines: vec![api::Line {
+                    function: api::Function {
+                        name: "phpinfo",
+                        system_name: "phpinfo",
+                        filename: "index.php",
+                        start_line: 0,
+                    },
+                    line: 0,
+                }],
+                ..Default::default()
+            },
+            api::Location {
+                mapping,
+                lines: vec![api::Line {
+                    function: index,
+                    line: 3,
+                }],
+                ..Default::default()
+            },
+        ];
+
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        let sample_id = profile
+            .add(api::Sample {
+                locations,
+                values: vec![1, 10000],
+                labels: vec![],
+                ..Default::default()
+            })
+            .expect("add to succeed");
+
+        assert_eq!(sample_id, PProfId(1));
+    }
+
+    fn provide_distinct_locations() -> crate::Profile {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+
+        let main_lines = vec![api::Line {
+            function: api::Function {
+                name: "{main}",
+                system_name: "{main}",
+                filename: "index.php",
+                start_line: 0,
+            },
+            line: 0,
+        }];
+
+        let test_lines = vec![api::Line {
+            function: api::Function {
+                name: "test",
+                system_name: "test",
+                filename: "index.php",
+                start_line: 3,
+            },
+            line: 0,
+        }];
+
+        let mapping = api::Mapping {
+            filename: "php",
+            ..Default::default()
+        };
+
+        let main_locations = vec![api::Location {
+            mapping,
+            lines: main_lines,
+            ..Default::default()
+        }];
+        let test_locations = vec![api::Location {
+            mapping,
+            lines: test_lines,
+            ..Default::default()
+        }];
+        let values: Vec<i64> = vec![1];
+        let labels = vec![api::Label {
+            key: "pid",
+            num: 101,
+            ..Default::default()
+        }];
+
+        let main_sample = api::Sample {
+            locations: main_locations,
+            values: values.clone(),
+            labels: labels.clone(),
+            ..Default::default()
+        };
+
+        let test_sample = api::Sample {
+            locations: test_locations,
+            values,
+            labels,
+            ..Default::default()
+        };
+
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let sample_id1 = profile.add(main_sample).expect("profile to not be full");
+        assert_eq!(sample_id1, PProfId(1));
+
+        let sample_id2 = profile.add(test_sample).expect("profile to not be full");
+        assert_eq!(sample_id2, PProfId(2));
+
+        profile
+    }
+
+    #[test]
+    fn default_sample_type() {
+        let sample_types = || {
+            vec![
+                api::ValueType {
+                    r#type: "cpu-time",
+                    unit: "nanoseconds",
+                },
+                api::ValueType {
+                    r#type: "wall-time",
+                    unit: "nanoseconds",
+                },
+            ]
+        };
+        let mut profile = Profile::builder()
+            .sample_types(sample_types())
+            .default_sample_type("wall-time")
+            .try_build()
+            .expect("build to succeed");
+        let default_type = |profile: &Profile| {
+            let encoded = profile.serialize().expect("serialize to succeed");
+            let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+            decoded.string_table[decoded.default_sample_type as usize].clone()
+        };
+        assert_eq!(default_type(&profile), "wall-time");
+        profile.reset().expect("reset to succeed");
+        assert_eq!(default_type(&profile), "wall-time");
+
+        let unknown = Profile::builder()
+            .sample_types(sample_types())
+            .default_sample_type("alloc-space");
+        assert_eq!(
+            unknown.try_build().err(),
+            Some(BuildError::UnknownDefaultSampleType(
+                "alloc-space".to_owned()
+            ))
+        );
+        let profile = Profile::builder()
+            .sample_types(sample_types())
+            .default_sample_type("alloc-space")
+            .build();
+        assert_eq!(default_type(&profile), "");
+    }
+
+    #[test]
+    fn serialize_and_reset() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .period(Some(api::Period {
+                r#type: api::ValueType {
+                    r#type: "cpu",
+                    unit: "nanoseconds",
+                },
+                value: 10_000_000,
+            }))
+            .build();
+        let sample = |name| api::Sample {
+            locations: vec![api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name,
+                    ..Default::default()
+                },
+                line: 0,
+            }])],
+            values: vec![1],
+            labels: vec![],
+            ..Default::default()
+        };
+        let function_names = |encoded: &EncodedProfile| {
+            let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+            let names: Vec<String> = decoded
+                .function
+                .iter()
+                .map(|function| decoded.string_table[function.name as usize].clone())
+                .collect();
+            (names, decoded.sample.len(), decoded.period)
+        };
+
+        profile.add(sample("before")).expect("add to succeed");
+        let first = profile.serialize_and_reset().expect("serialize to succeed");
+        profile.add(sample("after")).expect("add to succeed");
+        let second = profile.serialize_and_reset().expect("serialize to succeed");
+
+        assert_eq!(
+            function_names(&first),
+            (vec!["before".to_owned()], 1, 10_000_000)
+        );
+        assert_eq!(
+            function_names(&second),
+            (vec!["after".to_owned()], 1, 10_000_000)
+        );
+        assert!(second.start >= first.start);
+        assert_eq!(profile.sample_count(), 0);
+    }
+
+    #[test]
+    fn aggregate_by_labels() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .aggregate_by_labels(vec!["thread id"])
+            .build();
+
+        let sample = |thread_id, thread_name| api::Sample {
+            locations: vec![api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name: "{main}",
+                    ..Default::default()
+                },
+                line: 0,
+            }])],
+            values: vec![1],
+            labels: vec![
+                api::Label::num("thread id", thread_id, None),
+                api::Label::str("thread name", thread_name),
+            ],
+            ..Default::default()
+        };
+        let id1 = profile.add(sample(1, "main")).expect("add to succeed");
+        // Only the thread name differs: the first one is kept
+        let id2 = profile
+            .add(sample(1, "main-renamed"))
+            .expect("add to succeed");
+        assert_eq!(id1, id2);
+        let id3 = profile.add(sample(2, "worker")).expect("add to succeed");
+        assert_ne!(id1, id3);
+
+        let samples: Vec<_> = profile
+            .samples_iter()
+            .map(|(sample, values)| {
+                let labels: Vec<_> = sample
+                    .labels
+                    .iter()
+                    .map(|label| (label.key, label.num, label.str))
+                    .collect();
+                (labels, values.to_vec())
+            })
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                (
+                    vec![("thread id", 1, None), ("thread name", 0, Some("main"))],
+                    vec![2]
+                ),
+                (
+                    vec![("thread id", 2, None), ("thread name", 0, Some("worker"))],
+                    vec![1]
+                ),
+            ]
+        );
+
+        // Folding still works once string ids changed, and after a reset
+        profile.intern("unused");
+        profile.compact();
+        profile.add(sample(2, "worker-2")).expect("add to succeed");
+        assert_eq!(profile.sample_count(), 2);
+        profile.reset().expect("reset to succeed");
+        profile.add(sample(1, "a")).expect("add to succeed");
+        profile.add(sample(1, "b")).expect("add to succeed");
+        assert_eq!(profile.sample_count(), 1);
+    }
+
+    #[test]
+    fn intern_bytes() {
+        let mut profile = provide_distinct_locations();
+        let strings = profile.string_count();
+
+        // Valid UTF-8 is the same string
+        assert_eq!(
+            profile.intern_bytes(b"index.php"),
+            profile.intern("index.php")
+        );
+        assert_eq!(profile.string_count(), strings);
+
+        // Invalid sequences are replaced
+        let latin1 = profile.intern_bytes(b"/tmp/caf\xe9.so");
+        assert_eq!(
+            profile.get_string(latin1).map(String::as_str),
+            Some("/tmp/caf\u{fffd}.so")
+        );
+        assert_eq!(profile.intern_bytes(b"/tmp/caf\xe8.so"), latin1);
+        assert_eq!(profile.string_count(), strings + 1);
+
+        // Locations and mappings can be added with such bytes too
+        let mapping = api::BytesMapping {
+            filename: b"/tmp/caf\xe9.so",
+            ..Default::default()
+        };
+        let location = api::BytesLocation {
+            mapping,
+            address: 0x1000,
+            lines: vec![api::BytesLine {
+                function: api::BytesFunction {
+                    name: b"_Z3f\xffoo",
+                    ..Default::default()
+                },
+                line: 0,
+            }],
+            ..Default::default()
+        };
+        let id = profile.add_bytes_location(&location).unwrap();
+        profile
+            .add(api::Sample {
+                values: vec![1],
+                location_ids: vec![id],
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(profile.to_folded(0).unwrap().contains("_Z3f\u{fffd}oo 1\n"));
+        // The mapping is the one of the location
+        let MappingId(mapping_id) = profile.add_bytes_mapping(&mapping).unwrap();
+        assert_eq!(profile.locations[id.0 - 1].mapping_id, mapping_id);
+        let filename = profile.mappings[mapping_id.0 - 1].filename;
+        assert_eq!(profile.strings[filename.0], "/tmp/caf\u{fffd}.so");
+    }
+
+    #[test]
+    fn counts() {
+        let profile = provide_distinct_locations();
+        assert_eq!(profile.sample_count(), 2);
+        assert_eq!(profile.location_count(), 2);
+        assert_eq!(profile.function_count(), 2);
+        assert_eq!(profile.mapping_count(), 1);
+        // "", "samples", "count", "pid", "{main}", "index.php", "php", "test"
+        assert_eq!(profile.string_count(), 8);
+
+        let empty = Profile::new();
+        assert_eq!(empty.sample_count(), 0);
+        assert_eq!(empty.string_count(), 1);
+    }
+
+    #[test]
+    fn encoded_counts() {
+        let mut profile = provide_distinct_locations();
+        let encoded = profile.serialize().expect("serialize to succeed");
+        assert_eq!(encoded.samples_count, 2);
+        assert_eq!(encoded.endpoints_count, 0);
+
+        for endpoint in ["GET /users", "GET /users", "POST /login"] {
+            profile
+                .add(api::Sample {
+                    locations: vec![api::Location::with_inlined(vec![api::Line {
+                        function: api::Function {
+                            name: "handle",
+                            ..Default::default()
+                        },
+                        line: 0,
+                    }])],
+                    values: vec![1],
+                    labels: vec![api::Label::str("trace endpoint", endpoint)],
+                    ..Default::default()
+                })
+                .expect("add to succeed");
+        }
+        let encoded = profile.serialize().expect("serialize to succeed");
+        assert_eq!(encoded.samples_count, 4);
+        assert_eq!(encoded.endpoints_count, 2);
+    }
+
+    #[test]
+    fn scale_values() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        let mut add = |name: &'static str, values: Vec<i64>| {
+            profile
+                .add(api::Sample {
+                    locations: vec![api::Location::with_inlined(vec![api::Line {
+                        function: api::Function {
+                            name,
+                            ..Default::default()
+                        },
+                        line: 0,
+                    }])],
+                    values,
+                    labels: vec![],
+                    ..Default::default()
+                })
+                .unwrap();
+        };
+        add("main", vec![1, 10]);
+        add("main", vec![2, 20]);
+        add("work", vec![3, i64::MAX / 10]);
+
+        profile.scale_values(1, 100.0).unwrap();
+        let values: Vec<Vec<i64>> = profile
+            .samples_iter()
+            .map(|(_, values)| values.to_vec())
+            .collect();
+        assert_eq!(values, vec![vec![3, 3_000], vec![3, i64::MAX]]);
+
+        profile.scale_values(0, 0.5).unwrap();
+        let values: Vec<i64> = profile
+            .samples_iter()
+            .map(|(_, values)| values[0])
+            .collect();
+        // 1.5 rounds away from zero
+        assert_eq!(values, vec![2, 2]);
+
+        assert_eq!(
+            profile.scale_values(2, 100.0),
+            Err(UnknownSampleTypeError(2))
+        );
+    }
+
+    #[test]
+    fn reset_keeping_endpoint_counts() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let period = api::Period {
+            r#type: sample_types[0],
+            value: 10,
+        };
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .period(Some(period))
+            .build();
+        profile.add_endpoint_count("GET /users", 2);
+        profile.add_endpoint_count("GET /users", 3);
+        profile.add_endpoint_count("POST /login", i64::MAX);
+        profile.add_endpoint_count("POST /login", 1);
+        let counts = |pairs: &[(&str, i64)]| -> EndpointCounts {
+            pairs
+                .iter()
+                .map(|(endpoint, count)| (endpoint.to_string(), *count))
+                .collect()
+        };
+        let expected = counts(&[("GET /users", 5), ("POST /login", i64::MAX)]);
+        assert_eq!(profile.endpoint_counts(), &expected);
+
+        // Carried over, and kept by the previous profile too
+        let (previous, returned) = profile
+            .reset_keeping(ResetOptions {
+                endpoint_counts: EndpointCountsOnReset::CarryOver,
+            })
+            .unwrap();
+        assert_eq!(previous.endpoint_counts(), &expected);
+        assert!(returned.is_empty());
+        assert_eq!(profile.endpoint_counts(), &expected);
+        assert_eq!(profile.period, 10);
+
+        // Returned, and taken out of the previous profile
+        profile.add_endpoint_count("GET /users", 1);
+        let (previous, returned) = profile
+            .reset_keeping(ResetOptions {
+                endpoint_counts: EndpointCountsOnReset::Return,
+            })
+            .unwrap();
+        assert!(previous.endpoint_counts().is_empty());
+        assert_eq!(
+            returned,
+            counts(&[("GET /users", 6), ("POST /login", i64::MAX)])
+        );
+        assert!(profile.endpoint_counts().is_empty());
+
+        // Reset, and kept by the previous profile, like `reset` does
+        profile.add_endpoint_count("GET /users", 1);
+        let (previous, returned) = profile.reset_keeping(ResetOptions::default()).unwrap();
+        assert_eq!(previous.endpoint_counts(), &counts(&[("GET /users", 1)]));
+        assert!(returned.is_empty());
+        assert!(profile.endpoint_counts().is_empty());
+
+        profile.add_endpoint_count("GET /users", 1);
+        let previous = profile.reset().unwrap();
+        assert_eq!(previous.endpoint_counts(), &counts(&[("GET /users", 1)]));
+        assert!(profile.endpoint_counts().is_empty());
+        assert_eq!(profile.period, 10);
+    }
+
+    #[test]
+    fn max_string_len() {
+        let sample_types = vec![api::ValueType {
+            r#type: "allocated-objects-sampled",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .max_string_len(16)
+            .build();
+
+        // A generated name, megabytes long, of two byte characters
+        let name = format!("lambda${}", "\u{3bb}".repeat(1 << 20));
+        let samples = [api::Sample {
+            locations: vec![api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name: &name,
+                    filename: "index.php",
+                    ..Default::default()
+                },
+                line: 0,
+            }])],
+            values: vec![1],
+            labels: vec![api::Label::str("thread name", "main")],
+            ..Default::default()
+        }];
+        profile.add_all(&samples).unwrap();
+        profile.add_all(&samples).unwrap();
+        assert_eq!(profile.truncated_strings(), 2);
+
+        let encoded = profile
+            .serialize()
+            .expect("Unable to encode/serialize the profile");
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        assert_eq!(decoded.sample.len(), 1);
+        let truncated = "lambda$\u{3bb}\u{3bb}\u{3bb}\u{3bb}\u{2026}";
+        assert_eq!(truncated.len(), 15 + "\u{2026}".len());
+        let function = &decoded.function[0];
+        assert_eq!(decoded.string_table[function.name as usize], truncated);
+        // Short strings, and the sample types, are kept whole
+        assert_eq!(decoded.string_table[0], "");
+        for string in [
+            "index.php",
+            "thread name",
+            "main",
+            "allocated-objects-sampled",
+        ] {
+            assert!(
+                decoded.string_table.iter().any(|s| s == string),
+                "{}",
+                string
+            );
+        }
+        assert!(!decoded.string_table.iter().any(|s| s.len() > 64));
+
+        // The limit is kept across resets, unlike the count
+        profile.reset().unwrap();
+        assert_eq!(profile.truncated_strings(), 0);
+        // The truncated form goes over the limit too, and truncates to itself
+        assert_eq!(profile.intern(&name), profile.intern(truncated));
+        assert_eq!(profile.truncated_strings(), 2);
+    }
+
+    #[test]
+    fn value_type_display() {
+        let wall_time = api::ValueType {
+            r#type: "wall-time",
+            unit: "nanoseconds",
+        };
+        let samples = api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        };
+        assert_eq!(wall_time.to_string(), "wall-time/nanoseconds");
+        assert_eq!(samples.to_string(), "samples/count");
+
+        let period = api::Period {
+            r#type: wall_time,
+            value: 10_000_000,
+        };
+        assert_eq!(period.to_string(), "wall-time/nanoseconds=10000000");
+        let period = api::Period {
+            r#type: samples,
+            value: -1,
+        };
+        assert_eq!(period.to_string(), "samples/count=-1");
+    }
+
+    #[test]
+    fn set_period() {
+        let mut profile = provide_distinct_locations();
+        assert!(profile.period_type.is_none());
+
+        profile.set_period(
+            10_000_000,
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        );
+        assert_eq!(profile.samples_iter().count(), 2);
+
+        let encoded = profile
+            .serialize()
+            .expect("Unable to encode/serialize the profile");
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        assert_eq!(decoded.sample.len(), 2);
+        assert_eq!(decoded.period, 10_000_000);
+        let period_type = decoded.period_type.expect("period type to be set");
+        assert_eq!(
+            decoded.string_table[period_type.r#type as usize],
+            "wall-time"
+        );
+        assert_eq!(
+            decoded.string_table[period_type.unit as usize],
+            "nanoseconds"
+        );
+
+        // It survives a reset
+        profile.reset().expect("reset to succeed");
+        assert_eq!(profile.period, 10_000_000);
+        assert!(profile.period_type.is_some());
+    }
+
+    #[test]
+    fn location_cache() {
+        const FRAMES: [(u64, &str); 3] = [(0x1000, "main"), (0x2000, "run"), (0x3000, "work")];
+        fn location(name: &str) -> api::Location<'_> {
+            api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name,
+                    ..Default::default()
+                },
+                line: 0,
+            }])
+        }
+        let stacks: [&[usize]; 3] = [&[2, 1, 0], &[1, 0], &[2, 1, 0]];
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+
+        let mut expected = Profile::builder()
+            .sample_types(sample_types.clone())
+            .build();
+        for stack in stacks.iter() {
+            expected
+                .add(api::Sample {
+                    locations: stack
+                        .iter()
+                        .map(|&frame| location(FRAMES[frame].1))
+                        .collect(),
+                    values: vec![1],
+                    labels: vec![],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        let mut cache = LocationCache::new();
+        let built = std::cell::Cell::new(0);
+        let add_stacks = |profile: &mut Profile, cache: &mut LocationCache<u64>| {
+            for stack in stacks.iter() {
+                let location_ids = stack
+                    .iter()
+                    .map(|&frame| {
+                        let (address, name) = FRAMES[frame];
+                        cache.get_or_add(profile, address, || {
+                            built.set(built.get() + 1);
+                            location(name)
+                        })
+                    })
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                profile
+                    .add(api::Sample {
+                        locations: vec![],
+                        values: vec![1],
+                        labels: vec![],
+                        location_ids,
+                        ..Default::default()
+                    })
+                    .unwrap();
+            }
+        };
+        add_stacks(&mut profile, &mut cache);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(built.get(), 3);
+
+        // Each frame is only built once, and the profile is the same as if
+        // its samples had been added with their locations
+        let mut decoded: pprof::Profile = (&profile).into();
+        let mut wanted: pprof::Profile = (&expected).into();
+        decoded.duration_nanos = 0;
+        decoded.time_nanos = 0;
+        wanted.duration_nanos = 0;
+        wanted.time_nanos = 0;
+        assert_eq!(decoded, wanted);
+
+        // Resetting the profile invalidates the ids
+        profile.reset().expect("sample types to be interned");
+        add_stacks(&mut profile, &mut cache);
+        assert_eq!(profile.location_count(), 3);
+        assert_eq!(profile.sample_count(), 2);
+        assert_eq!(built.get(), 6);
+    }
+
+    #[test]
+    fn label_key_cache() {
+        fn add(profile: &mut Profile, key: &str) {
+            profile
+                .add(api::Sample {
+                    locations: vec![],
+                    values: vec![1],
+                    labels: vec![api::Label::num(key, 64, Some("bytes"))],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        fn label_keys(profile: &Profile) -> Vec<String> {
+            profile
+                .samples_iter()
+                .map(|(sample, _)| sample.labels[0].key.to_owned())
+                .collect()
+        }
+
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        // Cached keys give the same id as interning them
+        add(&mut profile, "allocation size");
+        let id = profile.intern("allocation size");
+        add(&mut profile, "allocation size");
+        assert_eq!(
+            profile.label_keys[..]
+                .iter()
+                .filter(|(_, _, cached)| *cached == id)
+                .count(),
+            1
+        );
+        assert_eq!(profile.sample_count(), 1);
+        assert_eq!(profile.samples_iter().next().unwrap().1, &[2]);
+
+        // The same memory holding another key isn't taken for the first one
+        let mut key = String::with_capacity(16);
+        key.push_str("bytes");
+        add(&mut profile, &key);
+        key.clear();
+        key.push_str("words");
+        add(&mut profile, &key);
+        assert_eq!(
+            label_keys(&profile),
+            vec!["allocation size", "bytes", "words"]
+        );
+
+        // Nor are ids from before the string table was reset
+        profile.reset().expect("sample types to be interned");
+        add(&mut profile, "words");
+        add(&mut profile, "allocation size");
+        add(&mut profile, "allocation size");
+        assert_eq!(label_keys(&profile), vec!["words", "allocation size"]);
+    }
+
+    #[test]
+    fn negative_start_line() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        let location = api::Location::with_inlined(vec![api::Line {
+            function: api::Function {
+                name: "jitted",
+                start_line: -1,
+                ..Default::default()
+            },
+            line: 0,
+        }]);
+        profile
+            .add(api::Sample {
+                locations: vec![location],
+                values: vec![1],
+                labels: vec![],
+                ..Default::default()
+            })
+            .expect("add to succeed");
+
+        let encoded = profile.serialize().expect("serialize to succeed");
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        assert_eq!(decoded.function[0].start_line, -1);
+
+        #[cfg(feature = "decode")]
+        {
+            let restored = Profile::from_pprof(&encoded.buffer).expect("profile to decode");
+            let (sample, _) = restored.samples_iter().next().unwrap();
+            assert_eq!(sample.locations[0].lines[0].function.start_line, -1);
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let mut profile = provide_distinct_locations();
+        let unused = profile.intern("SELECT * FROM users WHERE id = 42");
+        let strings = profile.strings.len();
+
+        profile.compact();
+        assert_eq!(profile.strings.len(), strings - 1);
+        assert!(!profile
+            .strings
+            .contains("SELECT * FROM users WHERE id = 42"));
+        assert!(profile.get_string(unused).is_none());
+
+        // The ids were remapped along with the strings
+        let names: Vec<_> = profile
+            .samples_iter()
+            .map(|(sample, _)| sample.locations[0].lines[0].function.name)
+            .collect();
+        assert_eq!(names, vec!["{main}", "test"]);
+        let summary = profile.summary();
+        assert_eq!(summary.sample_types[0].r#type, "samples");
+        assert_eq!(summary.sample_types[0].unit, "count");
+        let encoded = profile.serialize().expect("serialize to succeed");
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        assert_eq!(decoded.string_table.len(), strings - 1);
+
+        // Nothing else to remove
+        profile.compact();
+        assert_eq!(profile.strings.len(), strings - 1);
+    }
+
+    #[test]
+    fn samples_iter() {
+        let profile = provide_distinct_locations();
+
+        let samples: Vec<_> = profile
+            .samples_iter()
+            .map(|(sample, values)| {
+                let names: Vec<&str> = sample
+                    .locations
+                    .iter()
+                    .flat_map(|location| location.lines.iter())
+                    .map(|line| line.function.name)
+                    .collect();
+                assert!(sample
+                    .locations
+                    .iter()
+                    .all(|location| location.mapping.filename == "php"));
+                assert_eq!(sample.labels.len(), 1);
+                assert_eq!(sample.labels[0].key, "pid");
+                assert_eq!(sample.labels[0].num, 101);
+                assert_eq!(sample.labels[0].str, None);
+                (names, values.to_vec())
+            })
+            .collect();
+        assert_eq!(
+            samples,
+            vec![(vec!["{main}"], vec![1]), (vec!["test"], vec![1])]
+        );
+    }
+
+    #[test]
+    fn impl_from_profile_for_pprof_profile() {
+        let profile: pprof::Profile = (&provide_distinct_locations()).into();
+
+        assert_eq!(profile.sample.len(), 2);
+        assert_eq!(profile.mapping.len(), 1);
+        assert_eq!(profile.location.len(), 2);
+        assert_eq!(profile.function.len(), 2);
+
+        for (index, mapping) in profile.mapping.iter().enumerate() {
+            assert_eq!((index + 1) as u64, mapping.id);
+        }
+
+        for (index, location) in profile.location.iter().enumerate() {
+            assert_eq!((index + 1) as u64, location.id);
+        }
+
+        for (index, function) in profile.function.iter().enumerate() {
+            assert_eq!((index + 1) as u64, function.id);
+        }
+
+        let sample = profile.sample.first().expect("index 0 to exist");
+        assert_eq!(sample.label.len(), 1);
+        let label = sample.label.first().expect("index 0 to exist");
+        let key = profile
+            .string_table
+            .get(label.key as usize)
+            .expect("index to exist");
+        let str = profile
+            .string_table
+            .get(label.str as usize)
+            .expect("index to exist");
+        let num_unit = profile
+            .string_table
+            .get(label.num_unit as usize)
+            .expect("index to exist");
+        assert_eq!(key, "pid");
+        assert_eq!(label.num, 101);
+        assert_eq!(str, "");
+        assert_eq!(num_unit, "");
+    }
+
+    #[test]
+    fn mixed_labels() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        profile
+            .add(api::Sample {
+                locations: vec![],
+                values: vec![1],
+                labels: vec![
+                    api::Label::str("thread name", "main"),
+                    api::Label::num("span id", 42, None),
+                    api::Label::num("cpu-time", 1000, Some("nanoseconds")),
+                ],
+                ..Default::default()
+            })
+            .expect("add to succeed");
+
+        let profile: pprof::Profile = (&profile).into();
+        let sample = profile.sample.first().expect("index 0 to exist");
+        assert_eq!(sample.label.len(), 3);
+
+        let string = |id: i64| profile.string_table[id as usize].as_str();
+        let labels: Vec<(&str, &str, i64, &str)> = sample
+            .label
+            .iter()
+            .map(|label| {
+                (
+                    string(label.key),
+                    string(label.str),
+                    label.num,
+                    string(label.num_unit),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            labels,
+            vec![
+                ("thread name", "main", 0, ""),
+                ("span id", "", 42, ""),
+                ("cpu-time", "", 1000, "nanoseconds"),
+            ]
+        );
+    }
+
+    #[test]
+    fn infer_period() {
+        let samples = api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        };
+        let cpu_time = api::ValueType {
+            r#type: "cpu-time",
+            unit: "nanoseconds",
+        };
+
+        let build = |infer_period_from| {
+            let mut profile = Profile::builder()
+                .sample_types(vec![samples, cpu_time])
+                .infer_period_from(infer_period_from)
+                .build();
+            for (i, values) in [vec![1, 10_000_000], vec![3, 30_000_000]]
+                .iter()
+                .enumerate()
+            {
+                let label = api::Label::num("sample", i as i64, None);
+                profile
+                    .add(api::Sample {
+                        locations: vec![],
+                        values: values.clone(),
+                        labels: vec![label],
+                        ..Default::default()
+                    })
+                    .expect("add to succeed");
+            }
+            pprof::Profile::from(&profile)
+        };
+
+        let profile = build(Some(cpu_time));
+        let period_type = profile.period_type.expect("period type to be inferred");
+        assert_eq!(
+            profile.string_table[period_type.r#type as usize],
+            "cpu-time"
+        );
+        assert_eq!(
+            profile.string_table[period_type.unit as usize],
+            "nanoseconds"
+        );
+        assert_eq!(profile.period, 10_000_000);
+
+        let profile = build(None);
+        assert!(profile.period_type.is_none());
+        assert_eq!(profile.period, 0);
+    }
+
+    #[test]
+    fn folded_and_top_functions() {
+        let profile = provide_distinct_locations();
+
+        assert_eq!(
+            profile.to_folded(0).expect("sample type to exist"),
+            "{main} 1\ntest 1\n"
+        );
+        assert!(profile.to_folded(1).is_none());
+
+        let top = profile.top_functions(0, 1).expect("sample type to exist");
+        assert_eq!(top, vec![("test", 1)]);
+        let top = profile.top_functions(0, 10).expect("sample type to exist");
+        assert_eq!(top, vec![("test", 1), ("{main}", 1)]);
+        assert!(profile.top_functions(1, 10).is_none());
+    }
+
+    #[test]
+    fn process_info() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let runtime_id = "2a3b7c1e-5f0d-4c8e-9a6b-1d2e3f405162";
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .process_info(4242, runtime_id)
+            .build();
+        profile
+            .add(api::Sample {
+                locations: vec![],
+                values: vec![1],
+                labels: vec![],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let expected = vec![
+            "process_id:4242".to_owned(),
+            format!("runtime-id:{}", runtime_id),
+        ];
+        for _ in 0..2 {
+            let encoded = profile.serialize().unwrap();
+            let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+            let comments: Vec<&str> = decoded
+                .comment
+                .iter()
+                .map(|&id| decoded.string_table[id as usize].as_str())
+                .collect();
+            assert_eq!(comments, expected);
+            // Applied to the profile rather than to each sample
+            assert!(decoded.sample.iter().all(|sample| sample.label.is_empty()));
+
+            // The process info is kept across resets
+            profile.reset().unwrap();
+        }
+    }
+
+    #[test]
+    fn mapping_debug_files() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let debug_link = "https://debuginfod.example/buildid/c0ffee/debuginfo";
+        let mappings = [
+            api::Mapping {
+                filename: "/usr/sbin/nginx",
+                build_id: "c0ffee",
+                debug_file: debug_link,
+                ..Default::default()
+            },
+            api::Mapping {
+                filename: "/usr/lib/libc.so.6",
+                build_id: "decaf",
+                ..Default::default()
+            },
+        ];
+        // The first mapping is added twice, but only has one comment
+        for mapping in [mappings[0], mappings[1], mappings[0]] {
+            let location = api::Location {
+                mapping,
+                address: 0x1000,
+                ..Default::default()
+            };
+            profile
+                .add(api::Sample {
+                    locations: vec![location],
+                    values: vec![1],
+                    labels: vec![],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let encoded = profile.serialize().unwrap();
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        let string = |id: i64| decoded.string_table[id as usize].as_str();
+        assert_eq!(decoded.mapping.len(), 2);
+        let nginx = &decoded.mapping[0];
+        assert_eq!(string(nginx.filename), "/usr/sbin/nginx");
+        assert_eq!(string(nginx.build_id), "c0ffee");
+
+        let comments: Vec<&str> = decoded.comment.iter().map(|&id| string(id)).collect();
+        assert_eq!(
+            comments,
+            vec![format!("debug_file:{}:{}", nginx.id, debug_link)]
+        );
+        let (mapping_id, debug_file) = comments[0]
+            .strip_prefix("debug_file:")
+            .and_then(|rest| rest.split_once(':'))
+            .unwrap();
+        assert_eq!(mapping_id, "1");
+        assert_eq!(debug_file, debug_link);
+    }
+
+    #[test]
+    fn paired_allocation_values() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "alloc-samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "alloc-space",
+                unit: "bytes",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        assert_eq!(profile.allocation_sample_types(), Some((0, 1)));
+
+        let location = || {
+            api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name: "malloc",
+                    ..Default::default()
+                },
+                line: 0,
+            }])
+        };
+        for (count, bytes) in [(1, 1024), (2, 2048)] {
+            let mut sample = api::Sample::with_allocation(&profile, count, bytes).unwrap();
+            sample.locations = vec![location()];
+            profile.add(sample).unwrap();
+        }
+        let values: Vec<&Vec<i64>> = profile.samples.values().collect();
+        assert_eq!(values, vec![&vec![3, 3072]]);
+
+        let sample = api::Sample {
+            locations: vec![location()],
+            values: vec![0, 1024],
+            labels: vec![],
+            ..Default::default()
+        };
+        assert_eq!(profile.add(sample), Err(AddError::PartialAllocation));
+        let values: Vec<&Vec<i64>> = profile.samples.values().collect();
+        assert_eq!(values, vec![&vec![3, 3072]]);
+
+        // malloc(0) allocates nothing, but is still an allocation
+        let mut sample = api::Sample::with_allocation(&profile, 1, 0).unwrap();
+        sample.locations = vec![location()];
+        profile.add(sample).unwrap();
+        let values: Vec<&Vec<i64>> = profile.samples.values().collect();
+        assert_eq!(values, vec![&vec![4, 3072]]);
+
+        // The indexes are kept on reset
+        profile.reset().unwrap();
+        assert_eq!(profile.allocation_sample_types(), Some((0, 1)));
+
+        // Profiles without both sample types don't pair them
+        let profile = Profile::builder()
+            .sample_types(vec![api::ValueType {
+                r#type: "alloc-space",
+                unit: "bytes",
+            }])
+            .build();
+        assert_eq!(profile.allocation_sample_types(), None);
+        assert!(api::Sample::with_allocation(&profile, 1, 1024).is_none());
+    }
+
+    #[test]
+    fn sample_with_values() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "cpu-time",
+                unit: "nanoseconds",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+        let (samples, cpu, wall) = (0, 1, 2);
+
+        let mut sample = api::Sample::with_values(&profile, &[(wall, 300), (cpu, 200)]).unwrap();
+        assert_eq!(sample.values, vec![0, 200, 300]);
+        sample.locations = vec![api::Location::with_inlined(vec![api::Line {
+            function: api::Function {
+                name: "work",
+                ..Default::default()
+            },
+            line: 0,
+        }])];
+        profile.add(sample).unwrap();
+
+        let sample =
+            api::Sample::with_values(&profile, &[(samples, 1), (wall, 50), (wall, 50)]).unwrap();
+        assert_eq!(sample.values, vec![1, 0, 100]);
+        profile.add(sample).unwrap();
+
+        let values: Vec<&Vec<i64>> = profile.samples.values().collect();
+        assert_eq!(values, vec![&vec![0, 200, 300], &vec![1, 0, 100]]);
+
+        assert_eq!(
+            api::Sample::with_values(&profile, &[(cpu, 1), (3, 1)]).err(),
+            Some(AddError::UnknownSampleType(3))
+        );
+
+        let sample = api::Sample::with_values(
+            &profile,
+            &[(wall, i64::MAX - 1), (wall, 2), (cpu, i64::MIN), (cpu, -1)],
+        )
+        .unwrap();
+        assert_eq!(sample.values, vec![0, i64::MIN, i64::MAX]);
+    }
+
+    #[test]
+    fn breakdown_by_label() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let mut add = |endpoint: Option<&str>, function: &str, values: Vec<i64>| {
+            let labels = endpoint
+                .map(|endpoint| api::Label {
+                    key: "trace endpoint",
+                    str: Some(endpoint),
+                    num: 0,
+                    num_unit: None,
+                })
+                .into_iter()
+                .collect();
+            let location = api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name: function,
+                    ..Default::default()
+                },
+                line: 0,
+            }]);
+            profile
+                .add(api::Sample {
+                    locations: vec![location],
+                    values,
+                    labels,
+                    ..Default::default()
+                })
+                .unwrap();
+        };
+        add(Some("GET /users"), "query", vec![2, 200]);
+        add(Some("GET /users"), "render", vec![1, 100]);
+        add(Some("POST /login"), "hash", vec![4, 50]);
+        add(None, "gc", vec![1, 10]);
+
+        assert_eq!(
+            profile.breakdown_by_label("trace endpoint", 0),
+            vec![
+                ("POST /login".to_owned(), 4),
+                ("GET /users".to_owned(), 3),
+                ("".to_owned(), 1),
+            ]
+        );
+        assert_eq!(
+            profile.breakdown_by_label("trace endpoint", 1),
+            vec![
+                ("GET /users".to_owned(), 300),
+                ("POST /login".to_owned(), 50),
+                ("".to_owned(), 10),
+            ]
+        );
+        assert_eq!(
+            profile.breakdown_by_label("thread id", 0),
+            vec![("".to_owned(), 8)]
+        );
+        assert!(profile.breakdown_by_label("trace endpoint", 2).is_empty());
+    }
+
+    #[test]
+    fn start_time() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let hour = Duration::from_secs(3600);
+        let start_time = SystemTime::now() - hour;
+        let profile = Profile::builder()
+            .sample_types(sample_types)
+            .start_time(start_time)
+            .build();
+
+        let encoded = profile.serialize().unwrap();
+        assert_eq!(encoded.start, start_time);
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        let start_nanos = start_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as i64;
+        assert_eq!(decoded.time_nanos, start_nanos);
+        // The duration covers the hour since, not just since building
+        let duration = Duration::from_nanos(decoded.duration_nanos as u64);
+        assert!(duration >= hour && duration < hour + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn comments() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .process_info(1234, "0f0e0d0c")
+            .comments(vec!["profiler:1.2.3"])
+            .build();
+        profile.add_comment("GOGC=off");
+
+        let comments = |profile: &Profile| -> Vec<String> {
+            let encoded = profile.serialize().unwrap();
+            let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+            decoded
+                .comment
+                .iter()
+                .map(|id| decoded.string_table[*id as usize].clone())
+                .collect()
+        };
+        let expected = [
+            "process_id:1234",
+            "runtime-id:0f0e0d0c",
+            "profiler:1.2.3",
+            "GOGC=off",
+        ];
+        assert_eq!(comments(&profile), expected);
+
+        // Kept across a reset, like the sample types
+        profile.reset().unwrap();
+        assert_eq!(comments(&profile), expected);
+
+        // and a round trip through pprof
+        #[cfg(feature = "decode")]
+        {
+            let encoded = profile.serialize().unwrap();
+            let mut parsed = Profile::from_pprof(&encoded.buffer).unwrap();
+            parsed.reset().unwrap();
+            assert_eq!(comments(&parsed), expected);
+        }
+    }
+
+    #[test]
+    fn drop_frames() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .drop_frames("^ddprof_.*")
+            .build();
+
+        let decode = |profile: &Profile| {
+            let encoded = profile.serialize().unwrap();
+            pprof::Profile::decode(encoded.buffer.as_slice()).unwrap()
+        };
+        let decoded = decode(&profile);
+        assert_eq!(
+            decoded.string_table[decoded.drop_frames as usize],
+            "^ddprof_.*"
+        );
+        assert_eq!(decoded.keep_frames, 0);
+
+        // Kept across a reset and a round trip through pprof
+        profile.reset().unwrap();
+        let decoded = decode(&profile);
+        assert_eq!(
+            decoded.string_table[decoded.drop_frames as usize],
+            "^ddprof_.*"
+        );
+        #[cfg(feature = "decode")]
+        {
+            let encoded = profile.serialize().unwrap();
+            let parsed = Profile::from_pprof(&encoded.buffer).unwrap();
+            assert_eq!(parsed.strings[parsed.drop_frames.0], "^ddprof_.*");
+        }
+
+        // Unset by default
+        let decoded = decode(&provide_distinct_locations());
+        assert_eq!((decoded.drop_frames, decoded.keep_frames), (0, 0));
+    }
+
+    #[test]
+    #[cfg(feature = "decode")]
+    fn from_pprof() {
+        let mut profile = provide_distinct_locations();
+        for comment in ["process_id:1234", "runtime-id:0f0e0d0c"] {
+            let comment = profile.intern(comment);
+            profile.comments.push(comment);
+        }
+        let debug_link = "https://debuginfod.example/buildid/c0ffee/debuginfo";
+        let location = api::Location {
+            mapping: api::Mapping {
+                filename: "/usr/sbin/nginx",
+                build_id: "c0ffee",
+                debug_file: debug_link,
+                ..Default::default()
+            },
+            lines: vec![api::Line {
+                function: api::Function {
+                    name: "ngx_worker",
+                    ..Default::default()
+                },
+                line: 0,
+            }],
+            ..Default::default()
+        };
+        profile
+            .add(api::Sample {
+                locations: vec![location],
+                values: vec![1],
+                labels: vec![],
+                ..Default::default()
+            })
+            .unwrap();
+        let encoded = profile.serialize().unwrap();
+
+        let mut parsed = Profile::from_pprof(&encoded.buffer).unwrap();
+        assert_eq!(parsed.start_time, profile.start_time);
+        assert_eq!(parsed.process_info, Some((1234, "0f0e0d0c".to_owned())));
+        let debug_files: Vec<&str> = parsed
+            .mappings
+            .iter()
+            .map(|mapping| parsed.strings[mapping.debug_file.0].as_str())
+            .collect();
+        assert_eq!(debug_files, vec!["", debug_link]);
+        assert_eq!(parsed.comments, profile.comments);
+        let reencoded = parsed.serialize().unwrap();
+        let decode = |buffer: &[u8]| {
+            let mut decoded = pprof::Profile::decode(buffer).unwrap();
+            // The only thing which moves on is the duration
+            assert!(decoded.duration_nanos >= 0);
+            decoded.duration_nanos = 0;
+            decoded
+        };
+        assert_eq!(decode(&reencoded.buffer), decode(&encoded.buffer));
+
+        // New samples aggregate with the parsed ones
+        let location = api::Location {
+            mapping: api::Mapping {
+                filename: "php",
+                ..Default::default()
+            },
+            lines: vec![api::Line {
+                function: api::Function {
+                    name: "test",
+                    system_name: "test",
+                    filename: "index.php",
+                    start_line: 3,
+                },
+                line: 0,
+            }],
+            ..Default::default()
+        };
+        let id = parsed
+            .add(api::Sample {
+                locations: vec![location],
+                values: vec![2],
+                labels: vec![api::Label::num("pid", 101, None)],
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(id, PProfId(2));
+        assert_eq!(
+            parsed.to_folded(0).unwrap(),
+            "{main} 1\ntest 3\nngx_worker 1\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "decode")]
+    fn from_pprof_invalid_references() {
+        let profile = provide_distinct_locations();
+        let mut pprof: pprof::Profile = (&profile).into();
+        pprof.location[0].line[0].function_id = 42;
+        let mut buffer = Vec::new();
+        pprof.encode(&mut buffer).unwrap();
+        assert!(matches!(
+            Profile::from_pprof(&buffer),
+            Err(crate::decode::DecodeError::CorruptReference {
+                field: "Line.function_id"
+            })
+        ));
+
+        let mut pprof: pprof::Profile = (&profile).into();
+        pprof.sample[0].label[0].key = pprof.string_table.len() as i64;
+        let mut buffer = Vec::new();
+        pprof.encode(&mut buffer).unwrap();
+        assert!(matches!(
+            Profile::from_pprof(&buffer),
+            Err(crate::decode::DecodeError::CorruptReference { field: "Label.key" })
+        ));
+    }
+
+    #[test]
+    fn partition_by_label() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .process_info(1234, "0f0e0d0c")
+            .build();
+
+        let stack = |names: &[&'static str]| -> Vec<api::Location> {
+            names
+                .iter()
+                .map(|name| api::Location {
+                    mapping: api::Mapping {
+                        filename: "app",
+                        debug_file: "app.debug",
+                        ..Default::default()
+                    },
+                    lines: vec![api::Line {
+                        function: api::Function {
+                            name,
+                            ..Default::default()
+                        },
+                        line: 0,
+                    }],
+                    ..Default::default()
+                })
+                .collect()
+        };
+        for (tenant, leaf, value) in [
+            (Some("acme"), "parse", 1),
+            (Some("globex"), "render", 2),
+            (Some("acme"), "parse", 3),
+            (None, "gc", 4),
+            (Some("acme"), "render", 5),
+        ] {
+            profile
+                .add(api::Sample {
+                    locations: stack(&[leaf, "main"]),
+                    values: vec![value],
+                    labels: tenant
+                        .map(|tenant| api::Label::str("tenant", tenant))
+                        .into_iter()
+                        .collect(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let partitions = profile.partition_by_label("tenant");
+        let values: Vec<&str> = partitions.iter().map(|(value, _)| value.as_str()).collect();
+        assert_eq!(values, ["acme", "globex", "(unlabeled)"]);
+        let folded: Vec<String> = partitions
+            .iter()
+            .map(|(_, profile)| profile.to_folded(0).unwrap())
+            .collect();
+        assert_eq!(
+            folded,
+            [
+                "main;parse 4\nmain;render 5\n",
+                "main;render 2\n",
+                "main;gc 4\n"
+            ]
+        );
+
+        for (value, partition) in partitions.iter() {
+            assert_eq!(partition.start_time, profile.start_time);
+            let encoded = partition.serialize().unwrap();
+            let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+            assert_eq!(decoded.sample.len(), partition.samples.len());
+            assert_eq!(decoded.mapping.len(), 1);
+            assert_eq!(decoded.location.len(), decoded.function.len());
+            let strings = &decoded.string_table;
+            assert_eq!(strings[decoded.sample_type[0].r#type as usize], "samples");
+            for sample in decoded.sample.iter() {
+                let tenants: Vec<&str> = sample
+                    .label
+                    .iter()
+                    .map(|label| strings[label.str as usize].as_str())
+                    .collect();
+                let expected: &[&str] = match value.as_str() {
+                    "(unlabeled)" => &[],
+                    tenant => &[tenant],
+                };
+                assert_eq!(tenants, expected);
+            }
+            let comments: Vec<&str> = decoded
+                .comment
+                .iter()
+                .map(|comment| strings[*comment as usize].as_str())
+                .collect();
+            assert_eq!(
+                comments,
+                [
+                    "process_id:1234",
+                    "runtime-id:0f0e0d0c",
+                    "debug_file:1:app.debug"
+                ]
+            );
+        }
+        // Only the items of its own samples are in each profile
+        let (_, globex) = &partitions[1];
+        assert!(!globex.strings.contains("parse"));
+        assert!(!globex.strings.contains("acme"));
+    }
+
+    #[test]
+    fn redact_labels() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        for (user, thread) in [
+            ("alice@example.com", "main"),
+            ("bob@example.com", "main"),
+            ("anonymous", "worker"),
+        ] {
+            let location = api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name: "handle_request",
+                    ..Default::default()
+                },
+                line: 0,
+            }]);
+            profile
+                .add(api::Sample {
+                    locations: vec![location],
+                    values: vec![1],
+                    labels: vec![
+                        api::Label::str("user", user),
+                        api::Label::str("thread name", thread),
+                    ],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let mut calls = 0;
+        profile.redact_labels(|key, value| {
+            calls += 1;
+            if key == "user" && value.contains('@') {
+                Some("[redacted]".to_owned())
+            } else {
+                None
+            }
+        });
+        assert_eq!(calls, 5);
+
+        // The samples of alice and bob were merged
+        let encoded = profile.serialize().unwrap();
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        let string = |id: i64| decoded.string_table[id as usize].as_str();
+        let samples: Vec<_> = decoded
+            .sample
+            .iter()
+            .map(|sample| {
+                let labels: Vec<_> = sample
+                    .label
+                    .iter()
+                    .map(|label| (string(label.key), string(label.str)))
+                    .collect();
+                (labels, sample.value.clone())
+            })
+            .collect();
+        assert_eq!(
+            samples,
+            vec![
+                (
+                    vec![("user", "[redacted]"), ("thread name", "main")],
+                    vec![2]
+                ),
+                (
+                    vec![("user", "anonymous"), ("thread name", "worker")],
+                    vec![1]
+                ),
+            ]
+        );
+        assert!(decoded.string_table.iter().all(|s| !s.contains('@')));
+        assert_eq!(string(decoded.function[0].name), "handle_request");
+        assert_eq!(string(decoded.sample_type[0].r#type), "samples");
+    }
+
+    #[test]
+    fn write_ndjson() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "alloc-space",
+                unit: "bytes",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let function = |name| api::Line {
+            function: api::Function {
+                name,
+                ..Default::default()
+            },
+            line: 0,
+        };
+        profile
+            .add(api::Sample {
+                locations: vec![
+                    api::Location::with_inlined(vec![function("malloc"), function("alloc")]),
+                    api::Location::with_inlined(vec![function("main")]),
+                ],
+                values: vec![1, 512],
+                labels: vec![api::Label {
+                    key: "thread id",
+                    str: None,
+                    num: 7,
+                    num_unit: None,
+                }],
+                ..Default::default()
+            })
+            .unwrap();
+        profile
+            .add(api::Sample {
+                locations: vec![api::Location::with_inlined(vec![function("main")])],
+                values: vec![2, 64],
+                labels: vec![api::Label {
+                    key: "trace endpoint",
+                    str: Some("GET /users"),
+                    num: 0,
+                    num_unit: None,
+                }],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let mut out = Vec::new();
+        profile.write_ndjson(&mut out, 1).unwrap();
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({
+                    "frames": ["main", "alloc", "malloc"],
+                    "labels": [{ "key": "thread id", "num": 7, "num_unit": "" }],
+                    "value": 512,
+                }),
+                serde_json::json!({
+                    "frames": ["main"],
+                    "labels": [{ "key": "trace endpoint", "str": "GET /users" }],
+                    "value": 64,
+                }),
+            ]
+        );
+
+        let err = profile.write_ndjson(&mut Vec::new(), 2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn ignore_location_addresses() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+
+        let sample = |address| api::Sample {
+            locations: vec![api::Location {
+                address,
+                lines: vec![api::Line {
+                    function: api::Function {
+                        name: "phpinfo",
+                        filename: "index.php",
+                        ..Default::default()
+                    },
+                    line: 3,
+                }],
+                ..Default::default()
+            }],
+            values: vec![1],
+            labels: vec![],
+            ..Default::default()
+        };
+
+        for (ignore, expected_samples) in [(false, 2), (true, 1)] {
+            let mut profile = Profile::builder()
+                .sample_types(sample_types.clone())
+                .ignore_location_addresses(ignore)
+                .build();
+            profile.add(sample(0x1000)).expect("add to succeed");
+            profile.add(sample(0x2000)).expect("add to succeed");
+
+            assert_eq!(profile.locations.len(), expected_samples);
+            assert_eq!(profile.samples.len(), expected_samples);
+            if ignore {
+                assert_eq!(profile.samples[0], vec![2]);
+            }
+        }
+    }
+
+    /// A description of a sample which doesn't depend on ids: the stack as
+    /// (mapping filename, address, [(function name, filename, line)]) from the
+    /// leaf, and the labels as (key, str, num, num_unit).
+    type SampleKey = (
+        Vec<(String, u64, Vec<(String, String, i64)>)>,
+        Vec<(String, String, i64, String)>,
+    );
+
+    /// Rebuilds the samples of a decoded profile, checking that every id it
+    /// uses follows the id convention and refers to an existing item.
+    fn decoded_samples(profile: &pprof::Profile) -> std::collections::HashMap<SampleKey, Vec<i64>> {
+        for (index, mapping) in profile.mapping.iter().enumerate() {
+            assert_eq!(mapping.id, index as u64 + 1);
+        }
+        for (index, location) in profile.location.iter().enumerate() {
+            assert_eq!(location.id, index as u64 + 1);
+        }
+        for (index, function) in profile.function.iter().enumerate() {
+            assert_eq!(function.id, index as u64 + 1);
+        }
+
+        let string = |id: i64| profile.string_table[id as usize].clone();
+        let mut samples = std::collections::HashMap::new();
+        for sample in profile.sample.iter() {
+            let stack = sample
+                .location_id
+                .iter()
+                .map(|&id| {
+                    let location = &profile.location[id as usize - 1];
+                    let mapping = match location.mapping_id {
+                        0 => String::new(),
+                        id => string(profile.mapping[id as usize - 1].filename),
+                    };
+                    let lines = location
+                        .line
+                        .iter()
+                        .map(|line| {
+                            let function = &profile.function[line.function_id as usize - 1];
+                            (string(function.name), string(function.filename), line.line)
+                        })
+                        .collect();
+                    (mapping, location.address, lines)
+                })
+                .collect();
+            let labels = sample
+                .label
+                .iter()
+                .map(|l| (string(l.key), string(l.str), l.num, string(l.num_unit)))
+                .collect();
+            let previous = samples.insert((stack, labels), sample.value.clone());
+            assert!(previous.is_none(), "samples must be unique");
+        }
+        samples
+    }
+
+    #[test]
+    fn round_trip() {
+        // A small xorshift generator keeps the test deterministic without
+        // pulling in a dependency.
+        struct Rng(u64);
+        impl Rng {
+            fn below(&mut self, n: usize) -> usize {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                (self.0 % n as u64) as usize
+            }
+        }
+
+        const MAPPINGS: [&str; 3] = ["", "php", "libc.so.6"];
+        const FUNCTIONS: [&str; 5] = ["{main}", "run", "render", "strlen", "malloc"];
+        const FILES: [&str; 3] = ["", "index.php", "string.c"];
+        const LABELS: [&str; 3] = ["pid", "thread name", "span id"];
+
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+
+        for seed in 1..=20u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut profile = Profile::builder()
+                .sample_types(sample_types.clone())
+                .build();
+            let mut expected: std::collections::HashMap<SampleKey, Vec<i64>> =
+                std::collections::HashMap::new();
+
+            for _ in 0..rng.below(50) {
+                let mut stack = Vec::new();
+                let mut locations = Vec::new();
+                for _ in 0..rng.below(4) + 1 {
+                    let mapping = MAPPINGS[rng.below(MAPPINGS.len())];
+                    let address = rng.below(3) as u64 * 0x1000;
+                    let lines: Vec<(&str, &str, i64)> = (0..rng.below(3))
+                        .map(|_| {
+                            (
+                                FUNCTIONS[rng.below(FUNCTIONS.len())],
+                                FILES[rng.below(FILES.len())],
+                                rng.below(3) as i64,
+                            )
+                        })
+                        .collect();
+                    stack.push((
+                        mapping.to_string(),
+                        address,
+                        lines
+                            .iter()
+                            .map(|&(n, f, l)| (n.to_string(), f.to_string(), l))
+                            .collect(),
+                    ));
+                    locations.push(api::Location {
+                        mapping: api::Mapping {
+                            filename: mapping,
+                            ..Default::default()
+                        },
+                        address,
+                        lines: lines
+                            .iter()
+                            .map(|&(name, filename, line)| api::Line {
+                                function: api::Function {
+                                    name,
+                                    filename,
+                                    ..Default::default()
+                                },
+                                line,
+                            })
+                            .collect(),
+                        ..Default::default()
+                    });
+                }
+
+                let labels: Vec<api::Label> = match rng.below(3) {
+                    0 => vec![],
+                    1 => vec![api::Label::str(LABELS[1], FUNCTIONS[rng.below(2)])],
+                    _ => vec![api::Label::num(
+                        LABELS[rng.below(LABELS.len())],
+                        rng.below(3) as i64,
+                        None,
+                    )],
+                };
+                let label_keys = labels
+                    .iter()
+                    .map(|l| {
+                        let str = l.str.unwrap_or_default().to_string();
+                        (l.key.to_string(), str, l.num, String::new())
+                    })
+                    .collect();
+
+                let values = vec![1, rng.below(1000) as i64];
+                let totals = expected
+                    .entry((stack, label_keys))
+                    .or_insert_with(|| vec![0, 0]);
+                totals[0] += values[0];
+                totals[1] += values[1];
+
+                let id = profile
+                    .add(api::Sample {
+                        locations,
+                        values,
+                        labels,
+                        ..Default::default()
+                    })
+                    .expect("add to succeed");
+                let (_, values) = profile.samples.get_index(id.0 - 1).unwrap();
+                assert_eq!(values[0], totals[0], "add must return the sample's id");
+            }
+
+            let encoded = profile.serialize().expect("serialize to succeed");
+            let decoded =
+                pprof::Profile::decode(encoded.buffer.as_slice()).expect("decode to succeed");
+            assert_eq!(decoded.sample.len(), profile.samples.len());
+            assert_eq!(decoded_samples(&decoded), expected, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn inlined_lines() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .validate_lines(true)
+            .build();
+
+        let line = |name, start_line, line| api::Line {
+            function: api::Function {
+                name,
+                filename: "string.h",
+                start_line,
+                ..Default::default()
+            },
+            line,
+        };
+
+        // memcpy is inlined into printf
+        let location =
+            api::Location::with_inlined(vec![line("memcpy", 10, 12), line("printf", 40, 45)]);
+        profile
+            .add(api::Sample {
+                locations: vec![location],
+                values: vec![1],
+                labels: vec![],
+                ..Default::default()
+            })
+            .expect("add to succeed");
+
+        let serialized: pprof::Profile = (&profile).into();
+        let names: Vec<&str> = serialized.location[0]
+            .line
+            .iter()
+            .map(|line| {
+                let function = &serialized.function[line.function_id as usize - 1];
+                serialized.string_table[function.name as usize].as_str()
+            })
+            .collect();
+        assert_eq!(names, vec!["memcpy", "printf"]);
+        assert_eq!(profile.to_folded(0).unwrap(), "printf;memcpy 1\n");
+
+        // A call site before the start of printf can't be right
+        let location =
+            || api::Location::with_inlined(vec![line("memcpy", 10, 12), line("printf", 40, 3)]);
+        assert_eq!(
+            profile.add_location(location()),
+            Err(AddError::ImplausibleLines)
+        );
+
+        // Nothing of a rejected sample is left behind, not even its labels
+        let strings = profile.strings.len();
+        assert_eq!(
+            profile.add(api::Sample {
+                locations: vec![location()],
+                values: vec![1],
+                labels: vec![api::Label::str("thread name", "worker")],
+                ..Default::default()
+            }),
+            Err(AddError::ImplausibleLines)
+        );
+        assert_eq!(profile.strings.len(), strings);
+    }
+
+    #[test]
+    fn empty_stacks() {
+        let sample_types = vec![api::ValueType {
+            r#type: "gc-pause",
+            unit: "nanoseconds",
+        }];
+
+        for enabled in [false, true] {
+            let mut profile = Profile::builder()
+                .sample_types(sample_types.clone())
+                .process_location_for_empty_stacks(enabled)
+                .build();
+            profile
+                .add(api::Sample {
+                    locations: vec![],
+                    values: vec![12000],
+                    labels: vec![api::Label::str("gc reason", "allocation")],
+                    ..Default::default()
+                })
+                .expect("add to succeed");
+
+            let encoded = profile.serialize().expect("serialize to succeed");
+            let decoded =
+                pprof::Profile::decode(encoded.buffer.as_slice()).expect("decode to succeed");
+            assert_eq!(decoded.sample.len(), 1);
+            assert_eq!(decoded.sample[0].value, vec![12000]);
+
+            if enabled {
+                assert_eq!(decoded.sample[0].location_id, vec![1]);
+                let function_id = decoded.location[0].line[0].function_id;
+                let function = &decoded.function[function_id as usize - 1];
+                assert_eq!(decoded.string_table[function.name as usize], "[process]");
+            } else {
+                assert!(decoded.sample[0].location_id.is_empty());
+                assert!(decoded.location.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_stacks() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let location = |name| {
+            api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name,
+                    ..Default::default()
+                },
+                line: 0,
+            }])
+        };
+        for leaf in ["memcpy", "strlen"] {
+            profile
+                .add(api::Sample {
+                    locations: vec![location(leaf), location("parse")],
+                    values: vec![1],
+                    labels: vec![],
+                    truncated: true,
+                    ..Default::default()
+                })
+                .expect("add to succeed");
+        }
+        profile
+            .add(api::Sample {
+                locations: vec![location("parse"), location("main")],
+                values: vec![1],
+                labels: vec![],
+                ..Default::default()
+            })
+            .expect("add to succeed");
+
+        // The marker is the root of the truncated stacks, and is shared
+        assert_eq!(
+            profile.to_folded(0).unwrap(),
+            "[truncated];parse;memcpy 1\n[truncated];parse;strlen 1\nmain;parse 1\n"
+        );
+        assert_eq!(profile.locations.len(), 5);
+    }
+
+    #[test]
+    fn stack_hash_labels() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let location = |name, line| {
+            api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name,
+                    filename: "main.c",
+                    ..Default::default()
+                },
+                line,
+            }])
+        };
+        let sample = |leaf, thread| api::Sample {
+            locations: vec![location(leaf, 10), location("main", 3)],
+            values: vec![1],
+            labels: vec![api::Label::str("thread", thread)],
+            ..Default::default()
+        };
+
+        // Returns the stack hash of each sample, in the order they were added
+        let stack_hashes = |samples: Vec<api::Sample>| -> Vec<i64> {
+            let mut profile = Profile::builder()
+                .sample_types(sample_types.clone())
+                .stack_hash_labels(true)
+                .build();
+            for sample in samples {
+                profile.add(sample).expect("add to succeed");
+            }
+            let encoded = profile.serialize().expect("serialize to succeed");
+            let decoded =
+                pprof::Profile::decode(encoded.buffer.as_slice()).expect("decode to succeed");
+            decoded
+                .sample
+                .iter()
+                .map(|sample| {
+                    let label = sample
+                        .label
+                        .iter()
+                        .find(|label| decoded.string_table[label.key as usize] == "stack_hash")
+                        .expect("stack_hash label");
+                    label.num
+                })
+                .collect()
+        };
+
+        let hashes = stack_hashes(vec![
+            sample("memcpy", "1"),
+            sample("memcpy", "2"),
+            sample("strlen", "1"),
+        ]);
+        assert_eq!(hashes[0], hashes[1]);
+        assert_ne!(hashes[0], hashes[2]);
+
+        // Location ids differ in another profile, the hashes don't
+        let mut other = stack_hashes(vec![sample("strlen", "3"), sample("memcpy", "3")]);
+        other.reverse();
+        assert_eq!(other, vec![hashes[0], hashes[2]]);
+    }
+
+    #[test]
+    fn mark_folded() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        for name in ["vector_size", "string_size", "main"] {
+            let mut location = api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name,
+                    ..Default::default()
+                },
+                line: 0,
+            }]);
+            // Identical code folding gave two functions the same address
+            location.address = if name == "main" { 0x2000 } else { 0x1000 };
+            profile
+                .add(api::Sample {
+                    locations: vec![location],
+                    values: vec![1],
+                    labels: vec![],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        profile.mark_folded(|lines| lines[0].function.name.ends_with("_size"));
+
+        let encoded = profile.serialize().unwrap();
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        let folded: Vec<(u64, bool)> = decoded
+            .location
+            .iter()
+            .map(|location| (location.address, location.is_folded))
+            .collect();
+        assert_eq!(
+            folded,
+            vec![(0x1000, true), (0x1000, true), (0x2000, false)]
+        );
+        assert_eq!(decoded.sample.len(), 3);
+    }
+
+    #[test]
+    fn compatibility_mode() {
+        let sample_types = vec![api::ValueType {
+            r#type: "alloc-space",
+            unit: "bytes",
+        }];
+
+        for mode in [CompatibilityMode::Current, CompatibilityMode::Legacy] {
+            let mut profile = Profile::builder()
+                .sample_types(sample_types.clone())
+                .compatibility_mode(mode)
+                .build();
+            let mut location = api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name: "malloc",
+                    ..Default::default()
+                },
+                line: 0,
+            }]);
+            location.is_folded = true;
+            profile
+                .add(api::Sample {
+                    locations: vec![location],
+                    values: vec![4096],
+                    labels: vec![api::Label::num("allocation size", 4096, Some("bytes"))],
+                    ..Default::default()
+                })
+                .expect("add to succeed");
+
+            let encoded = profile.serialize().expect("serialize to succeed");
+            let decoded =
+                pprof::Profile::decode(encoded.buffer.as_slice()).expect("decode to succeed");
+
+            let legacy = mode == CompatibilityMode::Legacy;
+            let label = &decoded.sample[0].label[0];
+            assert_eq!(label.num_unit == 0, legacy);
+            assert_eq!(decoded.location[0].is_folded, !legacy);
+
+            assert_eq!(decoded.string_table[0], "");
+            assert_eq!(decoded.sample[0].value, vec![4096]);
+            assert_eq!(decoded.sample[0].location_id, vec![1]);
+            assert_eq!(decoded.string_table[label.key as usize], "allocation size");
+            assert_eq!(label.num, 4096);
+            let function_id = decoded.location[0].line[0].function_id;
+            assert_eq!(function_id, 1);
+            let function = &decoded.function[0];
+            assert_eq!(decoded.string_table[function.name as usize], "malloc");
+        }
+    }
+
+    #[test]
+    fn dropped_samples() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .soft_limits(SoftLimits {
+                samples: Some(2),
+                ..Default::default()
+            })
+            .build();
+
+        let sample = |thread: i64| api::Sample {
+            locations: vec![],
+            values: vec![1, 100],
+            labels: vec![api::Label::num("thread id", thread, None)],
+            ..Default::default()
+        };
+        profile.add(sample(1)).expect("add to succeed");
+        profile.add(sample(2)).expect("add to succeed");
+        let strings = profile.strings.len();
+        for thread in 3..6 {
+            assert_eq!(
+                profile.add(sample(thread)),
+                Err(AddError::SoftLimitExceeded {
+                    kind: ItemKind::Samples,
+                    limit: 2
+                })
+            );
+        }
+        // Samples aggregating into existing ones are still accepted
+        profile.add(sample(1)).expect("add to succeed");
+        assert_eq!(profile.strings.len(), strings);
+        assert_eq!(profile.dropped_samples(), 3);
+        assert_eq!(profile.dropped_values(), &[3, 300]);
+
+        let encoded = profile.serialize().unwrap();
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        assert_eq!(decoded.sample.len(), 3);
+        let dropped = decoded.sample.last().unwrap();
+        assert!(dropped.location_id.is_empty());
+        assert_eq!(dropped.value, vec![3, 300]);
+        assert_eq!(
+            decoded.string_table[dropped.label[0].key as usize],
+            "dropped_samples"
+        );
+        assert_eq!(dropped.label[0].num, 3);
+
+        // The counters start over with the next profile
+        profile.reset().unwrap();
+        assert_eq!(profile.dropped_samples(), 0);
+        assert!(profile.dropped_values().is_empty());
+    }
+
+    #[test]
+    fn soft_limits() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .soft_limits(SoftLimits {
+                locations: Some(3),
+                ..Default::default()
+            })
+            .build();
+
+        let names = ["main", "parse", "memcpy", "strlen"];
+        let sample = |leaf: usize| api::Sample {
+            locations: (0..=leaf)
+                .rev()
+                .map(|index| {
+                    api::Location::with_inlined(vec![api::Line {
+                        function: api::Function {
+                            name: names[index],
+                            ..Default::default()
+                        },
+                        line: 0,
+                    }])
+                })
+                .collect(),
+            values: vec![1],
+            labels: vec![],
+            ..Default::default()
+        };
+
+        // main;parse;memcpy uses up the three locations
+        profile.add(sample(2)).expect("add to succeed");
+        profile.add(sample(1)).expect("add to succeed");
+        assert_eq!(profile.locations.len(), 3);
+
+        // A fourth one is refused, and nothing of it is kept
+        let strings = profile.strings.len();
+        let functions = profile.functions.len();
+        assert_eq!(
+            profile.add(sample(3)),
+            Err(AddError::SoftLimitExceeded {
+                kind: ItemKind::Locations,
+                limit: 3
+            })
+        );
+        assert_eq!(profile.locations.len(), 3);
+        assert_eq!(profile.functions.len(), functions);
+        assert_eq!(profile.strings.len(), strings);
+        assert_eq!(profile.samples.len(), 2);
+
+        // Samples within the limit can still be added, and the limit carries
+        // over a reset
+        profile.add(sample(2)).expect("add to succeed");
+        profile.reset().expect("reset to succeed");
+        profile.add(sample(2)).expect("add to succeed");
+        assert!(profile.add(sample(3)).is_err());
+    }
+
+    #[test]
+    fn location_ids() {
+        let sample_types = vec![api::ValueType {
             r#type: "samples",
             unit: "count",
         }];
-        let mut profiles = Profile::builder().sample_types(sample_types).build();
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let main = profile
+            .add_location(api::Location {
+                lines: vec![api::Line {
+                    function: api::Function {
+                        name: "{main}",
+                        filename: "index.php",
+                        ..Default::default()
+                    },
+                    line: 0,
+                }],
+                ..Default::default()
+            })
+            .expect("add to succeed");
+
+        let sample = |location_ids| api::Sample {
+            locations: vec![],
+            values: vec![1],
+            labels: vec![],
+            location_ids,
+            ..Default::default()
+        };
+        let id1 = profile.add(sample(vec![main])).expect("add to succeed");
+        let id2 = profile.add(sample(vec![main])).expect("add to succeed");
+
+        assert_eq!(id1, id2);
+        assert_eq!(profile.locations.len(), 1);
+        assert_eq!(profile.samples.len(), 1);
+        assert_eq!(profile.samples[0], vec![2]);
+
+        // Inline locations come first, then the referenced ones
+        let leaf = api::Location {
+            address: 0x1000,
+            ..Default::default()
+        };
+        profile
+            .add(api::Sample {
+                locations: vec![leaf],
+                ..sample(vec![main])
+            })
+            .expect("add to succeed");
+        let (stack, _) = profile.samples.get_index(1).unwrap();
+        assert_eq!(stack.locations, vec![PProfId(2), main]);
+
+        // Nothing of a rejected sample is left behind, not even its labels
+        let strings = profile.strings.len();
+        let labels = vec![api::Label::str("thread name", "worker")];
+        assert_eq!(
+            profile.add(api::Sample {
+                labels: labels.clone(),
+                ..sample(vec![main, PProfId(3)])
+            }),
+            Err(AddError::InvalidLocationId(PProfId(3)))
+        );
+        assert_eq!(
+            profile.add(api::Sample {
+                labels,
+                ..sample(vec![PProfId(0)])
+            }),
+            Err(AddError::InvalidLocationId(PProfId(0)))
+        );
+        assert_eq!(profile.samples.len(), 2);
+        assert_eq!(profile.strings.len(), strings);
+    }
+
+    #[test]
+    fn add_all() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let names: Vec<String> = (0..10).map(|i| format!("f{}", i)).collect();
+        let samples: Vec<_> = (0..1000)
+            .map(|i| api::Sample {
+                locations: vec![api::Location::with_inlined(vec![api::Line {
+                    function: api::Function {
+                        name: &names[i % 10],
+                        ..Default::default()
+                    },
+                    line: 0,
+                }])],
+                values: vec![i as i64],
+                labels: vec![],
+                ..Default::default()
+            })
+            .collect();
+        profile.add_all(&samples).expect("add to succeed");
+
+        assert_eq!(profile.samples.len(), 10);
+        assert_eq!(profile.locations.len(), 10);
+        for (i, (_, values)) in profile.samples.iter().enumerate() {
+            // i + (i + 10) + ... + (i + 990)
+            assert_eq!(values, &vec![100 * i as i64 + 49_500]);
+        }
+
+        let bad = api::Sample {
+            locations: vec![],
+            values: vec![1, 2],
+            labels: vec![],
+            ..Default::default()
+        };
+        let mut batch: Vec<_> = samples.into_iter().take(3).collect();
+        batch.insert(2, bad);
+        assert_eq!(
+            profile.add_all(&batch),
+            Err(AddError::InBatch {
+                index: 2,
+                error: Box::new(AddError::ValueCountMismatch {
+                    expected: 1,
+                    actual: 2
+                })
+            })
+        );
+        // The samples before the bad one were added
+        assert_eq!(profile.samples[0], vec![49_500]);
+        assert_eq!(profile.samples[1], vec![49_601]);
+        assert_eq!(profile.samples[2], vec![49_700]);
+    }
+
+    #[test]
+    fn add_with_timestamp() {
+        let sample_types = vec![api::ValueType {
+            r#type: "wall-time",
+            unit: "nanoseconds",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let sample = || api::Sample {
+            locations: vec![api::Location::with_inlined(vec![api::Line {
+                function: api::Function {
+                    name: "{main}",
+                    ..Default::default()
+                },
+                line: 0,
+            }])],
+            values: vec![10],
+            labels: vec![api::Label::num("thread id", 1, None)],
+            ..Default::default()
+        };
+        let id1 = profile
+            .add_with_timestamp(sample(), 1_000)
+            .expect("add to succeed");
+        let id2 = profile
+            .add_with_timestamp(sample(), 2_000)
+            .expect("add to succeed");
+        assert_ne!(id1, id2);
+        let id3 = profile
+            .add_with_timestamp(sample(), 2_000)
+            .expect("add to succeed");
+        assert_eq!(id2, id3);
+        let id4 = profile.add(sample()).expect("add to succeed");
+        assert_ne!(id4, id1);
+        assert_ne!(id4, id2);
+        assert_eq!(profile.samples.len(), 3);
+
+        let encoded = profile.serialize().expect("serialize to succeed");
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        let key = decoded
+            .string_table
+            .iter()
+            .position(|s| s == "end_timestamp_ns")
+            .expect("key to be interned") as i64;
+        let timestamps: Vec<_> = decoded
+            .sample
+            .iter()
+            .map(|sample| {
+                let value = sample.value[0];
+                let label = sample.label.iter().find(|label| label.key == key);
+                (label.map(|label| label.num), value)
+            })
+            .collect();
+        assert_eq!(
+            timestamps,
+            vec![(Some(1_000), 10), (Some(2_000), 20), (None, 10)]
+        );
+    }
+
+    #[test]
+    fn container_max() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        // Functions differing only by their start line, so strings don't
+        // run out first
+        let function = |start_line| api::Function {
+            name: "f",
+            start_line,
+            ..Default::default()
+        };
+        let location = |start_line| api::Location {
+            lines: vec![api::Line {
+                function: function(start_line),
+                line: 0,
+            }],
+            ..Default::default()
+        };
+        let sample = |locations| api::Sample {
+            locations,
+            values: vec![1],
+            labels: vec![],
+            ..Default::default()
+        };
+        let max = CONTAINER_MAX as i64;
+        profile
+            .add(sample(vec![location(0)]))
+            .expect("add to succeed");
+        for start_line in 1..max - 2 {
+            profile
+                .add_function(&function(start_line))
+                .expect("add to succeed");
+        }
+        assert_eq!(profile.functions.len(), CONTAINER_MAX - 2);
+
+        // There is room for two more functions but not three, and nothing of
+        // the sample is kept
+        let before = profile.item_counts();
+        let samples = profile.samples.len();
+        let full = sample(vec![location(max - 2), location(max - 1), location(max)]);
+        assert_eq!(profile.add(full), Err(AddError::Full));
+        assert_eq!(profile.item_counts(), before);
+        assert_eq!(profile.samples.len(), samples);
+
+        let last = profile
+            .add_function(&function(max - 2))
+            .expect("add to succeed");
+        assert_eq!(last, FunctionId(PProfId(CONTAINER_MAX - 1)));
+        profile
+            .add_function(&function(max - 1))
+            .expect("add to succeed");
+        assert!(profile.add_function(&function(max)).is_err());
+
+        // Locations are limited too
+        let mut profile = Profile::builder()
+            .sample_types(vec![api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            }])
+            .build();
+        for address in 1..=CONTAINER_MAX as u64 {
+            profile
+                .add_location(api::Location {
+                    address,
+                    ..Default::default()
+                })
+                .expect("add to succeed");
+        }
+        let before = profile.item_counts();
+        let location = api::Location {
+            address: 0,
+            ..Default::default()
+        };
+        assert_eq!(profile.add_location(location), Err(AddError::Full));
+        assert_eq!(profile.item_counts(), before);
+    }
+
+    #[test]
+    fn value_count_mismatch() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let result = profile.add(api::Sample {
+            locations: vec![],
+            values: vec![1],
+            labels: vec![],
+            ..Default::default()
+        });
+        assert_eq!(
+            result,
+            Err(AddError::ValueCountMismatch {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert!(profile.samples.is_empty());
+    }
+
+    #[test]
+    fn add_mapping_and_function() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let vdso = api::Mapping {
+            memory_start: 0x7fff0000,
+            memory_limit: 0x7fff2000,
+            filename: "[vdso]",
+            ..Default::default()
+        };
+        let libc = api::Mapping {
+            filename: "/usr/lib/libc.so.6",
+            ..vdso
+        };
+        let vdso_id = profile.add_mapping(&vdso).expect("add to succeed");
+        let libc_id = profile.add_mapping(&libc).expect("add to succeed");
+        assert_ne!(vdso_id, libc_id);
+        assert_eq!(profile.add_mapping(&vdso).expect("add to succeed"), vdso_id);
+        assert_eq!(profile.mappings.len(), 2);
+
+        let function = api::Function {
+            name: "clock_gettime",
+            ..Default::default()
+        };
+        let function_id = profile.add_function(&function).expect("add to succeed");
+        assert_eq!(
+            profile.add_function(&function).expect("add to succeed"),
+            function_id
+        );
+        assert_eq!(profile.functions.len(), 1);
+
+        let location = |address| api::MappedLocation {
+            mapping_id: vdso_id,
+            address,
+            lines: vec![api::Line { function, line: 0 }],
+            is_folded: false,
+        };
+        let id1 = profile
+            .add_mapped_location(location(0x7fff0100))
+            .expect("add to succeed");
+        let id2 = profile
+            .add_mapped_location(location(0x7fff0100))
+            .expect("add to succeed");
+        assert_eq!(id1, id2);
+        assert_eq!(profile.mappings.len(), 2);
+        assert_eq!(profile.functions.len(), 1);
+        let added = profile.locations.get_index(id1.0 - 1).unwrap();
+        assert_eq!(added.mapping_id, vdso_id.0);
+        assert_eq!(added.lines[0].function_id, function_id.0);
+
+        // Ids from before a reset refer to mappings which are gone
+        profile.reset().expect("reset to succeed");
+        assert_eq!(
+            profile.add_mapped_location(location(0x7fff0100)),
+            Err(AddError::InvalidMappingId(vdso_id))
+        );
+        assert!(profile.locations.is_empty());
+    }
+
+    #[test]
+    fn summary() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let samples = [
+            ("a", vec![1, 100]),
+            ("b", vec![2, i64::MAX]),
+            ("a", vec![3, 50]),
+        ];
+        for (name, values) in samples.iter() {
+            let sample = api::Sample {
+                locations: vec![api::Location {
+                    lines: vec![api::Line {
+                        function: api::Function {
+                            name,
+                            ..Default::default()
+                        },
+                        line: 0,
+                    }],
+                    ..Default::default()
+                }],
+                values: values.clone(),
+                labels: vec![],
+                ..Default::default()
+            };
+            profile.add(sample).expect("add to succeed");
+        }
+
+        assert_eq!(
+            profile.summary(),
+            ProfileSummary {
+                sample_types: vec![
+                    SampleTypeSummary {
+                        r#type: "samples",
+                        unit: "count",
+                        total: 6,
+                    },
+                    SampleTypeSummary {
+                        r#type: "wall-time",
+                        unit: "nanoseconds",
+                        total: i64::MAX,
+                    },
+                ],
+                sample_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn resymbolize() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let sample = |addresses: &[u64]| api::Sample {
+            locations: addresses
+                .iter()
+                .map(|&address| api::Location {
+                    address,
+                    lines: vec![api::Line {
+                        function: api::Function {
+                            name: "[unknown]",
+                            ..Default::default()
+                        },
+                        line: 0,
+                    }],
+                    ..Default::default()
+                })
+                .collect(),
+            values: vec![1],
+            labels: vec![],
+            ..Default::default()
+        };
+        profile.add(sample(&[0x1010, 0x2000])).unwrap();
+        profile.add(sample(&[0x1020, 0x2000])).unwrap();
+        profile.add(sample(&[0x3000])).unwrap();
+        assert_eq!(profile.functions.len(), 1);
+
+        // 0x1010 and 0x1020 are in the same function, 0x3000 is unknown
+        profile.resymbolize(|address| match address {
+            0x1000..=0x1fff => Some(api::ResolvedSymbol {
+                name: "handle",
+                filename: "server.c",
+                line: (address - 0x1000) as i64,
+                ..Default::default()
+            }),
+            0x2000 => Some(api::ResolvedSymbol {
+                name: "main",
+                filename: "main.c",
+                line: 12,
+                ..Default::default()
+            }),
+            _ => None,
+        });
+
+        // The placeholder is still used by 0x3000
+        assert_eq!(profile.functions.len(), 3);
+        assert_eq!(profile.locations.len(), 4);
+        assert_eq!(profile.samples.len(), 3);
+        assert_eq!(
+            profile.to_folded(0).unwrap(),
+            "main;handle 2\n[unknown] 1\n"
+        );
+
+        let names: Vec<&str> = profile
+            .functions
+            .iter()
+            .map(|f| profile.strings[f.name.0].as_str())
+            .collect();
+        assert_eq!(names, vec!["[unknown]", "handle", "main"]);
+        let handle = profile.strings.get_index_of("handle").unwrap();
+        assert_eq!(
+            profile
+                .locations
+                .iter()
+                .filter(|l| l.lines[0].function_id == PProfId(2))
+                .map(|l| l.lines[0].line)
+                .collect::<Vec<_>>(),
+            vec![16, 32]
+        );
+        assert_eq!(profile.functions[1].name, PProfId(handle));
+
+        // Once nothing uses the placeholder anymore, it is removed
+        profile.resymbolize(|_| {
+            Some(api::ResolvedSymbol {
+                name: "main",
+                filename: "main.c",
+                line: 12,
+                ..Default::default()
+            })
+        });
+        assert_eq!(profile.functions.len(), 1);
+        assert_eq!(profile.locations.len(), 4);
+    }
+
+    #[test]
+    fn symbolize_pending() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        let location = |filename, address| api::Location {
+            mapping: api::Mapping {
+                memory_start: 0x1000,
+                memory_limit: 0x9000,
+                filename,
+                ..Default::default()
+            },
+            address,
+            ..Default::default()
+        };
+        let mut sample = |locations| {
+            profile
+                .add(api::Sample {
+                    locations,
+                    values: vec![1],
+                    labels: vec![],
+                    ..Default::default()
+                })
+                .unwrap()
+        };
+        sample(vec![location("app", 0x1010), location("app", 0x2000)]);
+        sample(vec![location("libc.so", 0x5000), location("app", 0x2000)]);
+        assert!(profile.functions.is_empty());
+
+        // The stub knows app, with work inlined in handle, but not libc
+        let mut calls = vec![];
+        profile.symbolize_pending(|mapping, address| {
+            calls.push((mapping.filename.to_owned(), address));
+            let symbol = |name, line| api::ResolvedSymbol {
+                name,
+                filename: "app.c",
+                line,
+                ..Default::default()
+            };
+            match (mapping.filename, address) {
+                ("app", 0x1010) => vec![symbol("work", 7), symbol("handle", 20)],
+                ("app", 0x2000) => vec![symbol("main", 3)],
+                _ => vec![],
+            }
+        });
+        calls.sort();
+        assert_eq!(
+            calls,
+            [
+                ("app".to_owned(), 0x1010),
+                ("app".to_owned(), 0x2000),
+                ("libc.so".to_owned(), 0x5000)
+            ]
+        );
+
+        let encoded = profile.serialize().unwrap();
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        let lines = |address: u64| -> Vec<(String, i64)> {
+            let location = decoded
+                .location
+                .iter()
+                .find(|location| location.address == address)
+                .unwrap();
+            location
+                .line
+                .iter()
+                .map(|line| {
+                    let function = &decoded.function[line.function_id as usize - 1];
+                    (
+                        decoded.string_table[function.name as usize].clone(),
+                        line.line,
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(
+            lines(0x1010),
+            [("work".to_owned(), 7), ("handle".to_owned(), 20)]
+        );
+        assert_eq!(lines(0x2000), [("main".to_owned(), 3)]);
+        assert!(lines(0x5000).is_empty());
+
+        // Only the pending locations are resolved again
+        let mut calls = 0;
+        profile.symbolize_pending(|_, _| {
+            calls += 1;
+            vec![]
+        });
+        assert_eq!(calls, 1);
+        assert_eq!(profile.samples.len(), 2);
+    }
+
+    #[test]
+    fn coalesce_functions() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let mut profile = Profile::builder().sample_types(sample_types).build();
+
+        // Two placeholders for the same address, so two locations
+        for name in ["[unknown]", "[unresolved]"] {
+            let location = api::Location {
+                address: 0x1010,
+                lines: vec![api::Line {
+                    function: api::Function {
+                        name,
+                        ..Default::default()
+                    },
+                    line: 0,
+                }],
+                ..Default::default()
+            };
+            profile
+                .add(api::Sample {
+                    locations: vec![location],
+                    values: vec![1],
+                    labels: vec![],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
 
-        /* There have been 3 strings: "", "samples", and "count". Since the interning index starts at
-         * zero, this means the next string will be 3.
-         */
-        const EXPECTED_ID: PProfId = PProfId(3);
+        // Only the first lookup comes with the mangled name
+        let mut system_names = vec!["", "_Z6handlev"];
+        profile.resymbolize(|_| {
+            Some(api::ResolvedSymbol {
+                name: "handle",
+                system_name: system_names.pop().unwrap(),
+                filename: "server.c",
+                line: 16,
+            })
+        });
+        assert_eq!(profile.functions.len(), 2);
+        assert_eq!(profile.locations.len(), 2);
+        assert_eq!(profile.samples.len(), 2);
 
-        let string = "a";
-        let id1 = profiles.intern(string);
-        let id2 = profiles.intern(string);
+        // The system name tells them apart
+        profile.coalesce_functions(|function| {
+            (
+                function.name.to_owned(),
+                function.system_name.to_owned(),
+                function.filename.to_owned(),
+            )
+        });
+        assert_eq!(profile.functions.len(), 2);
+        assert_eq!(profile.locations.len(), 2);
+        assert_eq!(profile.samples.len(), 2);
 
-        assert_eq!(id1, id2);
-        assert_eq!(id1, EXPECTED_ID);
+        profile.coalesce_functions(|function| {
+            (function.name.to_owned(), function.filename.to_owned())
+        });
+        assert_eq!(profile.functions.len(), 1);
+        assert_eq!(profile.locations.len(), 1);
+        assert_eq!(profile.samples.len(), 1);
+        assert_eq!(profile.to_folded(0).unwrap(), "handle 2\n");
+        let function = &profile.functions[0];
+        assert_eq!(profile.strings[function.system_name.0], "_Z6handlev");
+        assert_eq!(profile.locations[0].lines[0].function_id, PProfId(1));
     }
 
     #[test]
-    fn api() {
+    fn omit_zero_samples() {
         let sample_types = vec![
             api::ValueType {
                 r#type: "samples",
@@ -635,163 +6265,87 @@ mod api_test {
             },
         ];
 
-        let mapping = api::Mapping {
-            filename: "php",
-            ..Default::default()
-        };
-
-        let index = api::Function {
-            filename: "index.php",
-            ..Default::default()
-        };
+        for (omit, expected_samples) in [(false, 2), (true, 1)] {
+            let mut profile = Profile::builder()
+                .sample_types(sample_types.clone())
+                .omit_zero_samples(omit)
+                .build();
 
-        let locations = vec![
-            api::Location {
-                mapping,
-                lines: vec![api::Line {
-                    function: api::Function {
-                        name: "phpinfo",
-                        system_name: "phpinfo",
-                        filename: "index.php",
-                        start_line: 0,
-                    },
-                    line: 0,
-                }],
-                ..Default::default()
-            },
-            api::Location {
-                mapping,
-                lines: vec![api::Line {
-                    function: index,
-                    line: 3,
-                }],
-                ..Default::default()
-            },
-        ];
+            for (name, values) in [("idle", vec![0, 0]), ("work", vec![0, 10000])] {
+                let sample = api::Sample {
+                    locations: vec![api::Location {
+                        lines: vec![api::Line {
+                            function: api::Function {
+                                name,
+                                ..Default::default()
+                            },
+                            line: 0,
+                        }],
+                        ..Default::default()
+                    }],
+                    values,
+                    labels: vec![],
+                    ..Default::default()
+                };
+                profile.add(sample).expect("add to succeed");
+            }
 
-        let mut profile = Profile::builder().sample_types(sample_types).build();
-        let sample_id = profile
-            .add(api::Sample {
-                locations,
-                values: vec![1, 10000],
-                labels: vec![],
-            })
-            .expect("add to succeed");
+            let serialized: pprof::Profile = (&profile).into();
+            assert_eq!(serialized.sample.len(), expected_samples);
+            assert!(serialized.sample.iter().any(|s| s.value == vec![0, 10000]));
 
-        assert_eq!(sample_id, PProfId(1));
+            // The profile itself still holds both samples
+            assert_eq!(profile.samples.len(), 2);
+        }
     }
 
-    fn provide_distinct_locations() -> crate::Profile {
+    #[test]
+    fn no_mappings() {
         let sample_types = vec![api::ValueType {
             r#type: "samples",
             unit: "count",
         }];
 
-        let main_lines = vec![api::Line {
-            function: api::Function {
-                name: "{main}",
-                system_name: "{main}",
-                filename: "index.php",
-                start_line: 0,
-            },
-            line: 0,
-        }];
-
-        let test_lines = vec![api::Line {
-            function: api::Function {
-                name: "test",
-                system_name: "test",
-                filename: "index.php",
-                start_line: 3,
-            },
-            line: 0,
-        }];
-
-        let mapping = api::Mapping {
-            filename: "php",
-            ..Default::default()
-        };
-
-        let main_locations = vec![api::Location {
-            mapping,
-            lines: main_lines,
-            ..Default::default()
-        }];
-        let test_locations = vec![api::Location {
-            mapping,
-            lines: test_lines,
-            ..Default::default()
-        }];
-        let values: Vec<i64> = vec![1];
-        let labels = vec![api::Label {
-            key: "pid",
-            num: 101,
+        let sample = |filename| api::Sample {
+            locations: vec![api::Location {
+                mapping: api::Mapping {
+                    filename,
+                    ..Default::default()
+                },
+                lines: vec![api::Line {
+                    function: api::Function {
+                        name: "phpinfo",
+                        filename: "index.php",
+                        ..Default::default()
+                    },
+                    line: 3,
+                }],
+                ..Default::default()
+            }],
+            values: vec![1],
+            labels: vec![],
             ..Default::default()
-        }];
-
-        let main_sample = api::Sample {
-            locations: main_locations,
-            values: values.clone(),
-            labels: labels.clone(),
-        };
-
-        let test_sample = api::Sample {
-            locations: test_locations,
-            values,
-            labels,
         };
 
-        let mut profile = Profile::builder().sample_types(sample_types).build();
-
-        let sample_id1 = profile.add(main_sample).expect("profile to not be full");
-        assert_eq!(sample_id1, PProfId(1));
-
-        let sample_id2 = profile.add(test_sample).expect("profile to not be full");
-        assert_eq!(sample_id2, PProfId(2));
-
-        profile
-    }
-
-    #[test]
-    fn impl_from_profile_for_pprof_profile() {
-        let profile: pprof::Profile = (&provide_distinct_locations()).into();
-
-        assert_eq!(profile.sample.len(), 2);
-        assert_eq!(profile.mapping.len(), 1);
-        assert_eq!(profile.location.len(), 2);
-        assert_eq!(profile.function.len(), 2);
-
-        for (index, mapping) in profile.mapping.iter().enumerate() {
-            assert_eq!((index + 1) as u64, mapping.id);
-        }
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .no_mappings()
+            .build();
+        profile.add(sample("php")).expect("add to succeed");
+        profile.add(sample("libphp.so")).expect("add to succeed");
 
-        for (index, location) in profile.location.iter().enumerate() {
-            assert_eq!((index + 1) as u64, location.id);
-        }
+        assert!(profile.mappings.is_empty());
+        assert_eq!(profile.locations.len(), 1);
+        assert_eq!(profile.samples.len(), 1);
+        assert_eq!(profile.samples[0], vec![2]);
 
-        for (index, function) in profile.function.iter().enumerate() {
-            assert_eq!((index + 1) as u64, function.id);
-        }
+        let serialized: pprof::Profile = (&profile).into();
+        assert!(serialized.mapping.is_empty());
+        assert_eq!(serialized.location[0].mapping_id, 0);
 
-        let sample = profile.sample.get(0).expect("index 0 to exist");
-        assert_eq!(sample.label.len(), 1);
-        let label = sample.label.get(0).expect("index 0 to exist");
-        let key = profile
-            .string_table
-            .get(label.key as usize)
-            .expect("index to exist");
-        let str = profile
-            .string_table
-            .get(label.str as usize)
-            .expect("index to exist");
-        let num_unit = profile
-            .string_table
-            .get(label.num_unit as usize)
-            .expect("index to exist");
-        assert_eq!(key, "pid");
-        assert_eq!(label.num, 101);
-        assert_eq!(str, "");
-        assert_eq!(num_unit, "");
+        profile.reset().expect("reset to succeed");
+        profile.add(sample("php")).expect("add to succeed");
+        assert!(profile.mappings.is_empty());
     }
 
     #[test]
@@ -831,6 +6385,289 @@ mod api_test {
         assert!(profile.started_at >= prev.started_at);
     }
 
+    #[test]
+    fn serialize_with_checksum() {
+        let profile = provide_distinct_locations();
+        let (encoded, checksum) = profile.serialize_with_checksum().unwrap();
+        assert!(crate::verify_checksum(&encoded.buffer, checksum));
+        // The check value of CRC-32, for the usual variant
+        assert!(crate::verify_checksum(b"123456789", 0xcbf4_3926));
+
+        let mut corrupted = encoded.buffer.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0x10;
+        assert!(!crate::verify_checksum(&corrupted, checksum));
+        assert!(!crate::verify_checksum(
+            &encoded.buffer[..encoded.buffer.len() - 1],
+            checksum
+        ));
+    }
+
+    #[test]
+    fn serialize_compressed() {
+        use std::io::Read;
+
+        let profile = provide_distinct_locations();
+        let encoded = profile.serialize_compressed(6).unwrap();
+        assert_eq!(encoded.start, profile.start_time);
+        assert!(encoded.end >= encoded.start);
+        assert_eq!(&encoded.buffer[..2], &[0x1f, 0x8b]);
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(encoded.buffer.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        let decoded = pprof::Profile::decode(decompressed.as_slice()).unwrap();
+        let mut expected: pprof::Profile = (&profile).into();
+        expected.duration_nanos = decoded.duration_nanos;
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn serialize_with_transform() {
+        use std::io;
+
+        let xor = |buffer: Vec<u8>| -> io::Result<Vec<u8>> {
+            Ok(buffer.into_iter().map(|byte| byte ^ 0x5a).collect())
+        };
+
+        let profile = provide_distinct_locations();
+        let encoded = profile.serialize_with_transform(xor).unwrap();
+        assert_eq!(encoded.start, profile.start_time);
+        assert!(encoded.end >= encoded.start);
+        assert_eq!(encoded.samples_count, 2);
+
+        // Undoing the transform gives back the profile
+        let decoded = pprof::Profile::decode(xor(encoded.buffer).unwrap().as_slice()).unwrap();
+        let mut expected: pprof::Profile = (&profile).into();
+        expected.duration_nanos = decoded.duration_nanos;
+        assert_eq!(decoded, expected);
+
+        let err = match profile.serialize_with_transform(|_| {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "no key"))
+        }) {
+            Ok(_) => panic!("the transform to fail"),
+            Err(err) => err,
+        };
+        assert!(matches!(err, SerializeError::Transform(_)));
+        assert_eq!(err.to_string(), "cannot transform profile: no key");
+    }
+
+    #[test]
+    fn serialize_into() {
+        let sample_types = vec![
+            api::ValueType {
+                r#type: "samples",
+                unit: "count",
+            },
+            api::ValueType {
+                r#type: "wall-time",
+                unit: "nanoseconds",
+            },
+        ];
+        let mut profile = Profile::builder()
+            .sample_types(sample_types.clone())
+            .period(Some(api::Period {
+                r#type: sample_types[1],
+                value: 10_000_000,
+            }))
+            .process_info(1234, "0f0e0d0c")
+            .drop_frames("^runtime\\.")
+            .keep_frames("^main\\.")
+            .default_sample_type("wall-time")
+            .build();
+        profile.add_comment("deployment: canary");
+        let mapping = api::Mapping {
+            memory_start: 0x1000,
+            memory_limit: 0x2000,
+            file_offset: 0x100,
+            filename: "/usr/bin/app",
+            build_id: "c0ffee",
+            ..Default::default()
+        };
+        for (name, address, value) in [("main.main", 0x1010, 10), ("main.work", 0x1020, 20)] {
+            let location = api::Location {
+                mapping,
+                address,
+                lines: vec![api::Line {
+                    function: api::Function {
+                        name,
+                        system_name: name,
+                        filename: "main.go",
+                        start_line: 1,
+                    },
+                    line: 3,
+                }],
+                ..Default::default()
+            };
+            profile
+                .add(api::Sample {
+                    locations: vec![location],
+                    values: vec![1, value],
+                    labels: vec![
+                        api::Label::str("thread name", "main"),
+                        api::Label::num("bytes", 64, Some("bytes")),
+                    ],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        profile.mark_folded(|lines| lines[0].function.name == "main.work");
+        profile.record_dropped(&[1, 30]);
+
+        let now = Instant::now();
+        let pprof = profile.to_pprof(now);
+        assert!(pprof.mapping.iter().all(|m| m.build_id != 0));
+        assert!(pprof.location.iter().any(|l| l.is_folded));
+        assert!(pprof.function.iter().all(|f| f.system_name != 0));
+        assert!(pprof
+            .sample
+            .iter()
+            .any(|s| s.label.iter().any(|l| l.num_unit != 0)));
+        assert!(pprof.period != 0 && pprof.period_type.is_some());
+        assert!(pprof.time_nanos != 0 && pprof.duration_nanos != 0);
+        assert!(pprof.drop_frames != 0 && pprof.keep_frames != 0);
+        assert!(pprof.default_sample_type != 0 && pprof.comment.len() > 1);
+        assert_eq!(pprof.string_table.last().unwrap(), "dropped_samples");
+        let mut expected = Vec::new();
+        pprof.encode(&mut expected).unwrap();
+
+        let mut buffer = Vec::new();
+        profile.encode_into(now, &mut buffer).unwrap();
+        assert_eq!(buffer, expected);
+
+        // serialize goes through serialize_into, only the duration differs
+        let mut buffer = Vec::new();
+        let (start, end) = profile.serialize_into(&mut buffer).unwrap();
+        assert_eq!(start, profile.start_time);
+        assert!(end >= start);
+        let mut decoded = pprof::Profile::decode(buffer.as_slice()).unwrap();
+        let serialized = profile.serialize().unwrap();
+        let mut reference = pprof::Profile::decode(serialized.buffer.as_slice()).unwrap();
+        decoded.duration_nanos = 0;
+        reference.duration_nanos = 0;
+        assert_eq!(decoded, reference);
+    }
+
+    #[test]
+    fn encoded_len() {
+        // A profile which just started has a duration of microseconds, whose
+        // varint can take a byte more between the two calls, but not one
+        // which started an hour ago.
+        let an_hour_ago = || SystemTime::now() - Duration::from_secs(3600);
+        let mut profile = provide_distinct_locations();
+        profile.set_start_time(an_hour_ago());
+        let encoded = profile.serialize().unwrap();
+        assert_eq!(profile.encoded_len(), encoded.buffer.len());
+
+        // Comments and dropped samples are counted too
+        profile.add_comment("deployment: canary");
+        profile.record_dropped(&[3]);
+        let encoded = profile.serialize().unwrap();
+        assert_eq!(profile.encoded_len(), encoded.buffer.len());
+
+        profile.reset().expect("reset to succeed");
+        profile.set_start_time(an_hour_ago());
+        let encoded = profile.serialize().unwrap();
+        assert_eq!(profile.encoded_len(), encoded.buffer.len());
+    }
+
+    thread_local! {
+        /// The time `test_clock` reads, for tests to step it.
+        static TEST_TIME: Cell<(SystemTime, Instant)> =
+            Cell::new((SystemTime::now(), Instant::now()));
+    }
+
+    fn test_clock() -> (SystemTime, Instant) {
+        TEST_TIME.with(Cell::get)
+    }
+
+    #[test]
+    fn backward_wall_clock_step() {
+        let sample_types = vec![api::ValueType {
+            r#type: "samples",
+            unit: "count",
+        }];
+        let (start, started_at) = test_clock();
+        let mut profile = Profile::builder()
+            .sample_types(sample_types)
+            .clock(test_clock)
+            .build();
+        assert_eq!(profile.start_time, start);
+
+        // Ten seconds later, the wall clock is stepped back an hour
+        let stepped = start - Duration::from_secs(3600);
+        let ten_seconds = Duration::from_secs(10);
+        TEST_TIME.with(|time| time.set((stepped, started_at + ten_seconds)));
+
+        let encoded = profile.serialize().unwrap();
+        assert_eq!(encoded.start, start);
+        assert_eq!(encoded.end, start + ten_seconds);
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        let start_nanos = start
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as i64;
+        assert_eq!(decoded.time_nanos, start_nanos);
+        assert_eq!(decoded.duration_nanos, ten_seconds.as_nanos() as i64);
+
+        // The next profile starts at the stepped time, and its duration is
+        // measured from then
+        let mut buf = Vec::new();
+        let (flushed_start, end) = profile.flush(stepped, &mut buf).unwrap();
+        assert_eq!((flushed_start, end), (start, start + ten_seconds));
+        assert_eq!(profile.start_time, stepped);
+        assert_eq!(profile.started_at, started_at + ten_seconds);
+
+        profile.reset().unwrap();
+        assert_eq!(profile.start_time, stepped);
+        assert_eq!(profile.started_at, started_at + ten_seconds);
+    }
+
+    #[test]
+    fn flush() {
+        let mut profile = provide_distinct_locations();
+        let sample_types = profile.sample_types.clone();
+        let first_start = profile.start_time;
+        let mut buf = Vec::new();
+
+        let start = SystemTime::now() - Duration::from_secs(10);
+        let (flushed_start, end) = profile.flush(start, &mut buf).unwrap();
+        assert_eq!(flushed_start, first_start);
+        assert!(end >= first_start);
+        let decoded = pprof::Profile::decode(buf.as_slice()).unwrap();
+        assert_eq!(decoded.sample.len(), 2);
+
+        assert!(profile.samples.is_empty());
+        assert!(profile.locations.is_empty());
+        assert_eq!(profile.sample_types, sample_types);
+        assert_eq!(profile.start_time, start);
+        assert!(profile.started_at.elapsed() >= Duration::from_secs(10));
+
+        // The second flush reuses the buffer, replacing its contents
+        let capacity = buf.capacity();
+        let (flushed_start, _) = profile.flush(SystemTime::now(), &mut buf).unwrap();
+        assert_eq!(flushed_start, start);
+        assert!(buf.capacity() >= capacity);
+        let decoded = pprof::Profile::decode(buf.as_slice()).unwrap();
+        assert!(decoded.sample.is_empty());
+        assert!(decoded.duration_nanos >= 10_000_000_000);
+        assert_eq!(
+            decoded.time_nanos,
+            start
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as i64
+        );
+
+        // A start in the future is measured from as well, as the profile
+        // hasn't started yet
+        let start = SystemTime::now() + Duration::from_secs(3600);
+        profile.flush(start, &mut buf).unwrap();
+        assert_eq!(profile.start_time, start);
+        assert!(profile.started_at > Instant::now() + Duration::from_secs(3000));
+    }
+
     #[test]
     fn reset_period() {
         /* The previous test (reset) checked quite a few properties already, so
@@ -0,0 +1,81 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash};
+
+use rustc_hash::FxHasher;
+
+use crate::{api, AddError, PProfId, Profile};
+
+/// The ids of the locations of a profile, by a key of the caller's, such as
+/// the address of a native frame, so that frames seen before are neither
+/// built nor interned again. The ids go in `api::Sample::location_ids`.
+///
+/// Resetting the profile, or anything else which renumbers its locations
+/// such as `Profile::resymbolize`, empties the cache the next time it's
+/// used, as does using it with another profile. Keys must identify their
+/// location for as long as the profile isn't reset: a key which could
+/// stand for another location later, like the address of code which was
+/// unloaded, must be removed with `remove`.
+pub struct LocationCache<K> {
+    ids: HashMap<K, PProfId, BuildHasherDefault<FxHasher>>,
+    /// The locations epoch of the profile the ids are from.
+    epoch: Option<u64>,
+}
+
+impl<K: Eq + Hash> LocationCache<K> {
+    pub fn new() -> Self {
+        Self {
+            ids: Default::default(),
+            epoch: None,
+        }
+    }
+
+    /// Returns the id of the location cached for `key`, or else adds the
+    /// location returned by `location` to the profile, like
+    /// `Profile::add_location`, and caches its id. Nothing is cached if
+    /// adding the location fails.
+    pub fn get_or_add<'a, F>(
+        &mut self,
+        profile: &mut Profile,
+        key: K,
+        location: F,
+    ) -> Result<PProfId, AddError>
+    where
+        F: FnOnce() -> api::Location<'a>,
+    {
+        let epoch = profile.locations_epoch();
+        if self.epoch != Some(epoch) {
+            self.ids.clear();
+            self.epoch = Some(epoch);
+        }
+        if let Some(id) = self.ids.get(&key) {
+            return Ok(*id);
+        }
+        let id = profile.add_location(location())?;
+        self.ids.insert(key, id);
+        Ok(id)
+    }
+
+    /// Forgets the location of `key`, if it was cached.
+    pub fn remove(&mut self, key: &K) {
+        self.ids.remove(key);
+    }
+
+    /// The number of locations cached, which may have been invalidated by
+    /// the profile since.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl<K: Eq + Hash> Default for LocationCache<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,270 @@
+// Unless explicitly stated otherwise all files in this repository are licensed under the Apache License Version 2.0.
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
+
+//! Importing the stack samples printed by Linux `perf script`, which look
+//! like this, leaf first, with a blank line after each sample:
+//!
+//! ```text
+//! app 1234/1235 [002] 5087.420137:     250000 cpu-clock:
+//!             55d0c0ffee00 work+0x20 (/usr/bin/app)
+//!             7f1c2a3b4c5d [unknown] (/usr/lib/libc.so.6)
+//! ```
+//!
+//! The cpu and the timestamp are optional, and so is the period, which is
+//! taken to be 1 when missing.
+
+use core::fmt;
+
+use crate::{api, AddError, Profile};
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The line, counting from 1, should start a sample but has no pid.
+    InvalidHeader(usize),
+    /// The line, counting from 1, should be a frame but has no address.
+    InvalidFrame(usize),
+    /// A sample couldn't be added to the profile.
+    Add(AddError),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidHeader(line) => write!(f, "line {}: invalid sample header", line),
+            ParseError::InvalidFrame(line) => write!(f, "line {}: invalid stack frame", line),
+            ParseError::Add(err) => write!(f, "cannot add sample: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Add(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<AddError> for ParseError {
+    fn from(err: AddError) -> Self {
+        ParseError::Add(err)
+    }
+}
+
+struct Header {
+    comm: String,
+    pid: i64,
+    tid: Option<i64>,
+    period: i64,
+}
+
+struct Frame<'a> {
+    address: u64,
+    symbol: &'a str,
+    dso: &'a str,
+}
+
+fn is_pid(token: &str) -> bool {
+    let mut ids = token.splitn(2, '/');
+    ids.all(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn is_timestamp(token: &str) -> bool {
+    matches!(token.strip_suffix(':'), Some(time) if time.parse::<f64>().is_ok())
+}
+
+/// Parses `comm pid[/tid] [cpu] [time:] [period] event:`, where the command
+/// name may contain spaces.
+fn parse_header(text: &str) -> Option<Header> {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let pid_index = (1..tokens.len()).find(|&i| is_pid(tokens[i]))?;
+    let mut ids = tokens[pid_index].splitn(2, '/');
+    let pid = ids.next()?.parse().ok()?;
+    let tid = match ids.next() {
+        Some(tid) => Some(tid.parse().ok()?),
+        None => None,
+    };
+
+    let mut rest = tokens[pid_index + 1..].iter().peekable();
+    if matches!(rest.peek(), Some(token) if token.starts_with('[')) {
+        rest.next();
+    }
+    if matches!(rest.peek(), Some(token) if is_timestamp(token)) {
+        rest.next();
+    }
+    let period = match rest.peek().map(|token| token.parse::<i64>()) {
+        Some(Ok(period)) => period,
+        _ => 1,
+    };
+
+    Some(Header {
+        comm: tokens[..pid_index].join(" "),
+        pid,
+        tid,
+        period,
+    })
+}
+
+/// Parses `address symbol[+offset] (dso)`, where the symbol may contain
+/// spaces and the dso may be missing.
+fn parse_frame(text: &str) -> Option<Frame<'_>> {
+    let text = text.trim();
+    let (address, rest) = match text.split_once(char::is_whitespace) {
+        Some((address, rest)) => (address, rest.trim()),
+        None => (text, ""),
+    };
+    let address = u64::from_str_radix(address, 16).ok()?;
+
+    let (symbol, dso) = match rest.rfind(" (") {
+        Some(index) if rest.ends_with(')') => (&rest[..index], &rest[index + 2..rest.len() - 1]),
+        _ => (rest, ""),
+    };
+    let symbol = match symbol.rfind("+0x") {
+        Some(index) => &symbol[..index],
+        None => symbol,
+    };
+    Some(Frame {
+        address,
+        symbol: if symbol.is_empty() {
+            "[unknown]"
+        } else {
+            symbol
+        },
+        dso,
+    })
+}
+
+impl Profile {
+    /// Builds a profile from the text printed by `perf script`, with one
+    /// sample type whose values are the periods of the samples. Each frame
+    /// becomes a location with its address, its dso as mapping and its
+    /// symbol as function, without the offset; unsymbolized frames keep the
+    /// "[unknown]" name, so `resymbolize` can resolve them later. Samples
+    /// are labelled with the "thread name", "pid" and "thread id" of perf.
+    pub fn from_perf_script(
+        text: &str,
+        sample_type: api::ValueType,
+    ) -> Result<Profile, ParseError> {
+        let mut profile = Profile::builder().sample_types(vec![sample_type]).build();
+
+        let mut lines = text.lines().enumerate().peekable();
+        while let Some((index, line)) = lines.next() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let header = parse_header(line).ok_or(ParseError::InvalidHeader(index + 1))?;
+
+            let mut frames = Vec::new();
+            while let Some((index, line)) = lines.next_if(|(_, line)| !line.trim().is_empty()) {
+                frames.push(parse_frame(line).ok_or(ParseError::InvalidFrame(index + 1))?);
+            }
+
+            let locations = frames
+                .iter()
+                .map(|frame| api::Location {
+                    mapping: api::Mapping {
+                        filename: frame.dso,
+                        ..Default::default()
+                    },
+                    address: frame.address,
+                    lines: vec![api::Line {
+                        function: api::Function {
+                            name: frame.symbol,
+                            ..Default::default()
+                        },
+                        line: 0,
+                    }],
+                    ..Default::default()
+                })
+                .collect();
+            let mut labels = vec![
+                api::Label::str("thread name", &header.comm),
+                api::Label::num("pid", header.pid, None),
+            ];
+            if let Some(tid) = header.tid {
+                labels.push(api::Label::num("thread id", tid, None));
+            }
+            profile.add(api::Sample {
+                locations,
+                values: vec![header.period],
+                labels,
+                ..Default::default()
+            })?;
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pprof;
+    use prost::Message;
+
+    const SAMPLE_TYPE: api::ValueType = api::ValueType {
+        r#type: "cpu-time",
+        unit: "nanoseconds",
+    };
+
+    #[test]
+    fn perf_script_samples() {
+        let text = "\
+# ========
+# captured on    : Thu Jan  1 00:00:00 2022
+# ========
+app 1234/1235 [002] 5087.420137:     250000 cpu-clock:
+\t    55d0c0ffee20 work+0x20 (/usr/bin/app)
+\t    55d0c0ffee00 main+0x10 (/usr/bin/app)
+
+app 1234/1235 [002] 5087.420387:     250000 cpu-clock:
+\t    55d0c0ffee20 work+0x20 (/usr/bin/app)
+\t    55d0c0ffee00 main+0x10 (/usr/bin/app)
+
+Web Content 4321 5087.421000: cpu-clock:
+\t    7f1c2a3b4c5d [unknown] (/usr/lib/libc.so.6)
+\t    55d0c0ffee00 main+0x10 (/usr/bin/app)
+";
+        let profile = Profile::from_perf_script(text, SAMPLE_TYPE).unwrap();
+
+        // The first two samples have the same stack and labels
+        assert_eq!(profile.summary().sample_count, 2);
+        assert_eq!(
+            profile.to_folded(0).unwrap(),
+            "main;work 500000\nmain;[unknown] 1\n"
+        );
+        assert_eq!(
+            profile.breakdown_by_label("thread name", 0),
+            vec![("app".to_owned(), 500000), ("Web Content".to_owned(), 1)]
+        );
+        assert_eq!(
+            profile.breakdown_by_label("thread id", 0),
+            vec![("1235".to_owned(), 500000), ("".to_owned(), 1)]
+        );
+
+        let encoded = profile.serialize().unwrap();
+        let decoded = pprof::Profile::decode(encoded.buffer.as_slice()).unwrap();
+        let unknown = decoded
+            .location
+            .iter()
+            .find(|location| location.address == 0x7f1c2a3b4c5d)
+            .unwrap();
+        let mapping = &decoded.mapping[unknown.mapping_id as usize - 1];
+        assert_eq!(
+            decoded.string_table[mapping.filename as usize],
+            "/usr/lib/libc.so.6"
+        );
+    }
+
+    #[test]
+    fn invalid_perf_script() {
+        assert!(matches!(
+            Profile::from_perf_script("cpu-clock:\n", SAMPLE_TYPE),
+            Err(ParseError::InvalidHeader(1))
+        ));
+        assert!(matches!(
+            Profile::from_perf_script("app 1234 cpu-clock:\n\tmain (/usr/bin/app)\n", SAMPLE_TYPE),
+            Err(ParseError::InvalidFrame(2))
+        ));
+    }
+}
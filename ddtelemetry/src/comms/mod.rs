@@ -3,11 +3,13 @@
 
 use reqwest::{header, Body, IntoUrl, Response};
 
+use crate::config::Auth;
+
 // TODO: extract the reqwest to allow exchange for alternative implementations, in cases like wasm
 pub async fn request<B: Into<Body>, T: IntoUrl>(
     url: T,
     body: B,
-    api_key: Option<&str>,
+    auth: &Auth,
 ) -> anyhow::Result<Response> {
     let client = reqwest::ClientBuilder::new()
         .danger_accept_invalid_certs(true)
@@ -18,12 +20,11 @@ pub async fn request<B: Into<Body>, T: IntoUrl>(
             header::CONTENT_TYPE,
             header::HeaderValue::from_static("application/json"),
         )
-        .body(body);
-    if let Some(api_key) = api_key {
-        req = req.header("DD-API-KEY", api_key)
-    }
+        .body(body)
+        .build()?;
+    auth.apply(req.headers_mut())?;
 
-    let res = client.execute(req.build()?).await?;
+    let res = client.execute(req).await?;
 
     Ok(res)
 }
@@ -2,7 +2,8 @@
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021-Present Datadog, Inc.
 
 use lazy_static::lazy_static;
-use std::env;
+use reqwest::header;
+use std::{env, fmt};
 
 pub const DEFAULT_DD_SITE: &str = "datadoghq.com";
 pub const PROD_INTAKE_FORMAT_PREFIX: &str = "https://instrumentation-telemetry-intake";
@@ -14,8 +15,42 @@ const AGENT_TELEMETRY_URL_PATH: &str = "/telemetry/proxy/api/v2/apmtelemetry";
 const DEFAULT_AGENT_HOST: &str = "localhost";
 const DEFAULT_AGENT_PORT: u16 = 8126;
 
+/// How requests to the telemetry endpoint are authenticated.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Auth {
+    /// Requests go through the agent, which authenticates them itself.
+    Agent,
+    /// Requests go directly to the intake, with this api key.
+    ApiKey(String),
+}
+
+impl Auth {
+    /// Adds the headers authenticating a request to `headers`.
+    pub fn apply(&self, headers: &mut header::HeaderMap) -> anyhow::Result<()> {
+        match self {
+            Auth::Agent => {}
+            Auth::ApiKey(api_key) => {
+                let mut value = header::HeaderValue::from_str(api_key)?;
+                value.set_sensitive(true);
+                headers.insert("DD-API-KEY", value);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::Agent => write!(f, "Agent"),
+            Auth::ApiKey(_) => write!(f, "ApiKey(<redacted>)"),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Config {
-    api_key: Option<String>,
+    auth: Auth,
     #[allow(dead_code)]
     agent_url: String,
     telemetry_url: String,
@@ -60,16 +95,20 @@ impl Config {
         &CFG
     }
     pub fn read_env_config() -> Self {
-        let api_key = env::var("DD_API_KEY").ok().filter(|p| !p.is_empty());
+        let auth = match env::var("DD_API_KEY").ok().filter(|p| !p.is_empty()) {
+            Some(api_key) => Auth::ApiKey(api_key),
+            None => Auth::Agent,
+        };
         let agent_url = get_agent_base_url();
-        let telemetry_url = if api_key.is_some() {
-            let telemetry_intake_base_url = get_intake_base_url();
-            format!("{}{}", telemetry_intake_base_url, DIRECT_TELEMETRY_URL_PATH)
-        } else {
-            format!("{}{}", &agent_url, AGENT_TELEMETRY_URL_PATH)
+        let telemetry_url = match auth {
+            Auth::ApiKey(_) => {
+                let telemetry_intake_base_url = get_intake_base_url();
+                format!("{}{}", telemetry_intake_base_url, DIRECT_TELEMETRY_URL_PATH)
+            }
+            Auth::Agent => format!("{}{}", &agent_url, AGENT_TELEMETRY_URL_PATH),
         };
         Config {
-            api_key,
+            auth,
             agent_url,
             telemetry_url,
             telemetry_debug_logging_enabled: false,
@@ -80,8 +119,8 @@ impl Config {
         self.telemetry_debug_logging_enabled
     }
 
-    pub fn api_key(&self) -> Option<&str> {
-        self.api_key.as_deref()
+    pub fn auth(&self) -> &Auth {
+        &self.auth
     }
 
     pub fn telemetry_url(&self) -> &str {
@@ -89,6 +128,37 @@ impl Config {
     }
 
     pub fn is_direct(&self) -> bool {
-        self.api_key.is_some() // If API key is provided call directly
+        matches!(self.auth, Auth::ApiKey(_)) // If API key is provided call directly
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_headers() {
+        let mut headers = header::HeaderMap::new();
+        Auth::Agent.apply(&mut headers).unwrap();
+        assert!(headers.get("DD-API-KEY").is_none());
+
+        Auth::ApiKey("0123456789abcdef".into())
+            .apply(&mut headers)
+            .unwrap();
+        assert_eq!(headers.get("DD-API-KEY").unwrap(), "0123456789abcdef");
+    }
+
+    #[test]
+    fn test_auth_debug_redacts_api_key() {
+        let config = Config {
+            auth: Auth::ApiKey("0123456789abcdef".into()),
+            agent_url: String::new(),
+            telemetry_url: String::new(),
+            telemetry_debug_logging_enabled: false,
+        };
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("0123456789abcdef"), "{}", debug);
+        assert!(debug.contains("ApiKey(<redacted>)"), "{}", debug);
+        assert_eq!(format!("{:?}", Auth::Agent), "Agent");
     }
 }
@@ -51,6 +51,8 @@ pub struct Host {
     pub kernel_name: Option<String>,
     pub kernel_release: Option<String>,
     pub kernel_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
 }
 
 impl Application {
@@ -16,6 +16,40 @@ pub struct CounterGauge {
 pub enum Metric {
     #[serde(rename = "gauge")]
     Gauge(CounterGauge),
-    #[serde(rename = "gauge")]
+    #[serde(rename = "count")]
     Counter(CounterGauge),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point() -> CounterGauge {
+        CounterGauge {
+            metric: "spans_created".into(),
+            points: vec![(1_650_000_000, 3.0)],
+            tags: vec!["integration:rails".into()],
+            common: true,
+        }
+    }
+
+    #[test]
+    fn test_metric_type_tag() {
+        // A tag shared by two variants makes the second one unreachable when
+        // deserializing, which serde_derive reports as a warning.
+        for (metric, tag) in [
+            (Metric::Gauge(point()), "gauge"),
+            (Metric::Counter(point()), "count"),
+        ] {
+            let json = serde_json::to_value(&metric).unwrap();
+            assert_eq!(json["type"], tag);
+            assert_eq!(json["metric"], "spans_created");
+
+            let metric: Metric = serde_json::from_value(json).unwrap();
+            match (metric, tag) {
+                (Metric::Gauge(_), "gauge") | (Metric::Counter(_), "count") => {}
+                (metric, _) => panic!("{:?} deserialized from a {:?} tag", metric, tag),
+            }
+        }
+    }
+}
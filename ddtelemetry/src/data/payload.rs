@@ -3,6 +3,7 @@
 
 use crate::data::*;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "request_type", content = "payload")]
@@ -22,3 +23,197 @@ pub enum Payload {
     #[serde(rename = "logs")]
     Logs(Vec<Log>),
 }
+
+/// Returned when a payload is over the size limit and can't be split into
+/// smaller ones, either because of its type or because a single item is over
+/// the limit.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PayloadTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "telemetry payload of {} bytes can't be split to fit in {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+impl Payload {
+    /// Splits the payload into payloads which serialize to at most
+    /// `max_size` bytes each, by spreading the dependencies of
+    /// `AppDependenciesLoaded`, the series of `GenerateMetrics` or the logs
+    /// among them. Payloads which already fit are returned as they are, other
+    /// payloads fail with `PayloadTooLarge`.
+    pub fn split(self, max_size: usize) -> anyhow::Result<Vec<Payload>> {
+        let size = serde_json::to_vec(&self)?.len();
+        if size <= max_size {
+            return Ok(vec![self]);
+        }
+
+        match self {
+            Payload::AppDependenciesLoaded(AppDependenciesLoaded { dependencies }) => {
+                let empty = Payload::AppDependenciesLoaded(AppDependenciesLoaded {
+                    dependencies: Vec::new(),
+                });
+                let chunks = chunk(dependencies, serde_json::to_vec(&empty)?.len(), max_size)?;
+                Ok(chunks
+                    .into_iter()
+                    .map(|dependencies| {
+                        Payload::AppDependenciesLoaded(AppDependenciesLoaded { dependencies })
+                    })
+                    .collect())
+            }
+            Payload::GenerateMetrics(GenerateMetrics {
+                namespace,
+                lib_language,
+                lib_version,
+                series,
+            }) => {
+                let metrics = |series| {
+                    Payload::GenerateMetrics(GenerateMetrics {
+                        namespace: namespace.clone(),
+                        lib_language: lib_language.clone(),
+                        lib_version: lib_version.clone(),
+                        series,
+                    })
+                };
+                let envelope = serde_json::to_vec(&metrics(Vec::new()))?.len();
+                let chunks = chunk(series, envelope, max_size)?;
+                Ok(chunks.into_iter().map(metrics).collect())
+            }
+            Payload::Logs(logs) => {
+                let envelope = serde_json::to_vec(&Payload::Logs(Vec::new()))?.len();
+                let chunks = chunk(logs, envelope, max_size)?;
+                Ok(chunks.into_iter().map(Payload::Logs).collect())
+            }
+            _ => Err(PayloadTooLarge {
+                size,
+                limit: max_size,
+            }
+            .into()),
+        }
+    }
+}
+
+/// Groups `items` so each group serializes to at most `max_size` bytes within
+/// an envelope which takes `envelope` bytes with an empty list.
+fn chunk<T: Serialize>(
+    items: Vec<T>,
+    envelope: usize,
+    max_size: usize,
+) -> anyhow::Result<Vec<Vec<T>>> {
+    let mut chunks = Vec::new();
+    let mut current = Vec::new();
+    let mut size = envelope;
+    for item in items {
+        let item_size = serde_json::to_vec(&item)?.len();
+        if envelope + item_size > max_size {
+            return Err(PayloadTooLarge {
+                size: envelope + item_size,
+                limit: max_size,
+            }
+            .into());
+        }
+        // Items after the first one are preceded by a comma
+        if !current.is_empty() && size + 1 + item_size > max_size {
+            chunks.push(std::mem::take(&mut current));
+            size = envelope;
+        }
+        if !current.is_empty() {
+            size += 1;
+        }
+        size += item_size;
+        current.push(item);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dependency(index: usize) -> Dependency {
+        Dependency {
+            name: format!("dependency-{:03}", index),
+            version: Some("1.0.0".into()),
+            hash: None,
+            type_: DependencyType::PlatformStandard,
+        }
+    }
+
+    fn dependencies(payload: &Payload) -> &[Dependency] {
+        match payload {
+            Payload::AppDependenciesLoaded(loaded) => &loaded.dependencies,
+            _ => panic!("unexpected payload {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn test_split_dependencies() {
+        let payload = Payload::AppDependenciesLoaded(AppDependenciesLoaded {
+            dependencies: (0..100).map(dependency).collect(),
+        });
+
+        // Room for exactly 10 dependencies per payload
+        let empty = Payload::AppDependenciesLoaded(AppDependenciesLoaded {
+            dependencies: vec![],
+        });
+        let envelope = serde_json::to_vec(&empty).unwrap().len();
+        let item = serde_json::to_vec(&dependency(0)).unwrap().len();
+        let max_size = envelope + 10 * item + 9;
+
+        let payloads = payload.split(max_size).unwrap();
+        assert_eq!(payloads.len(), 10);
+        for (index, payload) in payloads.iter().enumerate() {
+            assert!(serde_json::to_vec(payload).unwrap().len() <= max_size);
+            let names: Vec<_> = dependencies(payload).iter().map(|d| &d.name).collect();
+            assert_eq!(names.len(), 10);
+            assert_eq!(*names[0], format!("dependency-{:03}", index * 10));
+        }
+
+        // One byte less and the last dependency of each payload is pushed to
+        // the next one
+        let payload = Payload::AppDependenciesLoaded(AppDependenciesLoaded {
+            dependencies: (0..100).map(dependency).collect(),
+        });
+        assert_eq!(payload.split(max_size - 1).unwrap().len(), 12);
+    }
+
+    #[test]
+    fn test_split_small_payload() {
+        let payload = Payload::AppDependenciesLoaded(AppDependenciesLoaded {
+            dependencies: vec![dependency(0)],
+        });
+        let payloads = payload.split(1024).unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(dependencies(&payloads[0]).len(), 1);
+    }
+
+    #[test]
+    fn test_split_too_large() {
+        let payload = Payload::AppDependenciesLoaded(AppDependenciesLoaded {
+            dependencies: vec![dependency(0), dependency(1)],
+        });
+        let err = payload.split(40).unwrap_err();
+        let err = err.downcast::<PayloadTooLarge>().unwrap();
+        assert_eq!(err.limit, 40);
+
+        // Other payloads can't be split at all
+        let payload = Payload::AppStarted(AppStarted {
+            integrations: vec![],
+            dependencies: (0..10).map(dependency).collect(),
+            config: vec![],
+        });
+        assert!(payload.split(40).unwrap_err().is::<PayloadTooLarge>());
+    }
+}
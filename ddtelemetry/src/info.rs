@@ -14,4 +14,44 @@ pub mod os {
     pub fn os_version() -> anyhow::Result<String> {
         sys_info::os_release().map_err(|e| e.into())
     }
+
+    pub fn architecture() -> anyhow::Result<String> {
+        Ok(String::from(std::env::consts::ARCH))
+    }
+
+    /// Returns the kernel's build version, only known on Linux.
+    pub fn kernel_version() -> anyhow::Result<String> {
+        #[cfg(target_os = "linux")]
+        {
+            let version = std::fs::read_to_string("/proc/sys/kernel/version")?;
+            Ok(String::from(version.trim()))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            Err(anyhow::anyhow!("the kernel version is only known on Linux"))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_architecture() {
+            let architecture = architecture().unwrap();
+            #[cfg(target_arch = "x86_64")]
+            assert_eq!(architecture, "x86_64");
+            #[cfg(target_arch = "aarch64")]
+            assert_eq!(architecture, "aarch64");
+            assert!(!architecture.is_empty());
+        }
+
+        #[test]
+        fn test_kernel_version() {
+            #[cfg(target_os = "linux")]
+            assert!(!kernel_version().unwrap().is_empty());
+            #[cfg(not(target_os = "linux"))]
+            assert!(kernel_version().is_err());
+        }
+    }
 }
@@ -64,7 +64,8 @@ pub async fn build_host() -> data::Host {
         os_version: info::os::os_version().ok(),
         kernel_name: None,
         kernel_release: None,
-        kernel_version: None,
+        kernel_version: info::os::kernel_version().ok(),
+        architecture: info::os::architecture().ok(),
     }
 }
 
@@ -106,12 +107,7 @@ pub async fn build_full(header: &mut Header) -> Telemetry<'_> {
 
 pub async fn push_telemetry(telemetry: &Telemetry<'_>) -> anyhow::Result<()> {
     let url = Config::get().telemetry_url();
-    let resp = request(
-        url,
-        serde_json::to_string(telemetry)?,
-        Config::get().api_key(),
-    )
-    .await?;
+    let resp = request(url, serde_json::to_string(telemetry)?, Config::get().auth()).await?;
     if !resp.status().is_success() {
         Err(anyhow::Error::msg(format!(
             "Telemetry error: response status: {}",
@@ -20,6 +20,7 @@ use anyhow::Result;
 use reqwest::{blocking, header};
 
 const TELEMETRY_HEARBEAT_DELAY: time::Duration = time::Duration::from_secs(30);
+const DEFAULT_MAX_PAYLOAD_SIZE: usize = 5 * 1024 * 1024;
 
 macro_rules! telemetry_worker_log {
     ($worker:expr , ERROR , $fmt_str:tt, $($arg:tt)*) => {
@@ -86,6 +87,7 @@ pub struct TelemetryWorker {
     client: blocking::Client,
     deadlines: Scheduler,
     data: TelemetryWorkerData,
+    max_payload_size: usize,
 }
 
 impl TelemetryWorker {
@@ -246,23 +248,50 @@ impl TelemetryWorker {
         self.seq_id
     }
 
+    /// Sends the payload, split into as many requests as needed to keep
+    /// their bodies under `max_payload_size`.
     fn send_payload(&mut self, payload: data::Payload) -> Result<()> {
-        let seq_id = self.next_seq_id();
+        let tracer_time = time::SystemTime::now()
+            .duration_since(time::SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let envelope_size = self.envelope_size(tracer_time)?;
+        let payloads = payload.split(self.max_payload_size.saturating_sub(envelope_size))?;
+
+        for payload in payloads {
+            let seq_id = self.next_seq_id();
+            let tel = Telemetry {
+                api_version: DEFAULT_API_VERSION,
+                tracer_time,
+                runtime_id: &self.runtime_id,
+                seq_id,
+                host: &self.data.host,
+                application: &self.data.app,
+                payload,
+            };
+            telemetry_worker_log!(self, DEBUG, "Sending payload: {:?}", tel);
+
+            self.push_telemetry(&tel)?;
+        }
+        Ok(())
+    }
+
+    /// The size of a request body besides its payload. The payload's fields
+    /// are flattened into the body, so this doesn't depend on the payload.
+    /// The largest possible seq_id is assumed, so it can't be underestimated.
+    fn envelope_size(&self, tracer_time: u64) -> Result<usize> {
+        let payload = data::Payload::AppHearbeat(());
+        let payload_size = serde_json::to_vec(&payload)?.len();
         let tel = Telemetry {
             api_version: DEFAULT_API_VERSION,
-            tracer_time: time::SystemTime::now()
-                .duration_since(time::SystemTime::UNIX_EPOCH)
-                .map(|d| d.as_secs())
-                .unwrap_or(0),
+            tracer_time,
             runtime_id: &self.runtime_id,
-            seq_id,
+            seq_id: u64::MAX,
             host: &self.data.host,
             application: &self.data.app,
             payload,
         };
-        telemetry_worker_log!(self, DEBUG, "Sending payload: {:?}", tel);
-
-        self.push_telemetry(&tel)
+        Ok(serde_json::to_vec(&tel)?.len() - payload_size)
     }
 
     fn push_telemetry(&self, payload: &Telemetry) -> Result<()> {
@@ -275,10 +304,7 @@ impl TelemetryWorker {
             header::CONTENT_TYPE,
             header::HeaderValue::from_static("application/json"),
         );
-        if let Some(api_key) = self.config.api_key() {
-            req.headers_mut()
-                .insert("DD-API-KEY", header::HeaderValue::from_str(api_key)?);
-        }
+        self.config.auth().apply(req.headers_mut())?;
 
         let body = serde_json::to_vec(&payload)?;
         *req.body_mut() = Some(blocking::Body::from(body));
@@ -385,6 +411,8 @@ pub struct TelemetryWorkerBuilder {
     pub library_config: Vec<(String, String)>,
     pub native_deps: bool,
     pub rust_shared_lib_deps: bool,
+    /// Requests with larger bodies are split into several requests.
+    pub max_payload_size: usize,
 }
 
 impl TelemetryWorkerBuilder {
@@ -407,6 +435,7 @@ impl TelemetryWorkerBuilder {
             library_config: Vec::new(),
             native_deps: true,
             rust_shared_lib_deps: false,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
         }
     }
 
@@ -433,6 +462,7 @@ impl TelemetryWorkerBuilder {
             library_config: Vec::new(),
             native_deps: true,
             rust_shared_lib_deps: false,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
         }
     }
 
@@ -468,6 +498,7 @@ impl TelemetryWorkerBuilder {
                     .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
                 client: reqwest::blocking::Client::new(),
                 deadlines: Scheduler::new(),
+                max_payload_size: self.max_payload_size,
             };
             worker.run();
             worker_shutdown.shutdown_finished();
@@ -44,6 +44,10 @@ int main(int argc, char *argv[]) {
   const ddprof_ffi_Period period = {wall_time, 60};
   std::unique_ptr<ddprof_ffi_Profile, Deleter> profile{
       ddprof_ffi_Profile_new(sample_types, &period)};
+  if (!profile) {
+    printf("Failed to create the profile\n");
+    return 1;
+  }
 
   ddprof_ffi_Line root_line = {
       .function =